@@ -0,0 +1,84 @@
+use rocket::tokio::sync::{Mutex, RwLock};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::{AppleAuthKey, AppleAuthResponse, CredentialsError};
+
+const APPLE_KEYS_URL: &str = "https://appleid.apple.com/auth/keys";
+
+// Apple rotates its keys rarely, and a log in with an unknown kid refetches them anyway
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+// Also the least time between fetches, so made-up kids can't have us hammer Apple
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+// Apple's public keys for Sign in with Apple, fetched once instead of on every log in
+#[derive(Debug, Clone)]
+pub struct AppleKeys {
+    keys: Arc<RwLock<Option<Vec<AppleAuthKey>>>>,
+    // Held for the whole fetch, so log ins that miss at the same time share one
+    last_fetch: Arc<Mutex<Option<Instant>>>,
+}
+
+impl AppleKeys {
+    pub fn new() -> AppleKeys {
+        AppleKeys { keys: Arc::new(RwLock::new(None)), last_fetch: Arc::new(Mutex::new(None)) }
+    }
+
+    pub async fn is_warm(&self) -> bool {
+        self.keys.read().await.is_some()
+    }
+
+    pub async fn refresh(&self) -> Result<Vec<AppleAuthKey>, CredentialsError> {
+        let mut last_fetch = self.last_fetch.lock().await;
+        self.fetch(&mut last_fetch).await
+    }
+
+    async fn fetch(&self, last_fetch: &mut Option<Instant>) -> Result<Vec<AppleAuthKey>, CredentialsError> {
+        *last_fetch = Some(Instant::now());
+        let keys_response = match reqwest::get(APPLE_KEYS_URL).await {
+            Ok(_keys_response) => _keys_response,
+            Err(_keys_response) => return Err(CredentialsError::FetchKeys(_keys_response))
+        };
+        let deserialized_keys_response = match keys_response.json::<AppleAuthResponse>().await {
+            Ok(_deserialized_keys_response) => _deserialized_keys_response,
+            Err(_deserialized_keys_response) => return Err(CredentialsError::DeserializeJson(_deserialized_keys_response))
+        };
+
+        *self.keys.write().await = Some(deserialized_keys_response.keys.clone());
+        Ok(deserialized_keys_response.keys)
+    }
+
+    // The cached keys if one of them has this kid, otherwise whatever Apple serves now.
+    // Right after a fetch the cache is as fresh as it gets, so it's returned even without the kid.
+    pub async fn keys_with(&self, kid: &str) -> Result<Vec<AppleAuthKey>, CredentialsError> {
+        if let Some(keys) = self.cached_with(kid).await {
+            return Ok(keys)
+        }
+
+        let mut last_fetch = self.last_fetch.lock().await;
+        if last_fetch.is_some_and(|fetched_at| fetched_at.elapsed() < RETRY_INTERVAL) {
+            return Ok(self.keys.read().await.clone().unwrap_or_default())
+        }
+        self.fetch(&mut last_fetch).await
+    }
+
+    async fn cached_with(&self, kid: &str) -> Option<Vec<AppleAuthKey>> {
+        let keys = self.keys.read().await;
+        keys.as_ref().filter(|keys| keys.iter().any(|key| key.kid == kid)).cloned()
+    }
+}
+
+// Warms the cache at launch, so the first log in doesn't wait on Apple and /readyz can tell when it can't reach them
+pub async fn run_refresher(keys: AppleKeys) {
+    loop {
+        let next_refresh = match keys.refresh().await {
+            Ok(_) => REFRESH_INTERVAL,
+            Err(error) => {
+                warn!(?error, "Couldn't fetch Apple's keys");
+                RETRY_INTERVAL
+            },
+        };
+        rocket::tokio::time::sleep(next_refresh).await;
+    }
+}
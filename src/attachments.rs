@@ -0,0 +1,569 @@
+use mongodb::bson;
+use mongodb::error::Error;
+use rocket::State;
+use rocket::form::Form;
+use rocket::fs::TempFile;
+use rocket::http::{ContentType, Header, Status};
+use rocket::serde::{Serialize, Deserialize, json::Json};
+use rocket::tokio::io::{AsyncRead, AsyncWriteExt};
+use std::io;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::{error, warn};
+
+use crate::{Token, User};
+use crate::config::{AppConfig, AttachmentBackend, ConfigError};
+use crate::audit::RequestMeta;
+use crate::repository::{Repository, Store};
+use crate::undo::OperationLog;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Attachment {
+    _id: bson::oid::ObjectId,
+    user: bson::oid::ObjectId,
+    // Exactly one of task and event is set
+    task: Option<bson::oid::ObjectId>,
+    event: Option<bson::oid::ObjectId>,
+    file_name: Option<String>,
+    content_type: String,
+    // In bytes
+    size: i64,
+    storage_key: String,
+    // Only images get a thumbnail
+    thumbnail_key: Option<String>,
+    created_at: bson::DateTime,
+}
+
+// Uploads are served from our own origin, so anything a browser could run there is only ever a download
+const INLINE_CONTENT_TYPES: [&str; 5] = ["image/jpeg", "image/png", "image/gif", "image/webp", "image/heic"];
+
+#[derive(Responder)]
+pub struct AttachmentFile {
+    bytes: Vec<u8>,
+    content_type: ContentType,
+    content_disposition: Header<'static>,
+    content_type_options: Header<'static>,
+}
+
+impl AttachmentFile {
+    fn new(content_type: ContentType, bytes: Vec<u8>) -> AttachmentFile {
+        let media_type = format!("{}/{}", content_type.top(), content_type.sub()).to_lowercase();
+        let disposition = if INLINE_CONTENT_TYPES.contains(&media_type.as_str()) { "inline" } else { "attachment" };
+        AttachmentFile {
+            bytes,
+            content_type,
+            content_disposition: Header::new("Content-Disposition", disposition),
+            content_type_options: Header::new("X-Content-Type-Options", "nosniff"),
+        }
+    }
+}
+
+#[derive(FromForm)]
+pub struct AttachmentUpload<'r> {
+    file: TempFile<'r>,
+    task: Option<String>,
+    event: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct AttachmentUsage {
+    // Both in bytes
+    used: i64,
+    quota: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct DeletedAttachments {
+    #[serde(rename = "deletedCount")]
+    deleted_count: u64,
+}
+
+// How much each user has stored, kept separately so uploads can reserve space atomically
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct AttachmentUsageCounter {
+    // The user's id
+    _id: bson::oid::ObjectId,
+    // In bytes
+    pub used: i64,
+}
+
+#[derive(Debug)]
+pub enum AttachmentError {
+    // Neither or both of task and event, or one that isn't the user's
+    InvalidOwner,
+    QuotaExceeded,
+    NotFound,
+    Storage(io::Error),
+    Database(Error),
+}
+
+impl From<Error> for AttachmentError {
+    fn from(error: Error) -> Self {
+        AttachmentError::Database(error)
+    }
+}
+
+impl From<io::Error> for AttachmentError {
+    fn from(error: io::Error) -> Self {
+        AttachmentError::Storage(error)
+    }
+}
+
+// Where attachment bytes live; the attachments collection only keeps the metadata
+#[rocket::async_trait]
+pub trait AttachmentStorage: Send + Sync {
+    // Streams from the reader so uploads never have to sit in memory whole
+    async fn put(&self, key: &str, source: &mut (dyn AsyncRead + Send + Unpin)) -> io::Result<()>;
+    async fn get(&self, key: &str) -> io::Result<Vec<u8>>;
+    async fn delete(&self, key: &str) -> io::Result<()>;
+}
+
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+#[rocket::async_trait]
+impl AttachmentStorage for LocalStorage {
+    async fn put(&self, key: &str, source: &mut (dyn AsyncRead + Send + Unpin)) -> io::Result<()> {
+        let path = self.root.join(key);
+        if let Some(parent) = path.parent() {
+            rocket::tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut file = rocket::tokio::fs::File::create(path).await?;
+        rocket::tokio::io::copy(source, &mut file).await?;
+        file.flush().await
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        rocket::tokio::fs::read(self.root.join(key)).await
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match rocket::tokio::fs::remove_file(self.root.join(key)).await {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Works against AWS as well as S3-compatible stand-ins like MinIO through s3_endpoint
+#[cfg(feature = "s3")]
+pub struct S3Storage {
+    store: Arc<object_store::aws::AmazonS3>,
+}
+
+#[cfg(feature = "s3")]
+impl S3Storage {
+    fn from_config(config: &AppConfig) -> Result<S3Storage, object_store::Error> {
+        let mut builder = object_store::aws::AmazonS3Builder::from_env()
+            .with_bucket_name(&config.s3_bucket);
+        if let Some(endpoint) = &config.s3_endpoint {
+            builder = builder.with_endpoint(endpoint).with_allow_http(true);
+        }
+        if let Some(region) = &config.s3_region {
+            builder = builder.with_region(region);
+        }
+        if let Some(access_key_id) = &config.s3_access_key_id {
+            builder = builder.with_access_key_id(access_key_id);
+        }
+        if let Some(secret_access_key) = &config.s3_secret_access_key {
+            builder = builder.with_secret_access_key(secret_access_key);
+        }
+        Ok(S3Storage { store: Arc::new(builder.build()?) })
+    }
+}
+
+#[cfg(feature = "s3")]
+fn s3_error(error: object_store::Error) -> io::Error {
+    match error {
+        object_store::Error::NotFound { .. } => io::Error::new(io::ErrorKind::NotFound, error),
+        _ => io::Error::other(error),
+    }
+}
+
+#[cfg(feature = "s3")]
+#[rocket::async_trait]
+impl AttachmentStorage for S3Storage {
+    // Small files go up in one request, bigger ones as a multipart upload
+    async fn put(&self, key: &str, source: &mut (dyn AsyncRead + Send + Unpin)) -> io::Result<()> {
+        let path = object_store::path::Path::from(key);
+        let mut writer = object_store::buffered::BufWriter::new(self.store.clone(), path);
+        if let Err(error) = rocket::tokio::io::copy(source, &mut writer).await {
+            if let Err(abort_error) = writer.abort().await {
+                warn!(error = ?abort_error, "Couldn't abort attachment upload");
+            }
+            return Err(error)
+        }
+        writer.shutdown().await
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        use object_store::ObjectStore;
+        let path = object_store::path::Path::from(key);
+        let result = self.store.get(&path).await.map_err(s3_error)?;
+        let bytes = result.bytes().await.map_err(s3_error)?;
+        Ok(bytes.to_vec())
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        use object_store::ObjectStore;
+        let path = object_store::path::Path::from(key);
+        match self.store.delete(&path).await {
+            Ok(_) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+            Err(error) => Err(s3_error(error)),
+        }
+    }
+}
+
+// Cloned into the trash purger, which deletes the files of purged tasks and events
+#[derive(Clone)]
+pub struct Storage {
+    backend: Arc<dyn AttachmentStorage>,
+}
+
+impl Storage {
+    // AppConfig::problems already turned away s3 in builds without the feature
+    pub fn from_config(config: &AppConfig) -> Result<Storage, ConfigError> {
+        #[cfg(feature = "s3")]
+        if config.attachment_storage == AttachmentBackend::S3 {
+            return match S3Storage::from_config(config) {
+                Ok(backend) => Ok(Storage { backend: Arc::new(backend) }),
+                Err(error) => Err(ConfigError::InvalidError(vec![format!("s3_bucket: {}", error)])),
+            }
+        }
+
+        match config.attachment_storage {
+            AttachmentBackend::Local => Ok(Storage { backend: Arc::new(LocalStorage { root: PathBuf::from(&config.attachment_dir) }) }),
+            AttachmentBackend::S3 => Err(ConfigError::InvalidError(vec![String::from("attachment_storage: s3 needs a build with the s3 feature")])),
+        }
+    }
+}
+
+// Decoding big photos is slow, so this runs on the blocking pool
+fn make_thumbnail(path: &Path) -> Option<Vec<u8>> {
+    let image = image::ImageReader::open(path).ok()?.with_guessed_format().ok()?.decode().ok()?;
+    let thumbnail = image::DynamicImage::ImageRgb8(image.thumbnail(320, 320).to_rgb8());
+    let mut thumbnail_bytes = Cursor::new(Vec::new());
+    thumbnail.write_to(&mut thumbnail_bytes, image::ImageFormat::Jpeg).ok()?;
+    Some(thumbnail_bytes.into_inner())
+}
+
+// Metadata goes before the files so a failed storage delete leaves an unreferenced file rather than a broken link
+async fn remove_attachment(repository: &dyn Repository, storage: &Storage, attachment: &Attachment) -> Result<u64, AttachmentError> {
+    // Someone else deleted it first and already gave the space back
+    if !repository.delete_attachment(attachment._id).await? {
+        return Ok(0)
+    }
+    repository.release_attachment_bytes(attachment.user, attachment.size).await?;
+
+    storage.backend.delete(&attachment.storage_key).await?;
+    if let Some(thumbnail_key) = &attachment.thumbnail_key {
+        storage.backend.delete(thumbnail_key).await?;
+    }
+
+    Ok(1)
+}
+
+// Best effort, since a leftover file only costs storage
+async fn discard_files(storage: &Storage, attachment: &Attachment) {
+    let keys = std::iter::once(&attachment.storage_key).chain(attachment.thumbnail_key.as_ref());
+    for key in keys {
+        if let Err(error) = storage.backend.delete(key).await {
+            warn!(?error, attachment = %attachment._id, "Couldn't delete attachment file");
+        }
+    }
+}
+
+// For the trash purger: the tasks and events themselves are about to go, so nothing needs unlinking
+pub async fn purge_attachments(repository: &dyn Repository, storage: &Storage, task_ids: &[bson::oid::ObjectId], event_ids: &[bson::oid::ObjectId]) -> Result<(), Error> {
+    let attachments_to_purge = repository.find_owned_attachments(task_ids, event_ids).await?;
+
+    for attachment in attachments_to_purge {
+        match remove_attachment(repository, storage, &attachment).await {
+            Ok(_) => (),
+            Err(AttachmentError::Database(error)) => return Err(error),
+            Err(error) => warn!(?error, attachment = %attachment._id, "Couldn't delete purged attachment file"),
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_owner_id(id: &Option<String>) -> Result<Option<bson::oid::ObjectId>, AttachmentError> {
+    match id {
+        Some(_id) => match bson::oid::ObjectId::parse_str(_id) {
+            Ok(_object_id) => Ok(Some(_object_id)),
+            Err(_) => Err(AttachmentError::InvalidOwner),
+        },
+        None => Ok(None),
+    }
+}
+
+async fn create_attachment_action(token: Token, upload: AttachmentUpload<'_>, storage: &Storage, meta: &RequestMeta, store: &Store, config: &AppConfig) -> Result<Attachment, AttachmentError> {
+    let repository = store.backend.as_ref();
+
+    let user = token.0;
+
+    let task_id = parse_owner_id(&upload.task)?;
+    let event_id = parse_owner_id(&upload.event)?;
+    let owner_exists = match (task_id, event_id) {
+        (Some(_task_id), None) => repository.find_task(_task_id).await?.is_some_and(|task| task.user == Some(user._id) && task.deleted_at.is_none()),
+        (None, Some(_event_id)) => repository.find_event(_event_id).await?.is_some_and(|event| event.user == Some(user._id) && event.deleted_at.is_none()),
+        _ => false,
+    };
+    if !owner_exists {
+        return Err(AttachmentError::InvalidOwner)
+    }
+
+    let size = upload.file.len() as i64;
+    if !repository.reserve_attachment_bytes(user._id, size, config.attachment_quota_bytes()).await? {
+        return Err(AttachmentError::QuotaExceeded)
+    }
+
+    let attachment = store_attachment(repository, &user, task_id, event_id, upload, storage, meta).await;
+    if attachment.is_err() {
+        if let Err(error) = repository.release_attachment_bytes(user._id, size).await {
+            error!(?error, "Couldn't release reserved attachment space");
+        }
+    }
+
+    attachment
+}
+
+async fn store_attachment(repository: &dyn Repository, user: &User, task_id: Option<bson::oid::ObjectId>, event_id: Option<bson::oid::ObjectId>, upload: AttachmentUpload<'_>, storage: &Storage, meta: &RequestMeta) -> Result<Attachment, AttachmentError> {
+    let size = upload.file.len() as i64;
+    let content_type = upload.file.content_type().cloned().unwrap_or(ContentType::Binary);
+    // Only kept so the app can show it; storage keys are built from ids
+    let file_name = upload.file.raw_name().map(|name| name.dangerous_unsafe_unsanitized_raw().as_str().to_string());
+
+    let attachment_id = bson::oid::ObjectId::new();
+    let storage_key = format!("{}/{}", user._id.to_hex(), attachment_id.to_hex());
+
+    // Uploaded files always sit in a temporary file, which the thumbnail decoder reads straight from
+    let thumbnail = match upload.file.path() {
+        Some(path) if content_type.top() == "image" => {
+            let image_path = path.to_path_buf();
+            rocket::tokio::task::spawn_blocking(move || make_thumbnail(&image_path)).await.unwrap_or(None)
+        },
+        _ => None,
+    };
+    let thumbnail_key = match thumbnail {
+        Some(thumbnail_bytes) => {
+            let key = format!("{}-thumbnail", storage_key);
+            storage.backend.put(&key, &mut thumbnail_bytes.as_slice()).await?;
+            Some(key)
+        },
+        None => None,
+    };
+    storage.backend.put(&storage_key, &mut upload.file.open().await?).await?;
+
+    let attachment = Attachment {
+        _id: attachment_id,
+        user: user._id,
+        task: task_id,
+        event: event_id,
+        file_name,
+        content_type: content_type.to_string(),
+        size,
+        storage_key,
+        thumbnail_key,
+        created_at: bson::DateTime::now(),
+    };
+    let (owner_collection, owner_id) = match (task_id, event_id) {
+        (Some(_task_id), _) => ("tasks", _task_id),
+        (None, Some(_event_id)) => ("events", _event_id),
+        (None, None) => return Err(AttachmentError::InvalidOwner),
+    };
+    let mut operation = OperationLog::audit_only();
+    operation.snapshot_document(repository, owner_collection, owner_id).await?;
+
+    repository.insert_attachment(&attachment).await?;
+
+    // The owner was trashed since the upload started; the caller gives the reserved space back
+    let Some(linked_owner) = repository.link_attachment(owner_collection, owner_id, user._id, attachment._id).await? else {
+        repository.delete_attachment(attachment._id).await?;
+        discard_files(storage, &attachment).await;
+        return Err(AttachmentError::InvalidOwner)
+    };
+    operation.created("attachments", attachment._id, &attachment)?;
+    operation.wrote(owner_collection, owner_id, Some(linked_owner));
+    operation.record(repository, user._id, "create_attachment", meta).await;
+
+    Ok(attachment)
+}
+
+async fn read_attachments_action(token: Token, task: Option<bson::oid::ObjectId>, event: Option<bson::oid::ObjectId>, store: &Store) -> Result<Vec<Attachment>, AttachmentError> {
+    let repository = store.backend.as_ref();
+
+    let user = token.0;
+
+    Ok(repository.find_attachments(user._id, task, event).await?)
+}
+
+async fn read_attachment_usage_action(token: Token, store: &Store, config: &AppConfig) -> Result<AttachmentUsage, AttachmentError> {
+    let repository = store.backend.as_ref();
+
+    let user = token.0;
+
+    Ok(AttachmentUsage {
+        used: repository.attachment_bytes_used(user._id).await?,
+        quota: config.attachment_quota_bytes(),
+    })
+}
+
+async fn read_attachment_file_action(token: Token, attachment_id: bson::oid::ObjectId, thumbnail: bool, storage: &Storage, store: &Store) -> Result<AttachmentFile, AttachmentError> {
+    let repository = store.backend.as_ref();
+
+    let user = token.0;
+
+    let attachments_list = repository.find_attachments_by_ids(&[attachment_id]).await?;
+    let Some(attachment) = attachments_list.into_iter().find(|attachment| attachment.user == user._id) else {
+        return Err(AttachmentError::NotFound)
+    };
+
+    if thumbnail {
+        let Some(thumbnail_key) = attachment.thumbnail_key else {
+            return Err(AttachmentError::NotFound)
+        };
+        let bytes = storage.backend.get(&thumbnail_key).await?;
+        return Ok(AttachmentFile::new(ContentType::JPEG, bytes))
+    }
+
+    let content_type = ContentType::parse_flexible(&attachment.content_type).unwrap_or(ContentType::Binary);
+    let bytes = storage.backend.get(&attachment.storage_key).await?;
+    Ok(AttachmentFile::new(content_type, bytes))
+}
+
+async fn delete_attachments_action(token: Token, attachments_data: Vec<bson::oid::ObjectId>, storage: &Storage, meta: &RequestMeta, store: &Store) -> Result<DeletedAttachments, AttachmentError> {
+    let repository = store.backend.as_ref();
+
+    let user = token.0;
+
+    let mut attachments_to_delete = repository.find_attachments_by_ids(&attachments_data).await?;
+    attachments_to_delete.retain(|attachment| attachment.user == user._id);
+
+    let mut operation = OperationLog::audit_only();
+    for attachment in attachments_to_delete.iter() {
+        operation.snapshot_document(repository, "attachments", attachment._id).await?;
+        if let Some(task_id) = attachment.task {
+            operation.snapshot_document(repository, "tasks", task_id).await?;
+        }
+        if let Some(event_id) = attachment.event {
+            operation.snapshot_document(repository, "events", event_id).await?;
+        }
+    }
+
+    let mut deleted_count = 0;
+    for attachment in attachments_to_delete {
+        if let Some(task_id) = attachment.task {
+            let unlinked_task = repository.unlink_attachment("tasks", task_id, attachment._id).await?;
+            operation.wrote("tasks", task_id, unlinked_task);
+        }
+        if let Some(event_id) = attachment.event {
+            let unlinked_event = repository.unlink_attachment("events", event_id, attachment._id).await?;
+            operation.wrote("events", event_id, unlinked_event);
+        }
+        let removed_count = remove_attachment(repository, storage, &attachment).await?;
+        if removed_count > 0 {
+            operation.wrote("attachments", attachment._id, None);
+        }
+        deleted_count += removed_count;
+    }
+
+    operation.record(repository, user._id, "delete_attachments", meta).await;
+
+    Ok(DeletedAttachments {
+        deleted_count,
+    })
+}
+
+fn parse_query_id(id: Option<&str>) -> Result<Option<bson::oid::ObjectId>, Status> {
+    match id {
+        Some(_id) => match bson::oid::ObjectId::parse_str(_id) {
+            Ok(_object_id) => Ok(Some(_object_id)),
+            Err(_) => Err(Status::BadRequest),
+        },
+        None => Ok(None),
+    }
+}
+
+fn attachment_error_status(error: AttachmentError) -> Status {
+    match error {
+        AttachmentError::InvalidOwner => Status::UnprocessableEntity,
+        AttachmentError::QuotaExceeded => Status::InsufficientStorage,
+        AttachmentError::NotFound => Status::NotFound,
+        AttachmentError::Storage(ref _error) if _error.kind() == io::ErrorKind::NotFound => Status::NotFound,
+        _error => {
+            error!(error = ?_error, "Attachment request failed");
+            Status::InternalServerError
+        },
+    }
+}
+
+#[post("/api/attachments", data="<upload>")]
+pub async fn create_attachment(token: Token, upload: Form<AttachmentUpload<'_>>, storage: &State<Storage>, meta: RequestMeta, store: &State<Store>, config: &State<AppConfig>) -> Result<Json<Attachment>, Status> {
+    let attachment = create_attachment_action(token, upload.into_inner(), storage, &meta, store, config).await;
+
+    match attachment {
+        Ok(attachment_result) => Ok(Json(attachment_result)),
+        Err(error) => Err(attachment_error_status(error)),
+    }
+}
+
+#[get("/api/attachments?<task>&<event>", format="json")]
+pub async fn read_attachments(token: Token, task: Option<&str>, event: Option<&str>, store: &State<Store>) -> Result<Json<Vec<Attachment>>, Status> {
+    let task_id = parse_query_id(task)?;
+    let event_id = parse_query_id(event)?;
+    let attachments = read_attachments_action(token, task_id, event_id, store).await;
+
+    match attachments {
+        Ok(attachments_result) => Ok(Json(attachments_result)),
+        Err(error) => Err(attachment_error_status(error)),
+    }
+}
+
+#[get("/api/attachments/usage", format="json")]
+pub async fn read_attachment_usage(token: Token, store: &State<Store>, config: &State<AppConfig>) -> Result<Json<AttachmentUsage>, Status> {
+    let usage = read_attachment_usage_action(token, store, config).await;
+
+    match usage {
+        Ok(usage_result) => Ok(Json(usage_result)),
+        Err(error) => Err(attachment_error_status(error)),
+    }
+}
+
+#[get("/api/attachments/<id>")]
+pub async fn read_attachment_file(token: Token, id: &str, storage: &State<Storage>, store: &State<Store>) -> Result<AttachmentFile, Status> {
+    let Ok(attachment_id) = bson::oid::ObjectId::parse_str(id) else {
+        return Err(Status::NotFound)
+    };
+    let file = read_attachment_file_action(token, attachment_id, false, storage, store).await;
+
+    file.map_err(attachment_error_status)
+}
+
+#[get("/api/attachments/<id>/thumbnail")]
+pub async fn read_attachment_thumbnail(token: Token, id: &str, storage: &State<Storage>, store: &State<Store>) -> Result<AttachmentFile, Status> {
+    let Ok(attachment_id) = bson::oid::ObjectId::parse_str(id) else {
+        return Err(Status::NotFound)
+    };
+    let file = read_attachment_file_action(token, attachment_id, true, storage, store).await;
+
+    file.map_err(attachment_error_status)
+}
+
+#[delete("/api/attachments", format="json", data="<attachments>")]
+pub async fn delete_attachments(token: Token, attachments: Json<Vec<bson::oid::ObjectId>>, storage: &State<Storage>, meta: RequestMeta, store: &State<Store>) -> Result<Json<DeletedAttachments>, Status> {
+    let deserialized_attachments_list = attachments.into_inner();
+    let deleted = delete_attachments_action(token, deserialized_attachments_list, storage, &meta, store).await;
+
+    match deleted {
+        Ok(deleted_result) => Ok(Json(deleted_result)),
+        Err(error) => Err(attachment_error_status(error)),
+    }
+}
@@ -0,0 +1,169 @@
+use mongodb::bson;
+use mongodb::bson::{Bson, Document};
+use mongodb::error::Error;
+use rocket::State;
+use rocket::http::Status;
+use rocket::request::{Request, Outcome, FromRequest};
+use rocket::serde::{Serialize, Deserialize, json::Json};
+use std::collections::BTreeSet;
+use tracing::error;
+
+use crate::Token;
+use crate::repository::{Repository, Store};
+
+// Values that would hand out access if someone could read the audit log
+const REDACTED_FIELDS: [&str; 3] = ["token", "calendar_secret", "secret"];
+
+const DEFAULT_AUDIT_LIMIT: u32 = 50;
+const MAX_AUDIT_LIMIT: u32 = 200;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct RequestMeta {
+    ip: Option<String>,
+    user_agent: Option<String>,
+    method: String,
+    path: String,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequestMeta {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(RequestMeta {
+            ip: request.client_ip().map(|ip| ip.to_string()),
+            user_agent: request.headers().get_one("User-Agent").map(String::from),
+            method: request.method().as_str().to_string(),
+            path: request.uri().path().as_str().to_string(),
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct AuditChange {
+    collection: String,
+    document_id: bson::oid::ObjectId,
+    // Only the fields that changed, each as { before, after }
+    diff: Document,
+}
+
+impl AuditChange {
+    pub fn new(collection: &str, document_id: bson::oid::ObjectId, before: Option<&Document>, after: Option<&Document>) -> AuditChange {
+        let mut keys = BTreeSet::new();
+        for document in [before, after].into_iter().flatten() {
+            keys.extend(document.keys().cloned());
+        }
+
+        let mut diff = Document::new();
+        for key in keys {
+            let before_value = before.and_then(|document| document.get(&key)).cloned().unwrap_or(Bson::Null);
+            let after_value = after.and_then(|document| document.get(&key)).cloned().unwrap_or(Bson::Null);
+            if before_value == after_value {
+                continue
+            }
+            let field_diff = if REDACTED_FIELDS.contains(&key.as_str()) {
+                bson::doc! { "before": "[redacted]", "after": "[redacted]" }
+            } else {
+                bson::doc! { "before": before_value, "after": after_value }
+            };
+            diff.insert(key, field_diff);
+        }
+
+        AuditChange {
+            collection: collection.to_string(),
+            document_id,
+            diff,
+        }
+    }
+}
+
+// Entries are only ever inserted; nothing updates or deletes them
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct AuditEntry {
+    _id: bson::oid::ObjectId,
+    // Unset for failed log ins, which nobody could be signed in as
+    actor: Option<bson::oid::ObjectId>,
+    action: String,
+    // Why a failed log in failed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    targets: Vec<bson::oid::ObjectId>,
+    changes: Vec<AuditChange>,
+    request: RequestMeta,
+    created_at: bson::DateTime,
+}
+
+pub async fn record(repository: &dyn Repository, actor: bson::oid::ObjectId, action: &str, targets: Vec<bson::oid::ObjectId>, changes: Vec<AuditChange>, meta: &RequestMeta) -> Result<(), Error> {
+    let entry = AuditEntry {
+        _id: bson::oid::ObjectId::new(),
+        actor: Some(actor),
+        action: action.to_string(),
+        reason: None,
+        targets,
+        changes,
+        request: meta.clone(),
+        created_at: bson::DateTime::now(),
+    };
+    repository.insert_document("audit", bson::to_document(&entry)?).await?;
+
+    Ok(())
+}
+
+// Only admins see these, since they have no actor
+pub async fn record_failed_log_in(repository: &dyn Repository, reason: &str, meta: &RequestMeta) -> Result<(), Error> {
+    let entry = AuditEntry {
+        _id: bson::oid::ObjectId::new(),
+        actor: None,
+        action: String::from("log_in_failed"),
+        reason: Some(reason.to_string()),
+        targets: Vec::new(),
+        changes: Vec::new(),
+        request: meta.clone(),
+        created_at: bson::DateTime::now(),
+    };
+    repository.insert_document("audit", bson::to_document(&entry)?).await?;
+
+    Ok(())
+}
+
+async fn read_audit_action(token: Token, actor: Option<bson::oid::ObjectId>, action: Option<&str>, limit: u32, offset: u32, store: &Store) -> Result<Option<Vec<AuditEntry>>, Error> {
+    let repository = store.backend.as_ref();
+
+    let user = token.0;
+
+    // Until accounts can be shared, owners see their own activity and admins see everyone's
+    let actor_filter = match actor {
+        Some(actor_id) if actor_id != user._id && !user.is_admin => return Ok(None),
+        Some(actor_id) => Some(actor_id),
+        None if !user.is_admin => Some(user._id),
+        None => None,
+    };
+
+    let audit_list = repository.find_audit_entries(actor_filter, action, offset, limit).await?;
+
+    Ok(Some(audit_list))
+}
+
+#[get("/api/audit?<user>&<action>&<limit>&<offset>", format="json")]
+pub async fn read_audit(token: Token, user: Option<&str>, action: Option<&str>, limit: Option<u32>, offset: Option<u32>, store: &State<Store>) -> Result<Json<Vec<AuditEntry>>, Status> {
+    let actor = match user {
+        Some(_user) => match bson::oid::ObjectId::parse_str(_user) {
+            Ok(_actor) => Some(_actor),
+            Err(_) => return Err(Status::BadRequest),
+        },
+        None => None,
+    };
+    let audit = read_audit_action(token, actor, action, limit.unwrap_or(DEFAULT_AUDIT_LIMIT).clamp(1, MAX_AUDIT_LIMIT), offset.unwrap_or(0), store).await;
+
+    match audit {
+        Ok(Some(audit_result)) => Ok(Json(audit_result)),
+        Ok(None) => Err(Status::Forbidden),
+        Err(error) => {
+            error!(?error, "Couldn't read audit");
+            Err(Status::InternalServerError)
+        },
+    }
+}
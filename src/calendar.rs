@@ -0,0 +1,212 @@
+use chrono::{Duration, NaiveDate, Utc};
+use chrono_tz::Tz;
+use mongodb::bson;
+use mongodb::error::Error;
+use rocket::State;
+use rocket::http::{ContentType, Status};
+use rocket::serde::{Serialize, Deserialize, json::Json};
+use tracing::error;
+
+use crate::{TaskWithLatestEvent, Token, digest};
+use crate::config::AppConfig;
+use crate::audit::RequestMeta;
+use crate::repository::Store;
+use crate::undo::OperationLog;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct CalendarFeedRequestData {
+    tags: Option<Vec<bson::oid::ObjectId>>,
+    rotate: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct CalendarFeedData {
+    url: String,
+    // The same feed for calendar apps that subscribe through the webcal scheme
+    webcal_url: String,
+}
+
+fn new_calendar_secret() -> String {
+    format!("{}{}", bson::uuid::Uuid::new(), bson::uuid::Uuid::new()).replace("-", "")
+}
+
+fn feed_url(base_url: &str, secret: &str, tags: &Option<Vec<bson::oid::ObjectId>>) -> String {
+    let path = format!("{}/api/calendar/{}/feed.ics", base_url, secret);
+    match tags {
+        Some(_tags) if !_tags.is_empty() => {
+            let tag_ids: Vec<String> = _tags.iter().map(|tag| tag.to_hex()).collect();
+            format!("{}?tags={}", path, tag_ids.join(","))
+        },
+        _ => path,
+    }
+}
+
+fn webcal_url(url: &str) -> String {
+    let address = url.split_once("://").map_or(url, |(_, address)| address);
+    format!("webcal://{}", address)
+}
+
+fn parse_tag_filter(tags: Option<&str>) -> Option<Vec<bson::oid::ObjectId>> {
+    let tags = tags?;
+    let mut tag_ids = Vec::new();
+    for tag in tags.split(",").filter(|tag| !tag.is_empty()) {
+        tag_ids.push(bson::oid::ObjectId::parse_str(tag).ok()?);
+    }
+    Some(tag_ids)
+}
+
+// https://www.rfc-editor.org/rfc/rfc5545#section-3.3.11
+fn escape_text(value: &str) -> String {
+    value.replace("\\", "\\\\").replace(";", "\\;").replace(",", "\\,").replace("\r\n", "\\n").replace("\n", "\\n")
+}
+
+// Content lines longer than 75 octets have to be folded onto continuation lines starting with a space
+// https://www.rfc-editor.org/rfc/rfc5545#section-3.1
+fn push_line(calendar: &mut String, line: &str) {
+    let mut line_length = 0;
+    for character in line.chars() {
+        if line_length + character.len_utf8() > 75 {
+            calendar.push_str("\r\n ");
+            line_length = 1;
+        }
+        calendar.push(character);
+        line_length += character.len_utf8();
+    }
+    calendar.push_str("\r\n");
+}
+
+fn next_due_date(task: &TaskWithLatestEvent, timezone: Tz) -> Option<NaiveDate> {
+    let latest_event_date = task.latest_event_date?;
+    let next_due = latest_event_date.to_chrono() + Duration::days(i64::from(task.frequency));
+    Some(next_due.with_timezone(&timezone).date_naive())
+}
+
+fn render_calendar(tasks: Vec<TaskWithLatestEvent>, timezone: Tz) -> String {
+    let now = Utc::now();
+    let today = now.with_timezone(&timezone).date_naive();
+    let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+
+    let mut calendar = String::new();
+    push_line(&mut calendar, "BEGIN:VCALENDAR");
+    push_line(&mut calendar, "VERSION:2.0");
+    push_line(&mut calendar, "PRODID:-//mossy//mossy_behind//EN");
+    push_line(&mut calendar, "CALSCALE:GREGORIAN");
+    push_line(&mut calendar, "X-WR-CALNAME:mossy");
+    push_line(&mut calendar, "X-PUBLISHED-TTL:PT1H");
+
+    for task in tasks {
+        // Overdue and never-done tasks are shown on today rather than somewhere in the past
+        let (due_date, summary) = match next_due_date(&task, timezone) {
+            Some(_due_date) if _due_date >= today => (_due_date, task.name.clone()),
+            Some(_) => (today, format!("{} (overdue)", task.name)),
+            None => (today, format!("{} (never done)", task.name)),
+        };
+        let end_date = due_date + Duration::days(1);
+
+        push_line(&mut calendar, "BEGIN:VEVENT");
+        push_line(&mut calendar, &format!("UID:{}@mossy", task._id.to_hex()));
+        push_line(&mut calendar, &format!("DTSTAMP:{}", timestamp));
+        push_line(&mut calendar, &format!("DTSTART;VALUE=DATE:{}", due_date.format("%Y%m%d")));
+        push_line(&mut calendar, &format!("DTEND;VALUE=DATE:{}", end_date.format("%Y%m%d")));
+        push_line(&mut calendar, &format!("SUMMARY:{}", escape_text(&summary)));
+        let description = if task.frequency == 1 {
+            String::from("Every day")
+        } else {
+            format!("Every {} days", task.frequency)
+        };
+        push_line(&mut calendar, &format!("DESCRIPTION:{}", escape_text(&description)));
+        push_line(&mut calendar, "TRANSP:TRANSPARENT");
+        push_line(&mut calendar, "END:VEVENT");
+    }
+
+    push_line(&mut calendar, "END:VCALENDAR");
+    calendar
+}
+
+async fn create_calendar_feed_url_action(token: Token, feed_data: CalendarFeedRequestData, store: &Store, meta: &RequestMeta, config: &AppConfig) -> Result<CalendarFeedData, Error> {
+    let repository = store.backend.as_ref();
+
+    let user = token.0;
+
+    let secret = match user.calendar_secret {
+        Some(_secret) if feed_data.rotate != Some(true) => _secret,
+        _ => {
+            let new_secret = new_calendar_secret();
+            let updated_user = bson::doc! {
+                "calendar_secret": new_secret.clone(),
+            };
+            let mut operation = OperationLog::audit_only();
+            operation.snapshot_document(repository, "users", user._id).await?;
+            repository.update_user(user._id, updated_user.clone()).await?;
+            operation.updated("users", user._id, &updated_user);
+            operation.record(repository, user._id, "rotate_calendar_secret", meta).await;
+            new_secret
+        },
+    };
+
+    let url = feed_url(config.public_base_url(), &secret, &feed_data.tags);
+    Ok(CalendarFeedData {
+        webcal_url: webcal_url(&url),
+        url,
+    })
+}
+
+async fn read_calendar_feed_action(secret: &str, tags: Option<Vec<bson::oid::ObjectId>>, store: &Store) -> Result<Option<String>, Error> {
+    let repository = store.backend.as_ref();
+
+    let Some(user) = repository.find_user_by_calendar_secret(secret).await? else {
+        return Ok(None)
+    };
+
+    let timezone = digest::user_timezone(&user);
+
+    // Tasks with any of the tags
+    let tag_ids = tags.filter(|tag_ids| !tag_ids.is_empty());
+    let mut tasks_list = Vec::new();
+    for task_document in repository.find_tasks_with_moss(user._id, 0, 0).await? {
+        let Ok(task) = bson::from_document::<TaskWithLatestEvent>(task_document) else {
+            continue
+        };
+        let has_tag = |tag_ids: &Vec<bson::oid::ObjectId>| task.tags.iter().flatten().any(|tag| tag_ids.contains(tag));
+        if tag_ids.as_ref().is_none_or(has_tag) {
+            tasks_list.push(task);
+        }
+    }
+
+    Ok(Some(render_calendar(tasks_list, timezone)))
+}
+
+#[post("/api/calendar/feed-url", format="json", data="<feed_data>")]
+pub async fn create_calendar_feed_url(token: Token, feed_data: Json<CalendarFeedRequestData>, store: &State<Store>, meta: RequestMeta, config: &State<AppConfig>) -> Result<Json<CalendarFeedData>, Status> {
+    let deserialized_feed_data = feed_data.into_inner();
+    let feed = create_calendar_feed_url_action(token, deserialized_feed_data, store, &meta, config).await;
+
+    match feed {
+        Ok(feed_result) => Ok(Json(feed_result)),
+        Err(error) => {
+            error!(?error, "Couldn't create calendar feed URL");
+            Err(Status::InternalServerError)
+        },
+    }
+}
+
+// Calendar apps can't send our Authorization header, so the secret in the path is the credential
+#[get("/api/calendar/<secret>/feed.ics?<tags>")]
+pub async fn read_calendar_feed(secret: &str, tags: Option<&str>, store: &State<Store>) -> Result<(ContentType, String), Status> {
+    let tag_filter = parse_tag_filter(tags);
+    if tags.is_some() && tag_filter.is_none() {
+        return Err(Status::BadRequest)
+    }
+    let feed = read_calendar_feed_action(secret, tag_filter, store).await;
+
+    match feed {
+        Ok(Some(feed_result)) => Ok((ContentType::Calendar, feed_result)),
+        Ok(None) => Err(Status::NotFound),
+        Err(error) => {
+            error!(?error, "Couldn't read calendar feed");
+            Err(Status::InternalServerError)
+        },
+    }
+}
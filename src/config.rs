@@ -0,0 +1,421 @@
+use lettre::message::Mailbox;
+use mongodb::bson;
+use rocket::figment::{self, Figment, providers::Env};
+use rocket::serde::{Deserialize, Deserializer};
+use std::fmt;
+
+// The variable names .env used before there was an AppConfig, so existing deployments keep working.
+// The new names (Rocket.toml or ROCKET_DATABASE_URI etc.) win when both are set.
+const RENAMED_VARIABLES: [(&str, &str); 22] = [
+    ("MONGODB_ADDRESS", "database_uri"),
+    ("MONGODB_DATABASE", "database_name"),
+    ("APPLE_CLIENT_ID", "apple_client_ids"),
+    ("DATABASE_BACKEND", "database_backend"),
+    ("MIGRATE_ON_LAUNCH", "migrate_on_launch"),
+    ("ATTACHMENT_STORAGE", "attachment_storage"),
+    ("ATTACHMENT_DIR", "attachment_dir"),
+    ("ATTACHMENT_QUOTA_MB", "attachment_quota_mb"),
+    ("S3_BUCKET", "s3_bucket"),
+    ("S3_ENDPOINT", "s3_endpoint"),
+    ("S3_REGION", "s3_region"),
+    ("S3_ACCESS_KEY_ID", "s3_access_key_id"),
+    ("S3_SECRET_ACCESS_KEY", "s3_secret_access_key"),
+    ("TRASH_RETENTION_DAYS", "trash_retention_days"),
+    ("UNDO_WINDOW_SECONDS", "undo_window_seconds"),
+    ("CHANGE_SOURCE", "change_source"),
+    ("SMTP_HOST", "smtp_host"),
+    ("SMTP_SECURITY", "smtp_security"),
+    ("SMTP_PORT", "smtp_port"),
+    ("SMTP_USERNAME", "smtp_username"),
+    ("SMTP_PASSWORD", "smtp_password"),
+    ("SMTP_FROM", "smtp_from"),
+];
+
+// MongoDB's own limit, and characters it won't accept in a database name
+const MAX_DATABASE_NAME_LENGTH: usize = 63;
+const INVALID_DATABASE_NAME_CHARACTERS: [char; 8] = ['/', '\\', '.', ' ', '"', '$', '*', '<'];
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum DatabaseBackend {
+    Mongodb,
+    // Keeps everything in the process, for tests
+    Memory,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum AttachmentBackend {
+    // Files go under attachment_dir
+    Local,
+    // Needs the s3 feature
+    S3,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    // One JSON object per line, for log collectors
+    Json,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum ChangeSource {
+    // Each action publishes its own writes
+    Actions,
+    // Tails MongoDB change streams, which also picks up writes from other instances but needs a replica set
+    Mongodb,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(crate = "rocket::serde", rename_all = "lowercase")]
+pub enum SmtpSecurity {
+    // Plain SMTP, which is what local mail sinks like Mailpit or MailHog expect
+    None,
+    Tls,
+    Starttls,
+}
+
+// Parts of the app an instance can opt out of, e.g. so only one of several instances sends digests
+#[derive(Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct Features {
+    #[serde(default = "enabled")]
+    pub digests: bool,
+    #[serde(default = "enabled")]
+    pub webhooks: bool,
+    #[serde(default = "enabled")]
+    pub trash_purge: bool,
+    // Off hides /metrics, e.g. where it can't be kept off the public internet
+    #[serde(default = "enabled")]
+    pub metrics: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Features {
+            digests: true,
+            webhooks: true,
+            trash_purge: true,
+            metrics: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct AppConfig {
+    #[serde(default = "default_database_backend")]
+    pub database_backend: DatabaseBackend,
+    #[serde(default = "default_database_uri")]
+    pub database_uri: String,
+    // Tests point this somewhere disposable so they never touch real data
+    #[serde(default = "default_database_name")]
+    pub database_name: String,
+    // Every bundle id allowed to sign in with Apple, e.g. the app's own and Expo Go's
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub apple_client_ids: Vec<String>,
+    // Tokens never expire when this is unset
+    #[serde(default)]
+    pub token_ttl_days: Option<u32>,
+    // false leaves migrations to `mossy_behind migrate`, e.g. when several instances deploy at once
+    #[serde(default = "enabled")]
+    pub migrate_on_launch: bool,
+    // Which levels get logged is up to RUST_LOG, e.g. RUST_LOG=mossy_behind=debug
+    #[serde(default = "default_log_format")]
+    pub log_format: LogFormat,
+    #[serde(default)]
+    pub features: Features,
+    #[serde(default = "default_attachment_storage")]
+    pub attachment_storage: AttachmentBackend,
+    #[serde(default = "default_attachment_dir")]
+    pub attachment_dir: String,
+    #[serde(default = "default_attachment_quota_mb")]
+    pub attachment_quota_mb: u32,
+    // The s3_* settings only matter with attachment_storage = "s3". Anything unset falls back to the AWS_* variables.
+    #[serde(default = "default_s3_bucket")]
+    pub s3_bucket: String,
+    // For S3-compatible stand-ins like MinIO
+    #[serde(default)]
+    pub s3_endpoint: Option<String>,
+    #[serde(default)]
+    pub s3_region: Option<String>,
+    #[serde(default)]
+    pub s3_access_key_id: Option<String>,
+    #[serde(default)]
+    pub s3_secret_access_key: Option<String>,
+    // Anything deleted longer ago than this is purged for good
+    #[serde(default = "default_trash_retention_days")]
+    pub trash_retention_days: u32,
+    // How far back undo reaches
+    #[serde(default = "default_undo_window_seconds")]
+    pub undo_window_seconds: u32,
+    #[serde(default = "default_change_source")]
+    pub change_source: ChangeSource,
+    // Email digests are off when this is unset
+    #[serde(default)]
+    pub smtp_host: Option<String>,
+    #[serde(default = "default_smtp_security")]
+    pub smtp_security: SmtpSecurity,
+    // Defaults to the usual port for smtp_security
+    #[serde(default)]
+    pub smtp_port: Option<u16>,
+    #[serde(default)]
+    pub smtp_username: Option<String>,
+    #[serde(default)]
+    pub smtp_password: Option<String>,
+    // The digests' From, e.g. "mossy <digest@example.com>"
+    #[serde(default)]
+    pub smtp_from: Option<String>,
+    // Where clients reach this server, for the links it hands out like calendar feeds, e.g. "https://mossy.example.com"
+    #[serde(default = "default_public_base_url")]
+    pub public_base_url: String,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+fn default_database_backend() -> DatabaseBackend {
+    DatabaseBackend::Mongodb
+}
+
+fn default_log_format() -> LogFormat {
+    LogFormat::Text
+}
+
+fn default_attachment_storage() -> AttachmentBackend {
+    AttachmentBackend::Local
+}
+
+fn default_attachment_dir() -> String {
+    String::from("attachments")
+}
+
+fn default_attachment_quota_mb() -> u32 {
+    100
+}
+
+fn default_s3_bucket() -> String {
+    String::from("mossy-attachments")
+}
+
+fn default_trash_retention_days() -> u32 {
+    30
+}
+
+fn default_undo_window_seconds() -> u32 {
+    600
+}
+
+fn default_change_source() -> ChangeSource {
+    ChangeSource::Actions
+}
+
+fn default_smtp_security() -> SmtpSecurity {
+    SmtpSecurity::None
+}
+
+fn default_public_base_url() -> String {
+    String::from("http://localhost:8000")
+}
+
+fn default_database_uri() -> String {
+    String::from("mongodb://localhost:27017")
+}
+
+fn default_database_name() -> String {
+    String::from("mossy")
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde", untagged)]
+enum OneOrMany {
+    One(String),
+    Many(Vec<String>),
+}
+
+// APPLE_CLIENT_ID only ever held one id
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => Ok(vec![value]),
+        OneOrMany::Many(values) => Ok(values),
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    ExtractError(Box<figment::Error>),
+    // Every problem at once, so a bad deploy only needs fixing once
+    InvalidError(Vec<String>),
+}
+
+impl From<figment::Error> for ConfigError {
+    fn from(error: figment::Error) -> Self {
+        ConfigError::ExtractError(Box::new(error))
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::ExtractError(error) => {
+                for _error in (**error).clone() {
+                    writeln!(f, "{}", _error)?;
+                }
+                Ok(())
+            },
+            ConfigError::InvalidError(problems) => {
+                for problem in problems {
+                    writeln!(f, "{}", problem)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+// Rocket.toml and ROCKET_* variables, then the old .env names for anything they don't set
+pub fn figment() -> Figment {
+    // Loaded once at launch, not on every log in
+    let _ = dotenv::dotenv();
+
+    let renamed_variables = Env::raw()
+        .filter_map(|key| {
+            RENAMED_VARIABLES.iter()
+                .find(|(variable, _)| key.as_str().eq_ignore_ascii_case(variable))
+                .map(|(_, name)| (*name).into())
+        })
+        .global();
+
+    rocket::Config::figment().join(renamed_variables)
+}
+
+impl AppConfig {
+    pub fn from_figment(figment: &Figment) -> Result<AppConfig, ConfigError> {
+        let config: AppConfig = figment.extract()?;
+
+        let problems = config.problems();
+        if !problems.is_empty() {
+            return Err(ConfigError::InvalidError(problems))
+        }
+        Ok(config)
+    }
+
+    pub fn from_env() -> Result<AppConfig, ConfigError> {
+        AppConfig::from_figment(&figment())
+    }
+
+    pub fn uses_memory_backend(&self) -> bool {
+        self.database_backend == DatabaseBackend::Memory
+    }
+
+    // Tokens from before token_issued_at existed count as expired once there's a TTL
+    pub fn is_token_expired(&self, token_issued_at: Option<bson::DateTime>) -> bool {
+        let Some(token_ttl_days) = self.token_ttl_days else {
+            return false
+        };
+        let Some(_token_issued_at) = token_issued_at else {
+            return true
+        };
+        _token_issued_at.to_chrono() + chrono::Duration::days(token_ttl_days.into()) < chrono::Utc::now()
+    }
+
+    pub fn attachment_quota_bytes(&self) -> i64 {
+        i64::from(self.attachment_quota_mb) * 1024 * 1024
+    }
+
+    // Without a trailing slash, so paths can be appended as they are
+    pub fn public_base_url(&self) -> &str {
+        self.public_base_url.trim_end_matches('/')
+    }
+
+    pub fn smtp_port(&self) -> u16 {
+        match (self.smtp_port, self.smtp_security) {
+            (Some(_smtp_port), _) => _smtp_port,
+            (None, SmtpSecurity::None) => 25,
+            (None, SmtpSecurity::Tls) => 465,
+            (None, SmtpSecurity::Starttls) => 587,
+        }
+    }
+
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if !self.database_uri.starts_with("mongodb://") && !self.database_uri.starts_with("mongodb+srv://") {
+            problems.push(String::from("database_uri: must start with mongodb:// or mongodb+srv://"));
+        }
+        if self.database_name.is_empty() || self.database_name.len() > MAX_DATABASE_NAME_LENGTH {
+            problems.push(format!("database_name: must be between 1 and {} bytes long", MAX_DATABASE_NAME_LENGTH));
+        }
+        if self.database_name.contains(INVALID_DATABASE_NAME_CHARACTERS) {
+            problems.push(format!("database_name: can't contain any of {:?}", INVALID_DATABASE_NAME_CHARACTERS));
+        }
+        if self.apple_client_ids.iter().all(|client_id| client_id.trim().is_empty()) {
+            problems.push(String::from("apple_client_ids: needs at least one client id, or nobody can log in"));
+        }
+        if self.token_ttl_days == Some(0) {
+            problems.push(String::from("token_ttl_days: must be at least 1, or left unset so tokens never expire"));
+        }
+        if self.attachment_quota_mb == 0 {
+            problems.push(String::from("attachment_quota_mb: must be at least 1"));
+        }
+        match self.attachment_storage {
+            AttachmentBackend::Local => {
+                if self.attachment_dir.trim().is_empty() {
+                    problems.push(String::from("attachment_dir: can't be empty"));
+                }
+            },
+            AttachmentBackend::S3 => {
+                if !cfg!(feature = "s3") {
+                    problems.push(String::from("attachment_storage: s3 needs a build with the s3 feature"));
+                }
+                if self.s3_bucket.trim().is_empty() {
+                    problems.push(String::from("s3_bucket: can't be empty"));
+                }
+                if self.s3_endpoint.as_ref().is_some_and(|endpoint| !endpoint.starts_with("http://") && !endpoint.starts_with("https://")) {
+                    problems.push(String::from("s3_endpoint: must start with http:// or https://"));
+                }
+                if self.s3_region.as_ref().is_some_and(|region| region.trim().is_empty()) {
+                    problems.push(String::from("s3_region: can't be empty, leave it unset to use AWS_REGION"));
+                }
+                if self.s3_access_key_id.is_some() != self.s3_secret_access_key.is_some() {
+                    problems.push(String::from("s3_access_key_id: needs s3_secret_access_key as well, or neither"));
+                }
+            },
+        }
+        if self.trash_retention_days == 0 {
+            problems.push(String::from("trash_retention_days: must be at least 1"));
+        }
+        if self.undo_window_seconds == 0 {
+            problems.push(String::from("undo_window_seconds: must be at least 1"));
+        }
+        if let Some(smtp_host) = &self.smtp_host {
+            if smtp_host.trim().is_empty() {
+                problems.push(String::from("smtp_host: can't be empty, leave it unset to turn digests off"));
+            }
+            match &self.smtp_from {
+                Some(smtp_from) => {
+                    if smtp_from.parse::<Mailbox>().is_err() {
+                        problems.push(String::from("smtp_from: must be an email address, e.g. \"mossy <digest@example.com>\""));
+                    }
+                },
+                None => problems.push(String::from("smtp_from: needs to be set along with smtp_host")),
+            }
+            if self.smtp_port == Some(0) {
+                problems.push(String::from("smtp_port: must be a port number"));
+            }
+            if self.smtp_username.is_some() != self.smtp_password.is_some() {
+                problems.push(String::from("smtp_username: needs smtp_password as well, or neither"));
+            }
+        }
+
+        if !self.public_base_url.starts_with("http://") && !self.public_base_url.starts_with("https://") {
+            problems.push(String::from("public_base_url: must start with http:// or https://"));
+        }
+
+        problems
+    }
+}
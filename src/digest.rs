@@ -0,0 +1,408 @@
+use chrono::{DateTime, Datelike, Timelike, Utc, Weekday};
+use chrono_tz::Tz;
+use futures::stream::TryStreamExt;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials as SmtpCredentials;
+use mongodb::{Client, Database, options::ClientOptions, options::FindOptions};
+use mongodb::bson;
+use mongodb::error::Error;
+use rocket::serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+use tera::{Context, Tera};
+use tracing::{error, warn};
+
+use crate::{Event, Task, TaskWithLatestEvent, User, moss_pipeline};
+use crate::config::{AppConfig, SmtpSecurity};
+use crate::metrics;
+
+const DIGEST_TEXT_TEMPLATE: &str = include_str!("../templates/digest.txt.tera");
+const DIGEST_HTML_TEMPLATE: &str = include_str!("../templates/digest.html.tera");
+
+const DEFAULT_DIGEST_HOUR: u32 = 7;
+// A failed digest is retried after 5 minutes, doubling up to 6 hours
+const FIRST_RETRY_MINUTES: i64 = 5;
+const MAX_RETRY_MINUTES: i64 = 6 * 60;
+const MILLISECONDS_PER_DAY: i64 = 1000 * 60 * 60 * 24;
+
+#[derive(Debug)]
+pub enum DigestError {
+    Database(Error),
+    NoSmtpHost,
+    NoSender,
+    InvalidAddress,
+    SmtpConfig,
+    Template(tera::Error),
+    BuildMessage,
+    Send(lettre::transport::smtp::Error),
+}
+
+impl From<Error> for DigestError {
+    fn from(error: Error) -> Self {
+        DigestError::Database(error)
+    }
+}
+
+impl fmt::Display for DigestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DigestError::Database(error) => write!(f, "database error: {}", error),
+            DigestError::NoSmtpHost => write!(f, "smtp_host isn't set"),
+            DigestError::NoSender => write!(f, "smtp_from isn't set"),
+            DigestError::InvalidAddress => write!(f, "invalid email address"),
+            DigestError::SmtpConfig => write!(f, "couldn't set up the SMTP relay"),
+            DigestError::Template(error) => write!(f, "template error: {}", error),
+            DigestError::BuildMessage => write!(f, "couldn't build the message"),
+            DigestError::Send(error) => write!(f, "couldn't send: {}", error),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct DigestTask {
+    name: String,
+    days: Option<i64>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct DigestEvent {
+    task: String,
+    date: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DigestFrequency {
+    Daily,
+    Weekly,
+}
+
+impl DigestFrequency {
+    pub fn parse(value: &str) -> Option<DigestFrequency> {
+        match value {
+            "daily" => Some(DigestFrequency::Daily),
+            "weekly" => Some(DigestFrequency::Weekly),
+            _ => None,
+        }
+    }
+
+    fn period(&self) -> chrono::Duration {
+        match self {
+            DigestFrequency::Daily => chrono::Duration::days(1),
+            DigestFrequency::Weekly => chrono::Duration::days(7),
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            DigestFrequency::Daily => "daily",
+            DigestFrequency::Weekly => "weekly",
+        }
+    }
+}
+
+pub fn user_timezone(user: &User) -> Tz {
+    user.timezone.as_deref().and_then(|timezone| timezone.parse::<Tz>().ok()).unwrap_or(Tz::UTC)
+}
+
+// Digests go out once per local day (or on Mondays for weekly ones) at or after the chosen hour,
+// so a missed scheduler tick just delays the email rather than skipping it.
+fn is_digest_due(user: &User, frequency: DigestFrequency, now: DateTime<Utc>) -> bool {
+    let timezone = user_timezone(user);
+    let local_now = now.with_timezone(&timezone);
+
+    if local_now.hour() < user.digest_hour.unwrap_or(DEFAULT_DIGEST_HOUR) {
+        return false
+    }
+    if frequency == DigestFrequency::Weekly && local_now.weekday() != Weekday::Mon {
+        return false
+    }
+    match user.last_digest_sent_at {
+        Some(last_sent) => last_sent.to_chrono().with_timezone(&timezone).date_naive() != local_now.date_naive(),
+        None => true,
+    }
+}
+
+fn is_retry_due(user: &User, now: DateTime<Utc>) -> bool {
+    let Some(last_attempt) = user.last_digest_attempt_at else {
+        return true
+    };
+    let failed_attempts = user.digest_failed_attempts.unwrap_or(0);
+    if failed_attempts == 0 {
+        return true
+    }
+    let delay = (FIRST_RETRY_MINUTES << (failed_attempts - 1).min(8)).min(MAX_RETRY_MINUTES);
+    now >= last_attempt.to_chrono() + chrono::Duration::minutes(delay)
+}
+
+fn mailer(config: &AppConfig) -> Result<AsyncSmtpTransport<Tokio1Executor>, DigestError> {
+    let Some(host) = &config.smtp_host else {
+        return Err(DigestError::NoSmtpHost)
+    };
+
+    let mut builder = match config.smtp_security {
+        SmtpSecurity::Tls => match AsyncSmtpTransport::<Tokio1Executor>::relay(host) {
+            Ok(_builder) => _builder,
+            Err(_) => return Err(DigestError::SmtpConfig)
+        },
+        SmtpSecurity::Starttls => match AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host) {
+            Ok(_builder) => _builder,
+            Err(_) => return Err(DigestError::SmtpConfig)
+        },
+        SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+    };
+    builder = builder.port(config.smtp_port());
+
+    if let (Some(username), Some(password)) = (&config.smtp_username, &config.smtp_password) {
+        builder = builder.credentials(SmtpCredentials::new(username.clone(), password.clone()));
+    }
+
+    Ok(builder.build())
+}
+
+fn sender(config: &AppConfig) -> Result<Mailbox, DigestError> {
+    let Some(from) = &config.smtp_from else {
+        return Err(DigestError::NoSender)
+    };
+    match from.parse::<Mailbox>() {
+        Ok(_mailbox) => Ok(_mailbox),
+        Err(_) => Err(DigestError::InvalidAddress)
+    }
+}
+
+fn templates() -> Result<Tera, DigestError> {
+    let mut tera = Tera::default();
+    let templates_result = tera.add_raw_templates(vec![
+        ("digest.txt", DIGEST_TEXT_TEMPLATE),
+        ("digest.html", DIGEST_HTML_TEMPLATE),
+    ]);
+
+    match templates_result {
+        Ok(_) => Ok(tera),
+        Err(error) => Err(DigestError::Template(error))
+    }
+}
+
+async fn build_digest_context(db: &Database, user: &User, frequency: DigestFrequency, now: DateTime<Utc>) -> Result<Option<Context>, DigestError> {
+    let tasks = db.collection::<Task>("tasks");
+    let events = db.collection::<Event>("events");
+
+    let period_in_milliseconds = frequency.period().num_milliseconds();
+
+    let mut overdue = Vec::new();
+    let mut due_soon = Vec::new();
+
+    let mut tasks_cursor = tasks.aggregate(moss_pipeline(user._id), None).await?;
+    while let Some(task_document) = tasks_cursor.try_next().await? {
+        let Ok(task) = bson::from_document::<TaskWithLatestEvent>(task_document) else {
+            continue
+        };
+        match task.moss {
+            // Tasks that have never been done count as overdue
+            None => overdue.push(DigestTask { name: task.name, days: None }),
+            Some(moss) if moss > 0 => overdue.push(DigestTask { name: task.name, days: Some(moss / MILLISECONDS_PER_DAY) }),
+            Some(moss) if moss > -period_in_milliseconds => due_soon.push(DigestTask { name: task.name, days: Some(-moss / MILLISECONDS_PER_DAY) }),
+            Some(_) => {},
+        }
+    }
+    overdue.sort_by_key(|task| Reverse(task.days));
+    due_soon.sort_by_key(|task| task.days);
+
+    let period_start = match user.last_digest_sent_at {
+        Some(last_sent) => last_sent,
+        None => bson::DateTime::from_chrono(now - frequency.period()),
+    };
+    let events_filter = bson::doc! {
+        "user": user._id,
+        "date": {
+            "$gte": period_start,
+        },
+        "deleted_at": null,
+    };
+    let options = FindOptions::builder().sort(bson::doc! { "date": -1 }).build();
+    let period_events: Vec<Event> = events.find(events_filter, options).await?.try_collect().await?;
+
+    // One lookup for every task done in the period rather than one per event.
+    // Events detached from a trashed task are left out along with the task.
+    let task_ids: Vec<bson::oid::ObjectId> = period_events.iter().map(|event| event.task).collect();
+    let task_filter = bson::doc! {
+        "_id": {
+            "$in": task_ids,
+        },
+        "deleted_at": null,
+    };
+    let task_names: HashMap<bson::oid::ObjectId, String> = tasks.find(task_filter, None).await?
+        .map_ok(|task| (task._id, task.name))
+        .try_collect().await?;
+
+    let timezone = user_timezone(user);
+    let mut done = Vec::new();
+    for event in period_events {
+        let Some(task_name) = task_names.get(&event.task) else {
+            continue
+        };
+        done.push(DigestEvent {
+            task: task_name.clone(),
+            date: event.date.to_chrono().with_timezone(&timezone).format("%a %b %-d").to_string(),
+        });
+    }
+
+    if overdue.is_empty() && due_soon.is_empty() && done.is_empty() {
+        return Ok(None)
+    }
+
+    let mut context = Context::new();
+    context.insert("frequency", frequency.label());
+    context.insert("overdue", &overdue);
+    context.insert("due_soon", &due_soon);
+    context.insert("done", &done);
+
+    Ok(Some(context))
+}
+
+async fn send_digest(db: &Database, mailer: &AsyncSmtpTransport<Tokio1Executor>, sender: &Mailbox, tera: &Tera, user: &User, frequency: DigestFrequency, now: DateTime<Utc>) -> Result<(), DigestError> {
+    if let Some(context) = build_digest_context(db, user, frequency, now).await? {
+        let text = match tera.render("digest.txt", &context) {
+            Ok(_text) => _text,
+            Err(error) => return Err(DigestError::Template(error))
+        };
+        let html = match tera.render("digest.html", &context) {
+            Ok(_html) => _html,
+            Err(error) => return Err(DigestError::Template(error))
+        };
+        let recipient = match user.email.parse::<Mailbox>() {
+            Ok(_recipient) => _recipient,
+            Err(_) => return Err(DigestError::InvalidAddress)
+        };
+        let subject = match frequency {
+            DigestFrequency::Daily => "Your daily mossy digest",
+            DigestFrequency::Weekly => "Your weekly mossy digest",
+        };
+        let message = match Message::builder()
+            .from(sender.clone())
+            .to(recipient)
+            .subject(subject)
+            .multipart(MultiPart::alternative_plain_html(text, html)) {
+            Ok(_message) => _message,
+            Err(_) => return Err(DigestError::BuildMessage)
+        };
+        if let Err(error) = mailer.send(message).await {
+            return Err(DigestError::Send(error))
+        }
+    }
+
+    Ok(())
+}
+
+// Also records empty digests so we don't rebuild them every tick for the rest of the day
+async fn record_digest_attempt(db: &Database, user: &User, sent: bool, now: DateTime<Utc>) -> Result<(), Error> {
+    let users = db.collection::<User>("users");
+    let attempted_at = bson::DateTime::from_chrono(now);
+    let updated_user = if sent {
+        bson::doc! {
+            "$set": {
+                "last_digest_sent_at": attempted_at,
+                "last_digest_attempt_at": attempted_at,
+                "digest_failed_attempts": 0,
+            }
+        }
+    } else {
+        bson::doc! {
+            "$set": {
+                "last_digest_attempt_at": attempted_at,
+            },
+            "$inc": {
+                "digest_failed_attempts": 1,
+            }
+        }
+    };
+    users.update_one(bson::doc! { "_id": user._id }, updated_user, None).await?;
+
+    Ok(())
+}
+
+async fn connect(config: &AppConfig) -> Result<Database, Error> {
+    let mut client_options = ClientOptions::parse(&config.database_uri).await?;
+    client_options.app_name = Some("mossy".to_string());
+    client_options.command_event_handler = Some(metrics::command_timer());
+    let client = Client::with_options(client_options)?;
+    Ok(client.database(&config.database_name))
+}
+
+async fn send_due_digests(db: &Database, mailer: &AsyncSmtpTransport<Tokio1Executor>, sender: &Mailbox, tera: &Tera) -> Result<(), DigestError> {
+    let users = db.collection::<User>("users");
+
+    let users_filter = bson::doc! {
+        "digest_frequency": {
+            "$in": ["daily", "weekly"],
+        },
+    };
+    let mut users_cursor = users.find(users_filter, None).await?;
+
+    let now = Utc::now();
+    while let Some(user) = users_cursor.try_next().await? {
+        let Some(frequency) = user.digest_frequency.as_deref().and_then(DigestFrequency::parse) else {
+            continue
+        };
+        if !is_digest_due(&user, frequency, now) || !is_retry_due(&user, now) {
+            continue
+        }
+        // One bad address or template shouldn't hold up everyone else's digest
+        let sent = match send_digest(db, mailer, sender, tera, &user, frequency, now).await {
+            Ok(()) => true,
+            Err(error) => {
+                error!(%error, user_id = %user._id, "Couldn't send digest");
+                false
+            },
+        };
+        if let Err(error) = record_digest_attempt(db, &user, sent, now).await {
+            error!(%error, user_id = %user._id, "Couldn't record digest attempt");
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn run_scheduler(config: AppConfig) {
+    let mailer = match mailer(&config) {
+        Ok(_mailer) => _mailer,
+        Err(error) => {
+            warn!(%error, "Email digests disabled");
+            return
+        }
+    };
+    let sender = match sender(&config) {
+        Ok(_sender) => _sender,
+        Err(error) => {
+            warn!(%error, "Email digests disabled");
+            return
+        }
+    };
+    let tera = match templates() {
+        Ok(_tera) => _tera,
+        Err(error) => {
+            warn!(%error, "Email digests disabled");
+            return
+        }
+    };
+    // The client keeps its own connection pool, so every tick shares it
+    let db = match connect(&config).await {
+        Ok(_db) => _db,
+        Err(error) => {
+            warn!(%error, "Email digests disabled");
+            return
+        }
+    };
+
+    let mut interval = rocket::tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+        if let Err(error) = send_due_digests(&db, &mailer, &sender, &tera).await {
+            error!(%error, "Couldn't send due digests");
+        }
+    }
+}
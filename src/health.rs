@@ -0,0 +1,114 @@
+use futures::FutureExt;
+use rocket::{Shutdown, State};
+use rocket::http::Status;
+use rocket::serde::{Serialize, json::Json};
+use rocket::tokio::time::timeout;
+use std::time::Duration;
+use tracing::warn;
+
+use crate::apple_keys::AppleKeys;
+use crate::repository::{Repository, Store};
+
+// Orchestrators give up on a probe after a second or so, well before MongoDB's own 30 second default
+const DATABASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Health {
+    status: &'static str,
+    version: &'static str,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+}
+
+impl Check {
+    fn passed(detail: Option<String>) -> Check {
+        Check { ok: true, detail }
+    }
+
+    fn failed(detail: String) -> Check {
+        Check { ok: false, detail: Some(detail) }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Readiness {
+    ready: bool,
+    shutting_down: bool,
+    database: Check,
+    migrations: Check,
+    apple_keys: Check,
+}
+
+// Only says the process is up and serving requests; restarting won't fix MongoDB or Apple being unreachable
+#[get("/healthz")]
+pub async fn read_health() -> Json<Health> {
+    Json(Health {
+        status: "alive",
+        version: env!("CARGO_PKG_VERSION"),
+    })
+}
+
+// Whether this instance should get traffic, with every check spelled out for whoever is debugging a deploy.
+// Apple's keys are only reported: log ins refetch them on demand, and everything else works without them.
+#[get("/readyz")]
+pub async fn read_readiness(store: &State<Store>, apple_keys: &State<AppleKeys>, shutdown: Shutdown) -> (Status, Json<Readiness>) {
+    // Stop taking new work while in-flight requests drain
+    let shutting_down = shutdown.now_or_never().is_some();
+
+    let database = check_database(store.backend.as_ref()).await;
+    let migrations = if database.ok {
+        check_migrations(store.backend.as_ref()).await
+    } else {
+        Check::failed(String::from("database unreachable"))
+    };
+
+    let apple_keys = match apple_keys.is_warm().await {
+        true => Check::passed(None),
+        false => Check::failed(String::from("keys not fetched yet")),
+    };
+
+    let ready = !shutting_down && database.ok && migrations.ok;
+    let status = if ready { Status::Ok } else { Status::ServiceUnavailable };
+    (status, Json(Readiness {
+        ready,
+        shutting_down,
+        database,
+        migrations,
+        apple_keys,
+    }))
+}
+
+async fn check_database(repository: &dyn Repository) -> Check {
+    match timeout(DATABASE_TIMEOUT, repository.ping()).await {
+        Ok(Ok(_)) => Check::passed(None),
+        Ok(Err(error)) => {
+            warn!(?error, "Readiness check couldn't ping MongoDB");
+            Check::failed(error.to_string())
+        },
+        Err(_) => Check::failed(String::from("timed out")),
+    }
+}
+
+// An instance launched with migrate_on_launch off waits here until `mossy_behind migrate` has run
+async fn check_migrations(repository: &dyn Repository) -> Check {
+    match timeout(DATABASE_TIMEOUT, repository.pending_migrations()).await {
+        Ok(Ok(pending)) if pending.is_empty() => Check::passed(None),
+        Ok(Ok(pending)) => {
+            let names: Vec<&str> = pending.iter().map(|(_, name)| *name).collect();
+            Check::failed(format!("pending: {}", names.join(", ")))
+        },
+        Ok(Err(error)) => {
+            warn!(?error, "Readiness check couldn't read migrations");
+            Check::failed(error.to_string())
+        },
+        Err(_) => Check::failed(String::from("timed out")),
+    }
+}
@@ -1,20 +1,52 @@
 #[macro_use] extern crate rocket;
+mod apple_keys;
+mod attachments;
+mod audit;
+mod calendar;
+mod config;
+mod digest;
+mod health;
+mod metrics;
+mod migrations;
+mod realtime;
+mod repository;
+mod sync;
+mod telemetry;
+mod trash;
+mod undo;
+mod validation;
+mod versioning;
+mod webhooks;
+#[cfg(test)]
+mod tests;
 use bson::Document;
-use mongodb::results::{InsertOneResult, DeleteResult, UpdateResult, InsertManyResult};
-use mongodb::{Client, options::ClientOptions};
 use mongodb::error::Error;
-use futures::stream::TryStreamExt;
+use rocket::fairing::AdHoc;
+use rocket::{Build, Rocket, State};
 use rocket::http::Status;
 use rocket::request::{Request, Outcome, FromRequest};
-use rocket::serde::{Serialize, Deserialize, json::Json};
+use rocket::serde::{Serialize, Deserialize, Deserializer, json::Json};
 use mongodb::bson;
-use mongodb::options::FindOptions;
 use reqwest;
 use reqwest::Error as ReqwestError;
 use base64::{Engine as _, engine::general_purpose};
 use jsonwebtoken;
 use jsonwebtoken::{DecodingKey, Validation, Algorithm};
-use dotenv;
+use chrono_tz::Tz;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use apple_keys::AppleKeys;
+use config::AppConfig;
+use digest::DigestFrequency;
+use realtime::ChangeBus;
+use audit::RequestMeta;
+use undo::OperationLog;
+use repository::{DocumentWrite, Repository, Store};
+use telemetry::{RequestTracing, traced};
+use metrics::RequestMetrics;
+use tracing::{Span, debug, error, field, info, warn};
+use versioning::{IfMatch, UpdateError, UpdateResponse};
+use validation::{ActionError, Rejection, Valid, Validate, ValidationErrors};
 
 // https://www.mongodb.com/developer/languages/rust/serde-improvements/
 
@@ -35,7 +67,14 @@ struct Credentials {
     user: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl Validate for Credentials {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        errors.check("identity_token", !self.identity_token.is_empty(), "can't be empty");
+        errors.check("user", !self.user.is_empty(), "can't be empty");
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(crate = "rocket::serde")]
 struct AppleAuthKey {
     alg: String,
@@ -84,10 +123,20 @@ struct User {
     email: String,
     apple_user_id: String,
     token: String,
+    // Only matters once token_ttl_days is set
+    token_issued_at: Option<bson::DateTime>,
     is_admin: bool,
     should_color_scheme_use_system: bool,
     is_color_scheme_dark_mode: bool,
     color_theme: u32,
+    digest_frequency: Option<String>,
+    timezone: Option<String>,
+    digest_hour: Option<u32>,
+    last_digest_sent_at: Option<bson::DateTime>,
+    // Set on every send, failed or not, so failures back off instead of retrying every tick
+    last_digest_attempt_at: Option<bson::DateTime>,
+    digest_failed_attempts: Option<u32>,
+    calendar_secret: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -99,12 +148,43 @@ struct UserThemeData {
     color_theme: u32,
 }
 
+impl Validate for UserThemeData {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        errors.check("apple_user_id", !self.apple_user_id.is_empty(), "can't be empty");
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+struct UserDigestData {
+    apple_user_id: String,
+    digest_frequency: Option<String>,
+    timezone: Option<String>,
+    digest_hour: Option<u32>,
+}
+
+impl Validate for UserDigestData {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        let valid_frequency = self.digest_frequency.as_deref().is_none_or(|frequency| DigestFrequency::parse(frequency).is_some());
+        errors.check("digest_frequency", valid_frequency, "must be \"daily\" or \"weekly\"");
+        let valid_timezone = self.timezone.as_deref().is_none_or(|timezone| timezone.parse::<Tz>().is_ok());
+        errors.check("timezone", valid_timezone, "must be an IANA timezone like \"Europe/Berlin\"");
+        errors.check("digest_hour", self.digest_hour.is_none_or(|hour| hour <= 23), "must be between 0 and 23");
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(crate = "rocket::serde")]
 struct UserData {
     apple_user_id: String,
 }
 
+impl Validate for UserData {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        errors.check("apple_user_id", !self.apple_user_id.is_empty(), "can't be empty");
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(crate = "rocket::serde")]
 struct Tag {
@@ -112,7 +192,61 @@ struct Tag {
     name: String,
     description: Option<String>,
     parent_tag: Option<bson::oid::ObjectId>,
+    // Hex color like "#34C759" or "#34C75980"
+    color: Option<String>,
+    // SF Symbol name or emoji
+    icon: Option<String>,
+    sort_order: Option<i32>,
+    archived: Option<bool>,
     user: Option<bson::oid::ObjectId>,
+    updated_at: Option<bson::DateTime>,
+    deleted_at: Option<bson::DateTime>,
+    // Tasks the tag was removed from when it was deleted, so restoring it can link them again
+    unlinked_tasks: Option<Vec<bson::oid::ObjectId>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct MergeTagsData {
+    source_tags: Vec<bson::oid::ObjectId>,
+    target_tag: bson::oid::ObjectId,
+}
+
+impl Validate for MergeTagsData {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        errors.check("source_tags", !self.source_tags.is_empty(), "can't be empty");
+        errors.check("source_tags", !self.source_tags.contains(&self.target_tag), "can't include the target tag");
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct MergeTagsSummary {
+    target_tag: bson::oid::ObjectId,
+    merged_tags: Vec<bson::oid::ObjectId>,
+    updated_tasks: Vec<bson::oid::ObjectId>,
+    reparented_tags: Vec<bson::oid::ObjectId>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct RetagTasksData {
+    tasks: Vec<bson::oid::ObjectId>,
+    add: Option<Vec<bson::oid::ObjectId>>,
+    remove: Option<Vec<bson::oid::ObjectId>>,
+}
+
+impl Validate for RetagTasksData {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        errors.check("tasks", !self.tasks.is_empty(), "can't be empty");
+        errors.check("add", self.add.is_some() || self.remove.is_some(), "or remove has to be given");
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct RetagTasksSummary {
+    updated_tasks: Vec<bson::oid::ObjectId>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -121,8 +255,73 @@ struct NewTagData {
     name: String,
     description: Option<String>,
     parent_tag: Option<bson::oid::ObjectId>,
+    color: Option<String>,
+    icon: Option<String>,
+    sort_order: Option<i32>,
+    archived: Option<bool>,
+}
+
+impl Validate for NewTagData {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        errors.check_name("name", &self.name);
+        errors.check_text("description", &self.description);
+        check_tag_appearance(errors, &self.color, &self.icon);
+    }
+}
+
+// Only the fields that are present change, and null clears the optional ones
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct UpdateTagData {
+    _id: bson::oid::ObjectId,
+    name: Option<String>,
+    #[serde(default, deserialize_with = "patch_field")]
+    description: Option<Option<String>>,
+    #[serde(default, deserialize_with = "patch_field")]
+    parent_tag: Option<Option<bson::oid::ObjectId>>,
+    #[serde(default, deserialize_with = "patch_field")]
+    color: Option<Option<String>>,
+    #[serde(default, deserialize_with = "patch_field")]
+    icon: Option<Option<String>>,
+    #[serde(default, deserialize_with = "patch_field")]
+    sort_order: Option<Option<i32>>,
+    #[serde(default, deserialize_with = "patch_field")]
+    archived: Option<Option<bool>>,
+}
+
+impl Validate for UpdateTagData {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        if let Some(name) = &self.name {
+            errors.check_name("name", name);
+        }
+        errors.check_text("description", &self.description.clone().flatten());
+        check_tag_appearance(errors, &self.color.clone().flatten(), &self.icon.clone().flatten());
+    }
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct TagTreeNode {
+    _id: bson::oid::ObjectId,
+    name: String,
+    description: Option<String>,
+    parent_tag: Option<bson::oid::ObjectId>,
+    color: Option<String>,
+    icon: Option<String>,
+    sort_order: Option<i32>,
+    archived: Option<bool>,
+    // Tasks tagged with this tag directly
+    task_count: usize,
+    // Distinct tasks tagged with this tag or any of its descendants
+    total_task_count: usize,
+    // Sum of how overdue the tasks counted in total_task_count are, in milliseconds
+    moss: i64,
+    children: Vec<TagTreeNode>,
+}
+
+// Ten years, in days
+const MAX_FREQUENCY_DAYS: i32 = 3650;
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(crate = "rocket::serde")]
 struct NewTaskData {
@@ -131,6 +330,35 @@ struct NewTaskData {
     tags: Option<Vec<bson::oid::ObjectId>>
 }
 
+impl Validate for NewTaskData {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        errors.check_name("name", &self.name);
+        errors.check("frequency", (1..=MAX_FREQUENCY_DAYS).contains(&self.frequency), "must be between 1 and 3650 days");
+    }
+}
+
+// Only the fields that are present change; name and frequency can't be cleared so null leaves them alone
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct UpdateTaskData {
+    _id: bson::oid::ObjectId,
+    name: Option<String>,
+    frequency: Option<i32>,
+    #[serde(default, deserialize_with = "patch_field")]
+    tags: Option<Option<Vec<bson::oid::ObjectId>>>,
+}
+
+impl Validate for UpdateTaskData {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        if let Some(name) = &self.name {
+            errors.check_name("name", name);
+        }
+        if let Some(frequency) = self.frequency {
+            errors.check("frequency", (1..=MAX_FREQUENCY_DAYS).contains(&frequency), "must be between 1 and 3650 days");
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(crate = "rocket::serde")]
 struct Task {
@@ -138,7 +366,11 @@ struct Task {
     name: String,
     frequency: i32,
     tags: Option<Vec<bson::oid::ObjectId>>,
+    // Reference photos and files, uploaded through /api/attachments
+    attachments: Option<Vec<bson::oid::ObjectId>>,
     user: Option<bson::oid::ObjectId>,
+    updated_at: Option<bson::DateTime>,
+    deleted_at: Option<bson::DateTime>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -149,6 +381,7 @@ struct TaskWithLatestEvent {
     frequency: i32,
     tags: Option<Vec<bson::oid::ObjectId>>,
     latest_event_date: Option<bson::DateTime>,
+    moss: Option<i64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -157,7 +390,18 @@ struct Event {
     _id: bson::oid::ObjectId,
     task: bson::oid::ObjectId,
     date: bson::DateTime,
+    notes: Option<String>,
+    // Time spent, in seconds
+    duration: Option<i64>,
+    // Amount used, in whatever unit makes sense for the task (e.g. litres of fertilizer)
+    quantity: Option<f64>,
+    // How well it went, from 1 to 5
+    rating: Option<i32>,
+    // Proof photos and files, uploaded through /api/attachments
+    attachments: Option<Vec<bson::oid::ObjectId>>,
     user: Option<bson::oid::ObjectId>,
+    updated_at: Option<bson::DateTime>,
+    deleted_at: Option<bson::DateTime>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -166,6 +410,10 @@ struct EventWithStringValues {
     _id: bson::oid::ObjectId,
     task: Option<String>,
     date: bson::DateTime,
+    notes: Option<String>,
+    duration: Option<i64>,
+    quantity: Option<f64>,
+    rating: Option<i32>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -173,13 +421,229 @@ struct EventWithStringValues {
 struct NewEventData {
     task: bson::oid::ObjectId,
     date: String,
+    notes: Option<String>,
+    duration: Option<i64>,
+    quantity: Option<f64>,
+    rating: Option<i32>,
+}
+
+impl Validate for NewEventData {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        errors.check_date("date", &self.date);
+        check_event_details(errors, &self.notes, self.duration, self.quantity, self.rating);
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(crate = "rocket::serde")]
 struct UpdateEventData {
     _id: bson::oid::ObjectId,
+    date: Option<String>,
+    #[serde(default, deserialize_with = "patch_field")]
+    notes: Option<Option<String>>,
+    #[serde(default, deserialize_with = "patch_field")]
+    duration: Option<Option<i64>>,
+    #[serde(default, deserialize_with = "patch_field")]
+    quantity: Option<Option<f64>>,
+    #[serde(default, deserialize_with = "patch_field")]
+    rating: Option<Option<i32>>,
+}
+
+impl Validate for UpdateEventData {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        if let Some(date) = &self.date {
+            errors.check_date("date", date);
+        }
+        // Cleared fields are always valid, so only check the values being set
+        check_event_details(errors, &self.notes.clone().flatten(), self.duration.flatten(), self.quantity.flatten(), self.rating.flatten());
+    }
+}
+
+// Tells a missing field (None) apart from an explicit null (Some(None)) in update payloads
+fn patch_field<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+// Either a list of tasks, or every task tagged with the tag or one of its descendants
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct NewEventsBatchData {
+    tasks: Option<Vec<bson::oid::ObjectId>>,
+    tag: Option<bson::oid::ObjectId>,
     date: String,
+    notes: Option<String>,
+    duration: Option<i64>,
+    quantity: Option<f64>,
+    rating: Option<i32>,
+}
+
+impl Validate for NewEventsBatchData {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        errors.check("tasks", self.tasks.is_some() != self.tag.is_some(), "or tag has to be given, but not both");
+        errors.check_date("date", &self.date);
+        check_event_details(errors, &self.notes, self.duration, self.quantity, self.rating);
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct EventsBatchResult {
+    task: bson::oid::ObjectId,
+    // "created", or "not_found" for a task that doesn't exist, isn't the user's or is in the trash
+    status: String,
+    event: Option<bson::oid::ObjectId>,
+}
+
+#[derive(Debug)]
+enum EventsBatchError {
+    InvalidDate,
+    // Neither or both of tasks and tag, or a tag that isn't one of the user's
+    InvalidSelection,
+    Database(Error),
+}
+
+impl From<Error> for EventsBatchError {
+    fn from(error: Error) -> Self {
+        EventsBatchError::Database(error)
+    }
+}
+
+impl fmt::Display for EventsBatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EventsBatchError::InvalidDate => write!(f, "invalid date"),
+            EventsBatchError::InvalidSelection => write!(f, "invalid selection"),
+            EventsBatchError::Database(error) => write!(f, "database error: {}", error),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct TaskStats {
+    task: bson::oid::ObjectId,
+    event_count: i64,
+    first_event_date: Option<bson::DateTime>,
+    latest_event_date: Option<bson::DateTime>,
+    total_duration: i64,
+    // Averages only count events that have the value set
+    average_duration: Option<f64>,
+    total_quantity: f64,
+    average_quantity: Option<f64>,
+    average_rating: Option<f64>,
+}
+
+// What happens to the dependents of a deleted task or tag
+#[derive(FromFormField, Debug, Clone, Copy, PartialEq)]
+enum DeleteStrategy {
+    // Delete the task's events, or the tag's descendants, along with it
+    Cascade,
+    // Move the tag's children up to its parent
+    Reparent,
+    // Leave the task's events alone, or move the tag's children to the top level.
+    // Stats and digests skip a trashed task's events, and restoring the task brings them back.
+    Detach,
+}
+
+// Serialized like mongodb's InsertOneResult and UpdateResult so older clients keep working
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct InsertedData {
+    #[serde(rename = "insertedId")]
+    inserted_id: bson::oid::ObjectId,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct UpdatedData {
+    #[serde(rename = "matchedCount")]
+    matched_count: u64,
+    #[serde(rename = "modifiedCount")]
+    modified_count: u64,
+    #[serde(rename = "upsertedId")]
+    upserted_id: Option<bson::oid::ObjectId>,
+}
+
+// For the debug routes, which used to return mongodb's InsertManyResult and DeleteResult
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct InsertedManyData {
+    #[serde(rename = "insertedIds")]
+    inserted_ids: HashMap<usize, bson::oid::ObjectId>,
+}
+
+impl InsertedManyData {
+    fn new(inserted_ids: impl Iterator<Item = bson::oid::ObjectId>) -> InsertedManyData {
+        InsertedManyData { inserted_ids: inserted_ids.enumerate().collect() }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct DeletedData {
+    #[serde(rename = "deletedCount")]
+    deleted_count: u64,
+}
+
+// deletedCount is serialized like mongodb's DeleteResult so older clients keep working
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(crate = "rocket::serde")]
+struct DeleteSummary {
+    #[serde(rename = "deletedCount")]
+    deleted_count: u64,
+    deleted_tasks: Vec<bson::oid::ObjectId>,
+    deleted_events: Vec<bson::oid::ObjectId>,
+    deleted_tags: Vec<bson::oid::ObjectId>,
+    // Tasks that had a deleted tag removed from their tags
+    updated_tasks: Vec<bson::oid::ObjectId>,
+    reparented_tags: Vec<bson::oid::ObjectId>,
+}
+
+#[derive(Debug)]
+enum DebugError {
+    // The debug routes are only for admins
+    Forbidden,
+    DatabaseError(Error),
+}
+
+impl From<Error> for DebugError {
+    fn from(error: Error) -> Self {
+        DebugError::DatabaseError(error)
+    }
+}
+
+impl fmt::Display for DebugError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DebugError::Forbidden => write!(f, "not an admin"),
+            DebugError::DatabaseError(error) => write!(f, "database error: {}", error),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum DeleteError {
+    // One of the ids doesn't exist, belongs to someone else or is already in the trash
+    NotFound,
+    // Something else wrote to one of the documents between reading and deleting them
+    ConcurrentChange,
+    DatabaseError(Error),
+}
+
+impl From<Error> for DeleteError {
+    fn from(error: Error) -> Self {
+        DeleteError::DatabaseError(error)
+    }
+}
+
+impl From<bson::ser::Error> for DeleteError {
+    fn from(error: bson::ser::Error) -> Self {
+        DeleteError::DatabaseError(error.into())
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -188,6 +652,12 @@ struct DebugCreateTasksData {
     quantity: u8,
 }
 
+impl Validate for DebugCreateTasksData {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        errors.check("quantity", self.quantity > 0, "must be at least 1");
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(crate = "rocket::serde")]
 struct DebugCreateEventsData {
@@ -200,6 +670,12 @@ struct DebugCreateTagsData {
     quantity: u8,
 }
 
+impl Validate for DebugCreateTagsData {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        errors.check("quantity", self.quantity > 0, "must be at least 1");
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(crate = "rocket::serde")]
 struct ReadParams {
@@ -209,19 +685,82 @@ struct ReadParams {
 
 #[derive(Debug)]
 enum CredentialsError {
-    FetchKeysError(ReqwestError),
-    DotEnvError,
-    NoClientIdError,
-    NoDbAddressError,
-    DeserializeJsonError,
-    DecodeJwtError,
-    NoKidError,
-    NoMatchingKidError,
-    InvalidKeySucceededError,
-    MatchingKeyFailedError,
-    DecodeComponentError,
-    InvalidNonceError,
-    DatabaseError,
+    FetchKeys(ReqwestError),
+    DeserializeJson(ReqwestError),
+    DecodeJwt(jsonwebtoken::errors::Error),
+    NoKid,
+    NoMatchingKid,
+    InvalidKeySucceeded,
+    MatchingKeyFailed(jsonwebtoken::errors::Error),
+    DecodeComponent(base64::DecodeError),
+    InvalidNonce,
+    Database(Error),
+}
+
+impl fmt::Display for CredentialsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CredentialsError::FetchKeys(error) => write!(f, "couldn't fetch Apple's keys: {}", error),
+            CredentialsError::DeserializeJson(error) => write!(f, "couldn't read Apple's keys: {}", error),
+            CredentialsError::DecodeJwt(error) => write!(f, "couldn't decode the identity token: {}", error),
+            CredentialsError::NoKid => write!(f, "the identity token has no kid"),
+            CredentialsError::NoMatchingKid => write!(f, "none of Apple's keys has the identity token's kid"),
+            CredentialsError::InvalidKeySucceeded => write!(f, "the identity token validated against a key it shouldn't have"),
+            CredentialsError::MatchingKeyFailed(error) => write!(f, "the identity token didn't validate: {}", error),
+            CredentialsError::DecodeComponent(error) => write!(f, "couldn't decode a key component: {}", error),
+            CredentialsError::InvalidNonce => write!(f, "the nonce doesn't match"),
+            CredentialsError::Database(error) => write!(f, "database error: {}", error),
+        }
+    }
+}
+
+impl CredentialsError {
+    // Label for the logins metric, so a spike in one kind of failure stands out
+    fn name(&self) -> &'static str {
+        match self {
+            CredentialsError::FetchKeys(_) => "fetch_keys_error",
+            CredentialsError::DeserializeJson(_) => "deserialize_json_error",
+            CredentialsError::DecodeJwt(_) => "decode_jwt_error",
+            CredentialsError::NoKid => "no_kid_error",
+            CredentialsError::NoMatchingKid => "no_matching_kid_error",
+            CredentialsError::InvalidKeySucceeded => "invalid_key_succeeded_error",
+            CredentialsError::MatchingKeyFailed(_) => "matching_key_failed_error",
+            CredentialsError::DecodeComponent(_) => "decode_component_error",
+            CredentialsError::InvalidNonce => "invalid_nonce_error",
+            CredentialsError::Database(_) => "database_error",
+        }
+    }
+}
+
+#[derive(Debug)]
+enum TagError {
+    InvalidParentError,
+    ParentCycleError,
+    // A tag that doesn't exist, belongs to someone else or is in the trash
+    InvalidTag,
+    // A color that isn't hex or an icon that isn't a symbol name or emoji
+    InvalidAppearanceError,
+    // Another tag under the same parent already has this name
+    DuplicateNameError,
+    // The client's copy is stale; holds the current server copy
+    VersionConflict(Document),
+    // The tag being changed doesn't exist, belongs to someone else or is in the trash
+    NotFound,
+    // Something else wrote to one of the tasks or tags between reading and changing them
+    ConcurrentChange,
+    DatabaseError(Error),
+}
+
+impl From<Error> for TagError {
+    fn from(error: Error) -> Self {
+        TagError::DatabaseError(error)
+    }
+}
+
+impl From<bson::ser::Error> for TagError {
+    fn from(error: bson::ser::Error) -> Self {
+        TagError::DatabaseError(error.into())
+    }
 }
 
 #[get("/")]
@@ -233,110 +772,75 @@ async fn index() -> &'static str {
 // https://stackoverflow.com/questions/66067321/marshal-appleids-public-key-to-rsa-publickey
 // https://developer.apple.com/documentation/sign_in_with_apple/sign_in_with_apple_rest_api/verifying_a_user
 // https://jwt.io/ to decode JWT
-async fn validate_credentials(credentials: Credentials) -> Result<User, CredentialsError> {
-    let keys_response = match reqwest::get("https://appleid.apple.com/auth/keys").await {
-        Ok(_keys_response) => _keys_response,
-        Err(_keys_response) => return Err(CredentialsError::FetchKeysError(_keys_response))
-    };
-    let deserialized_keys_response = match keys_response.json::<AppleAuthResponse>().await {
-        Ok(_deserialized_keys_response) => _deserialized_keys_response,
-        Err(_deserialized_keys_response) => return Err(CredentialsError::DeserializeJsonError)
-    };
-
+async fn validate_credentials(credentials: Credentials, meta: &RequestMeta, config: &AppConfig, apple_keys: &AppleKeys, repository: &dyn Repository) -> Result<User, CredentialsError> {
     let credential_header = match jsonwebtoken::decode_header(&credentials.identity_token) {
         Ok(_credential_header) => _credential_header,
-        Err(_credential_header) => return Err(CredentialsError::DecodeJwtError)
+        Err(_credential_header) => return Err(CredentialsError::DecodeJwt(_credential_header))
     };
     let Some(credential_kid) = credential_header.kid else {
-        return Err(CredentialsError::NoKidError)
+        return Err(CredentialsError::NoKid)
     };
 
-    let _dotenv_result = match dotenv::dotenv() {
-        Ok(_dotenv_result) => _dotenv_result,
-        Err(_) => return Err(CredentialsError::DotEnvError),
-    };
-    let apple_client_id = match dotenv::var("APPLE_CLIENT_ID") {
-        Ok(_apple_client_id) => _apple_client_id,
-        Err(_) => return Err(CredentialsError::NoClientIdError)
-    };
+    let keys = apple_keys.keys_with(&credential_kid).await?;
 
     // We can specify validation predicates here per this list:
     // https://developer.apple.com/documentation/sign_in_with_apple/sign_in_with_apple_rest_api/verifying_a_user
     let mut validation = Validation::new(Algorithm::RS256);
     validation.set_issuer(&["https://appleid.apple.com"]);
-    validation.set_audience(&[apple_client_id]);
+    validation.set_audience(&config.apple_client_ids);
 
-    let mut keys_iterator = deserialized_keys_response.keys.into_iter();
+    let mut keys_iterator = keys.into_iter();
     let Some(matching_key) = keys_iterator.find(|key| key.kid == credential_kid) else {
-        return Err(CredentialsError::NoMatchingKidError)
+        return Err(CredentialsError::NoMatchingKid)
     };
 
     // Make sure an invalid key fails, if one exists in the response
     if let Some(invalid_key) = keys_iterator.find(|key| key.kid != credential_kid) {
         let decoded_n = match general_purpose::URL_SAFE_NO_PAD.decode(invalid_key.n) {
             Ok(_decoded_n) => _decoded_n,
-            Err(_decoded_n) => return Err(CredentialsError::DecodeComponentError)
+            Err(_decoded_n) => return Err(CredentialsError::DecodeComponent(_decoded_n))
         };
         let decoded_e = match general_purpose::URL_SAFE_NO_PAD.decode(invalid_key.e) {
             Ok(_decoded_e) => _decoded_e,
-            Err(_decoded_e) => return Err(CredentialsError::DecodeComponentError)
+            Err(_decoded_e) => return Err(CredentialsError::DecodeComponent(_decoded_e))
         };
         let decoding_key = DecodingKey::from_rsa_raw_components(&decoded_n, &decoded_e);
         let _claims = match jsonwebtoken::decode::<Claims>(&credentials.identity_token, &decoding_key, &validation) {
-            Ok(_) => return Err(CredentialsError::InvalidKeySucceededError),
-            Err(_) => println!("Invalid key failed as expected! 👍"),
+            Ok(_) => return Err(CredentialsError::InvalidKeySucceeded),
+            Err(_) => debug!("Invalid key failed as expected"),
         };
     };
 
     let decoded_n = match general_purpose::URL_SAFE_NO_PAD.decode(matching_key.n) {
         Ok(_decoded_n) => _decoded_n,
-        Err(_decoded_n) => return Err(CredentialsError::DecodeComponentError)
+        Err(_decoded_n) => return Err(CredentialsError::DecodeComponent(_decoded_n))
     };
     let decoded_e = match general_purpose::URL_SAFE_NO_PAD.decode(matching_key.e) {
         Ok(_decoded_e) => _decoded_e,
-        Err(_decoded_e) => return Err(CredentialsError::DecodeComponentError)
+        Err(_decoded_e) => return Err(CredentialsError::DecodeComponent(_decoded_e))
     };
     let decoding_key = DecodingKey::from_rsa_raw_components(&decoded_n, &decoded_e);
     let claims = match jsonwebtoken::decode::<Claims>(&credentials.identity_token, &decoding_key, &validation) {
         Ok(_claims) => _claims,
-        Err(_claims) => return Err(CredentialsError::MatchingKeyFailedError),
+        Err(_claims) => return Err(CredentialsError::MatchingKeyFailed(_claims)),
     };
 
     let Some(claims_nonce) = &claims.claims.nonce else {
-        return Err(CredentialsError::InvalidNonceError)
+        return Err(CredentialsError::InvalidNonce)
     };
     if *claims_nonce != credentials.nonce {
-        return Err(CredentialsError::InvalidNonceError)
+        return Err(CredentialsError::InvalidNonce)
     }
 
-    let mongodb_address = match dotenv::var("MONGODB_ADDRESS") {
-        Ok(_mongodb_address) => _mongodb_address,
-        Err(_) => return Err(CredentialsError::NoDbAddressError)
-    };
-    
-    let mut client_options = match ClientOptions::parse(mongodb_address).await {
-        Ok(_client_options) => _client_options,
-        Err(_) => return Err(CredentialsError::DatabaseError)
-    };
-    client_options.app_name = Some("mossy".to_string());
-    let client = match Client::with_options(client_options) {
-        Ok(_client) => _client,
-        Err(_) => return Err(CredentialsError::DatabaseError)
-    };
-    let db = client.database("mossy");
-
-    let users = db.collection::<User>("users");
-
-    let filter = bson::doc! {
-        "apple_user_id": credentials.user.clone(),
-    };
-    let existing_user_option = match users.find_one(filter, None).await {
+    let existing_user_option = match repository.find_user_by_apple_id(&credentials.user).await {
         Ok(_existing_user) => _existing_user,
-        Err(_existing_user) => return Err(CredentialsError::DatabaseError)
+        Err(_existing_user) => return Err(CredentialsError::Database(_existing_user))
     };
     let token = bson::uuid::Uuid::new().to_string();
     let token_create_user_copy = token.clone();
     let token_update_user_copy = token.clone();
+    let token_issued_at = bson::DateTime::now();
+    let existing_user_option_was_none = existing_user_option.is_none();
     let saved_user = if existing_user_option.is_none() {
         let email_copy = claims.claims.email.clone();
         let user_copy = credentials.user.clone();
@@ -345,133 +849,128 @@ async fn validate_credentials(credentials: Credentials) -> Result<User, Credenti
             email: email_copy,
             apple_user_id: user_copy,
             token: token_create_user_copy,
+            token_issued_at: Some(token_issued_at),
             should_color_scheme_use_system: false,
             is_color_scheme_dark_mode: false,
             color_theme: 1,
             is_admin: false,
+            digest_frequency: None,
+            timezone: None,
+            digest_hour: None,
+            last_digest_sent_at: None,
+            last_digest_attempt_at: None,
+            digest_failed_attempts: None,
+            calendar_secret: None,
         };
-        let user_copy = user.clone();
-        match users.insert_one(user_copy, None).await {
+        match repository.insert_user(&user).await {
             Ok(_user_result) => _user_result,
-            Err(_) => return Err(CredentialsError::DatabaseError)
+            Err(_error) => return Err(CredentialsError::Database(_error))
         };
         user
     } else {
         let updated_user = bson::doc! {
-            "$set": {
-                "token": token,
-            }
+            "token": token,
+            "token_issued_at": token_issued_at,
         };
         let mut existing_user = existing_user_option.unwrap();
-        let filter = bson::doc!{"_id": existing_user._id };
-        match users.update_one(filter, updated_user, None).await {
+        match repository.update_user(existing_user._id, updated_user).await {
             Ok(_user_result) => _user_result,
-            Err(_) => return Err(CredentialsError::DatabaseError)
+            Err(_error) => return Err(CredentialsError::Database(_error))
         };
         existing_user.token = token_update_user_copy;
+        existing_user.token_issued_at = Some(token_issued_at);
         existing_user
     };
 
+    // The token is already saved, so a missing audit entry isn't worth failing the log in over
+    let action = if existing_user_option_was_none { "sign_up" } else { "log_in" };
+    if let Err(error) = audit::record(repository, saved_user._id, action, vec![saved_user._id], Vec::new(), meta).await {
+        warn!(?error, "Couldn't audit log in");
+    }
+
     Ok(saved_user)
 }
 
-async fn read_user_action(token: Token<'_>, user_data: UserData) -> Result<User, Error> {
-    let mut client_options = ClientOptions::parse("mongodb://localhost:27017").await?;
-    client_options.app_name = Some("mossy".to_string());
-    let client = Client::with_options(client_options)?;
-    let db = client.database("mossy");
+// None when the token belongs to a different account than the one asked for
+async fn read_user_action(token: Token, user_data: UserData) -> Result<Option<User>, Error> {
+    let user_option = Some(token.0).filter(|user| user.apple_user_id == user_data.apple_user_id);
 
-    let users = db.collection::<User>("users");
+    Ok(user_option)
+}
 
-    let mut token_split = token.clone().0.split(" ");
-    let Some(token_value) = token_split.nth(1) else {
-        todo!()
-    };
+async fn update_user_theme_action(token: Token, user_theme_data: UserThemeData, store: &Store, meta: &RequestMeta) -> Result<Option<UpdatedData>, Error> {
+    let repository = store.backend.as_ref();
 
-    let filter = bson::doc! {
-        "apple_user_id": user_data.apple_user_id.clone(),
-        "token": token_value,
-    };
+    let user = token.0;
+    if user.apple_user_id != user_theme_data.apple_user_id {
+        return Ok(None)
+    }
 
-    let user_option = match users.find_one(filter, None).await {
-        Ok(_user) => _user,
-        Err(_) => todo!()
+    let updated_user = bson::doc! {
+        "should_color_scheme_use_system": user_theme_data.should_color_scheme_use_system,
+        "is_color_scheme_dark_mode": user_theme_data.is_color_scheme_dark_mode,
+        "color_theme": user_theme_data.color_theme,
     };
 
-    if let Some(user) = user_option {
-        Ok(user)
-    } else {
-        todo!()
-    }
-}
+    let mut operation = OperationLog::audit_only();
+    operation.snapshot_document(repository, "users", user._id).await?;
 
-async fn update_user_theme_action(token: Token<'_>, user_theme_data: UserThemeData) -> Result<UpdateResult, Error> {
-    let mut client_options = ClientOptions::parse("mongodb://localhost:27017").await?;
-    client_options.app_name = Some("mossy".to_string());
-    let client = Client::with_options(client_options)?;
-    let db = client.database("mossy");
+    let user_result = repository.update_user(user._id, updated_user.clone()).await;
 
-    let users = db.collection::<User>("users");
+    match user_result {
+        Ok(_) => {
+            operation.updated("users", user._id, &updated_user);
+            operation.record(repository, user._id, "update_user_theme", meta).await;
+            Ok(Some(UpdatedData {
+                matched_count: 1,
+                modified_count: 1,
+                upserted_id: None,
+            }))
+        },
+        Err(error) => Err(error),
+    }
+}
 
-    let mut token_split = token.clone().0.split(" ");
-    let Some(token_value) = token_split.nth(1) else {
-        todo!()
-    };
+async fn update_user_digest_action(token: Token, user_digest_data: UserDigestData, store: &Store, meta: &RequestMeta) -> Result<Option<UpdatedData>, Error> {
+    let repository = store.backend.as_ref();
 
-    let user_filter = bson::doc! {
-        "token": token_value,
-        "apple_user_id": user_theme_data.apple_user_id,
-    };
-    let Some(user) = users.find_one(user_filter, None).await? else {
-        todo!()
-    };
+    let user = token.0;
+    if user.apple_user_id != user_digest_data.apple_user_id {
+        return Ok(None)
+    }
 
     let updated_user = bson::doc! {
-        "$set": {
-            "should_color_scheme_use_system": user_theme_data.should_color_scheme_use_system,
-            "is_color_scheme_dark_mode": user_theme_data.is_color_scheme_dark_mode,
-            "color_theme": user_theme_data.color_theme,
-        }
+        "digest_frequency": user_digest_data.digest_frequency,
+        "timezone": user_digest_data.timezone,
+        "digest_hour": user_digest_data.digest_hour,
     };
 
-    let filter = bson::doc!{"_id": user._id };
+    let mut operation = OperationLog::audit_only();
+    operation.snapshot_document(repository, "users", user._id).await?;
 
-    let user_result = users.update_one(filter, updated_user, None).await;
+    let user_result = repository.update_user(user._id, updated_user.clone()).await;
 
     match user_result {
-        Ok(_user_result) => Ok(_user_result),
-        Err(_) => todo!(),
+        Ok(_) => {
+            operation.updated("users", user._id, &updated_user);
+            operation.record(repository, user._id, "update_user_digest", meta).await;
+            Ok(Some(UpdatedData {
+                matched_count: 1,
+                modified_count: 1,
+                upserted_id: None,
+            }))
+        },
+        Err(error) => Err(error),
     }
 }
 
-async fn read_tasks_action(token: Token<'_>, params: ReadParams) -> Result<Vec<Document>, Error> {
-    let limit = params.limit.unwrap_or(0);
-    let offset = params.offset.unwrap_or(0);
-
-    let mut client_options = ClientOptions::parse("mongodb://localhost:27017").await?;
-    client_options.app_name = Some("mossy".to_string());
-    let client = Client::with_options(client_options)?;
-    let db = client.database("mossy");
-
-    let users = db.collection::<User>("users");
-    let tasks = db.collection::<Task>("tasks");
-
-    let mut token_split = token.clone().0.split(" ");
-    let Some(token_value) = token_split.nth(1) else {
-        todo!()
-    };
-
-    let user_filter = bson::doc! {
-        "token": token_value,
-    };
-    let Some(user) = users.find_one(user_filter, None).await? else {
-        todo!()
-    };
-
-    let tasks_filter = vec! [
+// Computes each task's latest event date and "moss" (milliseconds past due, negative when not yet due)
+fn moss_pipeline(user_id: bson::oid::ObjectId) -> Vec<Document> {
+    vec! [
         bson::doc! {
             "$match": {
-                "user": user._id,
+                "user": user_id,
+                "deleted_at": null,
             }
         },
         bson::doc! {
@@ -479,6 +978,13 @@ async fn read_tasks_action(token: Token<'_>, params: ReadParams) -> Result<Vec<D
                 "from": "events",
                 "localField": "_id",
                 "foreignField": "task",
+                "pipeline": [
+                    {
+                        "$match": {
+                            "deleted_at": null,
+                        }
+                    },
+                ],
                 "as": "event_mapping", 
             },
         },
@@ -550,128 +1056,64 @@ async fn read_tasks_action(token: Token<'_>, params: ReadParams) -> Result<Vec<D
                 "frequency_in_milliseconds",
             ]
         },
-        bson::doc! {
-            // We also need to sort by a unique value (_id) to ensure we don't get duplicates in pagination
-            // We should eventually create a compound index for this: https://www.mongodb.com/docs/manual/tutorial/sort-results-with-indexes/#sort-on-multiple-fields
-            "$sort": {
-                "moss": -1,
-                "_id": -1,
-            }
-        },
-        bson::doc! {
-            "$skip": offset
-        },
-        bson::doc! {
-            "$limit": limit
-        },
-    ];
-    let mut tasks_cursor = tasks.aggregate(tasks_filter, None).await?;
-
-    let mut tasks_list = Vec::new();
-
-    while let Some(task) = tasks_cursor.try_next().await? {
-        tasks_list.push(task);
-    }
-
-    Ok(tasks_list)
+    ]
 }
 
-async fn read_events_action(token: Token<'_>, params: ReadParams) ->Result<Vec<Event>, Error> {
+async fn read_tasks_action(token: Token, params: ReadParams, store: &Store) -> Result<Vec<Document>, Error> {
     let limit = params.limit.unwrap_or(0);
     let offset = params.offset.unwrap_or(0);
 
-    let mut client_options = ClientOptions::parse("mongodb://localhost:27017").await?;
-    client_options.app_name = Some("mossy".to_string());
-    let client = Client::with_options(client_options)?;
-    let db = client.database("mossy");
+    let repository = store.backend.as_ref();
 
-    let users = db.collection::<User>("users");
-    let events = db.collection::<Event>("events");
+    let user = token.0;
 
-    let mut token_split = token.clone().0.split(" ");
-    let Some(token_value) = token_split.nth(1) else {
-        todo!()
-    };
-
-    let user_filter = bson::doc! {
-        "token": token_value,
-    };
-    let Some(user) = users.find_one(user_filter, None).await? else {
-        todo!()
-    };
+    repository.find_tasks_with_moss(user._id, offset, limit).await
+}
 
-    let events_filter = bson::doc! {
-        "user": user._id,
-    };
-    let sort_option = bson::doc! {
-        "date": -1,
-        "_id": -1,
-    };
-    let options = FindOptions::builder().sort(sort_option).skip(Some(u64::from(offset))).limit(Some(i64::from(limit))).build();
-    let mut cursor = events.find(events_filter, options).await?;
+async fn read_events_action(token: Token, params: ReadParams, store: &Store) ->Result<Vec<Event>, Error> {
+    let limit = params.limit.unwrap_or(0);
+    let offset = params.offset.unwrap_or(0);
 
-    let mut events_list = Vec::new();
+    let repository = store.backend.as_ref();
 
-    while let Some(event) = cursor.try_next().await? {
-        events_list.push(event);
-    }
+    let user = token.0;
 
-    Ok(events_list)
+    repository.find_events(user._id, offset, limit).await
 }
 
-async fn read_events_string_action(token: Token<'_>, params: ReadParams) ->Result<Vec<EventWithStringValues>, Error> {
+async fn read_events_string_action(token: Token, params: ReadParams, store: &Store) ->Result<Vec<EventWithStringValues>, Error> {
     let limit = params.limit.unwrap_or(0);
     let offset = params.offset.unwrap_or(0);
 
-    let mut client_options = ClientOptions::parse("mongodb://localhost:27017").await?;
-    client_options.app_name = Some("mossy".to_string());
-    let client = Client::with_options(client_options)?;
-    let db = client.database("mossy");
+    let repository = store.backend.as_ref();
 
-    let users = db.collection::<User>("users");
-    let events = db.collection::<Event>("events");
-    let tasks = db.collection::<Task>("tasks");
-
-    let mut token_split = token.clone().0.split(" ");
-    let Some(token_value) = token_split.nth(1) else {
-        todo!()
-    };
-
-    let user_filter = bson::doc! {
-        "token": token_value,
-    };
-    let Some(user) = users.find_one(user_filter, None).await? else {
-        todo!()
-    };
+    let user = token.0;
 
-    let events_filter = bson::doc! {
-        "user": user._id,
-    };
-    let sort_option = bson::doc! {
-        "date": -1,
-        "_id": -1,
-    };
-    let options = FindOptions::builder().sort(sort_option).skip(Some(u64::from(offset))).limit(Some(i64::from(limit))).build();
-    let mut cursor = events.find(events_filter, options).await?;
+    let events = repository.find_events(user._id, offset, limit).await?;
 
     let mut events_list = Vec::new();
 
-    while let Some(event) = cursor.try_next().await? {
-        let filter = bson::doc! {
-            "_id": event.task,
-        };
-        let task = tasks.find_one(filter, None).await?;
+    for event in events {
+        let task = repository.find_task(event.task).await?;
 
         let event_with_string_values = match task {
             Some(_task) => EventWithStringValues {
                 _id: event._id,
                 task: Some(_task.name),
                 date: event.date,
+                notes: event.notes,
+                duration: event.duration,
+                quantity: event.quantity,
+                rating: event.rating,
             },
             None => EventWithStringValues {
                 _id: event._id,
                 task: None,
                 date: event.date,
+                notes: event.notes,
+                duration: event.duration,
+                quantity: event.quantity,
+                rating: event.rating,
             }
         };
         events_list.push(event_with_string_values);
@@ -680,472 +1122,1027 @@ async fn read_events_string_action(token: Token<'_>, params: ReadParams) ->Resul
     Ok(events_list)
 }
 
-async fn read_tags_action(token: Token<'_>, params: ReadParams) ->Result<Vec<Tag>, Error> {
+async fn read_tags_action(token: Token, params: ReadParams, store: &Store) ->Result<Vec<Tag>, Error> {
     let limit = params.limit.unwrap_or(0);
     let offset = params.offset.unwrap_or(0);
 
-    let mut client_options = ClientOptions::parse("mongodb://localhost:27017").await?;
-    client_options.app_name = Some("mossy".to_string());
-    let client = Client::with_options(client_options)?;
-    let db = client.database("mossy");
+    let repository = store.backend.as_ref();
 
-    let users = db.collection::<User>("users");
-    let tags = db.collection::<Tag>("tags");
+    let user = token.0;
 
-    let mut token_split = token.clone().0.split(" ");
-    let Some(token_value) = token_split.nth(1) else {
-        todo!()
-    };
+    repository.find_tags(user._id, offset, limit).await
+}
 
-    let user_filter = bson::doc! {
-        "token": token_value,
-    };
-    let Some(user) = users.find_one(user_filter, None).await? else {
-        todo!()
-    };
+// A parent has to be one of the user's live tags, and can't be the tag itself or one of its descendants
+async fn validate_parent_tag(repository: &dyn Repository, user_id: bson::oid::ObjectId, tag_id: Option<bson::oid::ObjectId>, parent_tag: Option<bson::oid::ObjectId>) -> Result<(), TagError> {
+    let mut visited = HashSet::new();
+    let mut next_ancestor = parent_tag;
+    let mut is_direct_parent = true;
 
-    let tags_filter = bson::doc! {
-        "user": user._id,
-    };
-    let sort_option = bson::doc! {
-        "name": 1,
-        "_id": -1,
-    };
-    let options = FindOptions::builder().sort(sort_option).skip(Some(u64::from(offset))).limit(Some(i64::from(limit))).build();
-    let mut cursor = tags.find(tags_filter, options).await?;
+    while let Some(ancestor_id) = next_ancestor {
+        if Some(ancestor_id) == tag_id {
+            return Err(TagError::ParentCycleError)
+        }
+        // An older cycle further up that doesn't involve this tag
+        if !visited.insert(ancestor_id) {
+            break
+        }
+        let ancestor_option = repository.find_tag(ancestor_id).await?.filter(|tag| tag.user == Some(user_id) && tag.deleted_at.is_none());
+        let Some(ancestor) = ancestor_option else {
+            if is_direct_parent {
+                return Err(TagError::InvalidParentError)
+            }
+            break
+        };
+        is_direct_parent = false;
+        next_ancestor = ancestor.parent_tag;
+    }
 
-    let mut tags_list = Vec::new();
+    Ok(())
+}
 
-    while let Some(tag) = cursor.try_next().await? {
-        tags_list.push(tag);
-    }
+fn is_valid_color(color: &str) -> bool {
+    let Some(hex) = color.strip_prefix("#") else {
+        return false
+    };
+    (hex.len() == 6 || hex.len() == 8) && hex.chars().all(|character| character.is_ascii_hexdigit())
+}
 
-    Ok(tags_list)
+// SF Symbol names look like "cart.fill", emoji can be several code points, so only rule out what neither can contain
+fn is_valid_icon(icon: &str) -> bool {
+    !icon.is_empty() && icon.len() <= 64 && !icon.chars().any(|character| character.is_whitespace() || character.is_control())
 }
 
-async fn create_task_action(token: Token<'_>, task_data: NewTaskData) -> Result<InsertOneResult, Error> {
-    let mut client_options = ClientOptions::parse("mongodb://localhost:27017").await?;
-    client_options.app_name = Some("mossy".to_string());
-    let client = Client::with_options(client_options)?;
-    let db = client.database("mossy");
+fn check_tag_appearance(errors: &mut ValidationErrors, color: &Option<String>, icon: &Option<String>) {
+    errors.check("color", color.as_deref().is_none_or(is_valid_color), "must be a hex color like \"#34C759\"");
+    errors.check("icon", icon.as_deref().is_none_or(is_valid_icon), "must be a symbol name or emoji");
+}
 
-    let users = db.collection::<User>("users");
-    let tasks = db.collection::<Task>("tasks");
+// The field errors a client gets back for a tag that fails the checks that need the database
+fn tag_validation_errors(error: &TagError) -> Option<ValidationErrors> {
+    match error {
+        TagError::InvalidParentError => Some(ValidationErrors::field("parent_tag", "must be one of your tags")),
+        TagError::ParentCycleError => Some(ValidationErrors::field("parent_tag", "can't be the tag itself or one of its descendants")),
+        TagError::InvalidTag => Some(ValidationErrors::field("tags", "must all be your tags")),
+        TagError::InvalidAppearanceError => Some(ValidationErrors::field("color", "must be a hex color, and the icon a symbol name or emoji")),
+        TagError::DuplicateNameError => Some(ValidationErrors::field("name", "is already used by another tag with the same parent")),
+        _ => None,
+    }
+}
 
-    let mut token_split = token.clone().0.split(" ");
-    let Some(token_value) = token_split.nth(1) else {
-        todo!()
-    };
+async fn validate_tag(repository: &dyn Repository, user_id: bson::oid::ObjectId, tag_id: Option<bson::oid::ObjectId>, name: &str, parent_tag: Option<bson::oid::ObjectId>, color: &Option<String>, icon: &Option<String>) -> Result<(), TagError> {
+    if !color.as_deref().is_none_or(is_valid_color) || !icon.as_deref().is_none_or(is_valid_icon) {
+        return Err(TagError::InvalidAppearanceError)
+    }
 
-    let user_filter = bson::doc! {
-        "token": token_value,
-    };
-    let Some(user) = users.find_one(user_filter, None).await? else {
-        todo!()
+    validate_parent_tag(repository, user_id, tag_id, parent_tag).await?;
+
+    // Names are compared ignoring case so "Kitchen" and "kitchen" can't end up side by side
+    for sibling in repository.find_tags(user_id, 0, 0).await? {
+        if sibling.parent_tag == parent_tag && Some(sibling._id) != tag_id && sibling.name.to_lowercase() == name.to_lowercase() {
+            return Err(TagError::DuplicateNameError)
+        }
+    }
+
+    Ok(())
+}
+
+fn build_tag_tree_node(
+    tag: &Tag,
+    children_by_parent: &HashMap<bson::oid::ObjectId, Vec<&Tag>>,
+    tasks_by_tag: &HashMap<bson::oid::ObjectId, Vec<bson::oid::ObjectId>>,
+    moss_by_task: &HashMap<bson::oid::ObjectId, i64>,
+    visited: &mut HashSet<bson::oid::ObjectId>,
+) -> (TagTreeNode, HashSet<bson::oid::ObjectId>) {
+    visited.insert(tag._id);
+
+    let direct_tasks = tasks_by_tag.get(&tag._id).cloned().unwrap_or_default();
+    let mut subtree_tasks: HashSet<bson::oid::ObjectId> = direct_tasks.iter().cloned().collect();
+
+    let mut children = Vec::new();
+    for child in children_by_parent.get(&tag._id).cloned().unwrap_or_default() {
+        if visited.contains(&child._id) {
+            continue
+        }
+        let (child_node, child_tasks) = build_tag_tree_node(child, children_by_parent, tasks_by_tag, moss_by_task, visited);
+        subtree_tasks.extend(child_tasks);
+        children.push(child_node);
+    }
+
+    let moss = subtree_tasks.iter().map(|task_id| moss_by_task.get(task_id).cloned().unwrap_or(0)).sum();
+    let node = TagTreeNode {
+        _id: tag._id,
+        name: tag.name.clone(),
+        description: tag.description.clone(),
+        parent_tag: tag.parent_tag,
+        color: tag.color.clone(),
+        icon: tag.icon.clone(),
+        sort_order: tag.sort_order,
+        archived: tag.archived,
+        task_count: direct_tasks.len(),
+        total_task_count: subtree_tasks.len(),
+        moss,
+        children,
     };
 
+    (node, subtree_tasks)
+}
+
+async fn read_tags_tree_action(token: Token, store: &Store) -> Result<Vec<TagTreeNode>, Error> {
+    let repository = store.backend.as_ref();
+
+    let user = token.0;
+
+    let tags_list = repository.find_tags(user._id, 0, 0).await?;
+
+    let mut tasks_by_tag: HashMap<bson::oid::ObjectId, Vec<bson::oid::ObjectId>> = HashMap::new();
+    let mut moss_by_task = HashMap::new();
+    for task_document in repository.find_tasks_with_moss(user._id, 0, 0).await? {
+        let Ok(task) = bson::from_document::<TaskWithLatestEvent>(task_document) else {
+            continue
+        };
+        // Only overdue tasks add moss
+        moss_by_task.insert(task._id, task.moss.unwrap_or(0).max(0));
+        for tag_id in task.tags.unwrap_or_default() {
+            tasks_by_tag.entry(tag_id).or_default().push(task._id);
+        }
+    }
+
+    let tag_ids: HashSet<bson::oid::ObjectId> = tags_list.iter().map(|tag| tag._id).collect();
+    let mut children_by_parent: HashMap<bson::oid::ObjectId, Vec<&Tag>> = HashMap::new();
+    let mut roots = Vec::new();
+    for tag in &tags_list {
+        match tag.parent_tag {
+            Some(parent_id) if tag_ids.contains(&parent_id) => children_by_parent.entry(parent_id).or_default().push(tag),
+            // Tags whose parent is gone are shown at the top level
+            _ => roots.push(tag),
+        }
+    }
+
+    let mut visited = HashSet::new();
+    let mut tree = Vec::new();
+    for root in roots {
+        let (node, _) = build_tag_tree_node(root, &children_by_parent, &tasks_by_tag, &moss_by_task, &mut visited);
+        tree.push(node);
+    }
+    // Tags stuck in a cycle saved before parents were validated never reach a root, so list them too
+    for tag in &tags_list {
+        if !visited.contains(&tag._id) {
+            let (node, _) = build_tag_tree_node(tag, &children_by_parent, &tasks_by_tag, &moss_by_task, &mut visited);
+            tree.push(node);
+        }
+    }
+
+    Ok(tree)
+}
+
+async fn create_task_action(token: Token, task_data: NewTaskData, store: &Store, changes: &ChangeBus, meta: &RequestMeta) -> Result<InsertedData, ActionError> {
+    let repository = store.backend.as_ref();
+
+    let user = token.0;
+
+    validation::validate_tag_owner(repository, user._id, "tags", &task_data.tags).await?;
+
     let new_task = Task {
         _id: bson::oid::ObjectId::new(),
         name: task_data.name,
         frequency: task_data.frequency,
         tags: task_data.tags,
+        attachments: None,
         user: Some(user._id),
+        updated_at: Some(bson::DateTime::now()),
+        deleted_at: None,
     };
 
-    let task_result = tasks.insert_one(new_task, None).await;
+    let task_result = repository.insert_task(&new_task).await;
 
     match task_result {
-        Ok(_task_result) => Ok(_task_result),
-        Err(_) => todo!(),
+        Ok(_) => {
+            let mut operation = OperationLog::new();
+            operation.created("tasks", new_task._id, &new_task)?;
+            operation.record(repository, user._id, "create_task", meta).await;
+            webhooks::notify(repository, user._id, webhooks::TASK_CREATED, &new_task).await;
+            changes.publish(user._id, "task", "created", vec![new_task._id]);
+            Ok(InsertedData {
+                inserted_id: new_task._id,
+            })
+        },
+        Err(error) => Err(error.into()),
     }
 }
 
-async fn create_event_action(token: Token<'_>, event_data: NewEventData) -> Result<InsertOneResult, Error> {
-    let mut client_options = ClientOptions::parse("mongodb://localhost:27017").await?;
-    client_options.app_name = Some("mossy".to_string());
-    let client = Client::with_options(client_options)?;
-    let db = client.database("mossy");
+fn check_event_details(errors: &mut ValidationErrors, notes: &Option<String>, duration: Option<i64>, quantity: Option<f64>, rating: Option<i32>) {
+    errors.check_text("notes", notes);
+    errors.check("duration", duration.is_none_or(|_duration| _duration >= 0), "can't be negative");
+    errors.check("quantity", quantity.is_none_or(|_quantity| _quantity.is_finite() && _quantity >= 0.0), "must be a number that isn't negative");
+    errors.check("rating", rating.is_none_or(|_rating| (1..=5).contains(&_rating)), "must be between 1 and 5");
+}
 
-    let users = db.collection::<User>("users");
-    let events = db.collection::<Event>("events");
+async fn read_task_stats_action(token: Token, task: Option<bson::oid::ObjectId>, store: &Store) -> Result<Vec<TaskStats>, Error> {
+    let repository = store.backend.as_ref();
 
-    let mut token_split = token.clone().0.split(" ");
-    let Some(token_value) = token_split.nth(1) else {
-        todo!()
-    };
+    let user = token.0;
 
-    let user_filter = bson::doc! {
-        "token": token_value,
-    };
-    let Some(user) = users.find_one(user_filter, None).await? else {
-        todo!()
-    };
+    repository.find_task_stats(user._id, task).await
+}
+
+async fn create_event_action(token: Token, event_data: NewEventData, store: &Store, changes: &ChangeBus, meta: &RequestMeta) -> Result<InsertedData, ActionError> {
+    let repository = store.backend.as_ref();
+
+    let user = token.0;
+
+    validation::validate_task_owner(repository, user._id, "task", event_data.task).await?;
 
     let date = match bson::DateTime::parse_rfc3339_str(event_data.date) {
         Ok(_date) => _date,
-        Err(_date) => todo!()
+        Err(_) => return Err(ActionError::Invalid(ValidationErrors::field("date", "must be an RFC 3339 date"))),
     };
     let new_event = Event {
         _id: bson::oid::ObjectId::new(),
         task: event_data.task,
         date: date,
+        notes: event_data.notes,
+        duration: event_data.duration,
+        quantity: event_data.quantity,
+        rating: event_data.rating,
+        attachments: None,
         user: Some(user._id),
+        updated_at: Some(bson::DateTime::now()),
+        deleted_at: None,
     };
 
-    let event_result = events.insert_one(new_event, None).await;
+    let event_result = repository.insert_event(&new_event).await;
 
     match event_result {
-        Ok(_event_result) => Ok(_event_result),
-        Err(_) => todo!(),
+        Ok(_) => {
+            let mut operation = OperationLog::new();
+            operation.created("events", new_event._id, &new_event)?;
+            operation.record(repository, user._id, "create_event", meta).await;
+            webhooks::notify(repository, user._id, webhooks::EVENT_CREATED, &new_event).await;
+            changes.publish(user._id, "event", "created", vec![new_event._id]);
+            Ok(InsertedData {
+                inserted_id: new_event._id,
+            })
+        },
+        Err(error) => Err(error.into()),
     }
 }
 
-async fn create_tag_action(token: Token<'_>, tag_data: NewTagData) -> Result<InsertOneResult, Error> {
-    let mut client_options = ClientOptions::parse("mongodb://localhost:27017").await?;
-    client_options.app_name = Some("mossy".to_string());
-    let client = Client::with_options(client_options)?;
-    let db = client.database("mossy");
+async fn tag_subtree_task_ids(repository: &dyn Repository, user_id: bson::oid::ObjectId, tag_id: bson::oid::ObjectId) -> Result<Vec<bson::oid::ObjectId>, EventsBatchError> {
+    let mut children_by_parent: HashMap<bson::oid::ObjectId, Vec<bson::oid::ObjectId>> = HashMap::new();
+    let mut tag_ids = HashSet::new();
+    for tag in repository.find_user_tags(user_id, false).await? {
+        if let Some(parent_id) = tag.parent_tag {
+            children_by_parent.entry(parent_id).or_default().push(tag._id);
+        }
+        tag_ids.insert(tag._id);
+    }
+    if !tag_ids.contains(&tag_id) {
+        return Err(EventsBatchError::InvalidSelection)
+    }
 
-    let users = db.collection::<User>("users");
-    let tags = db.collection::<Tag>("tags");
+    let mut subtree = HashSet::from([tag_id]);
+    let mut pending = vec![tag_id];
+    while let Some(parent_id) = pending.pop() {
+        for child_id in children_by_parent.get(&parent_id).cloned().unwrap_or_default() {
+            if subtree.insert(child_id) {
+                pending.push(child_id);
+            }
+        }
+    }
 
-    let mut token_split = token.clone().0.split(" ");
-    let Some(token_value) = token_split.nth(1) else {
-        todo!()
-    };
+    let task_ids = repository.find_user_tasks(user_id, false).await?.into_iter()
+        .filter(|task| task.tags.as_ref().is_some_and(|task_tags| task_tags.iter().any(|task_tag| subtree.contains(task_tag))))
+        .map(|task| task._id)
+        .collect();
+
+    Ok(task_ids)
+}
 
-    let user_filter = bson::doc! {
-        "token": token_value,
+async fn create_events_batch_action(token: Token, batch_data: NewEventsBatchData, store: &Store, changes: &ChangeBus, meta: &RequestMeta) -> Result<Vec<EventsBatchResult>, EventsBatchError> {
+    let repository = store.backend.as_ref();
+
+    let user = token.0;
+
+    let Ok(date) = bson::DateTime::parse_rfc3339_str(&batch_data.date) else {
+        return Err(EventsBatchError::InvalidDate)
     };
-    let Some(user) = users.find_one(user_filter, None).await? else {
-        todo!()
+
+    let requested_tasks = match (batch_data.tasks, batch_data.tag) {
+        (Some(_tasks), None) => _tasks,
+        (None, Some(_tag)) => tag_subtree_task_ids(repository, user._id, _tag).await?,
+        _ => return Err(EventsBatchError::InvalidSelection),
     };
 
+    let found_tasks: HashSet<bson::oid::ObjectId> = repository.find_tasks_by_ids(&requested_tasks).await?.into_iter()
+        .filter(|task| task.user == Some(user._id) && task.deleted_at.is_none())
+        .map(|task| task._id)
+        .collect();
+
+    let mut results = Vec::new();
+    let mut new_events = Vec::new();
+    let mut seen_tasks = HashSet::new();
+    for task_id in requested_tasks {
+        // Listing a task twice still only logs it once
+        if !seen_tasks.insert(task_id) {
+            continue
+        }
+        if !found_tasks.contains(&task_id) {
+            results.push(EventsBatchResult {
+                task: task_id,
+                status: String::from("not_found"),
+                event: None,
+            });
+            continue
+        }
+        let new_event = Event {
+            _id: bson::oid::ObjectId::new(),
+            task: task_id,
+            date,
+            notes: batch_data.notes.clone(),
+            duration: batch_data.duration,
+            quantity: batch_data.quantity,
+            rating: batch_data.rating,
+            attachments: None,
+            user: Some(user._id),
+            updated_at: Some(bson::DateTime::now()),
+            deleted_at: None,
+        };
+        results.push(EventsBatchResult {
+            task: task_id,
+            status: String::from("created"),
+            event: Some(new_event._id),
+        });
+        new_events.push(new_event);
+    }
+
+    if new_events.is_empty() {
+        return Ok(results)
+    }
+    repository.insert_events(&new_events).await?;
+
+    let mut operation = OperationLog::new();
+    for new_event in new_events.iter() {
+        operation.created("events", new_event._id, new_event)?;
+    }
+    operation.record(repository, user._id, "create_events_batch", meta).await;
+
+    changes.publish(user._id, "event", "created", new_events.iter().map(|event| event._id).collect());
+    for new_event in new_events.iter() {
+        webhooks::notify(repository, user._id, webhooks::EVENT_CREATED, new_event).await;
+    }
+
+    Ok(results)
+}
+
+async fn create_tag_action(token: Token, tag_data: NewTagData, store: &Store, changes: &ChangeBus, meta: &RequestMeta) -> Result<InsertedData, TagError> {
+    let repository = store.backend.as_ref();
+
+    let user = token.0;
+
+    validate_tag(repository, user._id, None, &tag_data.name, tag_data.parent_tag, &tag_data.color, &tag_data.icon).await?;
+
     let new_tag = Tag {
         _id: bson::oid::ObjectId::new(),
         name: tag_data.name,
         description: tag_data.description,
         parent_tag: tag_data.parent_tag,
+        color: tag_data.color,
+        icon: tag_data.icon,
+        sort_order: tag_data.sort_order,
+        archived: tag_data.archived,
         user: Some(user._id),
+        updated_at: Some(bson::DateTime::now()),
+        deleted_at: None,
+        unlinked_tasks: None,
     };
 
-    let tag_result = tags.insert_one(new_tag, None).await;
+    let tag_result = repository.insert_tag(&new_tag).await;
 
     match tag_result {
-        Ok(_tag_result) => Ok(_tag_result),
-        Err(_) => todo!(),
+        Ok(_) => {
+            let mut operation = OperationLog::new();
+            operation.created("tags", new_tag._id, &new_tag)?;
+            operation.record(repository, user._id, "create_tag", meta).await;
+            changes.publish(user._id, "tag", "created", vec![new_tag._id]);
+            Ok(InsertedData {
+                inserted_id: new_tag._id,
+            })
+        },
+        Err(error) => Err(error.into()),
     }
 }
 
-async fn update_task_action(token: Token<'_>, task_data: Task) -> Result<UpdateResult, Error> {
-    let mut client_options = ClientOptions::parse("mongodb://localhost:27017").await?;
-    client_options.app_name = Some("mossy".to_string());
-    let client = Client::with_options(client_options)?;
-    let db = client.database("mossy");
+async fn update_task_action(token: Token, task_data: UpdateTaskData, if_match: &IfMatch, store: &Store, changes: &ChangeBus, meta: &RequestMeta) -> Result<(UpdatedData, bson::DateTime), UpdateError> {
+    let repository = store.backend.as_ref();
 
-    let users = db.collection::<User>("users");
-    let tasks = db.collection::<Task>("tasks");
+    let user = token.0;
 
-    let mut token_split = token.clone().0.split(" ");
-    let Some(token_value) = token_split.nth(1) else {
-        todo!()
+    // Make sure the task to update belongs to the user
+    let task_option = repository.find_task(task_data._id).await?.filter(|task| task.deleted_at.is_none());
+    let Some(task) = task_option.filter(|task| task.user == Some(user._id)) else {
+        return Err(UpdateError::NotFound)
     };
 
-    let user_filter = bson::doc! {
-        "token": token_value,
-    };
-    let Some(user) = users.find_one(user_filter, None).await? else {
-        todo!()
-    };
+    // The client edited an older copy than ours
+    if !if_match.matches(task.updated_at) {
+        return Err(UpdateError::VersionConflict(bson::to_document(&task)?))
+    }
 
-    // Make sure the task to update belongs to the user
-    let task_filter = bson::doc! {
-        "_id": task_data._id,
-    };
-    let Some(task) = tasks.find_one(task_filter, None).await? else {
-        todo!()
-    };
-    if task.user != Some(user._id) {
-        todo!()
-    };
+    if let Some(tags) = &task_data.tags {
+        validation::validate_tag_owner(repository, user._id, "tags", tags).await?;
+    }
 
-    let updated_task = bson::doc! {
-        "$set": {
-            "name": task_data.name,
-            "frequency": task_data.frequency,
-            "tags": task_data.tags,
-        }
+    let updated_at = versioning::next_updated_at(task.updated_at);
+    let mut updated_task = bson::doc! {
+        "updated_at": updated_at,
     };
+    if let Some(name) = task_data.name {
+        updated_task.insert("name", name);
+    }
+    if let Some(frequency) = task_data.frequency {
+        updated_task.insert("frequency", frequency);
+    }
+    if let Some(tags) = task_data.tags {
+        updated_task.insert("tags", tags);
+    }
 
-    let filter = bson::doc!{"_id": task_data._id };
+    let mut operation = OperationLog::new();
+    operation.snapshot_document(repository, "tasks", task_data._id).await?;
 
-    let task_result = tasks.update_one(filter, updated_task, None).await;
+    let task_result = repository.update_task(task_data._id, task.updated_at, updated_task.clone()).await;
 
     match task_result {
-        Ok(_task_result) => Ok(_task_result),
-        Err(_) => todo!(),
+        Ok(true) => {
+            operation.updated("tasks", task_data._id, &updated_task);
+            operation.record(repository, user._id, "update_task", meta).await;
+            if let Some(updated_task) = repository.find_task(task_data._id).await? {
+                webhooks::notify(repository, user._id, webhooks::TASK_UPDATED, &updated_task).await;
+            }
+            changes.publish(user._id, "task", "updated", vec![task_data._id]);
+            Ok((UpdatedData {
+                matched_count: 1,
+                modified_count: 1,
+                upserted_id: None,
+            }, updated_at))
+        },
+        // Something else wrote to the task after we read it
+        Ok(false) => match repository.find_task(task_data._id).await? {
+            Some(current_task) => Err(UpdateError::VersionConflict(bson::to_document(&current_task)?)),
+            // Deleted for good in the meantime
+            None => Err(UpdateError::NotFound),
+        },
+        Err(error) => Err(error.into()),
     }
 }
 
-async fn update_event_action(token: Token<'_>, event_data: UpdateEventData) -> Result<UpdateResult, Error> {
-    let mut client_options = ClientOptions::parse("mongodb://localhost:27017").await?;
-    client_options.app_name = Some("mossy".to_string());
-    let client = Client::with_options(client_options)?;
-    let db = client.database("mossy");
-
-    let users = db.collection::<User>("users");
-    let events = db.collection::<Event>("events");
-
-    let mut token_split = token.clone().0.split(" ");
-    let Some(token_value) = token_split.nth(1) else {
-        todo!()
-    };
+async fn update_event_action(token: Token, event_data: UpdateEventData, if_match: &IfMatch, store: &Store, changes: &ChangeBus, meta: &RequestMeta) -> Result<(UpdatedData, bson::DateTime), UpdateError> {
+    let repository = store.backend.as_ref();
 
-    let user_filter = bson::doc! {
-        "token": token_value,
-    };
-    let Some(user) = users.find_one(user_filter, None).await? else {
-        todo!()
-    };
+    let user = token.0;
 
     // Make sure the event to update belongs to the user
-    let event_filter = bson::doc! {
-        "_id": event_data._id,
-    };
-    let Some(event) = events.find_one(event_filter, None).await? else {
-        todo!()
-    };
-    if event.user != Some(user._id) {
-        todo!()
+    let event_option = repository.find_event(event_data._id).await?.filter(|event| event.deleted_at.is_none());
+    let Some(event) = event_option.filter(|event| event.user == Some(user._id)) else {
+        return Err(UpdateError::NotFound)
     };
 
-    let date = match bson::DateTime::parse_rfc3339_str(event_data.date) {
-        Ok(_date) => _date,
-        Err(_date) => todo!()
-    };
-    let updated_event = bson::doc! {
-        "$set": {
-            "date": date,
-        }
+    // The client edited an older copy than ours
+    if !if_match.matches(event.updated_at) {
+        return Err(UpdateError::VersionConflict(bson::to_document(&event)?))
+    }
+
+    let updated_at = versioning::next_updated_at(event.updated_at);
+    let mut updated_event = bson::doc! {
+        "updated_at": updated_at,
     };
+    if let Some(date_string) = event_data.date {
+        let date = match bson::DateTime::parse_rfc3339_str(date_string) {
+            Ok(_date) => _date,
+            Err(_) => return Err(UpdateError::Invalid(ValidationErrors::field("date", "must be an RFC 3339 date"))),
+        };
+        updated_event.insert("date", date);
+    }
+    if let Some(notes) = event_data.notes {
+        updated_event.insert("notes", notes);
+    }
+    if let Some(duration) = event_data.duration {
+        updated_event.insert("duration", duration);
+    }
+    if let Some(quantity) = event_data.quantity {
+        updated_event.insert("quantity", quantity);
+    }
+    if let Some(rating) = event_data.rating {
+        updated_event.insert("rating", rating);
+    }
 
-    let filter = bson::doc!{"_id": event_data._id };
+    let mut operation = OperationLog::new();
+    operation.snapshot_document(repository, "events", event_data._id).await?;
 
-    let event_result = events.update_one(filter, updated_event, None).await;
+    let event_result = repository.update_event(event_data._id, event.updated_at, updated_event.clone()).await;
 
     match event_result {
-        Ok(_event_result) => Ok(_event_result),
-        Err(_) => todo!(),
+        Ok(true) => {
+            operation.updated("events", event_data._id, &updated_event);
+            operation.record(repository, user._id, "update_event", meta).await;
+            changes.publish(user._id, "event", "updated", vec![event_data._id]);
+            Ok((UpdatedData {
+                matched_count: 1,
+                modified_count: 1,
+                upserted_id: None,
+            }, updated_at))
+        },
+        // Something else wrote to the event after we read it
+        Ok(false) => match repository.find_event(event_data._id).await? {
+            Some(current_event) => Err(UpdateError::VersionConflict(bson::to_document(&current_event)?)),
+            // Deleted for good in the meantime
+            None => Err(UpdateError::NotFound),
+        },
+        Err(error) => Err(error.into()),
     }
 }
 
-async fn update_tag_action(token: Token<'_>, tag_data: Tag) -> Result<UpdateResult, Error> {
-    let mut client_options = ClientOptions::parse("mongodb://localhost:27017").await?;
-    client_options.app_name = Some("mossy".to_string());
-    let client = Client::with_options(client_options)?;
-    let db = client.database("mossy");
+async fn update_tag_action(token: Token, tag_data: UpdateTagData, if_match: &IfMatch, store: &Store, changes: &ChangeBus, meta: &RequestMeta) -> Result<(UpdatedData, bson::DateTime), TagError> {
+    let repository = store.backend.as_ref();
 
-    let users = db.collection::<User>("users");
-    let tags = db.collection::<Tag>("tags");
+    let user = token.0;
 
-    let mut token_split = token.clone().0.split(" ");
-    let Some(token_value) = token_split.nth(1) else {
-        todo!()
+    // Make sure the tag to update belongs to the user
+    let tag_option = repository.find_tag(tag_data._id).await?.filter(|tag| tag.deleted_at.is_none());
+    let Some(tag) = tag_option.filter(|tag| tag.user == Some(user._id)) else {
+        return Err(TagError::NotFound)
     };
 
-    let user_filter = bson::doc! {
-        "token": token_value,
-    };
-    let Some(user) = users.find_one(user_filter, None).await? else {
-        todo!()
-    };
+    // The client edited an older copy than ours
+    if !if_match.matches(tag.updated_at) {
+        return Err(TagError::VersionConflict(bson::to_document(&tag)?))
+    }
 
-    // Make sure the tag to update belongs to the user
-    let tag_filter = bson::doc! {
-        "_id": tag_data._id,
-    };
-    let Some(tag) = tags.find_one(tag_filter, None).await? else {
-        todo!()
-    };
-    if tag.user != Some(user._id) {
-        todo!()
-    };
+    // Validate the tag as it will be once the changes are applied
+    let name = tag_data.name.clone().unwrap_or(tag.name.clone());
+    let parent_tag = tag_data.parent_tag.unwrap_or(tag.parent_tag);
+    let color = tag_data.color.clone().unwrap_or(tag.color.clone());
+    let icon = tag_data.icon.clone().unwrap_or(tag.icon.clone());
+    validate_tag(repository, user._id, Some(tag_data._id), &name, parent_tag, &color, &icon).await?;
 
-    let updated_tag = bson::doc! {
-        "$set": {
-            "name": tag_data.name,
-            "description": tag_data.description,
-            "parent_tag": tag_data.parent_tag,
-        }
+    let updated_at = versioning::next_updated_at(tag.updated_at);
+    let mut updated_tag = bson::doc! {
+        "updated_at": updated_at,
     };
+    if let Some(name) = tag_data.name {
+        updated_tag.insert("name", name);
+    }
+    if let Some(description) = tag_data.description {
+        updated_tag.insert("description", description);
+    }
+    if let Some(parent_tag) = tag_data.parent_tag {
+        updated_tag.insert("parent_tag", parent_tag);
+    }
+    if let Some(color) = tag_data.color {
+        updated_tag.insert("color", color);
+    }
+    if let Some(icon) = tag_data.icon {
+        updated_tag.insert("icon", icon);
+    }
+    if let Some(sort_order) = tag_data.sort_order {
+        updated_tag.insert("sort_order", sort_order);
+    }
+    if let Some(archived) = tag_data.archived {
+        updated_tag.insert("archived", archived);
+    }
 
-    let filter = bson::doc!{"_id": tag_data._id };
+    let mut operation = OperationLog::new();
+    operation.snapshot_document(repository, "tags", tag_data._id).await?;
 
-    let tag_result = tags.update_one(filter, updated_tag, None).await;
+    let tag_result = repository.update_tag(tag_data._id, tag.updated_at, updated_tag.clone()).await;
 
     match tag_result {
-        Ok(_tag_result) => Ok(_tag_result),
-        Err(_) => todo!(),
+        Ok(true) => {
+            operation.updated("tags", tag_data._id, &updated_tag);
+            operation.record(repository, user._id, "update_tag", meta).await;
+            changes.publish(user._id, "tag", "updated", vec![tag_data._id]);
+            Ok((UpdatedData {
+                matched_count: 1,
+                modified_count: 1,
+                upserted_id: None,
+            }, updated_at))
+        },
+        // Something else wrote to the tag after we read it
+        Ok(false) => match repository.find_tag(tag_data._id).await? {
+            Some(current_tag) => Err(TagError::VersionConflict(bson::to_document(&current_tag)?)),
+            // Deleted for good in the meantime
+            None => Err(TagError::NotFound),
+        },
+        Err(error) => Err(error.into()),
     }
 }
 
-async fn delete_tasks_action(token: Token<'_>, tasks_data: Vec<bson::oid::ObjectId>) -> Result<DeleteResult, Error> {
-    let mut client_options = ClientOptions::parse("mongodb://localhost:27017").await?;
-    client_options.app_name = Some("mossy".to_string());
-    let client = Client::with_options(client_options)?;
-    let db = client.database("mossy");
+// What a multi-document action is about to write, worked out from a read of everything it touches
+struct WritePlan<T> {
+    summary: T,
+    writes: Vec<DocumentWrite>,
+}
 
-    let users = db.collection::<User>("users");
-    let tasks = db.collection::<Task>("tasks");
+// Everything deleted together shares the same deleted_at, which is how a restore finds it again
+fn deleted_changes(now: bson::DateTime) -> Document {
+    bson::doc! {
+        "deleted_at": now,
+        "updated_at": now,
+    }
+}
 
-    let mut token_split = token.clone().0.split(" ");
-    let Some(token_value) = token_split.nth(1) else {
-        todo!()
-    };
+// Also returns the tasks as they were, for the webhooks
+async fn plan_task_delete(repository: &dyn Repository, user: &User, tasks_data: Vec<bson::oid::ObjectId>, strategy: DeleteStrategy, now: bson::DateTime) -> Result<(WritePlan<DeleteSummary>, Vec<Task>), DeleteError> {
+    let mut tasks_to_delete = Vec::new();
+    for task in repository.find_tasks_by_ids(&tasks_data).await? {
+        if task.deleted_at.is_some() {
+            continue
+        }
+        // Nothing is written unless every task is the user's
+        if task.user != Some(user._id) {
+            return Err(DeleteError::NotFound)
+        };
+        tasks_to_delete.push(task);
+    }
+    let task_ids: Vec<bson::oid::ObjectId> = tasks_to_delete.iter().map(|task| task._id).collect();
 
-    let user_filter = bson::doc! {
-        "token": token_value,
-    };
-    let Some(user) = users.find_one(user_filter, None).await? else {
-        todo!()
-    };
+    let mut summary = DeleteSummary::default();
+    let mut writes = Vec::new();
 
-    // Make sure the task to delete belongs to the user
-    let tasks_data_copy = tasks_data.clone();
-    let task_filter = bson::doc! {
-        "_id": {
-            "$in": tasks_data_copy,
-        },
-    };
-    let mut tasks_cursor = tasks.find(task_filter, None).await?;
-    while let Some(task) = tasks_cursor.try_next().await? {
-        if task.user != Some(user._id) {
-            todo!()
+    if strategy == DeleteStrategy::Cascade {
+        for event in repository.find_task_events(&task_ids).await?.into_iter().filter(|event| event.deleted_at.is_none()) {
+            writes.push(DocumentWrite::new("events", event._id, event.updated_at, deleted_changes(now)));
+            summary.deleted_events.push(event._id);
+        }
+    }
+    for task in tasks_to_delete.iter() {
+        writes.push(DocumentWrite::new("tasks", task._id, task.updated_at, deleted_changes(now)));
+    }
+
+    summary.deleted_count = task_ids.len() as u64;
+    summary.deleted_tasks = task_ids;
+
+    Ok((WritePlan { summary, writes }, tasks_to_delete))
+}
+
+async fn delete_tasks_action(token: Token, tasks_data: Vec<bson::oid::ObjectId>, strategy: DeleteStrategy, store: &Store, changes: &ChangeBus, meta: &RequestMeta) -> Result<DeleteSummary, DeleteError> {
+    let repository = store.backend.as_ref();
+
+    let user = token.0;
+
+    let (plan, deleted_tasks) = plan_task_delete(repository, &user, tasks_data, strategy, bson::DateTime::now()).await?;
+    let mut operation = OperationLog::new();
+    if !operation.apply_writes(repository, plan.writes).await? {
+        return Err(DeleteError::ConcurrentChange)
+    }
+    operation.record(repository, user._id, "delete_tasks", meta).await;
+
+    let summary = plan.summary;
+    changes.publish(user._id, "task", "deleted", summary.deleted_tasks.clone());
+    changes.publish(user._id, "event", "deleted", summary.deleted_events.clone());
+    for task in deleted_tasks {
+        webhooks::notify(repository, user._id, webhooks::TASK_DELETED, &task).await;
+    }
+
+    Ok(summary)
+}
+
+async fn delete_events_action(token: Token, events_data: Vec<bson::oid::ObjectId>, store: &Store, changes: &ChangeBus, meta: &RequestMeta) -> Result<DeleteSummary, DeleteError> {
+    let repository = store.backend.as_ref();
+
+    let user = token.0;
+
+    // Events have nothing depending on them, so there's nothing to cascade to
+    let now = bson::DateTime::now();
+    let mut summary = DeleteSummary::default();
+    let mut writes = Vec::new();
+    for event in repository.find_events_by_ids(&events_data).await? {
+        if event.deleted_at.is_some() {
+            continue
+        }
+        if event.user != Some(user._id) {
+            return Err(DeleteError::NotFound)
         };
+        writes.push(DocumentWrite::new("events", event._id, event.updated_at, deleted_changes(now)));
+        summary.deleted_events.push(event._id);
+    }
+    summary.deleted_count = summary.deleted_events.len() as u64;
+
+    let mut operation = OperationLog::new();
+    if !operation.apply_writes(repository, writes).await? {
+        return Err(DeleteError::ConcurrentChange)
     }
+    operation.record(repository, user._id, "delete_events", meta).await;
+    changes.publish(user._id, "event", "deleted", summary.deleted_events.clone());
 
-    let filter = bson::doc!{"_id": { "$in": tasks_data }};
+    Ok(summary)
+}
 
-    let tasks_result = tasks.delete_many(filter, None).await;
+// Walks up from a deleted tag to the closest ancestor that survives the delete
+fn surviving_ancestor(tag_id: bson::oid::ObjectId, tags_by_id: &HashMap<bson::oid::ObjectId, Tag>, deleted_tags: &HashSet<bson::oid::ObjectId>) -> Option<bson::oid::ObjectId> {
+    let mut visited = HashSet::new();
+    let mut next_ancestor = tags_by_id.get(&tag_id).and_then(|tag| tag.parent_tag);
 
-    match tasks_result {
-        Ok(_tasks_result) => Ok(_tasks_result),
-        Err(_) => todo!(),
+    while let Some(ancestor_id) = next_ancestor {
+        if !deleted_tags.contains(&ancestor_id) {
+            return Some(ancestor_id)
+        }
+        if !visited.insert(ancestor_id) {
+            return None
+        }
+        next_ancestor = tags_by_id.get(&ancestor_id).and_then(|tag| tag.parent_tag);
     }
+
+    None
 }
 
-async fn delete_events_action(token: Token<'_>, events_data: Vec<bson::oid::ObjectId>) -> Result<DeleteResult, Error> {
-    let mut client_options = ClientOptions::parse("mongodb://localhost:27017").await?;
-    client_options.app_name = Some("mossy".to_string());
-    let client = Client::with_options(client_options)?;
-    let db = client.database("mossy");
+async fn plan_tag_delete(repository: &dyn Repository, user: &User, tags_data: Vec<bson::oid::ObjectId>, strategy: DeleteStrategy, now: bson::DateTime) -> Result<WritePlan<DeleteSummary>, DeleteError> {
+    let mut deleted_tags = HashSet::new();
+    for tag in repository.find_tags_by_ids(&tags_data).await? {
+        if tag.deleted_at.is_some() {
+            continue
+        }
+        // Nothing is written unless every tag is the user's
+        if tag.user != Some(user._id) {
+            return Err(DeleteError::NotFound)
+        };
+        deleted_tags.insert(tag._id);
+    }
 
-    let users = db.collection::<User>("users");
-    let events = db.collection::<Event>("events");
+    let tags_by_id: HashMap<bson::oid::ObjectId, Tag> = repository.find_user_tags(user._id, false).await?.into_iter().map(|tag| (tag._id, tag)).collect();
 
-    let mut token_split = token.clone().0.split(" ");
-    let Some(token_value) = token_split.nth(1) else {
-        todo!()
-    };
+    if strategy == DeleteStrategy::Cascade {
+        let mut children_by_parent: HashMap<bson::oid::ObjectId, Vec<bson::oid::ObjectId>> = HashMap::new();
+        for tag in tags_by_id.values() {
+            if let Some(parent_id) = tag.parent_tag {
+                children_by_parent.entry(parent_id).or_default().push(tag._id);
+            }
+        }
+        let mut pending: Vec<bson::oid::ObjectId> = deleted_tags.iter().cloned().collect();
+        while let Some(tag_id) = pending.pop() {
+            for child_id in children_by_parent.get(&tag_id).cloned().unwrap_or_default() {
+                if deleted_tags.insert(child_id) {
+                    pending.push(child_id);
+                }
+            }
+        }
+    }
 
-    let user_filter = bson::doc! {
-        "token": token_value,
-    };
-    let Some(user) = users.find_one(user_filter, None).await? else {
-        todo!()
-    };
+    let mut summary = DeleteSummary::default();
+    let mut writes = Vec::new();
+    let deleted_tag_ids: Vec<bson::oid::ObjectId> = deleted_tags.iter().cloned().collect();
 
-    // Make sure the event to delete belongs to the user
-    let events_data_copy = events_data.clone();
-    let event_filter = bson::doc! {
-        "_id": {
-            "$in": events_data_copy,
-        },
-    };
-    let mut events_cursor = events.find(event_filter, None).await?;
-    while let Some(event) = events_cursor.try_next().await? {
-        if event.user != Some(user._id) {
-            todo!()
+    // Children of a deleted tag either move up to the closest surviving ancestor or to the top level
+    for tag in tags_by_id.values() {
+        let Some(parent_id) = tag.parent_tag else {
+            continue
+        };
+        if deleted_tags.contains(&tag._id) || !deleted_tags.contains(&parent_id) {
+            continue
+        }
+        let new_parent = match strategy {
+            DeleteStrategy::Reparent => surviving_ancestor(parent_id, &tags_by_id, &deleted_tags),
+            _ => None,
         };
+        let reparented_tag = bson::doc! {
+            "parent_tag": new_parent,
+            "updated_at": now,
+        };
+        writes.push(DocumentWrite::new("tags", tag._id, tag.updated_at, reparented_tag));
+        summary.reparented_tags.push(tag._id);
     }
 
-    let filter = bson::doc!{"_id": { "$in": events_data }};
+    // Drop the links from tasks so nothing points at a deleted tag
+    let mut unlinked_tasks_by_tag: HashMap<bson::oid::ObjectId, Vec<bson::oid::ObjectId>> = HashMap::new();
+    for task in repository.find_user_tasks(user._id, false).await? {
+        let task_tags = task.tags.unwrap_or_default();
+        if !task_tags.iter().any(|tag_id| deleted_tags.contains(tag_id)) {
+            continue
+        }
+        for tag_id in task_tags.iter().filter(|tag_id| deleted_tags.contains(tag_id)) {
+            unlinked_tasks_by_tag.entry(*tag_id).or_default().push(task._id);
+        }
+        let unlinked_task = bson::doc! {
+            "tags": task_tags.into_iter().filter(|tag_id| !deleted_tags.contains(tag_id)).collect::<Vec<bson::oid::ObjectId>>(),
+            "updated_at": now,
+        };
+        writes.push(DocumentWrite::new("tasks", task._id, task.updated_at, unlinked_task));
+        summary.updated_tasks.push(task._id);
+    }
+
+    for tag_id in deleted_tag_ids.iter() {
+        let Some(tag) = tags_by_id.get(tag_id) else {
+            continue
+        };
+        let mut deleted_tag = deleted_changes(now);
+        deleted_tag.insert("unlinked_tasks", unlinked_tasks_by_tag.remove(tag_id).unwrap_or_default());
+        writes.push(DocumentWrite::new("tags", tag._id, tag.updated_at, deleted_tag));
+        summary.deleted_count += 1;
+    }
+
+    summary.deleted_tags = deleted_tag_ids;
 
-    let events_result = events.delete_many(filter, None).await;
+    Ok(WritePlan { summary, writes })
+}
+
+async fn delete_tags_action(token: Token, tags_data: Vec<bson::oid::ObjectId>, strategy: DeleteStrategy, store: &Store, changes: &ChangeBus, meta: &RequestMeta) -> Result<DeleteSummary, DeleteError> {
+    let repository = store.backend.as_ref();
+
+    let user = token.0;
 
-    match events_result {
-        Ok(_events_result) => Ok(_events_result),
-        Err(_) => todo!(),
+    let plan = plan_tag_delete(repository, &user, tags_data, strategy, bson::DateTime::now()).await?;
+    let mut operation = OperationLog::new();
+    if !operation.apply_writes(repository, plan.writes).await? {
+        return Err(DeleteError::ConcurrentChange)
     }
+    operation.record(repository, user._id, "delete_tags", meta).await;
+
+    let summary = plan.summary;
+    changes.publish(user._id, "tag", "deleted", summary.deleted_tags.clone());
+    changes.publish(user._id, "tag", "updated", summary.reparented_tags.clone());
+    changes.publish(user._id, "task", "updated", summary.updated_tasks.clone());
+    for task in repository.find_tasks_by_ids(&summary.updated_tasks).await? {
+        webhooks::notify(repository, user._id, webhooks::TASK_UPDATED, &task).await;
+    }
+
+    Ok(summary)
 }
 
-async fn delete_tags_action(token: Token<'_>, tags_data: Vec<bson::oid::ObjectId>) -> Result<DeleteResult, Error> {
-    let mut client_options = ClientOptions::parse("mongodb://localhost:27017").await?;
-    client_options.app_name = Some("mossy".to_string());
-    let client = Client::with_options(client_options)?;
-    let db = client.database("mossy");
+// Loads the user's live tags, failing if any of the given ids isn't one of them
+async fn read_user_tags(repository: &dyn Repository, user_id: bson::oid::ObjectId, tag_ids: &[bson::oid::ObjectId]) -> Result<HashMap<bson::oid::ObjectId, Tag>, TagError> {
+    let tags_by_id: HashMap<bson::oid::ObjectId, Tag> = repository.find_user_tags(user_id, false).await?.into_iter().map(|tag| (tag._id, tag)).collect();
 
-    let users = db.collection::<User>("users");
-    let tags = db.collection::<Tag>("tags");
+    if tag_ids.iter().any(|tag_id| !tags_by_id.contains_key(tag_id)) {
+        return Err(TagError::InvalidTag)
+    }
 
-    let mut token_split = token.clone().0.split(" ");
-    let Some(token_value) = token_split.nth(1) else {
-        todo!()
-    };
+    Ok(tags_by_id)
+}
 
-    let user_filter = bson::doc! {
-        "token": token_value,
-    };
-    let Some(user) = users.find_one(user_filter, None).await? else {
-        todo!()
-    };
+async fn plan_tag_merge(repository: &dyn Repository, user: &User, merge_data: MergeTagsData, now: bson::DateTime) -> Result<WritePlan<MergeTagsSummary>, TagError> {
+    let source_tags: HashSet<bson::oid::ObjectId> = merge_data.source_tags.into_iter().filter(|tag_id| *tag_id != merge_data.target_tag).collect();
+    let source_tag_ids: Vec<bson::oid::ObjectId> = source_tags.iter().cloned().collect();
 
-    // Make sure the tag to delete belongs to the user
-    let tags_data_copy = tags_data.clone();
-    let tag_filter = bson::doc! {
-        "_id": {
-            "$in": tags_data_copy,
-        },
+    let mut all_tag_ids = source_tag_ids.clone();
+    all_tag_ids.push(merge_data.target_tag);
+    let tags_by_id = read_user_tags(repository, user._id, &all_tag_ids).await?;
+
+    // Folding a tag into one of its own descendants would leave the descendant as its own ancestor
+    let mut visited = HashSet::new();
+    let mut next_ancestor = tags_by_id.get(&merge_data.target_tag).and_then(|tag| tag.parent_tag);
+    while let Some(ancestor_id) = next_ancestor {
+        if source_tags.contains(&ancestor_id) {
+            return Err(TagError::ParentCycleError)
+        }
+        if !visited.insert(ancestor_id) {
+            break
+        }
+        next_ancestor = tags_by_id.get(&ancestor_id).and_then(|tag| tag.parent_tag);
+    }
+
+    let mut summary = MergeTagsSummary {
+        target_tag: merge_data.target_tag,
+        merged_tags: source_tag_ids.clone(),
+        updated_tasks: Vec::new(),
+        reparented_tags: Vec::new(),
     };
-    let mut tags_cursor = tags.find(tag_filter, None).await?;
-    while let Some(tag) = tags_cursor.try_next().await? {
-        if tag.user != Some(user._id) {
-            todo!()
+    let mut writes = Vec::new();
+
+    let mut unlinked_tasks_by_tag: HashMap<bson::oid::ObjectId, Vec<bson::oid::ObjectId>> = HashMap::new();
+    for task in repository.find_user_tasks(user._id, false).await? {
+        let task_tags = task.tags.unwrap_or_default();
+        if !task_tags.iter().any(|tag_id| source_tags.contains(tag_id)) {
+            continue
+        }
+        for tag_id in task_tags.iter().filter(|tag_id| source_tags.contains(tag_id)) {
+            unlinked_tasks_by_tag.entry(*tag_id).or_default().push(task._id);
+        }
+        let mut merged_tags: Vec<bson::oid::ObjectId> = task_tags.into_iter().filter(|tag_id| !source_tags.contains(tag_id)).collect();
+        if !merged_tags.contains(&merge_data.target_tag) {
+            merged_tags.push(merge_data.target_tag);
+        }
+        let linked_task = bson::doc! {
+            "tags": merged_tags,
+            "updated_at": now,
         };
+        writes.push(DocumentWrite::new("tasks", task._id, task.updated_at, linked_task));
+        summary.updated_tasks.push(task._id);
     }
 
-    let filter = bson::doc!{"_id": { "$in": tags_data }};
+    for tag in tags_by_id.values() {
+        if !tag.parent_tag.is_some_and(|parent_id| source_tags.contains(&parent_id)) || source_tags.contains(&tag._id) {
+            continue
+        }
+        let reparented_tag = bson::doc! {
+            "parent_tag": merge_data.target_tag,
+            "updated_at": now,
+        };
+        writes.push(DocumentWrite::new("tags", tag._id, tag.updated_at, reparented_tag));
+        summary.reparented_tags.push(tag._id);
+    }
 
-    let tags_result = tags.delete_many(filter, None).await;
+    // Merged tags go to the trash like deleted ones, so a mistaken merge can be undone from there
+    for tag_id in source_tag_ids.iter() {
+        let mut merged_tag = deleted_changes(now);
+        merged_tag.insert("unlinked_tasks", unlinked_tasks_by_tag.remove(tag_id).unwrap_or_default());
+        writes.push(DocumentWrite::new("tags", *tag_id, tags_by_id[tag_id].updated_at, merged_tag));
+    }
 
-    match tags_result {
-        Ok(_tags_result) => Ok(_tags_result),
-        Err(_) => todo!(),
+    Ok(WritePlan { summary, writes })
+}
+
+async fn merge_tags_action(token: Token, merge_data: MergeTagsData, store: &Store, changes: &ChangeBus, meta: &RequestMeta) -> Result<MergeTagsSummary, TagError> {
+    let repository = store.backend.as_ref();
+
+    let user = token.0;
+
+    let plan = plan_tag_merge(repository, &user, merge_data, bson::DateTime::now()).await?;
+    let mut operation = OperationLog::new();
+    if !operation.apply_writes(repository, plan.writes).await? {
+        return Err(TagError::ConcurrentChange)
     }
+    operation.record(repository, user._id, "merge_tags", meta).await;
+
+    let summary = plan.summary;
+    changes.publish(user._id, "tag", "deleted", summary.merged_tags.clone());
+    changes.publish(user._id, "tag", "updated", summary.reparented_tags.clone());
+    changes.publish(user._id, "task", "updated", summary.updated_tasks.clone());
+    for task in repository.find_tasks_by_ids(&summary.updated_tasks).await? {
+        webhooks::notify(repository, user._id, webhooks::TASK_UPDATED, &task).await;
+    }
+
+    Ok(summary)
 }
 
-async fn debug_create_tasks_action(token: Token<'_>, data: DebugCreateTasksData) -> Result<InsertManyResult, Error> {
-    let mut client_options = ClientOptions::parse("mongodb://localhost:27017").await?;
-    client_options.app_name = Some("mossy".to_string());
-    let client = Client::with_options(client_options)?;
-    let db = client.database("mossy");
+async fn plan_task_retag(repository: &dyn Repository, user: &User, retag_data: RetagTasksData, now: bson::DateTime) -> Result<WritePlan<RetagTasksSummary>, TagError> {
+    let add_tags = retag_data.add.unwrap_or_default();
+    let remove_tags = retag_data.remove.unwrap_or_default();
 
-    let users = db.collection::<User>("users");
-    let tasks = db.collection::<Task>("tasks");
+    // Only tags being added have to exist; removing an unknown id is harmless
+    read_user_tags(repository, user._id, &add_tags).await?;
 
-    let mut token_split = token.clone().0.split(" ");
-    let Some(token_value) = token_split.nth(1) else {
-        todo!()
+    let mut summary = RetagTasksSummary {
+        updated_tasks: Vec::new(),
     };
+    let mut writes = Vec::new();
+    for task in repository.find_tasks_by_ids(&retag_data.tasks).await? {
+        if task.deleted_at.is_some() {
+            continue
+        }
+        // Nothing is written unless every task is the user's
+        if task.user != Some(user._id) {
+            return Err(TagError::InvalidTag)
+        };
 
-    let user_filter = bson::doc! {
-        "token": token_value,
-    };
-    let Some(user) = users.find_one(user_filter, None).await? else {
-        todo!()
-    };
-    if user.is_admin == false {
-        todo!()
+        let mut task_tags = task.tags.clone().unwrap_or_default();
+        for tag_id in add_tags.iter() {
+            if !task_tags.contains(tag_id) {
+                task_tags.push(*tag_id);
+            }
+        }
+        task_tags.retain(|tag_id| !remove_tags.contains(tag_id));
+
+        let mut retagged_task = bson::doc! {
+            "updated_at": now,
+        };
+        // A task that never had tags keeps not having any when there's nothing to add
+        if task.tags.is_some() || !add_tags.is_empty() {
+            retagged_task.insert("tags", task_tags);
+        }
+        writes.push(DocumentWrite::new("tasks", task._id, task.updated_at, retagged_task));
+        summary.updated_tasks.push(task._id);
+    }
+
+    Ok(WritePlan { summary, writes })
+}
+
+async fn retag_tasks_action(token: Token, retag_data: RetagTasksData, store: &Store, changes: &ChangeBus, meta: &RequestMeta) -> Result<RetagTasksSummary, TagError> {
+    let repository = store.backend.as_ref();
+
+    let user = token.0;
+
+    let plan = plan_task_retag(repository, &user, retag_data, bson::DateTime::now()).await?;
+    let mut operation = OperationLog::new();
+    if !operation.apply_writes(repository, plan.writes).await? {
+        return Err(TagError::ConcurrentChange)
+    }
+    operation.record(repository, user._id, "retag_tasks", meta).await;
+
+    let summary = plan.summary;
+    changes.publish(user._id, "task", "updated", summary.updated_tasks.clone());
+    for task in repository.find_tasks_by_ids(&summary.updated_tasks).await? {
+        webhooks::notify(repository, user._id, webhooks::TASK_UPDATED, &task).await;
+    }
+
+    Ok(summary)
+}
+
+async fn debug_create_tasks_action(token: Token, data: DebugCreateTasksData, store: &Store) -> Result<InsertedManyData, DebugError> {
+    let repository = store.backend.as_ref();
+
+    let user = token.0;
+    if !user.is_admin {
+        return Err(DebugError::Forbidden)
     };
 
     let quantity_to_create = data.quantity;
@@ -1159,164 +2156,87 @@ async fn debug_create_tasks_action(token: Token<'_>, data: DebugCreateTasksData)
             name: String::from(iteration.to_string()),
             frequency: 7,
             tags: None,
+            attachments: None,
             user: Some(user._id),
+            updated_at: Some(bson::DateTime::now()),
+            deleted_at: None,
         };
         new_tasks.push(new_task);
         iteration += 1;
     }
 
 
-    let task_result = tasks.insert_many(new_tasks, None).await;
+    repository.insert_tasks(&new_tasks).await?;
 
-    match task_result {
-        Ok(_task_result) => Ok(_task_result),
-        Err(_) => todo!(),
-    }
+    Ok(InsertedManyData::new(new_tasks.iter().map(|task| task._id)))
 }
 
-async fn debug_delete_tasks_action(token: Token<'_>) -> Result<DeleteResult, Error> {
-    let mut client_options = ClientOptions::parse("mongodb://localhost:27017").await?;
-    client_options.app_name = Some("mossy".to_string());
-    let client = Client::with_options(client_options)?;
-    let db = client.database("mossy");
-
-    let users = db.collection::<User>("users");
-    let tasks = db.collection::<Task>("tasks");
+async fn debug_delete_tasks_action(token: Token, store: &Store) -> Result<DeletedData, DebugError> {
+    let repository = store.backend.as_ref();
 
-    let mut token_split = token.clone().0.split(" ");
-    let Some(token_value) = token_split.nth(1) else {
-        todo!()
+    let user = token.0;
+    if !user.is_admin {
+        return Err(DebugError::Forbidden)
     };
 
-    let user_filter = bson::doc! {
-        "token": token_value,
-    };
-    let Some(user) = users.find_one(user_filter, None).await? else {
-        todo!()
-    };
-    if user.is_admin == false {
-        todo!()
-    };
-
-    let filter = bson::doc!{"user": user._id};
+    let deleted_count = repository.delete_user_documents("tasks", user._id).await?;
 
-    let tasks_result = tasks.delete_many(filter, None).await;
-
-    match tasks_result {
-        Ok(_tasks_result) => Ok(_tasks_result),
-        Err(_) => todo!(),
-    }
+    Ok(DeletedData { deleted_count })
 }
 
-async fn debug_create_events_action(token: Token<'_>) -> Result<InsertManyResult, Error> {
-    let mut client_options = ClientOptions::parse("mongodb://localhost:27017").await?;
-    client_options.app_name = Some("mossy".to_string());
-    let client = Client::with_options(client_options)?;
-    let db = client.database("mossy");
-
-    let users = db.collection::<User>("users");
-    let tasks = db.collection::<Task>("tasks");
-    let events = db.collection::<Event>("events");
+async fn debug_create_events_action(token: Token, store: &Store) -> Result<InsertedManyData, DebugError> {
+    let repository = store.backend.as_ref();
 
-    let mut token_split = token.clone().0.split(" ");
-    let Some(token_value) = token_split.nth(1) else {
-        todo!()
-    };
-
-    let user_filter = bson::doc! {
-        "token": token_value,
-    };
-    let Some(user) = users.find_one(user_filter, None).await? else {
-        todo!()
-    };
-    if user.is_admin == false {
-        todo!()
+    let user = token.0;
+    if !user.is_admin {
+        return Err(DebugError::Forbidden)
     };
 
     let mut new_events = Vec::new();
 
-    let tasks_filter = bson::doc! {
-        "user": user._id,
-    };
-    let mut tasks_cursor = tasks.find(tasks_filter, None).await?;
-    while let Some(task) = tasks_cursor.try_next().await? {
-        let date = match bson::DateTime::parse_rfc3339_str("2023-10-01T05:43:48.487Z") {
-            Ok(_date) => _date,
-            Err(_date) => todo!(),
-        };
+    for task in repository.find_user_tasks(user._id, false).await? {
+        // 2023-10-01T05:43:48.487Z
+        let date = bson::DateTime::from_millis(1696139028487);
         let new_event = Event {
             _id: bson::oid::ObjectId::new(),
             task: task._id,
             date: date,
+            notes: None,
+            duration: None,
+            quantity: None,
+            rating: None,
+            attachments: None,
             user: Some(user._id),
+            updated_at: Some(bson::DateTime::now()),
+            deleted_at: None,
         };
         new_events.push(new_event);
     }
 
-    let events_result = events.insert_many(new_events, None).await;
+    repository.insert_events(&new_events).await?;
 
-    match events_result {
-        Ok(_events_result) => Ok(_events_result),
-        Err(_) => todo!(),
-    }
+    Ok(InsertedManyData::new(new_events.iter().map(|event| event._id)))
 }
 
-async fn debug_delete_events_action(token: Token<'_>) -> Result<DeleteResult, Error> {
-    let mut client_options = ClientOptions::parse("mongodb://localhost:27017").await?;
-    client_options.app_name = Some("mossy".to_string());
-    let client = Client::with_options(client_options)?;
-    let db = client.database("mossy");
+async fn debug_delete_events_action(token: Token, store: &Store) -> Result<DeletedData, DebugError> {
+    let repository = store.backend.as_ref();
 
-    let users = db.collection::<User>("users");
-    let events = db.collection::<Event>("events");
-
-    let mut token_split = token.clone().0.split(" ");
-    let Some(token_value) = token_split.nth(1) else {
-        todo!()
-    };
-
-    let user_filter = bson::doc! {
-        "token": token_value,
-    };
-    let Some(user) = users.find_one(user_filter, None).await? else {
-        todo!()
-    };
-    if user.is_admin == false {
-        todo!()
+    let user = token.0;
+    if !user.is_admin {
+        return Err(DebugError::Forbidden)
     };
 
-    let filter = bson::doc!{"user": user._id};
+    let deleted_count = repository.delete_user_documents("events", user._id).await?;
 
-    let events_result = events.delete_many(filter, None).await;
-
-    match events_result {
-        Ok(_events_result) => Ok(_events_result),
-        Err(_) => todo!(),
-    }
+    Ok(DeletedData { deleted_count })
 }
 
-async fn debug_create_tags_action(token: Token<'_>, data: DebugCreateTagsData) -> Result<InsertManyResult, Error> {
-    let mut client_options = ClientOptions::parse("mongodb://localhost:27017").await?;
-    client_options.app_name = Some("mossy".to_string());
-    let client = Client::with_options(client_options)?;
-    let db = client.database("mossy");
-
-    let users = db.collection::<User>("users");
-    let tags = db.collection::<Tag>("tags");
+async fn debug_create_tags_action(token: Token, data: DebugCreateTagsData, store: &Store) -> Result<InsertedManyData, DebugError> {
+    let repository = store.backend.as_ref();
 
-    let mut token_split = token.clone().0.split(" ");
-    let Some(token_value) = token_split.nth(1) else {
-        todo!()
-    };
-
-    let user_filter = bson::doc! {
-        "token": token_value,
-    };
-    let Some(user) = users.find_one(user_filter, None).await? else {
-        todo!()
-    };
-    if user.is_admin == false {
-        todo!()
+    let user = token.0;
+    if !user.is_admin {
+        return Err(DebugError::Forbidden)
     };
 
     let quantity_to_create = data.quantity;
@@ -1330,73 +2250,81 @@ async fn debug_create_tags_action(token: Token<'_>, data: DebugCreateTagsData) -
             name: String::from(iteration.to_string()),
             description: None,
             parent_tag: None,
+            color: None,
+            icon: None,
+            sort_order: None,
+            archived: None,
             user: Some(user._id),
+            updated_at: Some(bson::DateTime::now()),
+            deleted_at: None,
+            unlinked_tasks: None,
         };
         new_tags.push(new_tag);
         iteration += 1;
     }
 
 
-    let tags_result = tags.insert_many(new_tags, None).await;
+    repository.insert_tags(&new_tags).await?;
 
-    match tags_result {
-        Ok(_tags_result) => Ok(_tags_result),
-        Err(_) => todo!(),
-    }
+    Ok(InsertedManyData::new(new_tags.iter().map(|tag| tag._id)))
 }
 
-async fn debug_delete_tags_action(token: Token<'_>) -> Result<DeleteResult, Error> {
-    let mut client_options = ClientOptions::parse("mongodb://localhost:27017").await?;
-    client_options.app_name = Some("mossy".to_string());
-    let client = Client::with_options(client_options)?;
-    let db = client.database("mossy");
+async fn debug_delete_tags_action(token: Token, store: &Store) -> Result<DeletedData, DebugError> {
+    let repository = store.backend.as_ref();
 
-    let users = db.collection::<User>("users");
-    let tags = db.collection::<Task>("tags");
-
-    let mut token_split = token.clone().0.split(" ");
-    let Some(token_value) = token_split.nth(1) else {
-        todo!()
-    };
-
-    let user_filter = bson::doc! {
-        "token": token_value,
-    };
-    let Some(user) = users.find_one(user_filter, None).await? else {
-        todo!()
-    };
-    if user.is_admin == false {
-        todo!()
+    let user = token.0;
+    if !user.is_admin {
+        return Err(DebugError::Forbidden)
     };
 
-    let filter = bson::doc!{"user": user._id};
+    let deleted_count = repository.delete_user_documents("tags", user._id).await?;
 
-    let tags_result = tags.delete_many(filter, None).await;
-
-    match tags_result {
-        Ok(_tags_result) => Ok(_tags_result),
-        Err(_) => todo!(),
-    }
+    Ok(DeletedData { deleted_count })
 }
 
+// The owner of the request's bearer token
 #[derive(Debug, Clone)]
-struct Token<'r>(&'r str);
+struct Token(User);
 
 #[derive(Debug)]
 enum TokenError {
     Missing,
+    // No second part, or nobody has it
+    Unknown,
+    Expired,
+    // The database couldn't be asked
+    Lookup,
 }
 
 #[rocket::async_trait]
-impl<'r> FromRequest<'r> for Token<'r> {
+impl<'r> FromRequest<'r> for Token {
     type Error = TokenError;
 
     async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
-        if let Some(token) = request.headers().get_one("Authorization") {
-            return Outcome::Success(Token(token))
-        } else {
+        let Some(token) = request.headers().get_one("Authorization") else {
             return Outcome::Failure((Status::BadRequest, TokenError::Missing))
         };
+
+        let (Some(config), Some(store)) = (request.rocket().state::<AppConfig>(), request.rocket().state::<Store>()) else {
+            return Outcome::Failure((Status::InternalServerError, TokenError::Lookup))
+        };
+        let Some(token_value) = token.split(" ").nth(1).filter(|token_value| !token_value.is_empty()) else {
+            return Outcome::Failure((Status::Unauthorized, TokenError::Unknown))
+        };
+        match store.backend.find_user_by_token(token_value).await {
+            Ok(Some(user)) => {
+                Span::current().record("user_id", field::display(user._id));
+                if config.is_token_expired(user.token_issued_at) {
+                    return Outcome::Failure((Status::Unauthorized, TokenError::Expired))
+                }
+                Outcome::Success(Token(user))
+            },
+            Ok(None) => Outcome::Failure((Status::Unauthorized, TokenError::Unknown)),
+            Err(error) => {
+                error!(?error, "Couldn't look up the token's owner");
+                Outcome::Failure((Status::InternalServerError, TokenError::Lookup))
+            },
+        }
     }
 }
 
@@ -1406,284 +2334,624 @@ fn internal_error() -> &'static str {
 }
 
 #[post("/api/log-in", format="json", data="<credentials>")]
-async fn log_in(credentials: Json<Credentials>) -> Result<Json<User>, Status> {
+async fn log_in(credentials: Valid<Credentials>, meta: RequestMeta, config: &State<AppConfig>, apple_keys: &State<AppleKeys>, store: &State<Store>) -> Result<Json<User>, Status> {
     let deserialized_credentials = credentials.into_inner();
-    let log_in_result = validate_credentials(deserialized_credentials).await;
+    let log_in_result = validate_credentials(deserialized_credentials, &meta, config, apple_keys, store.backend.as_ref()).await;
 
     match log_in_result {
-        Ok(_log_in_result) => Ok(Json(_log_in_result)),
+        Ok(_log_in_result) => {
+            metrics::metrics().record_login("success");
+            Ok(Json(_log_in_result))
+        },
         Err(error) => {
-            // Print the specific error for dubugging until we can log it properly
-            println!("{:?}", error);
+            metrics::metrics().record_login(error.name());
+            warn!(%error, "Log in failed");
+            if let Err(audit_error) = audit::record_failed_log_in(store.backend.as_ref(), error.name(), &meta).await {
+                warn!(error = ?audit_error, "Couldn't audit failed log in");
+            }
             return Err(Status::InternalServerError)
         },
     }
 }
 
 #[post("/api/user", format="json", data="<user>")]
-async fn read_user(token: Token<'_>, user: Json<UserData>) -> Result<Json<User>, Status> {
+async fn read_user(token: Token, user: Valid<UserData>) -> Result<Json<User>, Status> {
     let deserialized_user = user.into_inner();
     let user = read_user_action(token, deserialized_user).await;
 
     match user {
-        Ok(user_result) => Ok(Json(user_result)),
-        Err(_) => Err(Status::InternalServerError),
+        Ok(Some(user_result)) => Ok(Json(user_result)),
+        Ok(None) => Err(Status::Forbidden),
+        Err(error) => {
+            error!(?error, "Couldn't read user");
+            Err(Status::InternalServerError)
+        },
     }
 }
 
 #[patch("/api/user/theme", format="json", data="<theme_data>")]
-async fn update_user_theme(token: Token<'_>, theme_data: Json<UserThemeData>) -> Result<Json<UpdateResult>, Status> {
+async fn update_user_theme(token: Token, theme_data: Valid<UserThemeData>, store: &State<Store>, meta: RequestMeta) -> Result<Json<UpdatedData>, Status> {
     let deserialized_theme = theme_data.into_inner();
-    let theme_result = update_user_theme_action(token, deserialized_theme).await;
+    let theme_result = update_user_theme_action(token, deserialized_theme, store, &meta).await;
 
     match theme_result {
-        Ok(_theme) => Ok(Json(_theme)),
-        Err(_) => Err(Status::InternalServerError),
+        Ok(Some(_theme)) => Ok(Json(_theme)),
+        Ok(None) => Err(Status::Forbidden),
+        Err(error) => {
+            error!(?error, "Couldn't update user theme");
+            Err(Status::InternalServerError)
+        },
+    }
+}
+
+#[patch("/api/user/digest", format="json", data="<digest_data>")]
+async fn update_user_digest(token: Token, digest_data: Valid<UserDigestData>, store: &State<Store>, meta: RequestMeta) -> Result<Json<UpdatedData>, Status> {
+    let deserialized_digest = digest_data.into_inner();
+    let digest_result = update_user_digest_action(token, deserialized_digest, store, &meta).await;
+
+    match digest_result {
+        Ok(Some(_digest)) => Ok(Json(_digest)),
+        Ok(None) => Err(Status::Forbidden),
+        Err(error) => {
+            error!(?error, "Couldn't update user digest");
+            Err(Status::InternalServerError)
+        },
     }
 }
 
 #[get("/api/tasks?<limit>&<offset>", format="json")]
-async fn read_tasks(token: Token<'_>, limit: Option<u32>, offset: Option<u32>) -> Result<Json<Vec<Document>>, Status> {
+async fn read_tasks(token: Token, limit: Option<u32>, offset: Option<u32>, store: &State<Store>) -> Result<Json<Vec<Document>>, Status> {
     let params = ReadParams {
         limit: limit,
         offset: offset,
     };
-    let tasks = read_tasks_action(token, params).await;
+    let tasks = read_tasks_action(token, params, store).await;
 
     match tasks {
         Ok(tasks_result) => Ok(Json(tasks_result)),
-        Err(_) => Err(Status::InternalServerError),
+        Err(error) => {
+            error!(?error, "Couldn't read tasks");
+            Err(Status::InternalServerError)
+        },
     }
 }
 
 #[post("/api/tasks", format="json", data="<task>")]
-async fn create_task(token: Token<'_>, task: Json<NewTaskData>) -> Result<Json<InsertOneResult>, Status> {
+async fn create_task(token: Token, task: Valid<NewTaskData>, store: &State<Store>, changes: &State<ChangeBus>, meta: RequestMeta) -> Result<Json<InsertedData>, Rejection> {
     let deserialized_task = task.into_inner();
-    let task = create_task_action(token, deserialized_task).await;
+    let task = create_task_action(token, deserialized_task, store, changes, &meta).await;
 
     match task {
         Ok(task_result) => Ok(Json(task_result)),
-        Err(_) => Err(Status::InternalServerError),
+        Err(ActionError::Invalid(errors)) => Err(Rejection::Invalid(errors)),
+        Err(error) => {
+            error!(?error, "Couldn't create task");
+            Err(Rejection::Failed(Status::InternalServerError))
+        },
     }
 }
 
 #[patch("/api/tasks", format="json", data="<task>")]
-async fn update_task(token: Token<'_>, task: Json<Task>) -> Result<Json<UpdateResult>, Status> {
+async fn update_task(token: Token, task: Valid<UpdateTaskData>, if_match: IfMatch, store: &State<Store>, changes: &State<ChangeBus>, meta: RequestMeta) -> UpdateResponse {
     let deserialized_task = task.into_inner();
-    let task = update_task_action(token, deserialized_task).await;
+    let task = update_task_action(token, deserialized_task, &if_match, store, changes, &meta).await;
 
     match task {
-        Ok(task_result) => Ok(Json(task_result)),
-        Err(_) => Err(Status::InternalServerError),
+        Ok((task_result, updated_at)) => UpdateResponse::updated(task_result, updated_at),
+        Err(UpdateError::VersionConflict(current_task)) => UpdateResponse::conflict(current_task),
+        Err(UpdateError::NotFound) => UpdateResponse::Failed(Status::NotFound),
+        Err(UpdateError::Invalid(errors)) => UpdateResponse::Invalid(errors),
+        Err(error) => {
+            error!(%error, "Couldn't update task");
+            UpdateResponse::Failed(Status::InternalServerError)
+        },
     }
 }
 
-#[delete("/api/tasks", format="json", data="<tasks>")]
-async fn delete_tasks(token: Token<'_>, tasks: Json<Vec<bson::oid::ObjectId>>) -> Result<Json<DeleteResult>, Status> {
+#[delete("/api/tasks?<strategy>", format="json", data="<tasks>")]
+async fn delete_tasks(token: Token, tasks: Json<Vec<bson::oid::ObjectId>>, strategy: Option<DeleteStrategy>, store: &State<Store>, changes: &State<ChangeBus>, meta: RequestMeta) -> Result<Json<DeleteSummary>, Status> {
+    // Tasks have no parents to move their events to
+    let strategy = strategy.unwrap_or(DeleteStrategy::Cascade);
+    if strategy == DeleteStrategy::Reparent {
+        return Err(Status::UnprocessableEntity)
+    }
     let deserialized_tasks_list = tasks.into_inner();
-    let tasks = delete_tasks_action(token, deserialized_tasks_list).await;
+    let tasks = delete_tasks_action(token, deserialized_tasks_list, strategy, store, changes, &meta).await;
 
     match tasks {
         Ok(tasks_result) => Ok(Json(tasks_result)),
-        Err(_) => Err(Status::InternalServerError),
+        Err(DeleteError::NotFound) => Err(Status::NotFound),
+        Err(DeleteError::ConcurrentChange) => Err(Status::Conflict),
+        Err(error) => {
+            error!(?error, "Couldn't delete tasks");
+            Err(Status::InternalServerError)
+        },
     }
 }
 
 #[get("/api/events?<limit>&<offset>", format="json")]
-async fn read_events(token: Token<'_>, limit: Option<u32>, offset: Option<u32>) -> Result<Json<Vec<Event>>, Status> {
+async fn read_events(token: Token, limit: Option<u32>, offset: Option<u32>, store: &State<Store>) -> Result<Json<Vec<Event>>, Status> {
     let params = ReadParams {
         limit: limit,
         offset: offset,
     };
-    let events = read_events_action(token, params).await;
+    let events = read_events_action(token, params, store).await;
 
     match events {
         Ok(events_result) => Ok(Json(events_result)),
-        Err(_) => Err(Status::InternalServerError),
+        Err(error) => {
+            error!(?error, "Couldn't read events");
+            Err(Status::InternalServerError)
+        },
     }
 }
 
 #[get("/api/events-string?<limit>&<offset>", format="json")]
-async fn read_events_string(token: Token<'_>, limit: Option<u32>, offset: Option<u32>) -> Result<Json<Vec<EventWithStringValues>>, Status> {
+async fn read_events_string(token: Token, limit: Option<u32>, offset: Option<u32>, store: &State<Store>) -> Result<Json<Vec<EventWithStringValues>>, Status> {
     let params = ReadParams {
         limit: limit,
         offset: offset,
     };
-    let events = read_events_string_action(token, params).await;
+    let events = read_events_string_action(token, params, store).await;
 
     match events {
         Ok(events_result) => Ok(Json(events_result)),
-        Err(_) => Err(Status::InternalServerError),
+        Err(error) => {
+            error!(?error, "Couldn't read events string");
+            Err(Status::InternalServerError)
+        },
+    }
+}
+
+#[get("/api/tasks/stats?<task>", format="json")]
+async fn read_task_stats(token: Token, task: Option<&str>, store: &State<Store>) -> Result<Json<Vec<TaskStats>>, Status> {
+    let task_id = match task {
+        Some(_task) => match bson::oid::ObjectId::parse_str(_task) {
+            Ok(_task_id) => Some(_task_id),
+            Err(_) => return Err(Status::BadRequest),
+        },
+        None => None,
+    };
+    let stats = read_task_stats_action(token, task_id, store).await;
+
+    match stats {
+        Ok(stats_result) => Ok(Json(stats_result)),
+        Err(error) => {
+            error!(?error, "Couldn't read task stats");
+            Err(Status::InternalServerError)
+        },
     }
 }
 
 #[post("/api/events", format="json", data="<event>")]
-async fn create_event(token: Token<'_>, event: Json<NewEventData>) -> Result<Json<InsertOneResult>, Status> {
+async fn create_event(token: Token, event: Valid<NewEventData>, store: &State<Store>, changes: &State<ChangeBus>, meta: RequestMeta) -> Result<Json<InsertedData>, Rejection> {
     let deserialized_event = event.into_inner();
-    let event = create_event_action(token, deserialized_event).await;
+    let event = create_event_action(token, deserialized_event, store, changes, &meta).await;
 
     match event {
         Ok(event_result) => Ok(Json(event_result)),
-        Err(_) => Err(Status::InternalServerError),
+        Err(ActionError::Invalid(errors)) => Err(Rejection::Invalid(errors)),
+        Err(error) => {
+            error!(?error, "Couldn't create event");
+            Err(Rejection::Failed(Status::InternalServerError))
+        },
+    }
+}
+
+#[post("/api/events/batch", format="json", data="<batch>")]
+async fn create_events_batch(token: Token, batch: Valid<NewEventsBatchData>, store: &State<Store>, changes: &State<ChangeBus>, meta: RequestMeta) -> Result<Json<Vec<EventsBatchResult>>, Rejection> {
+    let deserialized_batch = batch.into_inner();
+    let events = create_events_batch_action(token, deserialized_batch, store, changes, &meta).await;
+
+    match events {
+        Ok(events_result) => Ok(Json(events_result)),
+        Err(EventsBatchError::InvalidDate) => Err(Rejection::Invalid(ValidationErrors::field("date", "must be an RFC 3339 date"))),
+        Err(EventsBatchError::InvalidSelection) => Err(Rejection::Invalid(ValidationErrors::field("tag", "must be one of your tags"))),
+        Err(error) => {
+            error!(%error, "Couldn't create events batch");
+            Err(Rejection::Failed(Status::InternalServerError))
+        },
     }
 }
 
 #[patch("/api/events", format="json", data="<event>")]
-async fn update_event(token: Token<'_>, event: Json<UpdateEventData>) -> Result<Json<UpdateResult>, Status> {
+async fn update_event(token: Token, event: Valid<UpdateEventData>, if_match: IfMatch, store: &State<Store>, changes: &State<ChangeBus>, meta: RequestMeta) -> UpdateResponse {
     let deserialized_event = event.into_inner();
-    let event = update_event_action(token, deserialized_event).await;
+    let event = update_event_action(token, deserialized_event, &if_match, store, changes, &meta).await;
 
     match event {
-        Ok(event_result) => Ok(Json(event_result)),
-        Err(_) => Err(Status::InternalServerError),
+        Ok((event_result, updated_at)) => UpdateResponse::updated(event_result, updated_at),
+        Err(UpdateError::VersionConflict(current_event)) => UpdateResponse::conflict(current_event),
+        Err(UpdateError::NotFound) => UpdateResponse::Failed(Status::NotFound),
+        Err(UpdateError::Invalid(errors)) => UpdateResponse::Invalid(errors),
+        Err(error) => {
+            error!(%error, "Couldn't update event");
+            UpdateResponse::Failed(Status::InternalServerError)
+        },
     }
 }
 
 #[delete("/api/events", format="json", data="<events>")]
-async fn delete_events(token: Token<'_>, events: Json<Vec<bson::oid::ObjectId>>) -> Result<Json<DeleteResult>, Status> {
+async fn delete_events(token: Token, events: Json<Vec<bson::oid::ObjectId>>, store: &State<Store>, changes: &State<ChangeBus>, meta: RequestMeta) -> Result<Json<DeleteSummary>, Status> {
     let deserialized_events_list = events.into_inner();
-    let events = delete_events_action(token, deserialized_events_list).await;
+    let events = delete_events_action(token, deserialized_events_list, store, changes, &meta).await;
 
     match events {
         Ok(events_result) => Ok(Json(events_result)),
-        Err(_) => Err(Status::InternalServerError),
+        Err(DeleteError::NotFound) => Err(Status::NotFound),
+        Err(DeleteError::ConcurrentChange) => Err(Status::Conflict),
+        Err(error) => {
+            error!(?error, "Couldn't delete events");
+            Err(Status::InternalServerError)
+        },
     }
 }
 
 #[get("/api/tags?<limit>&<offset>", format="json")]
-async fn read_tags(token: Token<'_>, limit: Option<u32>, offset: Option<u32>) -> Result<Json<Vec<Tag>>, Status> {
+async fn read_tags(token: Token, limit: Option<u32>, offset: Option<u32>, store: &State<Store>) -> Result<Json<Vec<Tag>>, Status> {
     let params = ReadParams {
         limit: limit,
         offset: offset,
     };
-    let tags = read_tags_action(token, params).await;
+    let tags = read_tags_action(token, params, store).await;
 
     match tags {
         Ok(tags_result) => Ok(Json(tags_result)),
-        Err(_) => Err(Status::InternalServerError),
+        Err(error) => {
+            error!(?error, "Couldn't read tags");
+            Err(Status::InternalServerError)
+        },
+    }
+}
+
+#[get("/api/tags/tree", format="json")]
+async fn read_tags_tree(token: Token, store: &State<Store>) -> Result<Json<Vec<TagTreeNode>>, Status> {
+    let tree = read_tags_tree_action(token, store).await;
+
+    match tree {
+        Ok(tree_result) => Ok(Json(tree_result)),
+        Err(error) => {
+            error!(?error, "Couldn't read tag tree");
+            Err(Status::InternalServerError)
+        },
     }
 }
 
 #[post("/api/tags", format="json", data="<tag>")]
-async fn create_tag(token: Token<'_>, tag: Json<NewTagData>) -> Result<Json<InsertOneResult>, Status> {
+async fn create_tag(token: Token, tag: Valid<NewTagData>, store: &State<Store>, changes: &State<ChangeBus>, meta: RequestMeta) -> Result<Json<InsertedData>, Rejection> {
     let deserialized_tag = tag.into_inner();
-    let tag = create_tag_action(token, deserialized_tag).await;
+    let tag = create_tag_action(token, deserialized_tag, store, changes, &meta).await;
 
     match tag {
         Ok(tag_result) => Ok(Json(tag_result)),
-        Err(_) => Err(Status::InternalServerError),
+        Err(error) => match tag_validation_errors(&error) {
+            Some(errors) => Err(Rejection::Invalid(errors)),
+            None => {
+                error!(?error, "Couldn't create tag");
+                Err(Rejection::Failed(Status::InternalServerError))
+            },
+        },
     }
 }
 
 #[patch("/api/tags", format="json", data="<tag>")]
-async fn update_tag(token: Token<'_>, tag: Json<Tag>) -> Result<Json<UpdateResult>, Status> {
+async fn update_tag(token: Token, tag: Valid<UpdateTagData>, if_match: IfMatch, store: &State<Store>, changes: &State<ChangeBus>, meta: RequestMeta) -> UpdateResponse {
     let deserialized_tag = tag.into_inner();
-    let tag = update_tag_action(token, deserialized_tag).await;
+    let tag = update_tag_action(token, deserialized_tag, &if_match, store, changes, &meta).await;
 
     match tag {
-        Ok(tag_result) => Ok(Json(tag_result)),
-        Err(_) => Err(Status::InternalServerError),
+        Ok((tag_result, updated_at)) => UpdateResponse::updated(tag_result, updated_at),
+        Err(TagError::VersionConflict(current_tag)) => UpdateResponse::conflict(current_tag),
+        Err(TagError::NotFound) => UpdateResponse::Failed(Status::NotFound),
+        Err(error) => match tag_validation_errors(&error) {
+            Some(errors) => UpdateResponse::Invalid(errors),
+            None => {
+                error!(?error, "Couldn't update tag");
+                UpdateResponse::Failed(Status::InternalServerError)
+            },
+        },
     }
 }
 
-#[delete("/api/tags", format="json", data="<tags>")]
-async fn delete_tags(token: Token<'_>, tags: Json<Vec<bson::oid::ObjectId>>) -> Result<Json<DeleteResult>, Status> {
+#[delete("/api/tags?<strategy>", format="json", data="<tags>")]
+async fn delete_tags(token: Token, tags: Json<Vec<bson::oid::ObjectId>>, strategy: Option<DeleteStrategy>, store: &State<Store>, changes: &State<ChangeBus>, meta: RequestMeta) -> Result<Json<DeleteSummary>, Status> {
     let deserialized_tags_list = tags.into_inner();
-    let tags = delete_tags_action(token, deserialized_tags_list).await;
+    let tags = delete_tags_action(token, deserialized_tags_list, strategy.unwrap_or(DeleteStrategy::Reparent), store, changes, &meta).await;
 
     match tags {
         Ok(tags_result) => Ok(Json(tags_result)),
-        Err(_) => Err(Status::InternalServerError),
+        Err(DeleteError::NotFound) => Err(Status::NotFound),
+        Err(DeleteError::ConcurrentChange) => Err(Status::Conflict),
+        Err(error) => {
+            error!(?error, "Couldn't delete tags");
+            Err(Status::InternalServerError)
+        },
+    }
+}
+
+#[post("/api/tags/merge", format="json", data="<merge_data>")]
+async fn merge_tags(token: Token, merge_data: Valid<MergeTagsData>, store: &State<Store>, changes: &State<ChangeBus>, meta: RequestMeta) -> Result<Json<MergeTagsSummary>, Rejection> {
+    let deserialized_merge_data = merge_data.into_inner();
+    let merge = merge_tags_action(token, deserialized_merge_data, store, changes, &meta).await;
+
+    match merge {
+        Ok(merge_result) => Ok(Json(merge_result)),
+        Err(TagError::InvalidTag) => Err(Rejection::Invalid(ValidationErrors::field("source_tags", "and target_tag must all be your tags"))),
+        Err(TagError::ParentCycleError) => Err(Rejection::Invalid(ValidationErrors::field("target_tag", "can't be a descendant of a source tag"))),
+        Err(TagError::ConcurrentChange) => Err(Rejection::Failed(Status::Conflict)),
+        Err(error) => {
+            error!(?error, "Couldn't merge tags");
+            Err(Rejection::Failed(Status::InternalServerError))
+        },
+    }
+}
+
+#[patch("/api/tasks/tags", format="json", data="<retag_data>")]
+async fn retag_tasks(token: Token, retag_data: Valid<RetagTasksData>, store: &State<Store>, changes: &State<ChangeBus>, meta: RequestMeta) -> Result<Json<RetagTasksSummary>, Rejection> {
+    let deserialized_retag_data = retag_data.into_inner();
+    let retag = retag_tasks_action(token, deserialized_retag_data, store, changes, &meta).await;
+
+    match retag {
+        Ok(retag_result) => Ok(Json(retag_result)),
+        Err(TagError::InvalidTag) => Err(Rejection::Invalid(ValidationErrors::field("tasks", "and tags must all be yours"))),
+        Err(TagError::ConcurrentChange) => Err(Rejection::Failed(Status::Conflict)),
+        Err(error) => {
+            error!(?error, "Couldn't retag tasks");
+            Err(Rejection::Failed(Status::InternalServerError))
+        },
     }
 }
 
 #[post("/api/debug/tasks", format="json", data="<data>")]
-async fn debug_create_tasks(token: Token<'_>, data: Json<DebugCreateTasksData>) -> Result<Json<InsertManyResult>, Status> {
+async fn debug_create_tasks(token: Token, data: Valid<DebugCreateTasksData>, store: &State<Store>) -> Result<Json<InsertedManyData>, Status> {
     let deserialized_data = data.into_inner();
-    let tasks = debug_create_tasks_action(token, deserialized_data).await;
+    let tasks = debug_create_tasks_action(token, deserialized_data, store).await;
 
     match tasks {
         Ok(tasks_result) => Ok(Json(tasks_result)),
-        Err(_) => Err(Status::InternalServerError),
+        Err(DebugError::Forbidden) => Err(Status::Forbidden),
+        Err(error) => {
+            error!(%error, "Couldn't create debug tasks");
+            Err(Status::InternalServerError)
+        },
     }
 }
 
 #[delete("/api/debug/tasks", format="json")]
-async fn debug_delete_tasks(token: Token<'_>) -> Result<Json<DeleteResult>, Status> {
-    let delete_result = debug_delete_tasks_action(token).await;
+async fn debug_delete_tasks(token: Token, store: &State<Store>) -> Result<Json<DeletedData>, Status> {
+    let delete_result = debug_delete_tasks_action(token, store).await;
 
     match delete_result {
         Ok(_delete_result) => Ok(Json(_delete_result)),
-        Err(_) => Err(Status::InternalServerError),
+        Err(DebugError::Forbidden) => Err(Status::Forbidden),
+        Err(error) => {
+            error!(%error, "Couldn't delete debug tasks");
+            Err(Status::InternalServerError)
+        },
     }
 }
 
 #[post("/api/debug/events", format="json")]
-async fn debug_create_events(token: Token<'_>) -> Result<Json<InsertManyResult>, Status> {
-    let events = debug_create_events_action(token).await;
+async fn debug_create_events(token: Token, store: &State<Store>) -> Result<Json<InsertedManyData>, Status> {
+    let events = debug_create_events_action(token, store).await;
 
     match events {
         Ok(events_result) => Ok(Json(events_result)),
-        Err(_) => Err(Status::InternalServerError),
+        Err(DebugError::Forbidden) => Err(Status::Forbidden),
+        Err(error) => {
+            error!(%error, "Couldn't create debug events");
+            Err(Status::InternalServerError)
+        },
     }
 }
 
 #[delete("/api/debug/events", format="json")]
-async fn debug_delete_events(token: Token<'_>) -> Result<Json<DeleteResult>, Status> {
-    let delete_result = debug_delete_events_action(token).await;
+async fn debug_delete_events(token: Token, store: &State<Store>) -> Result<Json<DeletedData>, Status> {
+    let delete_result = debug_delete_events_action(token, store).await;
 
     match delete_result {
         Ok(_delete_result) => Ok(Json(_delete_result)),
-        Err(_) => Err(Status::InternalServerError),
+        Err(DebugError::Forbidden) => Err(Status::Forbidden),
+        Err(error) => {
+            error!(%error, "Couldn't delete debug events");
+            Err(Status::InternalServerError)
+        },
     }
 }
 
 #[post("/api/debug/tags", format="json", data="<data>")]
-async fn debug_create_tags(token: Token<'_>, data: Json<DebugCreateTagsData>) -> Result<Json<InsertManyResult>, Status> {
+async fn debug_create_tags(token: Token, data: Valid<DebugCreateTagsData>, store: &State<Store>) -> Result<Json<InsertedManyData>, Status> {
     let deserialized_data = data.into_inner();
-    let tags = debug_create_tags_action(token, deserialized_data).await;
+    let tags = debug_create_tags_action(token, deserialized_data, store).await;
 
     match tags {
         Ok(tags_result) => Ok(Json(tags_result)),
-        Err(_) => Err(Status::InternalServerError),
+        Err(DebugError::Forbidden) => Err(Status::Forbidden),
+        Err(error) => {
+            error!(%error, "Couldn't create debug tags");
+            Err(Status::InternalServerError)
+        },
     }
 }
 
 #[delete("/api/debug/tags", format="json")]
-async fn debug_delete_tags(token: Token<'_>) -> Result<Json<DeleteResult>, Status> {
-    let delete_result = debug_delete_tags_action(token).await;
+async fn debug_delete_tags(token: Token, store: &State<Store>) -> Result<Json<DeletedData>, Status> {
+    let delete_result = debug_delete_tags_action(token, store).await;
 
     match delete_result {
         Ok(_delete_result) => Ok(Json(_delete_result)),
-        Err(_) => Err(Status::InternalServerError),
-    }
-}
-
-#[launch]
-fn rocket() -> _ {
-    rocket::build()
-        .register("/", catchers![internal_error])
-        .mount("/", routes![index])
-        .mount("/", routes![log_in])
-        .mount("/", routes![read_user])
-        .mount("/", routes![update_user_theme])
-        .mount("/", routes![read_tasks])
-        .mount("/", routes![create_task])
-        .mount("/", routes![update_task])
-        .mount("/", routes![delete_tasks])
-        .mount("/", routes![read_events])
-        .mount("/", routes![read_events_string])
-        .mount("/", routes![create_event])
-        .mount("/", routes![update_event])
-        .mount("/", routes![delete_events])
-        .mount("/", routes![read_tags])
-        .mount("/", routes![create_tag])
-        .mount("/", routes![update_tag])
-        .mount("/", routes![delete_tags])
-        .mount("/", routes![debug_create_tasks])
-        .mount("/", routes![debug_delete_tasks])
-        .mount("/", routes![debug_create_events])
-        .mount("/", routes![debug_delete_events])
-        .mount("/", routes![debug_create_tags])
-        .mount("/", routes![debug_delete_tags])
-}
\ No newline at end of file
+        Err(DebugError::Forbidden) => Err(Status::Forbidden),
+        Err(error) => {
+            error!(%error, "Couldn't delete debug tags");
+            Err(Status::InternalServerError)
+        },
+    }
+}
+
+async fn rocket() -> Rocket<Build> {
+    rocket::custom(config::figment())
+        .attach(RequestTracing)
+        .attach(RequestMetrics)
+        .attach(AdHoc::try_on_ignite("Configuration", |rocket| Box::pin(async move {
+            let config = match AppConfig::from_figment(rocket.figment()) {
+                Ok(_config) => _config,
+                Err(error) => {
+                    error!("Invalid configuration:\n{}", error);
+                    return Err(rocket)
+                },
+            };
+            let storage = match attachments::Storage::from_config(&config) {
+                Ok(_storage) => _storage,
+                Err(error) => {
+                    error!("Invalid configuration:\n{}", error);
+                    return Err(rocket)
+                },
+            };
+            match Store::from_config(&config).await {
+                Ok(store) => Ok(rocket.manage(ChangeBus::new(&config)).manage(config).manage(store).manage(storage)),
+                Err(error) => {
+                    error!(?error, "Invalid database settings");
+                    Err(rocket)
+                },
+            }
+        })))
+        .attach(AdHoc::try_on_ignite("Database migrations", |rocket| Box::pin(async move {
+            let Some(config) = rocket.state::<AppConfig>() else {
+                return Err(rocket)
+            };
+            if config.uses_memory_backend() || !config.migrate_on_launch {
+                return Ok(rocket)
+            }
+            match migrations::run_with_config(config).await {
+                Ok(_applied_names) => {
+                    for name in _applied_names {
+                        info!(migration = %name, "Applied migration");
+                    }
+                    Ok(rocket)
+                },
+                Err(error) => {
+                    error!(?error, "Migrations failed");
+                    Err(rocket)
+                },
+            }
+        })))
+        .manage(AppleKeys::new())
+        .attach(AdHoc::on_liftoff("Change stream watcher", |rocket| Box::pin(async move {
+            if let (Some(changes), Some(config)) = (rocket.state::<ChangeBus>(), rocket.state::<AppConfig>()) {
+                if changes.uses_change_streams() {
+                    rocket::tokio::spawn(realtime::run_change_streams(changes.clone(), config.clone()));
+                }
+            }
+        })))
+        .attach(AdHoc::on_liftoff("Email digest scheduler", |rocket| Box::pin(async move {
+            if let Some(config) = rocket.state::<AppConfig>().filter(|_config| _config.features.digests) {
+                rocket::tokio::spawn(digest::run_scheduler(config.clone()));
+            }
+        })))
+        .attach(AdHoc::on_liftoff("Webhook delivery worker", |rocket| Box::pin(async move {
+            if let Some(config) = rocket.state::<AppConfig>().filter(|_config| _config.features.webhooks) {
+                rocket::tokio::spawn(webhooks::run_worker(config.clone()));
+            }
+        })))
+        .attach(AdHoc::on_liftoff("Trash purger", |rocket| Box::pin(async move {
+            if let (Some(config), Some(storage)) = (rocket.state::<AppConfig>().filter(|_config| _config.features.trash_purge), rocket.state::<attachments::Storage>()) {
+                rocket::tokio::spawn(trash::run_purger(config.clone(), storage.clone()));
+            }
+        })))
+        .attach(AdHoc::on_liftoff("Apple key refresher", |rocket| Box::pin(async move {
+            if let Some(apple_keys) = rocket.state::<AppleKeys>() {
+                rocket::tokio::spawn(apple_keys::run_refresher(apple_keys.clone()));
+            }
+        })))
+        // Rocket stops accepting connections and gives in-flight requests the [shutdown] grace period to finish
+        .attach(AdHoc::on_shutdown("Shutdown notice", |_| Box::pin(async move {
+            info!("Shutting down, draining in-flight requests");
+        })))
+        .register("/", catchers![internal_error, validation::unprocessable_entity])
+        .mount("/", traced(routes![index]))
+        .mount("/", traced(routes![health::read_health]))
+        .mount("/", traced(routes![health::read_readiness]))
+        .mount("/", traced(routes![log_in]))
+        .mount("/", traced(routes![metrics::read_metrics]))
+        .mount("/", traced(routes![read_user]))
+        .mount("/", traced(routes![update_user_theme]))
+        .mount("/", traced(routes![update_user_digest]))
+        .mount("/", traced(routes![read_tasks]))
+        .mount("/", traced(routes![read_task_stats]))
+        .mount("/", traced(routes![create_task]))
+        .mount("/", traced(routes![update_task]))
+        .mount("/", traced(routes![delete_tasks]))
+        .mount("/", traced(routes![read_events]))
+        .mount("/", traced(routes![read_events_string]))
+        .mount("/", traced(routes![create_event]))
+        .mount("/", traced(routes![create_events_batch]))
+        .mount("/", traced(routes![update_event]))
+        .mount("/", traced(routes![delete_events]))
+        .mount("/", traced(routes![read_tags]))
+        .mount("/", traced(routes![read_tags_tree]))
+        .mount("/", traced(routes![create_tag]))
+        .mount("/", traced(routes![update_tag]))
+        .mount("/", traced(routes![delete_tags]))
+        .mount("/", traced(routes![merge_tags]))
+        .mount("/", traced(routes![retag_tasks]))
+        .mount("/", traced(routes![sync::read_sync]))
+        .mount("/", traced(routes![sync::push_sync]))
+        .mount("/", traced(routes![realtime::stream_changes]))
+        .mount("/", traced(routes![calendar::create_calendar_feed_url]))
+        .mount("/", traced(routes![calendar::read_calendar_feed]))
+        .mount("/", traced(routes![webhooks::read_webhooks]))
+        .mount("/", traced(routes![webhooks::create_webhook]))
+        .mount("/", traced(routes![webhooks::delete_webhooks]))
+        .mount("/", traced(routes![webhooks::read_webhook_deliveries]))
+        .mount("/", traced(routes![attachments::create_attachment]))
+        .mount("/", traced(routes![attachments::read_attachments]))
+        .mount("/", traced(routes![attachments::read_attachment_usage]))
+        .mount("/", traced(routes![attachments::read_attachment_file]))
+        .mount("/", traced(routes![attachments::read_attachment_thumbnail]))
+        .mount("/", traced(routes![attachments::delete_attachments]))
+        .mount("/", traced(routes![trash::read_trash]))
+        .mount("/", traced(routes![undo::undo]))
+        .mount("/", traced(routes![audit::read_audit]))
+        .mount("/", traced(routes![trash::restore_tasks]))
+        .mount("/", traced(routes![trash::restore_events]))
+        .mount("/", traced(routes![trash::restore_tags]))
+        .mount("/", traced(routes![debug_create_tasks]))
+        .mount("/", traced(routes![debug_delete_tasks]))
+        .mount("/", traced(routes![debug_create_events]))
+        .mount("/", traced(routes![debug_delete_events]))
+        .mount("/", traced(routes![debug_create_tags]))
+        .mount("/", traced(routes![debug_delete_tags]))
+}
+
+#[rocket::main]
+async fn main() -> Result<(), Box<rocket::Error>> {
+    // Read here as well as at ignite, because logging has to be set up before Rocket starts logging
+    let config = match AppConfig::from_env() {
+        Ok(_config) => _config,
+        Err(error) => {
+            eprintln!("Invalid configuration:\n{}", error);
+            std::process::exit(1)
+        },
+    };
+    telemetry::init(&config);
+
+    if std::env::args().nth(1).as_deref() == Some("migrate") {
+        let applied_names = match migrations::run_with_config(&config).await {
+            Ok(_applied_names) => _applied_names,
+            Err(error) => {
+                error!(%error, "Migrations failed");
+                std::process::exit(1)
+            },
+        };
+        if applied_names.is_empty() {
+            println!("No pending migrations");
+        }
+        for name in applied_names {
+            println!("Applied migration {}", name);
+        }
+        return Ok(())
+    }
+
+    let _rocket = rocket().await.launch().await?;
+    Ok(())
+}
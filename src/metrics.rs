@@ -0,0 +1,158 @@
+use mongodb::bson;
+use mongodb::event::command::{CommandEventHandler, CommandFailedEvent, CommandSucceededEvent};
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use rocket::{Data, Request, Response, State};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{ContentType, Status};
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+use tracing::error;
+
+use crate::config::AppConfig;
+use crate::repository::{Repository, Store};
+
+// Requests that matched no route share one label, so scanners can't blow up the number of series
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+// Most queries should land in the low milliseconds, the default buckets start too coarse for that
+const MONGODB_COMMAND_BUCKETS: [f64; 12] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5];
+
+// Process-wide rather than managed state, because the per-action MongoDB clients have nowhere else to report to
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+pub struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_request_duration: HistogramVec,
+    mongodb_command_duration: HistogramVec,
+    logins: IntCounterVec,
+    users: IntGauge,
+    events_logged_last_hour: IntGauge,
+}
+
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::new)
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let registry = Registry::new_custom(Some(String::from("mossy")), None).expect("valid metrics prefix");
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "Requests handled, by route and status"),
+            &["method", "route", "status"],
+        ).expect("valid metric");
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "Time from receiving a request to sending the response"),
+            &["method", "route", "status"],
+        ).expect("valid metric");
+        let mongodb_command_duration = HistogramVec::new(
+            HistogramOpts::new("mongodb_command_duration_seconds", "Time MongoDB took to answer, by command").buckets(MONGODB_COMMAND_BUCKETS.to_vec()),
+            &["command", "outcome"],
+        ).expect("valid metric");
+        let logins = IntCounterVec::new(
+            Opts::new("logins_total", "Log in attempts, by success or the CredentialsError that stopped them"),
+            &["result"],
+        ).expect("valid metric");
+        let users = IntGauge::new("users", "Everyone who has ever signed up").expect("valid metric");
+        let events_logged_last_hour = IntGauge::new("events_logged_last_hour", "Events created in the past hour, leaving out deleted ones").expect("valid metric");
+
+        registry.register(Box::new(http_requests.clone())).expect("unique metric");
+        registry.register(Box::new(http_request_duration.clone())).expect("unique metric");
+        registry.register(Box::new(mongodb_command_duration.clone())).expect("unique metric");
+        registry.register(Box::new(logins.clone())).expect("unique metric");
+        registry.register(Box::new(users.clone())).expect("unique metric");
+        registry.register(Box::new(events_logged_last_hour.clone())).expect("unique metric");
+
+        Metrics {
+            registry,
+            http_requests,
+            http_request_duration,
+            mongodb_command_duration,
+            logins,
+            users,
+            events_logged_last_hour,
+        }
+    }
+
+    pub fn record_login(&self, result: &str) {
+        self.logins.with_label_values(&[result]).inc();
+    }
+
+    // Business gauges are counted when scraped instead of kept up to date on every write
+    async fn update_gauges(&self, repository: &dyn Repository) -> Result<(), mongodb::error::Error> {
+        let hour_ago = bson::DateTime::from_chrono(chrono::Utc::now() - chrono::Duration::hours(1));
+
+        self.users.set(repository.count_users().await? as i64);
+        self.events_logged_last_hour.set(repository.count_events_created_since(hour_ago).await? as i64);
+        Ok(())
+    }
+}
+
+struct CommandTimer;
+
+impl CommandEventHandler for CommandTimer {
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        metrics().mongodb_command_duration.with_label_values(&[&event.command_name, "succeeded"]).observe(event.duration.as_secs_f64());
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        metrics().mongodb_command_duration.with_label_values(&[&event.command_name, "failed"]).observe(event.duration.as_secs_f64());
+    }
+}
+
+// Goes in every client's options next to app_name
+pub fn command_timer() -> Arc<dyn CommandEventHandler> {
+    Arc::new(CommandTimer)
+}
+
+struct RequestStart(Instant);
+
+pub struct RequestMetrics;
+
+#[rocket::async_trait]
+impl Fairing for RequestMetrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let started_at = request.local_cache(|| RequestStart(Instant::now())).0;
+        let method = request.method().as_str();
+        let route = request.route().map(|_route| _route.uri.path().to_string()).unwrap_or(String::from(UNMATCHED_ROUTE));
+        let status = response.status().code.to_string();
+
+        let labels = [method, route.as_str(), status.as_str()];
+        metrics().http_requests.with_label_values(&labels).inc();
+        metrics().http_request_duration.with_label_values(&labels).observe(started_at.elapsed().as_secs_f64());
+    }
+}
+
+#[get("/metrics")]
+pub async fn read_metrics(store: &State<Store>, config: &State<AppConfig>) -> Result<(ContentType, Vec<u8>), Status> {
+    if !config.features.metrics {
+        return Err(Status::NotFound)
+    }
+
+    // Stale gauges are better than no request and MongoDB metrics, which is when they're needed most
+    if let Err(error) = metrics().update_gauges(store.backend.as_ref()).await {
+        error!(?error, "Couldn't update business gauges");
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(error) = encoder.encode(&metrics().registry.gather(), &mut buffer) {
+        error!(?error, "Couldn't encode metrics");
+        return Err(Status::InternalServerError)
+    }
+
+    let content_type = ContentType::parse_flexible(encoder.format_type()).unwrap_or(ContentType::Plain);
+    Ok((content_type, buffer))
+}
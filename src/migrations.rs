@@ -0,0 +1,206 @@
+use futures::stream::TryStreamExt;
+use mongodb::{Client, Database, IndexModel, options::ClientOptions, options::IndexOptions, options::UpdateOptions};
+use mongodb::bson;
+use mongodb::bson::Document;
+use mongodb::error::{Error, ErrorKind, WriteFailure};
+use rocket::serde::{Serialize, Deserialize};
+use std::time::Duration;
+
+use crate::config::AppConfig;
+use crate::metrics;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct AppliedMigration {
+    // The migration's version
+    _id: i32,
+    name: String,
+    applied_at: bson::DateTime,
+}
+
+// Never change a migration once it has shipped, add a new version instead.
+// Every step has to be safe to run twice, since the process can die before the version is recorded.
+const MIGRATIONS: [(i32, &str); 6] = [
+    (1, "create_user_indexes"),
+    (2, "create_task_event_and_tag_indexes"),
+    (3, "backfill_updated_at"),
+    (4, "create_operation_and_audit_indexes"),
+    (5, "create_attachment_and_webhook_indexes"),
+    (6, "backfill_attachment_usage"),
+];
+
+// Undo only looks back undo_window_seconds, so this just has to comfortably outlive any sensible window
+const OPERATION_EXPIRY_SECONDS: u64 = 7 * 24 * 60 * 60;
+
+fn index(keys: Document) -> IndexModel {
+    IndexModel::builder().keys(keys).build()
+}
+
+fn unique_index(keys: Document) -> IndexModel {
+    let index_options = IndexOptions::builder().unique(true).build();
+    IndexModel::builder().keys(keys).options(index_options).build()
+}
+
+// Only indexes documents that are in the trash, which is all the purger looks at
+fn deleted_at_index() -> IndexModel {
+    let index_options = IndexOptions::builder()
+        .partial_filter_expression(bson::doc! { "deleted_at": { "$type": "date" } })
+        .build();
+    IndexModel::builder().keys(bson::doc! { "deleted_at": 1 }).options(index_options).build()
+}
+
+async fn create_indexes(db: &Database, collection: &str, indexes: Vec<IndexModel>) -> Result<(), Error> {
+    // Creating an index that already exists with the same options does nothing
+    db.collection::<Document>(collection).create_indexes(indexes, None).await?;
+    Ok(())
+}
+
+async fn apply(db: &Database, version: i32) -> Result<(), Error> {
+    match version {
+        1 => {
+            // Users who never asked for a calendar feed have a null secret, which a plain unique index would reject
+            let calendar_secret_options = IndexOptions::builder()
+                .unique(true)
+                .partial_filter_expression(bson::doc! { "calendar_secret": { "$type": "string" } })
+                .build();
+            create_indexes(db, "users", vec! [
+                unique_index(bson::doc! { "token": 1 }),
+                unique_index(bson::doc! { "apple_user_id": 1 }),
+                IndexModel::builder().keys(bson::doc! { "calendar_secret": 1 }).options(calendar_secret_options).build(),
+            ]).await
+        },
+        2 => {
+            // moss is computed in the pipeline so it can't be indexed, but matching on user first keeps the sort small
+            create_indexes(db, "tasks", vec! [
+                index(bson::doc! { "user": 1, "updated_at": 1 }),
+                deleted_at_index(),
+            ]).await?;
+            create_indexes(db, "events", vec! [
+                index(bson::doc! { "task": 1, "date": -1 }),
+                index(bson::doc! { "user": 1, "date": -1, "_id": -1 }),
+                index(bson::doc! { "user": 1, "updated_at": 1 }),
+                deleted_at_index(),
+            ]).await?;
+            create_indexes(db, "tags", vec! [
+                index(bson::doc! { "user": 1, "parent_tag": 1 }),
+                index(bson::doc! { "user": 1, "updated_at": 1 }),
+                deleted_at_index(),
+            ]).await
+        },
+        3 => {
+            // Documents from before sync existed have no updated_at, so change tokens never pick them up
+            for collection in ["tasks", "events", "tags"] {
+                let missing_filter = bson::doc! {
+                    "updated_at": {
+                        "$exists": false,
+                    },
+                };
+                let backfill = bson::doc! {
+                    "$set": {
+                        "updated_at": bson::DateTime::now(),
+                    },
+                };
+                db.collection::<Document>(collection).update_many(missing_filter, backfill, None).await?;
+            }
+            Ok(())
+        },
+        4 => {
+            let expiry_options = IndexOptions::builder()
+                .expire_after(Duration::from_secs(OPERATION_EXPIRY_SECONDS))
+                .build();
+            create_indexes(db, "operations", vec! [
+                IndexModel::builder().keys(bson::doc! { "created_at": 1 }).options(expiry_options).build(),
+                index(bson::doc! { "user": 1, "created_at": -1, "_id": -1 }),
+            ]).await?;
+            create_indexes(db, "audit", vec! [
+                index(bson::doc! { "actor": 1, "created_at": -1 }),
+                index(bson::doc! { "action": 1, "created_at": -1 }),
+            ]).await
+        },
+        5 => {
+            create_indexes(db, "attachments", vec! [
+                index(bson::doc! { "user": 1 }),
+                index(bson::doc! { "task": 1 }),
+                index(bson::doc! { "event": 1 }),
+            ]).await?;
+            create_indexes(db, "webhooks", vec! [
+                index(bson::doc! { "user": 1, "events": 1 }),
+            ]).await?;
+            create_indexes(db, "webhook_deliveries", vec! [
+                index(bson::doc! { "status": 1, "next_attempt_at": 1 }),
+                index(bson::doc! { "webhook": 1, "created_at": -1 }),
+            ]).await
+        },
+        6 => {
+            // Uploads reserve space on a per-user counter now, which has to start from what's already stored
+            let usage_pipeline = vec![
+                bson::doc! {
+                    "$group": {
+                        "_id": "$user",
+                        "used": { "$sum": "$size" },
+                    }
+                },
+            ];
+            let mut usage_cursor = db.collection::<Document>("attachments").aggregate(usage_pipeline, None).await?;
+            let options = UpdateOptions::builder().upsert(true).build();
+            while let Some(usage) = usage_cursor.try_next().await? {
+                let usage_filter = bson::doc! { "_id": usage.get("_id") };
+                let backfill = bson::doc! {
+                    "$set": {
+                        "used": usage.get_i64("used").unwrap_or(0),
+                    },
+                };
+                db.collection::<Document>("attachment_usage").update_one(usage_filter, backfill, options.clone()).await?;
+            }
+            Ok(())
+        },
+        _ => unreachable!(),
+    }
+}
+
+pub async fn pending_migrations(db: &Database) -> Result<Vec<(i32, &'static str)>, Error> {
+    let mut applied_cursor = db.collection::<AppliedMigration>("migrations").find(None, None).await?;
+
+    let mut applied_versions = Vec::new();
+    while let Some(applied_migration) = applied_cursor.try_next().await? {
+        applied_versions.push(applied_migration._id);
+    }
+
+    Ok(MIGRATIONS.into_iter().filter(|(version, _)| !applied_versions.contains(version)).collect())
+}
+
+// Applies anything not yet recorded in the migrations collection and returns the names of what ran
+pub async fn run(db: &Database) -> Result<Vec<String>, Error> {
+    let migrations = db.collection::<AppliedMigration>("migrations");
+
+    let mut applied_names = Vec::new();
+    for (version, name) in pending_migrations(db).await? {
+        apply(db, version).await?;
+
+        let applied_migration = AppliedMigration {
+            _id: version,
+            name: name.to_string(),
+            applied_at: bson::DateTime::now(),
+        };
+        match migrations.insert_one(applied_migration, None).await {
+            Ok(_) => applied_names.push(name.to_string()),
+            // Another instance started at the same time and got there first
+            Err(error) => match *error.kind {
+                ErrorKind::Write(WriteFailure::WriteError(ref write_error)) if write_error.code == 11000 => (),
+                _ => return Err(error),
+            },
+        }
+    }
+
+    Ok(applied_names)
+}
+
+pub async fn run_with_config(config: &AppConfig) -> Result<Vec<String>, Error> {
+    let mut client_options = ClientOptions::parse(&config.database_uri).await?;
+    client_options.app_name = Some("mossy".to_string());
+    client_options.command_event_handler = Some(metrics::command_timer());
+    let client = Client::with_options(client_options)?;
+    let db = client.database(&config.database_name);
+
+    run(&db).await
+}
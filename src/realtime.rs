@@ -0,0 +1,158 @@
+use futures::stream::TryStreamExt;
+use mongodb::{Client, Database, options::ChangeStreamOptions, options::ClientOptions};
+use mongodb::bson;
+use mongodb::bson::Document;
+use mongodb::change_stream::event::{ChangeStreamEvent, OperationType};
+use mongodb::error::Error;
+use mongodb::options::{FullDocumentBeforeChangeType, FullDocumentType};
+use rocket::{Shutdown, State};
+use rocket::response::stream::{Event, EventStream};
+use rocket::serde::{Serialize, Deserialize};
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{error, warn};
+
+use crate::Token;
+use crate::config::{AppConfig, ChangeSource};
+use crate::metrics;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct ChangeNotification {
+    user: bson::oid::ObjectId,
+    // "task", "event" or "tag"
+    kind: String,
+    // "created", "updated" or "deleted"
+    action: String,
+    ids: Vec<bson::oid::ObjectId>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChangeBus {
+    sender: broadcast::Sender<ChangeNotification>,
+    source: ChangeSource,
+}
+
+impl ChangeBus {
+    pub fn new(config: &AppConfig) -> ChangeBus {
+        let (sender, _) = broadcast::channel(1024);
+        ChangeBus { sender, source: config.change_source }
+    }
+
+    pub fn publish(&self, user: bson::oid::ObjectId, kind: &str, action: &str, ids: Vec<bson::oid::ObjectId>) {
+        if self.source != ChangeSource::Actions || ids.is_empty() {
+            return
+        }
+        self.send(ChangeNotification {
+            user,
+            kind: kind.to_string(),
+            action: action.to_string(),
+            ids,
+        });
+    }
+
+    fn send(&self, notification: ChangeNotification) {
+        // Sending only fails when nobody is listening, which is fine
+        let _ = self.sender.send(notification);
+    }
+
+    pub fn uses_change_streams(&self) -> bool {
+        self.source == ChangeSource::Mongodb
+    }
+}
+
+fn notification_from_change(kind: &str, change: ChangeStreamEvent<Document>) -> Option<ChangeNotification> {
+    let action = match change.operation_type {
+        OperationType::Insert => "created",
+        OperationType::Update | OperationType::Replace => "updated",
+        OperationType::Delete => "deleted",
+        _ => return None,
+    };
+    // Deletes only carry the owner when the collection has changeStreamPreAndPostImages enabled
+    let document = change.full_document.or(change.full_document_before_change)?;
+    let user = document.get_object_id("user").ok()?;
+    let id = change.document_key?.get_object_id("_id").ok()?;
+    // Deletes through the API only set a tombstone, so they arrive as updates
+    let action = if document.get_datetime("deleted_at").is_ok() { "deleted" } else { action };
+
+    Some(ChangeNotification {
+        user,
+        kind: kind.to_string(),
+        action: action.to_string(),
+        ids: vec![id],
+    })
+}
+
+async fn watch_collection(db: Database, collection_name: &str, kind: &str, bus: ChangeBus) -> Result<(), Error> {
+    let collection = db.collection::<Document>(collection_name);
+    let options = ChangeStreamOptions::builder()
+        .full_document(Some(FullDocumentType::UpdateLookup))
+        .full_document_before_change(Some(FullDocumentBeforeChangeType::WhenAvailable))
+        .build();
+    let mut change_stream = collection.watch(None, options).await?;
+
+    while let Some(change) = change_stream.try_next().await? {
+        if let Some(notification) = notification_from_change(kind, change) {
+            bus.send(notification);
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn run_change_streams(bus: ChangeBus, config: AppConfig) {
+    let mut client_options = match ClientOptions::parse(&config.database_uri).await {
+        Ok(_client_options) => _client_options,
+        Err(error) => {
+            warn!(?error, "Change streams disabled");
+            return
+        }
+    };
+    client_options.app_name = Some("mossy".to_string());
+    client_options.command_event_handler = Some(metrics::command_timer());
+    let client = match Client::with_options(client_options) {
+        Ok(_client) => _client,
+        Err(error) => {
+            warn!(?error, "Change streams disabled");
+            return
+        }
+    };
+    let db = client.database(&config.database_name);
+
+    for (collection_name, kind) in [("tasks", "task"), ("events", "event"), ("tags", "tag")] {
+        let db = db.clone();
+        let bus = bus.clone();
+        rocket::tokio::spawn(async move {
+            if let Err(error) = watch_collection(db, collection_name, kind, bus).await {
+                error!(?error, collection = collection_name, "Change stream stopped");
+            }
+        });
+    }
+}
+
+#[get("/api/changes")]
+pub async fn stream_changes(token: Token, changes: &State<ChangeBus>, mut shutdown: Shutdown) -> EventStream![] {
+    let user = token.0;
+    let mut receiver = changes.sender.subscribe();
+
+    EventStream! {
+        loop {
+            let notification = select! {
+                message = receiver.recv() => match message {
+                    Ok(_notification) => _notification,
+                    Err(RecvError::Closed) => break,
+                    // We dropped notifications for this client, so it has to refetch everything
+                    Err(RecvError::Lagged(_)) => {
+                        yield Event::empty().event("resync");
+                        continue
+                    },
+                },
+                _ = &mut shutdown => break,
+            };
+            if notification.user != user._id {
+                continue
+            }
+            yield Event::json(&notification).event("change");
+        }
+    }
+}
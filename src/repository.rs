@@ -0,0 +1,1413 @@
+use futures::stream::TryStreamExt;
+use mongodb::{Client, Database, options::ClientOptions, options::FindOneAndUpdateOptions, options::FindOptions, options::ReturnDocument, options::UpdateOptions};
+use mongodb::bson;
+use mongodb::bson::{Bson, Document};
+use mongodb::error::{Error, ErrorKind, WriteFailure};
+use rocket::serde::{Serialize, DeserializeOwned};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::{Event, Tag, Task, TaskStats, User, moss_pipeline, webhooks};
+use crate::attachments::{Attachment, AttachmentUsageCounter};
+use crate::audit::AuditEntry;
+use crate::config::AppConfig;
+use crate::metrics;
+use crate::migrations;
+use crate::undo::Operation;
+use crate::webhooks::{Webhook, WebhookDelivery};
+
+// One document's share of an apply_writes call
+#[derive(Debug)]
+pub struct DocumentWrite {
+    pub collection: &'static str,
+    pub document_id: bson::oid::ObjectId,
+    // What the document had when it was read; the write is refused if it changed since
+    pub updated_at: Option<bson::DateTime>,
+    // Fields to $set
+    pub changes: Document,
+    // The document didn't exist when it was read, so changes is the whole document and the write is refused if it exists by now
+    pub create: bool,
+}
+
+impl DocumentWrite {
+    pub fn new(collection: &'static str, document_id: bson::oid::ObjectId, updated_at: Option<bson::DateTime>, changes: Document) -> DocumentWrite {
+        DocumentWrite { collection, document_id, updated_at, changes, create: false }
+    }
+
+    pub fn create(collection: &'static str, document_id: bson::oid::ObjectId, document: Document) -> DocumentWrite {
+        DocumentWrite { collection, document_id, updated_at: None, changes: document, create: true }
+    }
+}
+
+// One document's share of an undo
+#[derive(Debug, Clone)]
+pub struct DocumentRevert {
+    pub collection: String,
+    pub document_id: bson::oid::ObjectId,
+    // What the operation left it at; the undo is refused if anything wrote to it since
+    pub updated_at: Option<bson::DateTime>,
+    // Replaces the whole document
+    pub document: Document,
+}
+
+impl DocumentRevert {
+    pub fn new(collection: &str, document_id: bson::oid::ObjectId, updated_at: Option<bson::DateTime>, document: Document) -> DocumentRevert {
+        DocumentRevert { collection: collection.to_string(), document_id, updated_at, document }
+    }
+}
+
+// Storage for the core resources, so routes built on it can run against MongoDB or, in tests, memory.
+// Lookups by id return trashed documents too; callers check deleted_at themselves.
+// Task, event and tag updates only apply while the document still has the given updated_at, and return false otherwise.
+#[rocket::async_trait]
+pub trait Repository: Send + Sync {
+    async fn find_user_by_token(&self, token: &str) -> Result<Option<User>, Error>;
+    async fn find_user_by_apple_id(&self, apple_user_id: &str) -> Result<Option<User>, Error>;
+    async fn find_user_by_calendar_secret(&self, secret: &str) -> Result<Option<User>, Error>;
+    async fn insert_user(&self, user: &User) -> Result<(), Error>;
+    async fn update_user(&self, user_id: bson::oid::ObjectId, changes: Document) -> Result<(), Error>;
+
+    // Live tasks with latest_event_date and moss, most overdue first
+    async fn find_tasks_with_moss(&self, user_id: bson::oid::ObjectId, offset: u32, limit: u32) -> Result<Vec<Document>, Error>;
+    async fn find_task(&self, task_id: bson::oid::ObjectId) -> Result<Option<Task>, Error>;
+    async fn insert_task(&self, task: &Task) -> Result<(), Error>;
+    async fn insert_tasks(&self, tasks: &[Task]) -> Result<(), Error>;
+    async fn update_task(&self, task_id: bson::oid::ObjectId, updated_at: Option<bson::DateTime>, changes: Document) -> Result<bool, Error>;
+    // Whoever they belong to; callers check the user themselves
+    async fn find_tasks_by_ids(&self, task_ids: &[bson::oid::ObjectId]) -> Result<Vec<Task>, Error>;
+    // The user's tasks that are in the trash, or the live ones
+    async fn find_user_tasks(&self, user_id: bson::oid::ObjectId, trashed: bool) -> Result<Vec<Task>, Error>;
+    // Live tasks with at least one live event, the ones with the latest event first
+    async fn find_task_stats(&self, user_id: bson::oid::ObjectId, task_id: Option<bson::oid::ObjectId>) -> Result<Vec<TaskStats>, Error>;
+
+    // Live events, newest first
+    async fn find_events(&self, user_id: bson::oid::ObjectId, offset: u32, limit: u32) -> Result<Vec<Event>, Error>;
+    async fn find_event(&self, event_id: bson::oid::ObjectId) -> Result<Option<Event>, Error>;
+    async fn insert_event(&self, event: &Event) -> Result<(), Error>;
+    async fn update_event(&self, event_id: bson::oid::ObjectId, updated_at: Option<bson::DateTime>, changes: Document) -> Result<bool, Error>;
+    async fn insert_events(&self, events: &[Event]) -> Result<(), Error>;
+    async fn find_events_by_ids(&self, event_ids: &[bson::oid::ObjectId]) -> Result<Vec<Event>, Error>;
+    // Live and trashed events of the tasks
+    async fn find_task_events(&self, task_ids: &[bson::oid::ObjectId]) -> Result<Vec<Event>, Error>;
+    async fn find_user_events(&self, user_id: bson::oid::ObjectId, trashed: bool) -> Result<Vec<Event>, Error>;
+
+    // Live tags in the order the app lists them
+    async fn find_tags(&self, user_id: bson::oid::ObjectId, offset: u32, limit: u32) -> Result<Vec<Tag>, Error>;
+    async fn find_tag(&self, tag_id: bson::oid::ObjectId) -> Result<Option<Tag>, Error>;
+    async fn insert_tag(&self, tag: &Tag) -> Result<(), Error>;
+    async fn insert_tags(&self, tags: &[Tag]) -> Result<(), Error>;
+    async fn update_tag(&self, tag_id: bson::oid::ObjectId, updated_at: Option<bson::DateTime>, changes: Document) -> Result<bool, Error>;
+    async fn find_tags_by_ids(&self, tag_ids: &[bson::oid::ObjectId]) -> Result<Vec<Tag>, Error>;
+    async fn find_user_tags(&self, user_id: bson::oid::ObjectId, trashed: bool) -> Result<Vec<Tag>, Error>;
+
+    // Deletes, restores, merges, retags and sync pushes touch several documents that have to change together.
+    // Either every write applies or none does, and false means one of the documents changed, or a created one appeared, since it was read.
+    // Give each document at most one write.
+    async fn apply_writes(&self, writes: Vec<DocumentWrite>) -> Result<bool, Error>;
+
+    // Everything of the user's written since then, tombstones included, or without since every live document
+    async fn find_changed_documents(&self, collection: &str, user_id: bson::oid::ObjectId, since: Option<bson::DateTime>) -> Result<Vec<Document>, Error>;
+
+    // The user's operations since then that haven't been undone, newest first
+    async fn find_undoable_operations(&self, user_id: bson::oid::ObjectId, since: bson::DateTime, limit: u32) -> Result<Vec<Operation>, Error>;
+    // Puts every document back and marks the operation undone, or does nothing and returns false
+    // when one of the documents changed or the operation was undone in the meantime
+    async fn revert_operation(&self, operation_id: bson::oid::ObjectId, reverts: Vec<DocumentRevert>, undone_at: bson::DateTime) -> Result<bool, Error>;
+
+    // Raw documents for the operations and audit logs
+    async fn find_document(&self, collection: &str, document_id: bson::oid::ObjectId) -> Result<Option<Document>, Error>;
+    async fn find_documents(&self, collection: &str, document_ids: &[bson::oid::ObjectId]) -> Result<Vec<Document>, Error>;
+    async fn insert_document(&self, collection: &str, document: Document) -> Result<(), Error>;
+    // Newest first; None matches every actor or action
+    async fn find_audit_entries(&self, actor: Option<bson::oid::ObjectId>, action: Option<&str>, offset: u32, limit: u32) -> Result<Vec<AuditEntry>, Error>;
+
+    // Webhook subscriptions, newest first, and their delivery log
+    async fn find_user_webhooks(&self, user_id: bson::oid::ObjectId) -> Result<Vec<Webhook>, Error>;
+    async fn find_webhooks_by_ids(&self, webhook_ids: &[bson::oid::ObjectId]) -> Result<Vec<Webhook>, Error>;
+    async fn insert_webhook(&self, webhook: &Webhook) -> Result<(), Error>;
+    // Their pending deliveries could never succeed, so those are marked failed but kept in the log
+    async fn delete_webhooks(&self, webhook_ids: &[bson::oid::ObjectId]) -> Result<u64, Error>;
+    async fn find_webhook_deliveries(&self, user_id: bson::oid::ObjectId, webhook_id: Option<bson::oid::ObjectId>, offset: u32, limit: u32) -> Result<Vec<WebhookDelivery>, Error>;
+    async fn enqueue_webhook(&self, user_id: bson::oid::ObjectId, event: &str, data: Document) -> Result<(), Error>;
+
+    // Attachments and the per-user byte counter their quota is kept in
+    async fn find_attachments(&self, user_id: bson::oid::ObjectId, task_id: Option<bson::oid::ObjectId>, event_id: Option<bson::oid::ObjectId>) -> Result<Vec<Attachment>, Error>;
+    async fn find_attachments_by_ids(&self, attachment_ids: &[bson::oid::ObjectId]) -> Result<Vec<Attachment>, Error>;
+    // Attached to any of the tasks or events
+    async fn find_owned_attachments(&self, task_ids: &[bson::oid::ObjectId], event_ids: &[bson::oid::ObjectId]) -> Result<Vec<Attachment>, Error>;
+    async fn insert_attachment(&self, attachment: &Attachment) -> Result<(), Error>;
+    // False when someone else deleted it first
+    async fn delete_attachment(&self, attachment_id: bson::oid::ObjectId) -> Result<bool, Error>;
+    // Only links to a live task or event of the user. Both return the owner as the write left it, or None when there was none.
+    async fn link_attachment(&self, collection: &str, owner_id: bson::oid::ObjectId, user_id: bson::oid::ObjectId, attachment_id: bson::oid::ObjectId) -> Result<Option<Document>, Error>;
+    async fn unlink_attachment(&self, collection: &str, owner_id: bson::oid::ObjectId, attachment_id: bson::oid::ObjectId) -> Result<Option<Document>, Error>;
+    async fn attachment_bytes_used(&self, user_id: bson::oid::ObjectId) -> Result<i64, Error>;
+    // Only counts them while they still fit, so two uploads at once can't both squeeze under the quota
+    async fn reserve_attachment_bytes(&self, user_id: bson::oid::ObjectId, size: i64, quota: i64) -> Result<bool, Error>;
+    async fn release_attachment_bytes(&self, user_id: bson::oid::ObjectId, size: i64) -> Result<(), Error>;
+
+    // For the debug routes: removes all of the user's tasks, events or tags for good and says how many there were
+    async fn delete_user_documents(&self, collection: &str, user_id: bson::oid::ObjectId) -> Result<u64, Error>;
+
+    // For the business gauges on /metrics
+    async fn count_users(&self) -> Result<u64, Error>;
+    // Live events created since then, going by the time in their ObjectId since events have no created_at
+    async fn count_events_created_since(&self, since: bson::DateTime) -> Result<u64, Error>;
+
+    // For /readyz
+    async fn ping(&self) -> Result<(), Error>;
+    async fn pending_migrations(&self) -> Result<Vec<(i32, &'static str)>, Error>;
+}
+
+// The smallest ObjectId that could have been generated at that time
+fn first_object_id_at(time: bson::DateTime) -> bson::oid::ObjectId {
+    let mut bytes = [0; 12];
+    bytes[..4].copy_from_slice(&((time.timestamp_millis() / 1000) as u32).to_be_bytes());
+    bson::oid::ObjectId::from_bytes(bytes)
+}
+
+async fn find_one_by_id<T>(db: &Database, collection: &str, document_id: bson::oid::ObjectId) -> Result<Option<T>, Error>
+where
+    T: DeserializeOwned + Unpin + Send + Sync,
+{
+    db.collection::<T>(collection).find_one(bson::doc! { "_id": document_id }, None).await
+}
+
+async fn find_all<T>(db: &Database, collection: &str, filter: Document) -> Result<Vec<T>, Error>
+where
+    T: DeserializeOwned + Unpin + Send + Sync,
+{
+    let mut cursor = db.collection::<T>(collection).find(filter, None).await?;
+
+    let mut documents = Vec::new();
+    while let Some(document) = cursor.try_next().await? {
+        documents.push(document);
+    }
+
+    Ok(documents)
+}
+
+async fn insert_all<T>(db: &Database, collection: &str, documents: &[T]) -> Result<(), Error>
+where
+    T: Serialize + Send + Sync,
+{
+    // insert_many refuses an empty list
+    if documents.is_empty() {
+        return Ok(())
+    }
+    db.collection::<T>(collection).insert_many(documents, None).await?;
+    Ok(())
+}
+
+fn by_ids_filter(document_ids: &[bson::oid::ObjectId]) -> Document {
+    bson::doc! {
+        "_id": {
+            "$in": document_ids,
+        },
+    }
+}
+
+fn user_filter(user_id: bson::oid::ObjectId, trashed: bool) -> Document {
+    let deleted_at = if trashed { bson::doc! { "$ne": null } } else { bson::doc! { "$eq": null } };
+    bson::doc! {
+        "user": user_id,
+        "deleted_at": deleted_at,
+    }
+}
+
+async fn update_by_id(db: &Database, collection: &str, document_id: bson::oid::ObjectId, changes: Document) -> Result<(), Error> {
+    let update = bson::doc! {
+        "$set": changes,
+    };
+    db.collection::<Document>(collection).update_one(bson::doc! { "_id": document_id }, update, None).await?;
+    Ok(())
+}
+
+async fn update_if_unchanged(db: &Database, collection: &str, document_id: bson::oid::ObjectId, updated_at: Option<bson::DateTime>, changes: Document) -> Result<bool, Error> {
+    let document_filter = bson::doc! {
+        "_id": document_id,
+        "updated_at": updated_at,
+    };
+    let update = bson::doc! {
+        "$set": changes,
+    };
+    let update_result = db.collection::<Document>(collection).update_one(document_filter, update, None).await?;
+    Ok(update_result.matched_count == 1)
+}
+
+// Holds on to the client as well, since apply_writes needs a session
+#[derive(Clone)]
+pub struct MongoRepository {
+    client: Client,
+    db: Database,
+}
+
+impl MongoRepository {
+    pub fn new(client: &Client, database_name: &str) -> MongoRepository {
+        MongoRepository {
+            client: client.clone(),
+            db: client.database(database_name),
+        }
+    }
+
+    // For the background workers, which still query their collections directly
+    pub fn database(&self) -> &Database {
+        &self.db
+    }
+}
+
+#[rocket::async_trait]
+impl Repository for MongoRepository {
+    async fn find_user_by_token(&self, token: &str) -> Result<Option<User>, Error> {
+        let user_filter = bson::doc! {
+            "token": token,
+        };
+        self.db.collection::<User>("users").find_one(user_filter, None).await
+    }
+
+    async fn find_user_by_apple_id(&self, apple_user_id: &str) -> Result<Option<User>, Error> {
+        let user_filter = bson::doc! {
+            "apple_user_id": apple_user_id,
+        };
+        self.db.collection::<User>("users").find_one(user_filter, None).await
+    }
+
+    async fn find_user_by_calendar_secret(&self, secret: &str) -> Result<Option<User>, Error> {
+        let user_filter = bson::doc! {
+            "calendar_secret": secret,
+        };
+        self.db.collection::<User>("users").find_one(user_filter, None).await
+    }
+
+    async fn insert_user(&self, user: &User) -> Result<(), Error> {
+        self.db.collection::<User>("users").insert_one(user, None).await?;
+        Ok(())
+    }
+
+    async fn update_user(&self, user_id: bson::oid::ObjectId, changes: Document) -> Result<(), Error> {
+        update_by_id(&self.db, "users", user_id, changes).await
+    }
+
+    async fn find_tasks_with_moss(&self, user_id: bson::oid::ObjectId, offset: u32, limit: u32) -> Result<Vec<Document>, Error> {
+        let mut tasks_filter = moss_pipeline(user_id);
+        tasks_filter.extend(vec! [
+            bson::doc! {
+                // We also need to sort by a unique value (_id) to ensure we don't get duplicates in pagination
+                // moss is computed above so this can't use an index, see the tasks indexes in migrations.rs
+                "$sort": {
+                    "moss": -1,
+                    "_id": -1,
+                }
+            },
+            bson::doc! {
+                "$skip": offset
+            },
+        ]);
+        // $limit has to be positive, so leave it out to mean "everything" like find() does with 0
+        if limit > 0 {
+            tasks_filter.push(bson::doc! {
+                "$limit": limit
+            });
+        }
+        let mut tasks_cursor = self.db.collection::<Task>("tasks").aggregate(tasks_filter, None).await?;
+
+        let mut tasks_list = Vec::new();
+        while let Some(task) = tasks_cursor.try_next().await? {
+            tasks_list.push(task);
+        }
+
+        Ok(tasks_list)
+    }
+
+    async fn find_task(&self, task_id: bson::oid::ObjectId) -> Result<Option<Task>, Error> {
+        find_one_by_id(&self.db, "tasks", task_id).await
+    }
+
+    async fn insert_task(&self, task: &Task) -> Result<(), Error> {
+        self.db.collection::<Task>("tasks").insert_one(task, None).await?;
+        Ok(())
+    }
+
+    async fn insert_tasks(&self, tasks: &[Task]) -> Result<(), Error> {
+        insert_all(&self.db, "tasks", tasks).await
+    }
+
+    async fn update_task(&self, task_id: bson::oid::ObjectId, updated_at: Option<bson::DateTime>, changes: Document) -> Result<bool, Error> {
+        update_if_unchanged(&self.db, "tasks", task_id, updated_at, changes).await
+    }
+
+    async fn find_tasks_by_ids(&self, task_ids: &[bson::oid::ObjectId]) -> Result<Vec<Task>, Error> {
+        find_all(&self.db, "tasks", by_ids_filter(task_ids)).await
+    }
+
+    async fn find_user_tasks(&self, user_id: bson::oid::ObjectId, trashed: bool) -> Result<Vec<Task>, Error> {
+        find_all(&self.db, "tasks", user_filter(user_id, trashed)).await
+    }
+
+    async fn find_task_stats(&self, user_id: bson::oid::ObjectId, task_id: Option<bson::oid::ObjectId>) -> Result<Vec<TaskStats>, Error> {
+        let mut tasks_filter = user_filter(user_id, false);
+        if let Some(_task_id) = task_id {
+            tasks_filter.insert("_id", _task_id);
+        }
+        let tasks: Vec<Task> = find_all(&self.db, "tasks", tasks_filter).await?;
+        let task_ids: Vec<bson::oid::ObjectId> = tasks.iter().map(|task| task._id).collect();
+
+        let stats_pipeline = vec![
+            bson::doc! {
+                "$match": {
+                    "task": {
+                        "$in": task_ids,
+                    },
+                    "deleted_at": null,
+                }
+            },
+            bson::doc! {
+                "$group": {
+                    "_id": "$task",
+                    "event_count": { "$sum": 1_i64 },
+                    "first_event_date": { "$min": "$date" },
+                    "latest_event_date": { "$max": "$date" },
+                    "total_duration": { "$sum": { "$ifNull": ["$duration", 0_i64] } },
+                    "average_duration": { "$avg": "$duration" },
+                    "total_quantity": { "$sum": { "$ifNull": ["$quantity", 0.0] } },
+                    "average_quantity": { "$avg": "$quantity" },
+                    "average_rating": { "$avg": "$rating" },
+                }
+            },
+            bson::doc! {
+                "$addFields": {
+                    "task": "$_id",
+                }
+            },
+            bson::doc! {
+                "$sort": {
+                    "latest_event_date": -1,
+                }
+            },
+        ];
+        let mut stats_cursor = self.db.collection::<Event>("events").aggregate(stats_pipeline, None).await?;
+
+        let mut stats_list = Vec::new();
+        while let Some(stats_document) = stats_cursor.try_next().await? {
+            stats_list.push(bson::from_document::<TaskStats>(stats_document)?);
+        }
+
+        Ok(stats_list)
+    }
+
+    async fn find_events(&self, user_id: bson::oid::ObjectId, offset: u32, limit: u32) -> Result<Vec<Event>, Error> {
+        let events_filter = bson::doc! {
+            "user": user_id,
+            "deleted_at": null,
+        };
+        let sort_option = bson::doc! {
+            "date": -1,
+            "_id": -1,
+        };
+        let options = FindOptions::builder().sort(sort_option).skip(Some(u64::from(offset))).limit(Some(i64::from(limit))).build();
+        let mut cursor = self.db.collection::<Event>("events").find(events_filter, options).await?;
+
+        let mut events_list = Vec::new();
+        while let Some(event) = cursor.try_next().await? {
+            events_list.push(event);
+        }
+
+        Ok(events_list)
+    }
+
+    async fn find_event(&self, event_id: bson::oid::ObjectId) -> Result<Option<Event>, Error> {
+        find_one_by_id(&self.db, "events", event_id).await
+    }
+
+    async fn insert_event(&self, event: &Event) -> Result<(), Error> {
+        self.db.collection::<Event>("events").insert_one(event, None).await?;
+        Ok(())
+    }
+
+    async fn update_event(&self, event_id: bson::oid::ObjectId, updated_at: Option<bson::DateTime>, changes: Document) -> Result<bool, Error> {
+        update_if_unchanged(&self.db, "events", event_id, updated_at, changes).await
+    }
+
+    async fn insert_events(&self, events: &[Event]) -> Result<(), Error> {
+        insert_all(&self.db, "events", events).await
+    }
+
+    async fn find_events_by_ids(&self, event_ids: &[bson::oid::ObjectId]) -> Result<Vec<Event>, Error> {
+        find_all(&self.db, "events", by_ids_filter(event_ids)).await
+    }
+
+    async fn find_task_events(&self, task_ids: &[bson::oid::ObjectId]) -> Result<Vec<Event>, Error> {
+        let events_filter = bson::doc! {
+            "task": {
+                "$in": task_ids,
+            },
+        };
+        find_all(&self.db, "events", events_filter).await
+    }
+
+    async fn find_user_events(&self, user_id: bson::oid::ObjectId, trashed: bool) -> Result<Vec<Event>, Error> {
+        find_all(&self.db, "events", user_filter(user_id, trashed)).await
+    }
+
+    async fn find_tags(&self, user_id: bson::oid::ObjectId, offset: u32, limit: u32) -> Result<Vec<Tag>, Error> {
+        let tags_filter = bson::doc! {
+            "user": user_id,
+            "deleted_at": null,
+        };
+        let sort_option = bson::doc! {
+            "sort_order": 1,
+            "name": 1,
+            "_id": -1,
+        };
+        let options = FindOptions::builder().sort(sort_option).skip(Some(u64::from(offset))).limit(Some(i64::from(limit))).build();
+        let mut cursor = self.db.collection::<Tag>("tags").find(tags_filter, options).await?;
+
+        let mut tags_list = Vec::new();
+        while let Some(tag) = cursor.try_next().await? {
+            tags_list.push(tag);
+        }
+
+        Ok(tags_list)
+    }
+
+    async fn find_tag(&self, tag_id: bson::oid::ObjectId) -> Result<Option<Tag>, Error> {
+        find_one_by_id(&self.db, "tags", tag_id).await
+    }
+
+    async fn insert_tag(&self, tag: &Tag) -> Result<(), Error> {
+        self.db.collection::<Tag>("tags").insert_one(tag, None).await?;
+        Ok(())
+    }
+
+    async fn insert_tags(&self, tags: &[Tag]) -> Result<(), Error> {
+        insert_all(&self.db, "tags", tags).await
+    }
+
+    async fn update_tag(&self, tag_id: bson::oid::ObjectId, updated_at: Option<bson::DateTime>, changes: Document) -> Result<bool, Error> {
+        update_if_unchanged(&self.db, "tags", tag_id, updated_at, changes).await
+    }
+
+    async fn find_tags_by_ids(&self, tag_ids: &[bson::oid::ObjectId]) -> Result<Vec<Tag>, Error> {
+        find_all(&self.db, "tags", by_ids_filter(tag_ids)).await
+    }
+
+    async fn find_user_tags(&self, user_id: bson::oid::ObjectId, trashed: bool) -> Result<Vec<Tag>, Error> {
+        find_all(&self.db, "tags", user_filter(user_id, trashed)).await
+    }
+
+    async fn apply_writes(&self, writes: Vec<DocumentWrite>) -> Result<bool, Error> {
+        let mut session = self.client.start_session(None).await?;
+        session.start_transaction(None).await?;
+
+        for write in writes {
+            let collection = self.db.collection::<Document>(write.collection);
+            let applied = if write.create {
+                // Only inserts when nothing has the id yet
+                let insert = bson::doc! {
+                    "$setOnInsert": write.changes,
+                };
+                let options = UpdateOptions::builder().upsert(true).build();
+                let update_result = collection.update_one_with_session(bson::doc! { "_id": write.document_id }, insert, options, &mut session).await?;
+                update_result.upserted_id.is_some()
+            } else {
+                let document_filter = bson::doc! {
+                    "_id": write.document_id,
+                    "updated_at": write.updated_at,
+                };
+                let update = bson::doc! {
+                    "$set": write.changes,
+                };
+                let update_result = collection.update_one_with_session(document_filter, update, None, &mut session).await?;
+                update_result.matched_count == 1
+            };
+            // Aborting throws away everything written so far
+            if !applied {
+                session.abort_transaction().await?;
+                return Ok(false)
+            }
+        }
+        session.commit_transaction().await?;
+
+        Ok(true)
+    }
+
+    async fn find_changed_documents(&self, collection: &str, user_id: bson::oid::ObjectId, since: Option<bson::DateTime>) -> Result<Vec<Document>, Error> {
+        let documents_filter = match since {
+            Some(_since) => bson::doc! {
+                "user": user_id,
+                "updated_at": {
+                    "$gte": _since,
+                },
+            },
+            None => bson::doc! {
+                "user": user_id,
+                "deleted_at": null,
+            },
+        };
+        find_all(&self.db, collection, documents_filter).await
+    }
+
+    async fn find_undoable_operations(&self, user_id: bson::oid::ObjectId, since: bson::DateTime, limit: u32) -> Result<Vec<Operation>, Error> {
+        let operations_filter = bson::doc! {
+            "user": user_id,
+            "undone_at": null,
+            "created_at": {
+                "$gte": since,
+            },
+        };
+        let sort_option = bson::doc! {
+            "created_at": -1,
+            "_id": -1,
+        };
+        let options = FindOptions::builder().sort(sort_option).limit(Some(i64::from(limit))).build();
+        self.db.collection::<Operation>("operations").find(operations_filter, options).await?.try_collect().await
+    }
+
+    async fn revert_operation(&self, operation_id: bson::oid::ObjectId, reverts: Vec<DocumentRevert>, undone_at: bson::DateTime) -> Result<bool, Error> {
+        let mut session = self.client.start_session(None).await?;
+        session.start_transaction(None).await?;
+
+        for revert in reverts {
+            let document_filter = bson::doc! {
+                "_id": revert.document_id,
+                "updated_at": revert.updated_at,
+            };
+            let replace_result = self.db.collection::<Document>(&revert.collection).replace_one_with_session(document_filter, revert.document, None, &mut session).await?;
+            if replace_result.matched_count == 0 {
+                session.abort_transaction().await?;
+                return Ok(false)
+            }
+        }
+
+        let operation_filter = bson::doc! {
+            "_id": operation_id,
+            "undone_at": null,
+        };
+        let undone_operation = bson::doc! {
+            "$set": {
+                "undone_at": undone_at,
+            }
+        };
+        let update_result = self.db.collection::<Operation>("operations").update_one_with_session(operation_filter, undone_operation, None, &mut session).await?;
+        if update_result.matched_count == 0 {
+            session.abort_transaction().await?;
+            return Ok(false)
+        }
+        session.commit_transaction().await?;
+
+        Ok(true)
+    }
+
+    async fn find_document(&self, collection: &str, document_id: bson::oid::ObjectId) -> Result<Option<Document>, Error> {
+        find_one_by_id(&self.db, collection, document_id).await
+    }
+
+    async fn find_documents(&self, collection: &str, document_ids: &[bson::oid::ObjectId]) -> Result<Vec<Document>, Error> {
+        find_all(&self.db, collection, by_ids_filter(document_ids)).await
+    }
+
+    async fn insert_document(&self, collection: &str, document: Document) -> Result<(), Error> {
+        self.db.collection::<Document>(collection).insert_one(document, None).await?;
+        Ok(())
+    }
+
+    async fn find_audit_entries(&self, actor: Option<bson::oid::ObjectId>, action: Option<&str>, offset: u32, limit: u32) -> Result<Vec<AuditEntry>, Error> {
+        let mut audit_filter = Document::new();
+        if let Some(actor_id) = actor {
+            audit_filter.insert("actor", actor_id);
+        }
+        if let Some(action_name) = action {
+            audit_filter.insert("action", action_name);
+        }
+        let sort_option = bson::doc! {
+            "created_at": -1,
+            "_id": -1,
+        };
+        let options = FindOptions::builder().sort(sort_option).skip(Some(u64::from(offset))).limit(Some(i64::from(limit))).build();
+        self.db.collection::<AuditEntry>("audit").find(audit_filter, options).await?.try_collect().await
+    }
+
+    async fn find_user_webhooks(&self, user_id: bson::oid::ObjectId) -> Result<Vec<Webhook>, Error> {
+        let options = FindOptions::builder().sort(bson::doc! { "created_at": -1 }).build();
+        self.db.collection::<Webhook>("webhooks").find(bson::doc! { "user": user_id }, options).await?.try_collect().await
+    }
+
+    async fn find_webhooks_by_ids(&self, webhook_ids: &[bson::oid::ObjectId]) -> Result<Vec<Webhook>, Error> {
+        find_all(&self.db, "webhooks", by_ids_filter(webhook_ids)).await
+    }
+
+    async fn insert_webhook(&self, webhook: &Webhook) -> Result<(), Error> {
+        self.db.collection::<Webhook>("webhooks").insert_one(webhook, None).await?;
+        Ok(())
+    }
+
+    async fn delete_webhooks(&self, webhook_ids: &[bson::oid::ObjectId]) -> Result<u64, Error> {
+        let delete_result = self.db.collection::<Webhook>("webhooks").delete_many(by_ids_filter(webhook_ids), None).await?;
+
+        let pending_filter = bson::doc! {
+            "webhook": { "$in": webhook_ids },
+            "status": "pending",
+        };
+        let pending_update = bson::doc! {
+            "$set": {
+                "status": "failed",
+                "last_error": webhooks::DELETED_WEBHOOK_ERROR,
+            }
+        };
+        self.db.collection::<WebhookDelivery>("webhook_deliveries").update_many(pending_filter, pending_update, None).await?;
+
+        Ok(delete_result.deleted_count)
+    }
+
+    async fn find_webhook_deliveries(&self, user_id: bson::oid::ObjectId, webhook_id: Option<bson::oid::ObjectId>, offset: u32, limit: u32) -> Result<Vec<WebhookDelivery>, Error> {
+        let mut deliveries_filter = bson::doc! {
+            "user": user_id,
+        };
+        if let Some(_webhook_id) = webhook_id {
+            deliveries_filter.insert("webhook", _webhook_id);
+        }
+        let sort_option = bson::doc! {
+            "created_at": -1,
+            "_id": -1,
+        };
+        let options = FindOptions::builder().sort(sort_option).skip(Some(u64::from(offset))).limit(Some(i64::from(limit))).build();
+        self.db.collection::<WebhookDelivery>("webhook_deliveries").find(deliveries_filter, options).await?.try_collect().await
+    }
+
+    async fn enqueue_webhook(&self, user_id: bson::oid::ObjectId, event: &str, data: Document) -> Result<(), Error> {
+        webhooks::enqueue(&self.db, user_id, event, data).await
+    }
+
+    async fn find_attachments(&self, user_id: bson::oid::ObjectId, task_id: Option<bson::oid::ObjectId>, event_id: Option<bson::oid::ObjectId>) -> Result<Vec<Attachment>, Error> {
+        let mut attachments_filter = bson::doc! {
+            "user": user_id,
+        };
+        if let Some(_task_id) = task_id {
+            attachments_filter.insert("task", _task_id);
+        }
+        if let Some(_event_id) = event_id {
+            attachments_filter.insert("event", _event_id);
+        }
+        let options = FindOptions::builder().sort(bson::doc! { "created_at": -1 }).build();
+        self.db.collection::<Attachment>("attachments").find(attachments_filter, options).await?.try_collect().await
+    }
+
+    async fn find_attachments_by_ids(&self, attachment_ids: &[bson::oid::ObjectId]) -> Result<Vec<Attachment>, Error> {
+        find_all(&self.db, "attachments", by_ids_filter(attachment_ids)).await
+    }
+
+    async fn find_owned_attachments(&self, task_ids: &[bson::oid::ObjectId], event_ids: &[bson::oid::ObjectId]) -> Result<Vec<Attachment>, Error> {
+        let attachments_filter = bson::doc! {
+            "$or": [
+                { "task": { "$in": task_ids } },
+                { "event": { "$in": event_ids } },
+            ],
+        };
+        find_all(&self.db, "attachments", attachments_filter).await
+    }
+
+    async fn insert_attachment(&self, attachment: &Attachment) -> Result<(), Error> {
+        self.db.collection::<Attachment>("attachments").insert_one(attachment, None).await?;
+        Ok(())
+    }
+
+    async fn delete_attachment(&self, attachment_id: bson::oid::ObjectId) -> Result<bool, Error> {
+        let delete_result = self.db.collection::<Attachment>("attachments").delete_one(bson::doc! { "_id": attachment_id }, None).await?;
+        Ok(delete_result.deleted_count == 1)
+    }
+
+    async fn link_attachment(&self, collection: &str, owner_id: bson::oid::ObjectId, user_id: bson::oid::ObjectId, attachment_id: bson::oid::ObjectId) -> Result<Option<Document>, Error> {
+        let owner_filter = bson::doc! {
+            "_id": owner_id,
+            "user": user_id,
+            "deleted_at": null,
+        };
+        let linked_owner = bson::doc! {
+            "$push": {
+                "attachments": attachment_id,
+            },
+            "$set": {
+                "updated_at": bson::DateTime::now(),
+            }
+        };
+        let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
+        self.db.collection::<Document>(collection).find_one_and_update(owner_filter, linked_owner, options).await
+    }
+
+    async fn unlink_attachment(&self, collection: &str, owner_id: bson::oid::ObjectId, attachment_id: bson::oid::ObjectId) -> Result<Option<Document>, Error> {
+        let unlinked_owner = bson::doc! {
+            "$pull": {
+                "attachments": attachment_id,
+            },
+            "$set": {
+                "updated_at": bson::DateTime::now(),
+            }
+        };
+        let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
+        self.db.collection::<Document>(collection).find_one_and_update(bson::doc! { "_id": owner_id }, unlinked_owner, options).await
+    }
+
+    async fn attachment_bytes_used(&self, user_id: bson::oid::ObjectId) -> Result<i64, Error> {
+        let usage_option = self.db.collection::<AttachmentUsageCounter>("attachment_usage").find_one(bson::doc! { "_id": user_id }, None).await?;
+        Ok(usage_option.map_or(0, |counter| counter.used))
+    }
+
+    async fn reserve_attachment_bytes(&self, user_id: bson::oid::ObjectId, size: i64, quota: i64) -> Result<bool, Error> {
+        if size > quota {
+            return Ok(false)
+        }
+        let usage_filter = bson::doc! {
+            "_id": user_id,
+            "used": {
+                "$lte": quota - size,
+            },
+        };
+        let reserved_usage = bson::doc! {
+            "$inc": {
+                "used": size,
+            },
+        };
+        let options = UpdateOptions::builder().upsert(true).build();
+        match self.db.collection::<AttachmentUsageCounter>("attachment_usage").update_one(usage_filter, reserved_usage, options).await {
+            Ok(_) => Ok(true),
+            // The counter exists but is too full to match, so the upsert tried to insert a second one
+            Err(error) => match *error.kind {
+                ErrorKind::Write(WriteFailure::WriteError(ref write_error)) if write_error.code == 11000 => Ok(false),
+                _ => Err(error),
+            },
+        }
+    }
+
+    async fn release_attachment_bytes(&self, user_id: bson::oid::ObjectId, size: i64) -> Result<(), Error> {
+        let released_usage = bson::doc! {
+            "$inc": {
+                "used": -size,
+            },
+        };
+        self.db.collection::<AttachmentUsageCounter>("attachment_usage").update_one(bson::doc! { "_id": user_id }, released_usage, None).await?;
+        Ok(())
+    }
+
+    async fn delete_user_documents(&self, collection: &str, user_id: bson::oid::ObjectId) -> Result<u64, Error> {
+        let delete_result = self.db.collection::<Document>(collection).delete_many(bson::doc! { "user": user_id }, None).await?;
+        Ok(delete_result.deleted_count)
+    }
+
+    async fn count_users(&self) -> Result<u64, Error> {
+        self.db.collection::<Document>("users").count_documents(None, None).await
+    }
+
+    async fn count_events_created_since(&self, since: bson::DateTime) -> Result<u64, Error> {
+        let events_filter = bson::doc! {
+            "_id": {
+                "$gte": first_object_id_at(since),
+            },
+            "deleted_at": null,
+        };
+        self.db.collection::<Document>("events").count_documents(events_filter, None).await
+    }
+
+    async fn ping(&self) -> Result<(), Error> {
+        self.db.run_command(bson::doc! { "ping": 1 }, None).await?;
+        Ok(())
+    }
+
+    async fn pending_migrations(&self) -> Result<Vec<(i32, &'static str)>, Error> {
+        migrations::pending_migrations(&self.db).await
+    }
+}
+
+// Keeps every collection as plain documents, the way MongoDB would store them
+#[derive(Default)]
+pub struct MemoryRepository {
+    collections: Mutex<HashMap<String, Vec<Document>>>,
+}
+
+fn page<T>(documents: Vec<T>, offset: u32, limit: u32) -> Vec<T> {
+    let documents = documents.into_iter().skip(offset as usize);
+    if limit == 0 {
+        documents.collect()
+    } else {
+        documents.take(limit as usize).collect()
+    }
+}
+
+impl MemoryRepository {
+    pub fn new() -> MemoryRepository {
+        MemoryRepository::default()
+    }
+
+    fn all<T>(&self, collection: &str) -> Result<Vec<T>, Error>
+    where
+        T: DeserializeOwned,
+    {
+        let collections = self.collections.lock().unwrap();
+        let mut documents = Vec::new();
+        for document in collections.get(collection).into_iter().flatten() {
+            documents.push(bson::from_document(document.clone())?);
+        }
+        Ok(documents)
+    }
+
+    fn find_by_id<T>(&self, collection: &str, document_id: bson::oid::ObjectId) -> Result<Option<T>, Error>
+    where
+        T: DeserializeOwned,
+    {
+        let collections = self.collections.lock().unwrap();
+        let document = collections.get(collection).into_iter().flatten().find(|document| document.get_object_id("_id") == Ok(document_id));
+        match document {
+            Some(_document) => Ok(Some(bson::from_document(_document.clone())?)),
+            None => Ok(None),
+        }
+    }
+
+    fn insert<T>(&self, collection: &str, value: &T) -> Result<(), Error>
+    where
+        T: Serialize,
+    {
+        let document = bson::to_document(value)?;
+        let mut collections = self.collections.lock().unwrap();
+        collections.entry(collection.to_string()).or_default().push(document);
+        Ok(())
+    }
+
+    fn update(&self, collection: &str, document_id: bson::oid::ObjectId, changes: Document) -> Result<(), Error> {
+        let mut collections = self.collections.lock().unwrap();
+        let documents = collections.entry(collection.to_string()).or_default();
+        if let Some(document) = documents.iter_mut().find(|document| document.get_object_id("_id") == Ok(document_id)) {
+            for (key, value) in changes {
+                document.insert(key, value);
+            }
+        }
+        Ok(())
+    }
+
+    fn find_by_ids<T>(&self, collection: &str, document_ids: &[bson::oid::ObjectId]) -> Result<Vec<T>, Error>
+    where
+        T: DeserializeOwned,
+    {
+        let collections = self.collections.lock().unwrap();
+        let mut documents = Vec::new();
+        for document in collections.get(collection).into_iter().flatten() {
+            if document.get_object_id("_id").is_ok_and(|document_id| document_ids.contains(&document_id)) {
+                documents.push(bson::from_document(document.clone())?);
+            }
+        }
+        Ok(documents)
+    }
+
+    fn update_if_unchanged(&self, collection: &str, document_id: bson::oid::ObjectId, updated_at: Option<bson::DateTime>, changes: Document) -> Result<bool, Error> {
+        let expected_updated_at = updated_at.map(Bson::DateTime).unwrap_or(Bson::Null);
+        let mut collections = self.collections.lock().unwrap();
+        let documents = collections.entry(collection.to_string()).or_default();
+        let Some(document) = documents.iter_mut().find(|document| document.get_object_id("_id") == Ok(document_id)) else {
+            return Ok(false)
+        };
+        if document.get("updated_at").unwrap_or(&Bson::Null) != &expected_updated_at {
+            return Ok(false)
+        }
+        for (key, value) in changes {
+            document.insert(key, value);
+        }
+        Ok(true)
+    }
+}
+
+#[rocket::async_trait]
+impl Repository for MemoryRepository {
+    async fn find_user_by_token(&self, token: &str) -> Result<Option<User>, Error> {
+        let users: Vec<User> = self.all("users")?;
+        Ok(users.into_iter().find(|user| user.token == token))
+    }
+
+    async fn find_user_by_apple_id(&self, apple_user_id: &str) -> Result<Option<User>, Error> {
+        let users: Vec<User> = self.all("users")?;
+        Ok(users.into_iter().find(|user| user.apple_user_id == apple_user_id))
+    }
+
+    async fn find_user_by_calendar_secret(&self, secret: &str) -> Result<Option<User>, Error> {
+        let users: Vec<User> = self.all("users")?;
+        Ok(users.into_iter().find(|user| user.calendar_secret.as_deref() == Some(secret)))
+    }
+
+    async fn insert_user(&self, user: &User) -> Result<(), Error> {
+        self.insert("users", user)
+    }
+
+    async fn update_user(&self, user_id: bson::oid::ObjectId, changes: Document) -> Result<(), Error> {
+        self.update("users", user_id, changes)
+    }
+
+    // Mirrors moss_pipeline: tasks without events have no latest_event_date and a null moss, which sorts last
+    async fn find_tasks_with_moss(&self, user_id: bson::oid::ObjectId, offset: u32, limit: u32) -> Result<Vec<Document>, Error> {
+        let tasks: Vec<Task> = self.all("tasks")?;
+        let events: Vec<Event> = self.all("events")?;
+        let now = bson::DateTime::now().timestamp_millis();
+
+        let mut latest_event_dates = HashMap::new();
+        for event in events.iter().filter(|event| event.deleted_at.is_none()) {
+            let latest_event_date = latest_event_dates.entry(event.task).or_insert(event.date);
+            if event.date > *latest_event_date {
+                *latest_event_date = event.date;
+            }
+        }
+
+        let mut tasks_list = Vec::new();
+        for task in tasks.into_iter().filter(|task| task.user == Some(user_id) && task.deleted_at.is_none()) {
+            let latest_event_date = latest_event_dates.get(&task._id).cloned();
+            let time_since_latest_event = latest_event_date.map(|date| now - date.timestamp_millis());
+            let moss = time_since_latest_event.map(|time_since| time_since - i64::from(task.frequency) * 24 * 60 * 60 * 1000);
+
+            let mut task_document = bson::to_document(&task)?;
+            if let Some(date) = latest_event_date {
+                task_document.insert("latest_event_date", date);
+            }
+            task_document.insert("time_since_latest_event", time_since_latest_event.map_or(Bson::Null, Bson::Int64));
+            task_document.insert("moss", moss.map_or(Bson::Null, Bson::Int64));
+            tasks_list.push((moss, task._id, task_document));
+        }
+        tasks_list.sort_by_key(|(moss, task_id, _)| Reverse((*moss, *task_id)));
+
+        Ok(page(tasks_list.into_iter().map(|(_, _, task_document)| task_document).collect(), offset, limit))
+    }
+
+    async fn find_task(&self, task_id: bson::oid::ObjectId) -> Result<Option<Task>, Error> {
+        self.find_by_id("tasks", task_id)
+    }
+
+    async fn insert_task(&self, task: &Task) -> Result<(), Error> {
+        self.insert("tasks", task)
+    }
+
+    async fn insert_tasks(&self, tasks: &[Task]) -> Result<(), Error> {
+        for task in tasks {
+            self.insert("tasks", task)?;
+        }
+        Ok(())
+    }
+
+    async fn update_task(&self, task_id: bson::oid::ObjectId, updated_at: Option<bson::DateTime>, changes: Document) -> Result<bool, Error> {
+        self.update_if_unchanged("tasks", task_id, updated_at, changes)
+    }
+
+    async fn find_tasks_by_ids(&self, task_ids: &[bson::oid::ObjectId]) -> Result<Vec<Task>, Error> {
+        self.find_by_ids("tasks", task_ids)
+    }
+
+    async fn find_user_tasks(&self, user_id: bson::oid::ObjectId, trashed: bool) -> Result<Vec<Task>, Error> {
+        let mut tasks: Vec<Task> = self.all("tasks")?;
+        tasks.retain(|task| task.user == Some(user_id) && task.deleted_at.is_some() == trashed);
+        Ok(tasks)
+    }
+
+    // Mirrors the $group in the MongoDB version, including averages that skip unset values
+    async fn find_task_stats(&self, user_id: bson::oid::ObjectId, task_id: Option<bson::oid::ObjectId>) -> Result<Vec<TaskStats>, Error> {
+        let mut tasks: Vec<Task> = self.all("tasks")?;
+        tasks.retain(|task| task.user == Some(user_id) && task.deleted_at.is_none() && task_id.is_none_or(|_task_id| task._id == _task_id));
+        let events: Vec<Event> = self.all("events")?;
+
+        let average = |values: Vec<f64>| if values.is_empty() { None } else { Some(values.iter().sum::<f64>() / values.len() as f64) };
+        let mut stats_list = Vec::new();
+        for task in tasks {
+            let task_events: Vec<&Event> = events.iter().filter(|event| event.task == task._id && event.deleted_at.is_none()).collect();
+            if task_events.is_empty() {
+                continue
+            }
+            stats_list.push(TaskStats {
+                task: task._id,
+                event_count: task_events.len() as i64,
+                first_event_date: task_events.iter().map(|event| event.date).min(),
+                latest_event_date: task_events.iter().map(|event| event.date).max(),
+                total_duration: task_events.iter().filter_map(|event| event.duration).sum(),
+                average_duration: average(task_events.iter().filter_map(|event| event.duration).map(|duration| duration as f64).collect()),
+                total_quantity: task_events.iter().filter_map(|event| event.quantity).sum(),
+                average_quantity: average(task_events.iter().filter_map(|event| event.quantity).collect()),
+                average_rating: average(task_events.iter().filter_map(|event| event.rating).map(f64::from).collect()),
+            });
+        }
+        stats_list.sort_by_key(|stats| Reverse(stats.latest_event_date));
+
+        Ok(stats_list)
+    }
+
+    async fn find_events(&self, user_id: bson::oid::ObjectId, offset: u32, limit: u32) -> Result<Vec<Event>, Error> {
+        let mut events: Vec<Event> = self.all("events")?;
+        events.retain(|event| event.user == Some(user_id) && event.deleted_at.is_none());
+        events.sort_by_key(|event| Reverse((event.date, event._id)));
+        Ok(page(events, offset, limit))
+    }
+
+    async fn find_event(&self, event_id: bson::oid::ObjectId) -> Result<Option<Event>, Error> {
+        self.find_by_id("events", event_id)
+    }
+
+    async fn insert_event(&self, event: &Event) -> Result<(), Error> {
+        self.insert("events", event)
+    }
+
+    async fn update_event(&self, event_id: bson::oid::ObjectId, updated_at: Option<bson::DateTime>, changes: Document) -> Result<bool, Error> {
+        self.update_if_unchanged("events", event_id, updated_at, changes)
+    }
+
+    async fn insert_events(&self, events: &[Event]) -> Result<(), Error> {
+        for event in events {
+            self.insert("events", event)?;
+        }
+        Ok(())
+    }
+
+    async fn find_events_by_ids(&self, event_ids: &[bson::oid::ObjectId]) -> Result<Vec<Event>, Error> {
+        self.find_by_ids("events", event_ids)
+    }
+
+    async fn find_task_events(&self, task_ids: &[bson::oid::ObjectId]) -> Result<Vec<Event>, Error> {
+        let mut events: Vec<Event> = self.all("events")?;
+        events.retain(|event| task_ids.contains(&event.task));
+        Ok(events)
+    }
+
+    async fn find_user_events(&self, user_id: bson::oid::ObjectId, trashed: bool) -> Result<Vec<Event>, Error> {
+        let mut events: Vec<Event> = self.all("events")?;
+        events.retain(|event| event.user == Some(user_id) && event.deleted_at.is_some() == trashed);
+        Ok(events)
+    }
+
+    async fn find_tags(&self, user_id: bson::oid::ObjectId, offset: u32, limit: u32) -> Result<Vec<Tag>, Error> {
+        let mut tags: Vec<Tag> = self.all("tags")?;
+        tags.retain(|tag| tag.user == Some(user_id) && tag.deleted_at.is_none());
+        tags.sort_by(|a, b| (a.sort_order, &a.name, b._id).cmp(&(b.sort_order, &b.name, a._id)));
+        Ok(page(tags, offset, limit))
+    }
+
+    async fn find_tag(&self, tag_id: bson::oid::ObjectId) -> Result<Option<Tag>, Error> {
+        self.find_by_id("tags", tag_id)
+    }
+
+    async fn insert_tag(&self, tag: &Tag) -> Result<(), Error> {
+        self.insert("tags", tag)
+    }
+
+    async fn insert_tags(&self, tags: &[Tag]) -> Result<(), Error> {
+        for tag in tags {
+            self.insert("tags", tag)?;
+        }
+        Ok(())
+    }
+
+    async fn update_tag(&self, tag_id: bson::oid::ObjectId, updated_at: Option<bson::DateTime>, changes: Document) -> Result<bool, Error> {
+        self.update_if_unchanged("tags", tag_id, updated_at, changes)
+    }
+
+    async fn find_tags_by_ids(&self, tag_ids: &[bson::oid::ObjectId]) -> Result<Vec<Tag>, Error> {
+        self.find_by_ids("tags", tag_ids)
+    }
+
+    async fn find_user_tags(&self, user_id: bson::oid::ObjectId, trashed: bool) -> Result<Vec<Tag>, Error> {
+        let mut tags: Vec<Tag> = self.all("tags")?;
+        tags.retain(|tag| tag.user == Some(user_id) && tag.deleted_at.is_some() == trashed);
+        Ok(tags)
+    }
+
+    // Everything is checked before anything is written, all under the one lock
+    async fn apply_writes(&self, writes: Vec<DocumentWrite>) -> Result<bool, Error> {
+        let mut collections = self.collections.lock().unwrap();
+
+        for write in writes.iter() {
+            let expected_updated_at = write.updated_at.map(Bson::DateTime).unwrap_or(Bson::Null);
+            let document = collections.get(write.collection).into_iter().flatten().find(|document| document.get_object_id("_id") == Ok(write.document_id));
+            match document {
+                None if write.create => (),
+                Some(_document) if !write.create && _document.get("updated_at").unwrap_or(&Bson::Null) == &expected_updated_at => (),
+                _ => return Ok(false),
+            }
+        }
+        for write in writes {
+            let documents = collections.entry(write.collection.to_string()).or_default();
+            if write.create {
+                let mut document = write.changes;
+                document.insert("_id", write.document_id);
+                documents.push(document);
+            } else if let Some(document) = documents.iter_mut().find(|document| document.get_object_id("_id") == Ok(write.document_id)) {
+                for (key, value) in write.changes {
+                    document.insert(key, value);
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    async fn find_changed_documents(&self, collection: &str, user_id: bson::oid::ObjectId, since: Option<bson::DateTime>) -> Result<Vec<Document>, Error> {
+        let mut documents: Vec<Document> = self.all(collection)?;
+        documents.retain(|document| {
+            document.get_object_id("user") == Ok(user_id) && match since {
+                Some(_since) => document.get_datetime("updated_at").is_ok_and(|updated_at| *updated_at >= _since),
+                None => matches!(document.get("deleted_at"), None | Some(Bson::Null)),
+            }
+        });
+        Ok(documents)
+    }
+
+    async fn find_undoable_operations(&self, user_id: bson::oid::ObjectId, since: bson::DateTime, limit: u32) -> Result<Vec<Operation>, Error> {
+        let mut operations: Vec<Document> = self.all("operations")?;
+        operations.retain(|operation| {
+            operation.get_object_id("user") == Ok(user_id)
+                && matches!(operation.get("undone_at"), None | Some(Bson::Null))
+                && operation.get_datetime("created_at").is_ok_and(|created_at| *created_at >= since)
+        });
+        operations.sort_by_key(|operation| Reverse((operation.get_datetime("created_at").ok().copied(), operation.get_object_id("_id").ok())));
+        page(operations, 0, limit).into_iter().map(|operation| Ok(bson::from_document(operation)?)).collect()
+    }
+
+    async fn revert_operation(&self, operation_id: bson::oid::ObjectId, reverts: Vec<DocumentRevert>, undone_at: bson::DateTime) -> Result<bool, Error> {
+        let mut collections = self.collections.lock().unwrap();
+
+        for revert in reverts.iter() {
+            let expected_updated_at = revert.updated_at.map(Bson::DateTime).unwrap_or(Bson::Null);
+            let document = collections.get(&revert.collection).into_iter().flatten().find(|document| document.get_object_id("_id") == Ok(revert.document_id));
+            match document {
+                Some(_document) if _document.get("updated_at").unwrap_or(&Bson::Null) == &expected_updated_at => (),
+                _ => return Ok(false),
+            }
+        }
+        let operation = collections.entry(String::from("operations")).or_default().iter_mut().find(|operation| operation.get_object_id("_id") == Ok(operation_id));
+        let Some(_operation) = operation.filter(|_operation| matches!(_operation.get("undone_at"), None | Some(Bson::Null))) else {
+            return Ok(false)
+        };
+        _operation.insert("undone_at", undone_at);
+
+        for revert in reverts {
+            let documents = collections.entry(revert.collection).or_default();
+            if let Some(document) = documents.iter_mut().find(|document| document.get_object_id("_id") == Ok(revert.document_id)) {
+                *document = revert.document;
+            }
+        }
+
+        Ok(true)
+    }
+
+    async fn find_document(&self, collection: &str, document_id: bson::oid::ObjectId) -> Result<Option<Document>, Error> {
+        self.find_by_id(collection, document_id)
+    }
+
+    async fn find_documents(&self, collection: &str, document_ids: &[bson::oid::ObjectId]) -> Result<Vec<Document>, Error> {
+        self.find_by_ids(collection, document_ids)
+    }
+
+    async fn insert_document(&self, collection: &str, document: Document) -> Result<(), Error> {
+        self.insert(collection, &document)
+    }
+
+    async fn find_audit_entries(&self, actor: Option<bson::oid::ObjectId>, action: Option<&str>, offset: u32, limit: u32) -> Result<Vec<AuditEntry>, Error> {
+        let mut entries: Vec<Document> = self.all("audit")?;
+        entries.retain(|entry| actor.is_none_or(|actor_id| entry.get_object_id("actor") == Ok(actor_id)) && action.is_none_or(|action_name| entry.get_str("action") == Ok(action_name)));
+        entries.sort_by_key(|entry| Reverse((entry.get_datetime("created_at").ok().copied(), entry.get_object_id("_id").ok())));
+        let mut audit_list = Vec::new();
+        for entry in page(entries, offset, limit) {
+            audit_list.push(bson::from_document(entry)?);
+        }
+        Ok(audit_list)
+    }
+
+    async fn find_user_webhooks(&self, user_id: bson::oid::ObjectId) -> Result<Vec<Webhook>, Error> {
+        let mut webhooks: Vec<Document> = self.all("webhooks")?;
+        webhooks.retain(|webhook| webhook.get_object_id("user") == Ok(user_id));
+        webhooks.sort_by_key(|webhook| Reverse(webhook.get_datetime("created_at").ok().copied()));
+        webhooks.into_iter().map(|webhook| Ok(bson::from_document(webhook)?)).collect()
+    }
+
+    async fn find_webhooks_by_ids(&self, webhook_ids: &[bson::oid::ObjectId]) -> Result<Vec<Webhook>, Error> {
+        self.find_by_ids("webhooks", webhook_ids)
+    }
+
+    async fn insert_webhook(&self, webhook: &Webhook) -> Result<(), Error> {
+        self.insert("webhooks", webhook)
+    }
+
+    async fn delete_webhooks(&self, webhook_ids: &[bson::oid::ObjectId]) -> Result<u64, Error> {
+        let mut collections = self.collections.lock().unwrap();
+        let webhooks = collections.entry(String::from("webhooks")).or_default();
+        let count_before = webhooks.len();
+        webhooks.retain(|webhook| !webhook.get_object_id("_id").is_ok_and(|webhook_id| webhook_ids.contains(&webhook_id)));
+        let deleted_count = (count_before - webhooks.len()) as u64;
+
+        for delivery in collections.entry(String::from("webhook_deliveries")).or_default().iter_mut() {
+            if delivery.get_object_id("webhook").is_ok_and(|webhook_id| webhook_ids.contains(&webhook_id)) && delivery.get_str("status") == Ok("pending") {
+                delivery.insert("status", "failed");
+                delivery.insert("last_error", webhooks::DELETED_WEBHOOK_ERROR);
+            }
+        }
+
+        Ok(deleted_count)
+    }
+
+    async fn find_webhook_deliveries(&self, user_id: bson::oid::ObjectId, webhook_id: Option<bson::oid::ObjectId>, offset: u32, limit: u32) -> Result<Vec<WebhookDelivery>, Error> {
+        let mut deliveries: Vec<Document> = self.all("webhook_deliveries")?;
+        deliveries.retain(|delivery| delivery.get_object_id("user") == Ok(user_id) && webhook_id.is_none_or(|_webhook_id| delivery.get_object_id("webhook") == Ok(_webhook_id)));
+        deliveries.sort_by_key(|delivery| Reverse((delivery.get_datetime("created_at").ok().copied(), delivery.get_object_id("_id").ok())));
+        page(deliveries, offset, limit).into_iter().map(|delivery| Ok(bson::from_document(delivery)?)).collect()
+    }
+
+    // Nothing delivers from memory, so the deliveries only show up in the log
+    async fn enqueue_webhook(&self, user_id: bson::oid::ObjectId, event: &str, data: Document) -> Result<(), Error> {
+        let mut subscribed_webhooks: Vec<Document> = self.all("webhooks")?;
+        subscribed_webhooks.retain(|webhook| {
+            webhook.get_object_id("user") == Ok(user_id)
+                && webhook.get_bool("active") == Ok(true)
+                && webhook.get_array("events").is_ok_and(|events| events.iter().any(|listened| listened.as_str() == Some(event)))
+        });
+        let mut webhooks_list = Vec::new();
+        for webhook in subscribed_webhooks {
+            webhooks_list.push(bson::from_document(webhook)?);
+        }
+
+        for delivery in webhooks::new_deliveries(webhooks_list, user_id, event, &data) {
+            self.insert("webhook_deliveries", &delivery)?;
+        }
+        Ok(())
+    }
+
+    async fn find_attachments(&self, user_id: bson::oid::ObjectId, task_id: Option<bson::oid::ObjectId>, event_id: Option<bson::oid::ObjectId>) -> Result<Vec<Attachment>, Error> {
+        let mut attachments: Vec<Document> = self.all("attachments")?;
+        attachments.retain(|attachment| {
+            attachment.get_object_id("user") == Ok(user_id)
+                && task_id.is_none_or(|_task_id| attachment.get_object_id("task") == Ok(_task_id))
+                && event_id.is_none_or(|_event_id| attachment.get_object_id("event") == Ok(_event_id))
+        });
+        attachments.sort_by_key(|attachment| Reverse(attachment.get_datetime("created_at").ok().copied()));
+        attachments.into_iter().map(|attachment| Ok(bson::from_document(attachment)?)).collect()
+    }
+
+    async fn find_attachments_by_ids(&self, attachment_ids: &[bson::oid::ObjectId]) -> Result<Vec<Attachment>, Error> {
+        self.find_by_ids("attachments", attachment_ids)
+    }
+
+    async fn find_owned_attachments(&self, task_ids: &[bson::oid::ObjectId], event_ids: &[bson::oid::ObjectId]) -> Result<Vec<Attachment>, Error> {
+        let mut attachments: Vec<Document> = self.all("attachments")?;
+        attachments.retain(|attachment| {
+            attachment.get_object_id("task").is_ok_and(|task_id| task_ids.contains(&task_id))
+                || attachment.get_object_id("event").is_ok_and(|event_id| event_ids.contains(&event_id))
+        });
+        attachments.into_iter().map(|attachment| Ok(bson::from_document(attachment)?)).collect()
+    }
+
+    async fn insert_attachment(&self, attachment: &Attachment) -> Result<(), Error> {
+        self.insert("attachments", attachment)
+    }
+
+    async fn delete_attachment(&self, attachment_id: bson::oid::ObjectId) -> Result<bool, Error> {
+        let mut collections = self.collections.lock().unwrap();
+        let attachments = collections.entry(String::from("attachments")).or_default();
+        let count_before = attachments.len();
+        attachments.retain(|attachment| attachment.get_object_id("_id") != Ok(attachment_id));
+        Ok(attachments.len() < count_before)
+    }
+
+    async fn link_attachment(&self, collection: &str, owner_id: bson::oid::ObjectId, user_id: bson::oid::ObjectId, attachment_id: bson::oid::ObjectId) -> Result<Option<Document>, Error> {
+        let mut collections = self.collections.lock().unwrap();
+        let owner = collections.entry(collection.to_string()).or_default().iter_mut().find(|owner| {
+            owner.get_object_id("_id") == Ok(owner_id)
+                && owner.get_object_id("user") == Ok(user_id)
+                && matches!(owner.get("deleted_at"), None | Some(Bson::Null))
+        });
+        let Some(_owner) = owner else {
+            return Ok(None)
+        };
+        let mut attachment_ids = _owner.get_array("attachments").cloned().unwrap_or_default();
+        attachment_ids.push(Bson::ObjectId(attachment_id));
+        _owner.insert("attachments", attachment_ids);
+        _owner.insert("updated_at", bson::DateTime::now());
+        Ok(Some(_owner.clone()))
+    }
+
+    async fn unlink_attachment(&self, collection: &str, owner_id: bson::oid::ObjectId, attachment_id: bson::oid::ObjectId) -> Result<Option<Document>, Error> {
+        let mut collections = self.collections.lock().unwrap();
+        let owner = collections.entry(collection.to_string()).or_default().iter_mut().find(|owner| owner.get_object_id("_id") == Ok(owner_id));
+        let Some(_owner) = owner else {
+            return Ok(None)
+        };
+        let mut attachment_ids = _owner.get_array("attachments").cloned().unwrap_or_default();
+        attachment_ids.retain(|id| id.as_object_id() != Some(attachment_id));
+        _owner.insert("attachments", attachment_ids);
+        _owner.insert("updated_at", bson::DateTime::now());
+        Ok(Some(_owner.clone()))
+    }
+
+    async fn attachment_bytes_used(&self, user_id: bson::oid::ObjectId) -> Result<i64, Error> {
+        let usage_option: Option<AttachmentUsageCounter> = self.find_by_id("attachment_usage", user_id)?;
+        Ok(usage_option.map_or(0, |counter| counter.used))
+    }
+
+    async fn reserve_attachment_bytes(&self, user_id: bson::oid::ObjectId, size: i64, quota: i64) -> Result<bool, Error> {
+        let mut collections = self.collections.lock().unwrap();
+        let usage = collections.entry(String::from("attachment_usage")).or_default();
+        let used = usage.iter().find(|counter| counter.get_object_id("_id") == Ok(user_id)).and_then(|counter| counter.get_i64("used").ok()).unwrap_or(0);
+        if used + size > quota {
+            return Ok(false)
+        }
+        usage.retain(|counter| counter.get_object_id("_id") != Ok(user_id));
+        usage.push(bson::doc! { "_id": user_id, "used": used + size });
+        Ok(true)
+    }
+
+    async fn release_attachment_bytes(&self, user_id: bson::oid::ObjectId, size: i64) -> Result<(), Error> {
+        let mut collections = self.collections.lock().unwrap();
+        let counter = collections.entry(String::from("attachment_usage")).or_default().iter_mut().find(|counter| counter.get_object_id("_id") == Ok(user_id));
+        if let Some(_counter) = counter {
+            let used = _counter.get_i64("used").unwrap_or(0);
+            _counter.insert("used", used - size);
+        }
+        Ok(())
+    }
+
+    async fn delete_user_documents(&self, collection: &str, user_id: bson::oid::ObjectId) -> Result<u64, Error> {
+        let mut collections = self.collections.lock().unwrap();
+        let documents = collections.entry(collection.to_string()).or_default();
+        let count_before = documents.len();
+        documents.retain(|document| document.get_object_id("user") != Ok(user_id));
+        Ok((count_before - documents.len()) as u64)
+    }
+
+    async fn count_users(&self) -> Result<u64, Error> {
+        let users: Vec<User> = self.all("users")?;
+        Ok(users.len() as u64)
+    }
+
+    async fn count_events_created_since(&self, since: bson::DateTime) -> Result<u64, Error> {
+        let events: Vec<Event> = self.all("events")?;
+        let first_id = first_object_id_at(since);
+        Ok(events.iter().filter(|event| event._id >= first_id && event.deleted_at.is_none()).count() as u64)
+    }
+
+    async fn ping(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    // Memory starts out in the shape the migrations would leave MongoDB in
+    async fn pending_migrations(&self) -> Result<Vec<(i32, &'static str)>, Error> {
+        Ok(Vec::new())
+    }
+}
+
+pub struct Store {
+    pub backend: Box<dyn Repository>,
+}
+
+impl Store {
+    pub fn memory() -> Store {
+        Store { backend: Box::new(MemoryRepository::new()) }
+    }
+
+    pub async fn from_config(config: &AppConfig) -> Result<Store, Error> {
+        if config.uses_memory_backend() {
+            return Ok(Store::memory())
+        }
+
+        let mut client_options = ClientOptions::parse(&config.database_uri).await?;
+        client_options.app_name = Some("mossy".to_string());
+        client_options.command_event_handler = Some(metrics::command_timer());
+        let client = Client::with_options(client_options)?;
+        Ok(Store { backend: Box::new(MongoRepository::new(&client, &config.database_name)) })
+    }
+}
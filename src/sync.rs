@@ -0,0 +1,517 @@
+use base64::{Engine as _, engine::general_purpose};
+use mongodb::bson;
+use mongodb::bson::Document;
+use mongodb::error::Error;
+use rocket::State;
+use rocket::http::Status;
+use rocket::serde::{Serialize, Deserialize, DeserializeOwned, json::Json};
+use tracing::error;
+
+use crate::{DeleteError, DeleteStrategy, DeleteSummary, Event, NewEventData, NewTagData, NewTaskData, Tag, TagError, Task, Token, User};
+use crate::{plan_tag_delete, plan_task_delete, tag_validation_errors, trash, validate_tag, webhooks};
+use crate::config::AppConfig;
+use crate::audit::RequestMeta;
+use crate::realtime::ChangeBus;
+use crate::repository::{DocumentWrite, Repository, Store};
+use crate::undo::OperationLog;
+use crate::validation::{ActionError, Validate, ValidationErrors, validate_tag_owner, validate_task_owner};
+
+// updated_at is set before a write commits, and transactions can stay open for up to MongoDB's
+// default 60 second lifetime, so a write can become visible well after the time it's stamped with.
+// Each token starts that far back so late commits are picked up by the next pull.
+const SYNC_OVERLAP_MILLISECONDS: i64 = 60 * 1000;
+
+trait SyncDocument {
+    fn id(&self) -> bson::oid::ObjectId;
+    fn updated_at(&self) -> Option<bson::DateTime>;
+    fn deleted_at(&self) -> Option<bson::DateTime>;
+    fn user(&self) -> Option<bson::oid::ObjectId>;
+}
+
+impl SyncDocument for Task {
+    fn id(&self) -> bson::oid::ObjectId { self._id }
+    fn updated_at(&self) -> Option<bson::DateTime> { self.updated_at }
+    fn deleted_at(&self) -> Option<bson::DateTime> { self.deleted_at }
+    fn user(&self) -> Option<bson::oid::ObjectId> { self.user }
+}
+
+impl SyncDocument for Event {
+    fn id(&self) -> bson::oid::ObjectId { self._id }
+    fn updated_at(&self) -> Option<bson::DateTime> { self.updated_at }
+    fn deleted_at(&self) -> Option<bson::DateTime> { self.deleted_at }
+    fn user(&self) -> Option<bson::oid::ObjectId> { self.user }
+}
+
+impl SyncDocument for Tag {
+    fn id(&self) -> bson::oid::ObjectId { self._id }
+    fn updated_at(&self) -> Option<bson::DateTime> { self.updated_at }
+    fn deleted_at(&self) -> Option<bson::DateTime> { self.deleted_at }
+    fn user(&self) -> Option<bson::oid::ObjectId> { self.user }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct SyncCollection<T> {
+    created: Vec<T>,
+    updated: Vec<T>,
+    deleted: Vec<bson::oid::ObjectId>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct SyncChanges {
+    // Pass this back as `since` on the next pull to only get what changed after this response.
+    // Pulls overlap by SYNC_OVERLAP_MILLISECONDS, so apply created and updated documents by _id rather than appending them
+    token: String,
+    // The `since` token was older than the trash retention, so deletions may be missing and the
+    // client should replace its local copy with this full snapshot
+    reset: bool,
+    tasks: SyncCollection<Task>,
+    events: SyncCollection<Event>,
+    tags: SyncCollection<Tag>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct SyncChange<T> {
+    _id: bson::oid::ObjectId,
+    // "upsert" or "delete"
+    operation: String,
+    // The updated_at of the server copy the client last saw, or None for documents created offline
+    base_updated_at: Option<bson::DateTime>,
+    document: Option<T>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct SyncPushData {
+    tasks: Option<Vec<SyncChange<Task>>>,
+    events: Option<Vec<SyncChange<Event>>>,
+    tags: Option<Vec<SyncChange<Tag>>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct SyncPushResult {
+    _id: bson::oid::ObjectId,
+    kind: String,
+    // "applied", "conflict", "forbidden", "not_found" or "invalid"
+    status: String,
+    // The current server copy when the change conflicts, so the client can resolve it
+    server_document: Option<Document>,
+    // What's wrong with the document when the change is invalid
+    errors: Option<ValidationErrors>,
+}
+
+impl SyncPushResult {
+    fn new(_id: bson::oid::ObjectId, kind: &str, status: &str) -> SyncPushResult {
+        SyncPushResult {
+            _id,
+            kind: kind.to_string(),
+            status: status.to_string(),
+            server_document: None,
+            errors: None,
+        }
+    }
+
+    fn invalid(_id: bson::oid::ObjectId, kind: &str, errors: ValidationErrors) -> SyncPushResult {
+        let mut result = SyncPushResult::new(_id, kind, "invalid");
+        result.errors = Some(errors);
+        result
+    }
+}
+
+// Pushed documents get the same checks as the create endpoints
+fn validation_errors<T: Validate>(data: &T) -> ValidationErrors {
+    let mut errors = ValidationErrors::new();
+    data.validate(&mut errors);
+    errors
+}
+
+// Runs a delete the same way the DELETE endpoints do, so dependents are written together with it.
+// The document itself keeps the version check_change approved, so anything written to it since makes the delete a conflict.
+async fn delete_with_dependents(repository: &dyn Repository, operation: &mut OperationLog, user: &User, kind: &str, _id: bson::oid::ObjectId, updated_at: Option<bson::DateTime>) -> Result<DeleteSummary, DeleteError> {
+    let now = bson::DateTime::now();
+    let mut plan = match kind {
+        "task" => plan_task_delete(repository, user, vec![_id], DeleteStrategy::Cascade, now).await?.0,
+        _ => plan_tag_delete(repository, user, vec![_id], DeleteStrategy::Reparent, now).await?,
+    };
+    for write in plan.writes.iter_mut().filter(|write| write.document_id == _id) {
+        write.updated_at = updated_at;
+    }
+    if !operation.apply_writes(repository, plan.writes).await? {
+        return Err(DeleteError::ConcurrentChange)
+    }
+
+    Ok(plan.summary)
+}
+
+// Upserts replace every field of the server copy, and only while it's still the one check_change approved
+fn upsert_write<T>(collection: &'static str, existing: Option<&T>, document: &T) -> Result<DocumentWrite, Error>
+where
+    T: SyncDocument + Serialize,
+{
+    let mut fields = bson::to_document(document)?;
+    fields.remove("_id");
+    Ok(match existing {
+        Some(_existing) => DocumentWrite::new(collection, document.id(), _existing.updated_at(), fields),
+        None => DocumentWrite::create(collection, document.id(), fields),
+    })
+}
+
+// For a change that lost a race with another write after check_change let it through
+async fn conflict_result(repository: &dyn Repository, collection: &str, kind: &str, _id: bson::oid::ObjectId) -> Result<SyncPushResult, Error> {
+    let mut result = SyncPushResult::new(_id, kind, "conflict");
+    result.server_document = repository.find_document(collection, _id).await?;
+    Ok(result)
+}
+
+pub fn encode_token(date: bson::DateTime) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(date.timestamp_millis().to_string())
+}
+
+pub fn decode_token(token: &str) -> Option<bson::DateTime> {
+    let decoded_token = general_purpose::URL_SAFE_NO_PAD.decode(token).ok()?;
+    let milliseconds = String::from_utf8(decoded_token).ok()?.parse::<i64>().ok()?;
+    Some(bson::DateTime::from_millis(milliseconds))
+}
+
+async fn changed_documents<T>(repository: &dyn Repository, collection: &str, user_id: bson::oid::ObjectId, since: Option<bson::DateTime>) -> Result<SyncCollection<T>, Error>
+where
+    T: SyncDocument + DeserializeOwned,
+{
+    // A first sync only needs what currently exists, later ones also need tombstones
+    let documents = repository.find_changed_documents(collection, user_id, since).await?;
+
+    let mut changes = SyncCollection {
+        created: Vec::new(),
+        updated: Vec::new(),
+        deleted: Vec::new(),
+    };
+
+    for raw_document in documents {
+        let document: T = bson::from_document(raw_document)?;
+        if document.deleted_at().is_some() {
+            changes.deleted.push(document.id());
+            continue
+        }
+        // ObjectIds embed their creation time, which is enough to tell new documents from edits
+        match since {
+            Some(_since) if document.id().timestamp() <= _since => changes.updated.push(document),
+            _ => changes.created.push(document),
+        }
+    }
+
+    Ok(changes)
+}
+
+async fn read_sync_action(token: Token, since: Option<bson::DateTime>, store: &Store, config: &AppConfig) -> Result<SyncChanges, Error> {
+    let repository = store.backend.as_ref();
+
+    let user = token.0;
+
+    let reset = since.is_some_and(|_since| _since < trash::purge_horizon(config));
+    let since = if reset { None } else { since };
+
+    // Take the token before reading, and early enough that writes still committing show up again next time
+    let next_token = encode_token(bson::DateTime::from_millis(bson::DateTime::now().timestamp_millis() - SYNC_OVERLAP_MILLISECONDS));
+
+    let tasks = changed_documents(repository, "tasks", user._id, since).await?;
+    let events = changed_documents(repository, "events", user._id, since).await?;
+    let tags = changed_documents(repository, "tags", user._id, since).await?;
+
+    Ok(SyncChanges {
+        token: next_token,
+        reset,
+        tasks,
+        events,
+        tags,
+    })
+}
+
+// Returns the current server copy if the change may be applied, or the result to report if it may not
+async fn check_change<T>(repository: &dyn Repository, collection: &str, user_id: bson::oid::ObjectId, kind: &str, change: &SyncChange<T>) -> Result<Result<Option<T>, SyncPushResult>, Error>
+where
+    T: SyncDocument + Serialize + DeserializeOwned,
+{
+    if change.operation != "upsert" && change.operation != "delete" {
+        return Ok(Err(SyncPushResult::new(change._id, kind, "invalid")))
+    }
+    if change.operation == "upsert" && change.document.is_none() {
+        return Ok(Err(SyncPushResult::new(change._id, kind, "invalid")))
+    }
+
+    let Some(existing) = repository.find_document(collection, change._id).await? else {
+        return Ok(Ok(None))
+    };
+    let existing_document: T = bson::from_document(existing)?;
+
+    if existing_document.user() != Some(user_id) {
+        return Ok(Err(SyncPushResult::new(change._id, kind, "forbidden")))
+    }
+    if existing_document.updated_at() > change.base_updated_at {
+        let mut result = SyncPushResult::new(change._id, kind, "conflict");
+        result.server_document = bson::to_document(&existing_document).ok();
+        return Ok(Err(result))
+    }
+
+    Ok(Ok(Some(existing_document)))
+}
+
+async fn push_task_change(repository: &dyn Repository, operation: &mut OperationLog, user: &User, change: SyncChange<Task>, changes: &ChangeBus) -> Result<SyncPushResult, Error> {
+    let user_id = user._id;
+
+    let existing_task = match check_change(repository, "tasks", user_id, "task", &change).await? {
+        Ok(_existing_task) => _existing_task,
+        Err(result) => return Ok(result),
+    };
+
+    if change.operation == "delete" {
+        let Some(task) = existing_task else {
+            return Ok(SyncPushResult::new(change._id, "task", "not_found"))
+        };
+        let summary = match delete_with_dependents(repository, operation, user, "task", change._id, task.updated_at).await {
+            Ok(_summary) => _summary,
+            Err(DeleteError::NotFound) => return Ok(SyncPushResult::new(change._id, "task", "not_found")),
+            Err(DeleteError::ConcurrentChange) => return conflict_result(repository, "tasks", "task", change._id).await,
+            Err(DeleteError::DatabaseError(error)) => return Err(error),
+        };
+        webhooks::notify(repository, user_id, webhooks::TASK_DELETED, &task).await;
+        changes.publish(user_id, "task", "deleted", summary.deleted_tasks);
+        changes.publish(user_id, "event", "deleted", summary.deleted_events);
+        return Ok(SyncPushResult::new(change._id, "task", "applied"))
+    }
+
+    let Some(mut task) = change.document else {
+        return Ok(SyncPushResult::new(change._id, "task", "invalid"))
+    };
+    let task_data = NewTaskData {
+        name: task.name.clone(),
+        frequency: task.frequency,
+        tags: task.tags.clone(),
+    };
+    let errors = validation_errors(&task_data);
+    if !errors.is_empty() {
+        return Ok(SyncPushResult::invalid(change._id, "task", errors))
+    }
+    match validate_tag_owner(repository, user_id, "tags", &task.tags).await {
+        Ok(_) => {},
+        Err(ActionError::Invalid(errors)) => return Ok(SyncPushResult::invalid(change._id, "task", errors)),
+        Err(ActionError::DatabaseError(error)) => return Err(error),
+    }
+
+    // Attachments are only linked through /api/attachments, so keep whatever the server has
+    task.attachments = existing_task.as_ref().and_then(|_existing_task| _existing_task.attachments.clone());
+    task._id = change._id;
+    task.user = Some(user_id);
+    task.updated_at = Some(bson::DateTime::now());
+    task.deleted_at = None;
+
+    if !operation.apply_writes(repository, vec![upsert_write("tasks", existing_task.as_ref(), &task)?]).await? {
+        return conflict_result(repository, "tasks", "task", change._id).await
+    }
+
+    let (event, action) = match existing_task {
+        Some(_) => (webhooks::TASK_UPDATED, "updated"),
+        None => (webhooks::TASK_CREATED, "created"),
+    };
+    webhooks::notify(repository, user_id, event, &task).await;
+    changes.publish(user_id, "task", action, vec![change._id]);
+
+    Ok(SyncPushResult::new(change._id, "task", "applied"))
+}
+
+async fn push_event_change(repository: &dyn Repository, operation: &mut OperationLog, user: &User, change: SyncChange<Event>, changes: &ChangeBus) -> Result<SyncPushResult, Error> {
+    let user_id = user._id;
+
+    let existing_event = match check_change(repository, "events", user_id, "event", &change).await? {
+        Ok(_existing_event) => _existing_event,
+        Err(result) => return Ok(result),
+    };
+
+    if change.operation == "delete" {
+        let Some(event) = existing_event else {
+            return Ok(SyncPushResult::new(change._id, "event", "not_found"))
+        };
+        // Events have nothing depending on them, so there's nothing to clean up along with them
+        if event.deleted_at.is_none() {
+            let now = bson::DateTime::now();
+            let deleted_event = bson::doc! {
+                "deleted_at": now,
+                "updated_at": now,
+            };
+            if !operation.apply_writes(repository, vec![DocumentWrite::new("events", change._id, event.updated_at, deleted_event)]).await? {
+                return conflict_result(repository, "events", "event", change._id).await
+            }
+            changes.publish(user_id, "event", "deleted", vec![change._id]);
+        }
+        return Ok(SyncPushResult::new(change._id, "event", "applied"))
+    }
+
+    let Some(mut event) = change.document else {
+        return Ok(SyncPushResult::new(change._id, "event", "invalid"))
+    };
+    let event_data = NewEventData {
+        task: event.task,
+        date: event.date.try_to_rfc3339_string().unwrap_or_default(),
+        notes: event.notes.clone(),
+        duration: event.duration,
+        quantity: event.quantity,
+        rating: event.rating,
+    };
+    let errors = validation_errors(&event_data);
+    if !errors.is_empty() {
+        return Ok(SyncPushResult::invalid(change._id, "event", errors))
+    }
+    match validate_task_owner(repository, user_id, "task", event.task).await {
+        Ok(_) => {},
+        Err(ActionError::Invalid(errors)) => return Ok(SyncPushResult::invalid(change._id, "event", errors)),
+        Err(ActionError::DatabaseError(error)) => return Err(error),
+    }
+
+    event.attachments = existing_event.as_ref().and_then(|_existing_event| _existing_event.attachments.clone());
+    event._id = change._id;
+    event.user = Some(user_id);
+    event.updated_at = Some(bson::DateTime::now());
+    event.deleted_at = None;
+
+    if !operation.apply_writes(repository, vec![upsert_write("events", existing_event.as_ref(), &event)?]).await? {
+        return conflict_result(repository, "events", "event", change._id).await
+    }
+
+    let action = match existing_event {
+        Some(_) => "updated",
+        None => {
+            webhooks::notify(repository, user_id, webhooks::EVENT_CREATED, &event).await;
+            "created"
+        },
+    };
+    changes.publish(user_id, "event", action, vec![change._id]);
+
+    Ok(SyncPushResult::new(change._id, "event", "applied"))
+}
+
+async fn push_tag_change(repository: &dyn Repository, operation: &mut OperationLog, user: &User, change: SyncChange<Tag>, changes: &ChangeBus) -> Result<SyncPushResult, Error> {
+    let user_id = user._id;
+
+    let existing_tag = match check_change(repository, "tags", user_id, "tag", &change).await? {
+        Ok(_existing_tag) => _existing_tag,
+        Err(result) => return Ok(result),
+    };
+
+    if change.operation == "delete" {
+        let Some(tag) = existing_tag else {
+            return Ok(SyncPushResult::new(change._id, "tag", "not_found"))
+        };
+        let summary = match delete_with_dependents(repository, operation, user, "tag", change._id, tag.updated_at).await {
+            Ok(_summary) => _summary,
+            Err(DeleteError::NotFound) => return Ok(SyncPushResult::new(change._id, "tag", "not_found")),
+            Err(DeleteError::ConcurrentChange) => return conflict_result(repository, "tags", "tag", change._id).await,
+            Err(DeleteError::DatabaseError(error)) => return Err(error),
+        };
+        changes.publish(user_id, "tag", "deleted", summary.deleted_tags);
+        changes.publish(user_id, "tag", "updated", summary.reparented_tags);
+        changes.publish(user_id, "task", "updated", summary.updated_tasks);
+        return Ok(SyncPushResult::new(change._id, "tag", "applied"))
+    }
+
+    let Some(mut tag) = change.document else {
+        return Ok(SyncPushResult::new(change._id, "tag", "invalid"))
+    };
+    let tag_data = NewTagData {
+        name: tag.name.clone(),
+        description: tag.description.clone(),
+        parent_tag: tag.parent_tag,
+        color: tag.color.clone(),
+        icon: tag.icon.clone(),
+        sort_order: tag.sort_order,
+        archived: tag.archived,
+    };
+    let errors = validation_errors(&tag_data);
+    if !errors.is_empty() {
+        return Ok(SyncPushResult::invalid(change._id, "tag", errors))
+    }
+    match validate_tag(repository, user_id, Some(change._id), &tag.name, tag.parent_tag, &tag.color, &tag.icon).await {
+        Ok(_) => {},
+        Err(TagError::DatabaseError(error)) => return Err(error),
+        Err(error) => {
+            let errors = tag_validation_errors(&error).unwrap_or_default();
+            return Ok(SyncPushResult::invalid(change._id, "tag", errors))
+        },
+    }
+
+    // Only a delete fills unlinked_tasks, and a live tag has nothing to re-link
+    tag.unlinked_tasks = None;
+    tag._id = change._id;
+    tag.user = Some(user_id);
+    tag.updated_at = Some(bson::DateTime::now());
+    tag.deleted_at = None;
+
+    if !operation.apply_writes(repository, vec![upsert_write("tags", existing_tag.as_ref(), &tag)?]).await? {
+        return conflict_result(repository, "tags", "tag", change._id).await
+    }
+
+    let action = if existing_tag.is_some() { "updated" } else { "created" };
+    changes.publish(user_id, "tag", action, vec![change._id]);
+
+    Ok(SyncPushResult::new(change._id, "tag", "applied"))
+}
+
+async fn push_sync_action(token: Token, push_data: SyncPushData, changes: &ChangeBus, meta: &RequestMeta, store: &Store) -> Result<Vec<SyncPushResult>, Error> {
+    let repository = store.backend.as_ref();
+
+    let user = token.0;
+
+    // Every write snapshots what it touches, dependents of deletes included
+    let mut operation = OperationLog::audit_only();
+
+    // Tags and tasks go first so events created offline can reference tasks created offline
+    let mut results = Vec::new();
+    for change in push_data.tags.unwrap_or_default() {
+        results.push(push_tag_change(repository, &mut operation, &user, change, changes).await?);
+    }
+    for change in push_data.tasks.unwrap_or_default() {
+        results.push(push_task_change(repository, &mut operation, &user, change, changes).await?);
+    }
+    for change in push_data.events.unwrap_or_default() {
+        results.push(push_event_change(repository, &mut operation, &user, change, changes).await?);
+    }
+    operation.record(repository, user._id, "push_sync", meta).await;
+
+    Ok(results)
+}
+
+#[get("/api/sync?<since>", format="json")]
+pub async fn read_sync(token: Token, since: Option<&str>, store: &State<Store>, config: &State<AppConfig>) -> Result<Json<SyncChanges>, Status> {
+    let since_date = match since {
+        Some(_since) => match decode_token(_since) {
+            Some(_since_date) => Some(_since_date),
+            None => return Err(Status::BadRequest),
+        },
+        None => None,
+    };
+    let sync = read_sync_action(token, since_date, store, config).await;
+
+    match sync {
+        Ok(sync_result) => Ok(Json(sync_result)),
+        Err(error) => {
+            error!(?error, "Couldn't read sync");
+            Err(Status::InternalServerError)
+        },
+    }
+}
+
+// Pull again after pushing; the push response deliberately doesn't hand out a new change token
+#[post("/api/sync", format="json", data="<push_data>")]
+pub async fn push_sync(token: Token, push_data: Json<SyncPushData>, changes: &State<ChangeBus>, meta: RequestMeta, store: &State<Store>) -> Result<Json<Vec<SyncPushResult>>, Status> {
+    let deserialized_push_data = push_data.into_inner();
+    let sync = push_sync_action(token, deserialized_push_data, changes, &meta, store).await;
+
+    match sync {
+        Ok(sync_result) => Ok(Json(sync_result)),
+        Err(error) => {
+            error!(?error, "Couldn't push sync");
+            Err(Status::InternalServerError)
+        },
+    }
+}
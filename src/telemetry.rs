@@ -0,0 +1,115 @@
+use mongodb::bson;
+use rocket::{Data, Request, Response, Route};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::route::{Handler, Outcome};
+use std::time::Instant;
+use tracing::{Instrument, Span, field, info, info_span};
+use tracing_subscriber::EnvFilter;
+
+use crate::config::{AppConfig, LogFormat};
+
+// Clients can send their own X-Request-Id to tie our logs to theirs, as long as it's sensible
+const MAX_REQUEST_ID_LENGTH: usize = 64;
+
+pub fn init(config: &AppConfig) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+
+    // Also picks up Rocket's own log output, so JSON logs stay JSON all the way through
+    let init_result = match config.log_format {
+        LogFormat::Text => subscriber.try_init(),
+        LogFormat::Json => subscriber.json().with_current_span(true).with_span_list(false).try_init(),
+    };
+    if let Err(error) = init_result {
+        eprintln!("Logging isn't set up: {}", error);
+    }
+}
+
+struct RequestTrace {
+    id: String,
+    span: Span,
+    started_at: Instant,
+}
+
+impl RequestTrace {
+    fn new(request: &Request<'_>) -> RequestTrace {
+        let id = match request.headers().get_one("X-Request-Id") {
+            Some(_id) if is_valid_request_id(_id) => _id.to_string(),
+            _ => bson::uuid::Uuid::new().to_string(),
+        };
+        // Only the route's template is logged, never the URI, since some paths hold secrets like calendar feed ones
+        let span = info_span!(
+            "request",
+            request_id = %id,
+            method = %request.method(),
+            route = field::Empty,
+            user_id = field::Empty,
+            status = field::Empty,
+            latency_ms = field::Empty,
+        );
+        RequestTrace {
+            id,
+            span,
+            started_at: Instant::now(),
+        }
+    }
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LENGTH && id.chars().all(|character| character.is_ascii_alphanumeric() || character == '-')
+}
+
+// The span everything logged while handling this request belongs to
+pub fn request_span(request: &Request<'_>) -> Span {
+    request.local_cache(|| RequestTrace::new(request)).span.clone()
+}
+
+pub struct RequestTracing;
+
+#[rocket::async_trait]
+impl Fairing for RequestTracing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request tracing",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| RequestTrace::new(request));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let trace = request.local_cache(|| RequestTrace::new(request));
+        let latency = trace.started_at.elapsed();
+
+        trace.span.record("status", response.status().code);
+        trace.span.record("latency_ms", latency.as_millis() as u64);
+        trace.span.in_scope(|| info!("Finished request"));
+
+        response.set_header(Header::new("X-Request-Id", trace.id.clone()));
+    }
+}
+
+// Runs a route's handler, guards included, inside the request's span
+#[derive(Clone)]
+struct Traced(Box<dyn Handler>);
+
+#[rocket::async_trait]
+impl Handler for Traced {
+    async fn handle<'r>(&self, request: &'r Request<'_>, data: Data<'r>) -> Outcome<'r> {
+        let span = request_span(request);
+        if let Some(route) = request.route() {
+            span.record("route", route.uri.path());
+        }
+        self.0.handle(request, data).instrument(span).await
+    }
+}
+
+pub fn traced(routes: Vec<Route>) -> Vec<Route> {
+    routes.into_iter().map(|mut route| {
+        route.handler = Box::new(Traced(route.handler));
+        route
+    }).collect()
+}
@@ -0,0 +1,1365 @@
+use mongodb::{Client as MongoClient, Database, options::ClientOptions};
+use mongodb::bson;
+use rocket::figment::Figment;
+use rocket::http::{ContentType, Header, Status};
+use rocket::local::asynchronous::{Client, LocalResponse};
+use rocket::serde::json::{Value, json};
+use std::sync::Once;
+
+use super::{Event, Tag, Task, User, rocket};
+use crate::config::{self, AppConfig, ConfigError};
+use crate::repository::Store;
+
+static SETUP: Once = Once::new();
+
+// Every client gets a fresh in-memory store. Running with ROCKET_DATABASE_BACKEND=mongodb puts the
+// routes on a throwaway database shared by the run instead.
+fn setup() {
+    SETUP.call_once(|| {
+        if std::env::var("ROCKET_DATABASE_BACKEND").is_err() {
+            std::env::set_var("ROCKET_DATABASE_BACKEND", "memory");
+        }
+        std::env::set_var("ROCKET_DATABASE_NAME", format!("mossy_test_{}", bson::oid::ObjectId::new().to_hex()));
+        std::env::set_var("ROCKET_APPLE_CLIENT_IDS", "com.example.mossy");
+        std::env::set_var("ROCKET_ATTACHMENT_DIR", std::env::temp_dir().join("mossy_test_attachments"));
+    });
+}
+
+async fn client() -> Client {
+    setup();
+    Client::tracked(rocket().await).await.expect("valid rocket instance")
+}
+
+fn new_user(is_admin: bool) -> User {
+    let _id = bson::oid::ObjectId::new();
+    User {
+        _id,
+        email: format!("{}@example.com", _id.to_hex()),
+        apple_user_id: format!("apple-{}", _id.to_hex()),
+        token: bson::uuid::Uuid::new().to_string(),
+        token_issued_at: Some(bson::DateTime::now()),
+        is_admin,
+        should_color_scheme_use_system: false,
+        is_color_scheme_dark_mode: false,
+        color_theme: 1,
+        digest_frequency: None,
+        timezone: None,
+        digest_hour: None,
+        last_digest_sent_at: None,
+        last_digest_attempt_at: None,
+        digest_failed_attempts: None,
+        calendar_secret: None,
+    }
+}
+
+async fn insert_user(client: &Client, user: User) -> User {
+    client.rocket().state::<Store>().unwrap().backend.insert_user(&user).await.unwrap();
+    user
+}
+
+async fn seed_user(client: &Client) -> User {
+    insert_user(client, new_user(false)).await
+}
+
+async fn seed_admin(client: &Client) -> User {
+    insert_user(client, new_user(true)).await
+}
+
+fn new_task(user: &User, name: &str, tags: Option<Vec<bson::oid::ObjectId>>) -> Task {
+    Task {
+        _id: bson::oid::ObjectId::new(),
+        name: name.to_string(),
+        frequency: 7,
+        tags,
+        attachments: None,
+        user: Some(user._id),
+        updated_at: Some(bson::DateTime::now()),
+        deleted_at: None,
+    }
+}
+
+fn new_event(user: &User, task: &Task, days: i64) -> Event {
+    Event {
+        _id: bson::oid::ObjectId::new(),
+        task: task._id,
+        date: bson::DateTime::from_chrono(chrono::Utc::now() - chrono::Duration::days(days)),
+        notes: None,
+        duration: Some(600),
+        quantity: None,
+        rating: Some(4),
+        attachments: None,
+        user: Some(user._id),
+        updated_at: Some(bson::DateTime::now()),
+        deleted_at: None,
+    }
+}
+
+fn new_tag(user: &User, name: &str, parent_tag: Option<bson::oid::ObjectId>) -> Tag {
+    Tag {
+        _id: bson::oid::ObjectId::new(),
+        name: name.to_string(),
+        description: None,
+        parent_tag,
+        color: None,
+        icon: None,
+        sort_order: None,
+        archived: None,
+        user: Some(user._id),
+        updated_at: Some(bson::DateTime::now()),
+        deleted_at: None,
+        unlinked_tasks: None,
+    }
+}
+
+async fn seed_task(client: &Client, user: &User, name: &str, tags: Option<Vec<bson::oid::ObjectId>>) -> Task {
+    let task = new_task(user, name, tags);
+    client.rocket().state::<Store>().unwrap().backend.insert_task(&task).await.unwrap();
+    task
+}
+
+async fn seed_event(client: &Client, user: &User, task: &Task, days: i64) -> Event {
+    let event = new_event(user, task, days);
+    client.rocket().state::<Store>().unwrap().backend.insert_event(&event).await.unwrap();
+    event
+}
+
+async fn seed_tag(client: &Client, user: &User, name: &str, parent_tag: Option<bson::oid::ObjectId>) -> Tag {
+    let tag = new_tag(user, name, parent_tag);
+    client.rocket().state::<Store>().unwrap().backend.insert_tag(&tag).await.unwrap();
+    tag
+}
+
+fn auth(user: &User) -> Header<'static> {
+    Header::new("Authorization", format!("Bearer {}", user.token))
+}
+
+fn oid(value: &Value) -> String {
+    value["$oid"].as_str().unwrap().to_string()
+}
+
+fn days_ago(days: i64) -> String {
+    (chrono::Utc::now() - chrono::Duration::days(days)).to_rfc3339()
+}
+
+async fn json_body(response: LocalResponse<'_>) -> Value {
+    response.into_json::<Value>().await.unwrap()
+}
+
+async fn get<'c>(client: &'c Client, user: &User, uri: String) -> LocalResponse<'c> {
+    client.get(uri).header(auth(user)).dispatch().await
+}
+
+async fn send<'c>(client: &'c Client, user: &User, method: rocket::http::Method, uri: &str, body: Value) -> LocalResponse<'c> {
+    client.req(method, uri.to_string()).header(auth(user)).header(ContentType::JSON).body(body.to_string()).dispatch().await
+}
+
+async fn post<'c>(client: &'c Client, user: &User, uri: &str, body: Value) -> LocalResponse<'c> {
+    send(client, user, rocket::http::Method::Post, uri, body).await
+}
+
+async fn patch<'c>(client: &'c Client, user: &User, uri: &str, body: Value) -> LocalResponse<'c> {
+    send(client, user, rocket::http::Method::Patch, uri, body).await
+}
+
+async fn delete<'c>(client: &'c Client, user: &User, uri: &str, body: Value) -> LocalResponse<'c> {
+    send(client, user, rocket::http::Method::Delete, uri, body).await
+}
+
+async fn create_task(client: &Client, user: &User, name: &str, frequency: i32) -> String {
+    let response = post(client, user, "/api/tasks", json!({ "name": name, "frequency": frequency })).await;
+    assert_eq!(response.status(), Status::Ok);
+    oid(&json_body(response).await["insertedId"])
+}
+
+async fn create_event(client: &Client, user: &User, task: &str, date: String) -> String {
+    let response = post(client, user, "/api/events", json!({ "task": { "$oid": task }, "date": date })).await;
+    assert_eq!(response.status(), Status::Ok);
+    oid(&json_body(response).await["insertedId"])
+}
+
+async fn create_tag<'c>(client: &'c Client, user: &User, body: Value) -> LocalResponse<'c> {
+    post(client, user, "/api/tags", body).await
+}
+
+fn ids(list: &Value) -> Vec<String> {
+    list.as_array().unwrap().iter().map(|item| oid(&item["_id"])).collect()
+}
+
+#[rocket::async_test]
+async fn index_says_hello() {
+    let client = client().await;
+    let response = client.get("/").dispatch().await;
+
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.into_string().await.unwrap(), "Hello, world!");
+}
+
+#[rocket::async_test]
+async fn responses_carry_a_request_id() {
+    let client = client().await;
+
+    let response = client.get("/").dispatch().await;
+    let request_id = response.headers().get_one("X-Request-Id").unwrap();
+    assert_eq!(request_id.len(), 36);
+
+    let response = client.get("/").header(Header::new("X-Request-Id", "client-abc-123")).dispatch().await;
+    assert_eq!(response.headers().get_one("X-Request-Id"), Some("client-abc-123"));
+
+    // Nothing that could break a log line gets echoed back
+    let response = client.get("/").header(Header::new("X-Request-Id", "abc\", \"injected")).dispatch().await;
+    assert_ne!(response.headers().get_one("X-Request-Id"), Some("abc\", \"injected"));
+}
+
+#[rocket::async_test]
+async fn probes_report_health_and_readiness() {
+    let client = client().await;
+
+    let response = client.get("/healthz").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(json_body(response).await["status"], "alive");
+
+    // Apple's keys may or may not have been fetched yet, which doesn't hold up readiness
+    let response = client.get("/readyz").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let body = json_body(response).await;
+    assert_eq!(body["ready"], true);
+    assert_eq!(body["shutting_down"], false);
+    assert_eq!(body["database"]["ok"], true);
+    assert_eq!(body["migrations"]["ok"], true);
+    assert!(body["apple_keys"]["ok"].is_boolean());
+
+    client.rocket().shutdown().notify();
+    let response = client.get("/readyz").dispatch().await;
+    assert_eq!(response.status(), Status::ServiceUnavailable);
+    assert_eq!(json_body(response).await["shutting_down"], true);
+}
+
+#[rocket::async_test]
+async fn metrics_count_requests_and_activity() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+    let task = create_task(&client, &user, "Water plants", 7).await;
+    create_event(&client, &user, &task, days_ago(0)).await;
+
+    let response = client.get("/metrics").dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let body = response.into_string().await.unwrap();
+    assert!(body.contains("mossy_events_logged_last_hour 1"));
+    assert!(body.contains("mossy_users 1"));
+    // Labelled by the route's template, not the URI
+    assert!(body.contains("route=\"/api/events\""));
+}
+
+#[rocket::async_test]
+async fn routes_without_a_token_are_rejected() {
+    use rocket::http::Method::{Delete, Get, Patch, Post};
+
+    let client = client().await;
+    let id = bson::oid::ObjectId::new().to_hex();
+    let routes = vec![
+        (Post, String::from("/api/user"), Some(json!({ "apple_user_id": "apple" }))),
+        (Patch, String::from("/api/user/theme"), Some(json!({}))),
+        (Patch, String::from("/api/user/digest"), Some(json!({}))),
+        (Get, String::from("/api/tasks"), None),
+        (Get, String::from("/api/tasks/stats"), None),
+        (Post, String::from("/api/tasks"), Some(json!({}))),
+        (Patch, String::from("/api/tasks"), Some(json!({}))),
+        (Delete, String::from("/api/tasks"), Some(json!([]))),
+        (Patch, String::from("/api/tasks/tags"), Some(json!({}))),
+        (Get, String::from("/api/events"), None),
+        (Get, String::from("/api/events-string"), None),
+        (Post, String::from("/api/events"), Some(json!({}))),
+        (Post, String::from("/api/events/batch"), Some(json!({}))),
+        (Patch, String::from("/api/events"), Some(json!({}))),
+        (Delete, String::from("/api/events"), Some(json!([]))),
+        (Get, String::from("/api/tags"), None),
+        (Get, String::from("/api/tags/tree"), None),
+        (Post, String::from("/api/tags"), Some(json!({}))),
+        (Patch, String::from("/api/tags"), Some(json!({}))),
+        (Delete, String::from("/api/tags"), Some(json!([]))),
+        (Post, String::from("/api/tags/merge"), Some(json!({}))),
+        (Get, String::from("/api/sync"), None),
+        (Post, String::from("/api/sync"), Some(json!({}))),
+        (Get, String::from("/api/changes"), None),
+        (Post, String::from("/api/calendar/feed-url"), Some(json!({}))),
+        (Get, String::from("/api/webhooks"), None),
+        (Post, String::from("/api/webhooks"), Some(json!({}))),
+        (Delete, String::from("/api/webhooks"), Some(json!([]))),
+        (Get, String::from("/api/webhooks/deliveries"), None),
+        (Post, String::from("/api/attachments"), None),
+        (Get, String::from("/api/attachments"), None),
+        (Get, String::from("/api/attachments/usage"), None),
+        (Get, format!("/api/attachments/{}", id), None),
+        (Get, format!("/api/attachments/{}/thumbnail", id), None),
+        (Delete, String::from("/api/attachments"), Some(json!([]))),
+        (Get, String::from("/api/trash"), None),
+        (Post, String::from("/api/trash/tasks/restore"), Some(json!([]))),
+        (Post, String::from("/api/trash/events/restore"), Some(json!([]))),
+        (Post, String::from("/api/trash/tags/restore"), Some(json!([]))),
+        (Post, String::from("/api/undo"), Some(json!({}))),
+        (Get, String::from("/api/audit"), None),
+        (Post, String::from("/api/debug/tasks"), Some(json!({ "quantity": 1 }))),
+        (Delete, String::from("/api/debug/tasks"), None),
+        (Post, String::from("/api/debug/events"), None),
+        (Delete, String::from("/api/debug/events"), None),
+        (Post, String::from("/api/debug/tags"), Some(json!({ "quantity": 1 }))),
+        (Delete, String::from("/api/debug/tags"), None),
+    ];
+
+    for (method, uri, body) in routes {
+        let mut request = client.req(method, uri.clone()).header(ContentType::JSON);
+        if let Some(_body) = body {
+            request = request.body(_body.to_string());
+        }
+        let response = request.dispatch().await;
+        assert_eq!(response.status(), Status::BadRequest, "{} {}", method, uri);
+    }
+}
+
+#[rocket::async_test]
+async fn unknown_tokens_are_unauthorized() {
+    let client = client().await;
+    // Never saved, so nobody has its token
+    let stranger = new_user(false);
+
+    for uri in ["/api/tasks", "/api/tags/tree", "/api/trash", "/api/sync", "/api/changes", "/api/audit"] {
+        let response = get(&client, &stranger, String::from(uri)).await;
+        assert_eq!(response.status(), Status::Unauthorized, "{}", uri);
+    }
+
+    let response = client.get("/api/tasks").header(Header::new("Authorization", "Bearer")).dispatch().await;
+    assert_eq!(response.status(), Status::Unauthorized);
+    let response = client.get("/api/tasks").header(Header::new("Authorization", "Bearer ")).dispatch().await;
+    assert_eq!(response.status(), Status::Unauthorized);
+}
+
+#[rocket::async_test]
+async fn log_in_rejects_malformed_credentials() {
+    let client = client().await;
+    let response = client.post("/api/log-in").header(ContentType::JSON).body("{}").dispatch().await;
+
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+}
+
+#[rocket::async_test]
+async fn read_user_returns_the_token_owner() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+
+    let response = post(&client, &user, "/api/user", json!({ "apple_user_id": user.apple_user_id })).await;
+    assert_eq!(response.status(), Status::Ok);
+    let body = json_body(response).await;
+    assert_eq!(oid(&body["_id"]), user._id.to_hex());
+
+    // A valid token can't be used to read someone else's account
+    let other_user = seed_user(&client).await;
+    let response = post(&client, &user, "/api/user", json!({ "apple_user_id": other_user.apple_user_id })).await;
+    assert_eq!(response.status(), Status::Forbidden);
+    let theme = json!({
+        "apple_user_id": other_user.apple_user_id,
+        "should_color_scheme_use_system": true,
+        "is_color_scheme_dark_mode": true,
+        "color_theme": 3,
+    });
+    let response = patch(&client, &user, "/api/user/theme", theme).await;
+    assert_eq!(response.status(), Status::Forbidden);
+}
+
+#[rocket::async_test]
+async fn user_settings_are_saved() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+
+    let theme = json!({
+        "apple_user_id": user.apple_user_id,
+        "should_color_scheme_use_system": true,
+        "is_color_scheme_dark_mode": true,
+        "color_theme": 3,
+    });
+    let response = patch(&client, &user, "/api/user/theme", theme).await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(json_body(response).await["matchedCount"], 1);
+
+    let digest = json!({
+        "apple_user_id": user.apple_user_id,
+        "digest_frequency": "daily",
+        "timezone": "Europe/Berlin",
+        "digest_hour": 7,
+    });
+    let response = patch(&client, &user, "/api/user/digest", digest).await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let response = post(&client, &user, "/api/user", json!({ "apple_user_id": user.apple_user_id })).await;
+    let body = json_body(response).await;
+    assert_eq!(body["color_theme"], 3);
+    assert_eq!(body["is_color_scheme_dark_mode"], true);
+    assert_eq!(body["timezone"], "Europe/Berlin");
+    assert_eq!(body["digest_hour"], 7);
+}
+
+#[rocket::async_test]
+async fn invalid_digest_settings_are_rejected() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+
+    for digest in [
+        json!({ "apple_user_id": user.apple_user_id, "digest_frequency": "hourly" }),
+        json!({ "apple_user_id": user.apple_user_id, "timezone": "Mars/Olympus_Mons" }),
+        json!({ "apple_user_id": user.apple_user_id, "digest_hour": 24 }),
+    ] {
+        let response = patch(&client, &user, "/api/user/digest", digest).await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+    }
+}
+
+fn error_fields(body: &Value) -> Vec<&str> {
+    body["errors"].as_array().unwrap().iter().map(|error| error["field"].as_str().unwrap()).collect()
+}
+
+#[rocket::async_test]
+async fn invalid_payloads_get_field_errors() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+
+    let response = post(&client, &user, "/api/tasks", json!({ "name": " ", "frequency": 0 })).await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    assert_eq!(error_fields(&json_body(response).await), vec!["name", "frequency"]);
+
+    let response = post(&client, &user, "/api/tasks", json!({ "name": "x".repeat(201), "frequency": 7 })).await;
+    assert_eq!(error_fields(&json_body(response).await), vec!["name"]);
+
+    let response = post(&client, &user, "/api/tasks", json!({ "name": "Mow lawn" })).await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    assert_eq!(error_fields(&json_body(response).await), vec!["body"]);
+
+    let task = create_task(&client, &user, "Mow lawn", 14).await;
+    let response = post(&client, &user, "/api/events", json!({ "task": { "$oid": task }, "date": days_ago(-30), "rating": 0 })).await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    assert_eq!(error_fields(&json_body(response).await), vec!["date", "rating"]);
+}
+
+#[rocket::async_test]
+async fn references_must_belong_to_the_user() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+    let other_user = seed_user(&client).await;
+
+    let other_users_task = create_task(&client, &other_user, "Not yours", 7).await;
+    let response = create_tag(&client, &other_user, json!({ "name": "Not yours either" })).await;
+    let other_users_tag = oid(&json_body(response).await["insertedId"]);
+
+    let response = post(&client, &user, "/api/events", json!({ "task": { "$oid": other_users_task }, "date": days_ago(0) })).await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    assert_eq!(error_fields(&json_body(response).await), vec!["task"]);
+
+    let response = post(&client, &user, "/api/tasks", json!({ "name": "Mow lawn", "frequency": 7, "tags": [{ "$oid": other_users_tag }] })).await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    assert_eq!(error_fields(&json_body(response).await), vec!["tags"]);
+
+    let task = create_task(&client, &user, "Mow lawn", 7).await;
+    let response = patch(&client, &user, "/api/tasks", json!({ "_id": { "$oid": task }, "tags": [{ "$oid": other_users_tag }] })).await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+
+    let response = get(&client, &other_user, String::from("/api/events")).await;
+    assert_eq!(json_body(response).await, json!([]));
+}
+
+#[rocket::async_test]
+async fn tasks_are_sorted_by_moss() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+
+    let weekly = create_task(&client, &user, "Water plants", 7).await;
+    let daily = create_task(&client, &user, "Feed cat", 1).await;
+    let never_done = create_task(&client, &user, "Descale kettle", 30).await;
+    create_event(&client, &user, &weekly, days_ago(3)).await;
+    create_event(&client, &user, &daily, days_ago(3)).await;
+    // Only the latest event counts
+    create_event(&client, &user, &daily, days_ago(20)).await;
+
+    let response = get(&client, &user, String::from("/api/tasks")).await;
+    assert_eq!(response.status(), Status::Ok);
+    let body = json_body(response).await;
+    assert_eq!(ids(&body), vec![daily, weekly, never_done]);
+
+    let day = 24 * 60 * 60 * 1000;
+    let daily_moss = body[0]["moss"].as_i64().unwrap();
+    assert!(daily_moss > 2 * day - 60_000 && daily_moss <= 2 * day + 60_000);
+    assert!(body[1]["moss"].as_i64().unwrap() < 0);
+    assert!(body[2]["moss"].is_null());
+    assert!(body[2].get("latest_event_date").is_none());
+}
+
+#[rocket::async_test]
+async fn task_pages_cover_every_task_once() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+
+    let mut created = Vec::new();
+    for index in 0..5 {
+        created.push(create_task(&client, &user, &format!("Task {}", index), 7).await);
+    }
+
+    let mut paged = Vec::new();
+    for offset in [0, 2, 4] {
+        let response = get(&client, &user, format!("/api/tasks?limit=2&offset={}", offset)).await;
+        let page = ids(&json_body(response).await);
+        assert_eq!(page.len(), if offset == 4 { 1 } else { 2 });
+        paged.extend(page);
+    }
+    paged.sort();
+    created.sort();
+    assert_eq!(paged, created);
+
+    let response = get(&client, &user, String::from("/api/tasks?offset=5")).await;
+    assert_eq!(json_body(response).await, json!([]));
+
+    // No limit means every task
+    let response = get(&client, &user, String::from("/api/tasks?offset=1")).await;
+    assert_eq!(ids(&json_body(response).await).len(), 4);
+}
+
+#[rocket::async_test]
+async fn tasks_are_private_to_their_owner() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+    let other_user = seed_user(&client).await;
+
+    let task = create_task(&client, &user, "Mow lawn", 14).await;
+
+    let response = get(&client, &other_user, String::from("/api/tasks")).await;
+    assert_eq!(json_body(response).await, json!([]));
+
+    let response = patch(&client, &other_user, "/api/tasks", json!({ "_id": { "$oid": task }, "name": "Mine now", "frequency": 1 })).await;
+    assert_eq!(response.status(), Status::NotFound);
+
+    let response = get(&client, &user, String::from("/api/tasks")).await;
+    assert_eq!(json_body(response).await[0]["name"], "Mow lawn");
+
+    let missing_task = bson::oid::ObjectId::new().to_hex();
+    let response = patch(&client, &user, "/api/tasks", json!({ "_id": { "$oid": missing_task }, "name": "Ghost", "frequency": 1 })).await;
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[rocket::async_test]
+async fn tasks_can_be_updated() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+
+    let task = create_task(&client, &user, "Mow lawn", 14).await;
+    let response = patch(&client, &user, "/api/tasks", json!({ "_id": { "$oid": task }, "name": "Mow back lawn", "frequency": 10 })).await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(json_body(response).await["modifiedCount"], 1);
+
+    let response = get(&client, &user, String::from("/api/tasks")).await;
+    let body = json_body(response).await;
+    assert_eq!(body[0]["name"], "Mow back lawn");
+    assert_eq!(body[0]["frequency"], 10);
+}
+
+#[rocket::async_test]
+async fn updates_only_change_the_fields_sent() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+
+    let response = create_tag(&client, &user, json!({ "name": "Garden", "description": "Out the back", "color": "#34C759" })).await;
+    let garden = oid(&json_body(response).await["insertedId"]);
+    let response = post(&client, &user, "/api/tasks", json!({ "name": "Mow lawn", "frequency": 14, "tags": [{ "$oid": garden }] })).await;
+    let task = oid(&json_body(response).await["insertedId"]);
+
+    let response = patch(&client, &user, "/api/tasks", json!({ "_id": { "$oid": task }, "frequency": 10 })).await;
+    assert_eq!(response.status(), Status::Ok);
+    let response = get(&client, &user, String::from("/api/tasks")).await;
+    let body = json_body(response).await;
+    assert_eq!(body[0]["name"], "Mow lawn");
+    assert_eq!(body[0]["frequency"], 10);
+    assert_eq!(body[0]["tags"], json!([{ "$oid": garden }]));
+
+    let response = patch(&client, &user, "/api/tasks", json!({ "_id": { "$oid": task }, "tags": null })).await;
+    assert_eq!(response.status(), Status::Ok);
+    let response = get(&client, &user, String::from("/api/tasks")).await;
+    assert!(json_body(response).await[0]["tags"].is_null());
+
+    let response = patch(&client, &user, "/api/tags", json!({ "_id": { "$oid": garden }, "description": null })).await;
+    assert_eq!(response.status(), Status::Ok);
+    let response = get(&client, &user, String::from("/api/tags")).await;
+    let body = json_body(response).await;
+    assert_eq!(body[0]["name"], "Garden");
+    assert_eq!(body[0]["color"], "#34C759");
+    assert!(body[0]["description"].is_null());
+}
+
+#[rocket::async_test]
+async fn stale_task_updates_are_rejected() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+
+    let task = create_task(&client, &user, "Mow lawn", 14).await;
+    let update = json!({ "_id": { "$oid": task }, "name": "Mow back lawn", "frequency": 10 });
+
+    let response = patch(&client, &user, "/api/tasks", update.clone()).await;
+    assert_eq!(response.status(), Status::Ok);
+    let first_etag = response.headers().get_one("ETag").unwrap().to_string();
+
+    let response = client.patch("/api/tasks")
+        .header(auth(&user))
+        .header(ContentType::JSON)
+        .header(Header::new("If-Match", first_etag.clone()))
+        .body(json!({ "_id": { "$oid": task }, "name": "Mow front lawn", "frequency": 10 }).to_string())
+        .dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let second_etag = response.headers().get_one("ETag").unwrap().to_string();
+    assert_ne!(first_etag, second_etag);
+
+    // Another device still has the first version
+    let response = client.patch("/api/tasks")
+        .header(auth(&user))
+        .header(ContentType::JSON)
+        .header(Header::new("If-Match", first_etag))
+        .body(json!({ "_id": { "$oid": task }, "name": "Mow lawn", "frequency": 1 }).to_string())
+        .dispatch().await;
+    assert_eq!(response.status(), Status::Conflict);
+    assert_eq!(response.headers().get_one("ETag").unwrap(), second_etag);
+    assert_eq!(json_body(response).await["name"], "Mow front lawn");
+}
+
+#[rocket::async_test]
+async fn events_are_listed_newest_first() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+
+    let task = create_task(&client, &user, "Water plants", 7).await;
+    let oldest = create_event(&client, &user, &task, days_ago(9)).await;
+    let newest = create_event(&client, &user, &task, days_ago(1)).await;
+    let middle = create_event(&client, &user, &task, days_ago(5)).await;
+
+    let response = get(&client, &user, String::from("/api/events")).await;
+    assert_eq!(ids(&json_body(response).await), vec![newest.clone(), middle.clone(), oldest.clone()]);
+
+    let response = get(&client, &user, String::from("/api/events?limit=1&offset=1")).await;
+    assert_eq!(ids(&json_body(response).await), vec![middle]);
+
+    let response = get(&client, &user, String::from("/api/events?offset=3")).await;
+    assert_eq!(json_body(response).await, json!([]));
+
+    let response = get(&client, &user, String::from("/api/events-string?limit=1")).await;
+    let body = json_body(response).await;
+    assert_eq!(oid(&body[0]["_id"]), newest);
+    assert_eq!(body[0]["task"], "Water plants");
+}
+
+#[rocket::async_test]
+async fn events_can_be_updated() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+    let other_user = seed_user(&client).await;
+
+    let task = create_task(&client, &user, "Water plants", 7).await;
+    let event = create_event(&client, &user, &task, days_ago(2)).await;
+
+    let update = json!({ "_id": { "$oid": event }, "date": days_ago(1), "notes": "Used the rain water", "rating": 4 });
+    let response = patch(&client, &user, "/api/events", update.clone()).await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let response = get(&client, &user, String::from("/api/events")).await;
+    let body = json_body(response).await;
+    assert_eq!(body[0]["notes"], "Used the rain water");
+    assert_eq!(body[0]["rating"], 4);
+
+    let response = patch(&client, &other_user, "/api/events", update).await;
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[rocket::async_test]
+async fn invalid_event_details_are_rejected() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+
+    let task = create_task(&client, &user, "Water plants", 7).await;
+    for details in [json!({ "rating": 6 }), json!({ "duration": -1 }), json!({ "quantity": -0.5 })] {
+        let mut event = json!({ "task": { "$oid": task }, "date": days_ago(0) });
+        event.as_object_mut().unwrap().extend(details.as_object().unwrap().clone());
+        let response = post(&client, &user, "/api/events", event).await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+    }
+
+    let response = get(&client, &user, String::from("/api/events")).await;
+    assert_eq!(json_body(response).await, json!([]));
+}
+
+#[rocket::async_test]
+async fn tags_are_listed_in_order() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+
+    let mut created = Vec::new();
+    for (name, sort_order) in [("Garden", Some(2)), ("Kitchen", Some(1)), ("Bathroom", Some(1)), ("Attic", None)] {
+        let response = create_tag(&client, &user, json!({ "name": name, "sort_order": sort_order })).await;
+        assert_eq!(response.status(), Status::Ok);
+        created.push(oid(&json_body(response).await["insertedId"]));
+    }
+
+    let response = get(&client, &user, String::from("/api/tags")).await;
+    let body = json_body(response).await;
+    let names: Vec<&str> = body.as_array().unwrap().iter().map(|tag| tag["name"].as_str().unwrap()).collect();
+    assert_eq!(names, vec!["Attic", "Bathroom", "Kitchen", "Garden"]);
+
+    let response = get(&client, &user, String::from("/api/tags?limit=2&offset=2")).await;
+    assert_eq!(ids(&json_body(response).await), vec![created[1].clone(), created[0].clone()]);
+}
+
+#[rocket::async_test]
+async fn invalid_tags_are_rejected() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+    let other_user = seed_user(&client).await;
+
+    let response = create_tag(&client, &user, json!({ "name": "Garden" })).await;
+    let garden = oid(&json_body(response).await["insertedId"]);
+    let response = create_tag(&client, &user, json!({ "name": "Beds", "parent_tag": { "$oid": garden } })).await;
+    assert_eq!(response.status(), Status::Ok);
+    let beds = oid(&json_body(response).await["insertedId"]);
+    let response = create_tag(&client, &other_user, json!({ "name": "Elsewhere" })).await;
+    let other_users_tag = oid(&json_body(response).await["insertedId"]);
+
+    for tag in [
+        json!({ "name": "garden" }),
+        json!({ "name": "Pots", "color": "green" }),
+        json!({ "name": "Pots", "icon": "leaf fill" }),
+        json!({ "name": "Pots", "parent_tag": { "$oid": other_users_tag } }),
+    ] {
+        let response = create_tag(&client, &user, tag.clone()).await;
+        assert_eq!(response.status(), Status::UnprocessableEntity, "{}", tag);
+    }
+
+    // Same name under a different parent is fine
+    let response = create_tag(&client, &user, json!({ "name": "Garden", "parent_tag": { "$oid": beds } })).await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let cycle = json!({ "_id": { "$oid": garden }, "name": "Garden", "parent_tag": { "$oid": beds } });
+    let response = patch(&client, &user, "/api/tags", cycle).await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+}
+
+#[rocket::async_test]
+async fn tags_can_be_updated() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+    let other_user = seed_user(&client).await;
+
+    let response = create_tag(&client, &user, json!({ "name": "Garden" })).await;
+    let garden = oid(&json_body(response).await["insertedId"]);
+
+    let update = json!({ "_id": { "$oid": garden }, "name": "Back garden", "color": "#34C759", "archived": true });
+    let response = patch(&client, &user, "/api/tags", update.clone()).await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let response = get(&client, &user, String::from("/api/tags")).await;
+    let body = json_body(response).await;
+    assert_eq!(body[0]["name"], "Back garden");
+    assert_eq!(body[0]["color"], "#34C759");
+
+    let response = patch(&client, &other_user, "/api/tags", update).await;
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[test]
+fn invalid_config_lists_every_problem() {
+    let figment = Figment::new()
+        .merge(("database_uri", "localhost:27017"))
+        .merge(("database_name", "mossy.test"))
+        .merge(("token_ttl_days", 0));
+
+    match AppConfig::from_figment(&figment) {
+        Err(ConfigError::InvalidError(problems)) => {
+            assert_eq!(problems.len(), 4);
+            assert!(problems[0].starts_with("database_uri"));
+            assert!(problems[3].starts_with("token_ttl_days"));
+        },
+        other => panic!("expected an invalid config, got {:?}", other),
+    }
+
+    let figment = Figment::new().merge(("database_backend", "postgres"));
+    assert!(matches!(AppConfig::from_figment(&figment), Err(ConfigError::ExtractError(_))));
+}
+
+#[test]
+fn invalid_attachment_storage_is_a_config_problem() {
+    let figment = Figment::new()
+        .merge(("apple_client_ids", "com.example.mossy"))
+        .merge(("attachment_quota_mb", 0))
+        .merge(("attachment_storage", "s3"))
+        .merge(("s3_endpoint", "minio:9000"))
+        .merge(("s3_access_key_id", "mossy"));
+
+    match AppConfig::from_figment(&figment) {
+        Err(ConfigError::InvalidError(problems)) => {
+            assert!(problems.iter().any(|problem| problem.starts_with("attachment_quota_mb")));
+            assert!(problems.iter().any(|problem| problem.starts_with("s3_endpoint")));
+            assert!(problems.iter().any(|problem| problem.starts_with("s3_access_key_id")));
+        },
+        other => panic!("expected an invalid config, got {:?}", other),
+    }
+}
+
+#[test]
+fn public_base_url_needs_a_scheme() {
+    let figment = Figment::new()
+        .merge(("apple_client_ids", "com.example.mossy"))
+        .merge(("public_base_url", "mossy.example.com"));
+
+    match AppConfig::from_figment(&figment) {
+        Err(ConfigError::InvalidError(problems)) => assert_eq!(problems, vec!["public_base_url: must start with http:// or https://"]),
+        other => panic!("expected an invalid config, got {:?}", other),
+    }
+}
+
+#[test]
+fn invalid_digest_and_trash_settings_are_config_problems() {
+    let figment = Figment::new()
+        .merge(("apple_client_ids", "com.example.mossy"))
+        .merge(("trash_retention_days", 0))
+        .merge(("undo_window_seconds", 0))
+        .merge(("smtp_host", "mail.example.com"))
+        .merge(("smtp_username", "mossy"));
+
+    match AppConfig::from_figment(&figment) {
+        Err(ConfigError::InvalidError(problems)) => {
+            assert!(problems.iter().any(|problem| problem.starts_with("trash_retention_days")));
+            assert!(problems.iter().any(|problem| problem.starts_with("undo_window_seconds")));
+            assert!(problems.iter().any(|problem| problem.starts_with("smtp_from")));
+            assert!(problems.iter().any(|problem| problem.starts_with("smtp_username")));
+        },
+        other => panic!("expected an invalid config, got {:?}", other),
+    }
+
+    let figment = Figment::new()
+        .merge(("apple_client_ids", "com.example.mossy"))
+        .merge(("smtp_host", "mail.example.com"))
+        .merge(("smtp_security", "starttls"))
+        .merge(("smtp_from", "not an address"));
+    match AppConfig::from_figment(&figment) {
+        Err(ConfigError::InvalidError(problems)) => assert_eq!(problems, vec!["smtp_from: must be an email address, e.g. \"mossy <digest@example.com>\""]),
+        other => panic!("expected an invalid config, got {:?}", other),
+    }
+
+    let figment = Figment::new()
+        .merge(("apple_client_ids", "com.example.mossy"))
+        .merge(("smtp_host", "mail.example.com"))
+        .merge(("smtp_security", "starttls"))
+        .merge(("smtp_from", "mossy <digest@example.com>"));
+    let config = AppConfig::from_figment(&figment).unwrap();
+    assert_eq!(config.smtp_port(), 587);
+    assert_eq!(config.trash_retention_days, 30);
+    assert_eq!(config.undo_window_seconds, 600);
+}
+
+#[test]
+fn tokens_expire_after_the_ttl() {
+    let figment = Figment::new().merge(("apple_client_ids", "com.example.mossy"));
+    let mut config = AppConfig::from_figment(&figment).unwrap();
+    assert_eq!(config.apple_client_ids, vec!["com.example.mossy"]);
+    assert_eq!(config.database_name, "mossy");
+    assert!(!config.is_token_expired(None));
+
+    config.token_ttl_days = Some(30);
+    let issued_at = |days: i64| Some(bson::DateTime::from_chrono(chrono::Utc::now() - chrono::Duration::days(days)));
+    assert!(!config.is_token_expired(issued_at(29)));
+    assert!(config.is_token_expired(issued_at(31)));
+    assert!(config.is_token_expired(None));
+}
+
+#[rocket::async_test]
+async fn expired_tokens_are_unauthorized() {
+    setup();
+    let figment = config::figment().merge(("token_ttl_days", 30));
+    let client = Client::tracked(rocket().await.configure(figment)).await.expect("valid rocket instance");
+    let user = seed_user(&client).await;
+
+    let response = get(&client, &user, String::from("/api/tasks")).await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let mut expired_user = new_user(false);
+    expired_user.token_issued_at = Some(bson::DateTime::from_chrono(chrono::Utc::now() - chrono::Duration::days(31)));
+    let store = client.rocket().state::<Store>().unwrap();
+    store.backend.insert_document("users", bson::to_document(&expired_user).unwrap()).await.unwrap();
+
+    let response = get(&client, &expired_user, String::from("/api/tasks")).await;
+    assert_eq!(response.status(), Status::Unauthorized);
+}
+
+fn id_json(_id: bson::oid::ObjectId) -> Value {
+    json!({ "$oid": _id.to_hex() })
+}
+
+#[rocket::async_test]
+async fn deleted_tasks_go_to_the_trash_and_come_back() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+    let other_user = seed_user(&client).await;
+    let task = seed_task(&client, &user, "Water plants", None).await;
+    let event = seed_event(&client, &user, &task, 2).await;
+
+    // Tasks have no parent to move events to
+    let response = delete(&client, &user, "/api/tasks?strategy=reparent", json!([id_json(task._id)])).await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+
+    let response = delete(&client, &other_user, "/api/tasks", json!([id_json(task._id)])).await;
+    assert_eq!(response.status(), Status::NotFound);
+
+    let response = delete(&client, &user, "/api/tasks", json!([id_json(task._id)])).await;
+    assert_eq!(response.status(), Status::Ok);
+    let body = json_body(response).await;
+    assert_eq!(body["deletedCount"], 1);
+    assert_eq!(body["deleted_events"], json!([id_json(event._id)]));
+
+    let response = get(&client, &user, String::from("/api/trash")).await;
+    let body = json_body(response).await;
+    assert_eq!(ids(&body["tasks"]), vec![task._id.to_hex()]);
+    assert_eq!(ids(&body["events"]), vec![event._id.to_hex()]);
+
+    let response = post(&client, &user, "/api/trash/tasks/restore", json!([id_json(task._id)])).await;
+    assert_eq!(response.status(), Status::Ok);
+    let body = json_body(response).await;
+    assert_eq!(body["restored_tasks"], json!([id_json(task._id)]));
+    assert_eq!(body["restored_events"], json!([id_json(event._id)]));
+}
+
+#[rocket::async_test]
+async fn deleted_events_can_be_restored_unless_their_task_is_gone() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+    let task = seed_task(&client, &user, "Water plants", None).await;
+    let event = seed_event(&client, &user, &task, 2).await;
+
+    let response = delete(&client, &user, "/api/events", json!([id_json(event._id)])).await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(json_body(response).await["deletedCount"], 1);
+
+    let response = post(&client, &user, "/api/trash/events/restore", json!([id_json(event._id)])).await;
+    assert_eq!(json_body(response).await["restored_events"], json!([id_json(event._id)]));
+
+    let other_user = seed_user(&client).await;
+    let response = delete(&client, &other_user, "/api/events", json!([id_json(event._id)])).await;
+    assert_eq!(response.status(), Status::NotFound);
+
+    let response = delete(&client, &user, "/api/events", json!([id_json(event._id)])).await;
+    assert_eq!(response.status(), Status::Ok);
+    let response = delete(&client, &user, "/api/tasks?strategy=detach", json!([id_json(task._id)])).await;
+    assert_eq!(response.status(), Status::Ok);
+    let response = post(&client, &user, "/api/trash/events/restore", json!([id_json(event._id)])).await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+}
+
+#[rocket::async_test]
+async fn deleted_tags_reparent_their_children_and_can_be_restored() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+    let garden = seed_tag(&client, &user, "Garden", None).await;
+    let beds = seed_tag(&client, &user, "Beds", Some(garden._id)).await;
+    let roses = seed_tag(&client, &user, "Roses", Some(beds._id)).await;
+    let task = seed_task(&client, &user, "Prune roses", Some(vec![beds._id])).await;
+
+    let response = delete(&client, &user, "/api/tags", json!([id_json(beds._id)])).await;
+    assert_eq!(response.status(), Status::Ok);
+    let body = json_body(response).await;
+    assert_eq!(body["reparented_tags"], json!([id_json(roses._id)]));
+    assert_eq!(body["updated_tasks"], json!([id_json(task._id)]));
+
+    let response = post(&client, &user, "/api/trash/tags/restore", json!([id_json(beds._id)])).await;
+    assert_eq!(response.status(), Status::Ok);
+    let body = json_body(response).await;
+    assert_eq!(body["restored_tags"], json!([id_json(beds._id)]));
+    assert_eq!(body["updated_tasks"], json!([id_json(task._id)]));
+
+    let other_user = seed_user(&client).await;
+    let response = delete(&client, &other_user, "/api/tags", json!([id_json(garden._id)])).await;
+    assert_eq!(response.status(), Status::NotFound);
+
+    let response = delete(&client, &user, "/api/tags?strategy=cascade", json!([id_json(garden._id)])).await;
+    assert_eq!(json_body(response).await["deletedCount"], 3);
+}
+
+#[rocket::async_test]
+async fn tags_can_be_merged_and_tasks_retagged() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+    let other_user = seed_user(&client).await;
+    let garden = seed_tag(&client, &user, "Garden", None).await;
+    let yard = seed_tag(&client, &user, "Yard", None).await;
+    let outside = seed_tag(&client, &user, "Outside", None).await;
+    let task = seed_task(&client, &user, "Rake leaves", Some(vec![yard._id])).await;
+
+    let merge = json!({ "source_tags": [id_json(yard._id)], "target_tag": id_json(garden._id) });
+    let response = post(&client, &other_user, "/api/tags/merge", merge.clone()).await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+
+    let response = post(&client, &user, "/api/tags/merge", merge).await;
+    assert_eq!(response.status(), Status::Ok);
+    let body = json_body(response).await;
+    assert_eq!(body["merged_tags"], json!([id_json(yard._id)]));
+    assert_eq!(body["updated_tasks"], json!([id_json(task._id)]));
+
+    let retag = json!({ "tasks": [id_json(task._id)], "add": [id_json(outside._id)], "remove": [id_json(garden._id)] });
+    let response = patch(&client, &other_user, "/api/tasks/tags", retag.clone()).await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    let response = patch(&client, &user, "/api/tasks/tags", retag).await;
+    assert_eq!(response.status(), Status::Ok);
+    let store = client.rocket().state::<Store>().unwrap();
+    let saved_task = store.backend.find_task(task._id).await.unwrap().unwrap();
+    assert_eq!(saved_task.tags, Some(vec![outside._id]));
+}
+
+#[rocket::async_test]
+async fn task_stats_and_tag_tree_summarize_events() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+    let garden = seed_tag(&client, &user, "Garden", None).await;
+    let beds = seed_tag(&client, &user, "Beds", Some(garden._id)).await;
+    let task = seed_task(&client, &user, "Weed beds", Some(vec![beds._id])).await;
+    seed_event(&client, &user, &task, 10).await;
+    seed_event(&client, &user, &task, 3).await;
+
+    let response = get(&client, &user, format!("/api/tasks/stats?task={}", task._id.to_hex())).await;
+    assert_eq!(response.status(), Status::Ok);
+    let body = json_body(response).await;
+    assert_eq!(body[0]["event_count"], 2);
+    assert_eq!(body[0]["total_duration"], 1200);
+    assert_eq!(body[0]["average_rating"], 4.0);
+
+    let response = get(&client, &user, String::from("/api/tasks/stats?task=not-an-id")).await;
+    assert_eq!(response.status(), Status::BadRequest);
+
+    let response = get(&client, &user, String::from("/api/tags/tree")).await;
+    let body = json_body(response).await;
+    assert_eq!(oid(&body[0]["_id"]), garden._id.to_hex());
+    assert_eq!(body[0]["task_count"], 0);
+    assert_eq!(body[0]["total_task_count"], 1);
+    assert_eq!(oid(&body[0]["children"][0]["_id"]), beds._id.to_hex());
+
+    // Detached events stay live, but don't count towards a task in the trash
+    let response = delete(&client, &user, "/api/tasks?strategy=detach", json!([id_json(task._id)])).await;
+    assert_eq!(response.status(), Status::Ok);
+    let response = get(&client, &user, String::from("/api/tasks/stats")).await;
+    assert_eq!(json_body(response).await, json!([]));
+}
+
+#[rocket::async_test]
+async fn batch_events_skip_tasks_the_user_does_not_own() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+    let other_user = seed_user(&client).await;
+    let task = seed_task(&client, &user, "Water plants", None).await;
+    let other_users_task = seed_task(&client, &other_user, "Not yours", None).await;
+
+    let batch = json!({ "tasks": [id_json(task._id), id_json(other_users_task._id)], "date": days_ago(0) });
+    let response = post(&client, &user, "/api/events/batch", batch).await;
+    assert_eq!(response.status(), Status::Ok);
+    let body = json_body(response).await;
+    assert_eq!(body[0]["status"], "created");
+    assert_eq!(body[1]["status"], "not_found");
+
+    // Either tasks or a tag, not both
+    let response = post(&client, &user, "/api/events/batch", json!({ "date": days_ago(0) })).await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+}
+
+#[rocket::async_test]
+async fn sync_pushes_changes_and_pulls_them_back() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+    let other_user = seed_user(&client).await;
+    let other_users_task = seed_task(&client, &other_user, "Not yours", None).await;
+    let other_users_tag = seed_tag(&client, &other_user, "Not yours either", None).await;
+    let task = seed_task(&client, &user, "Water plants", None).await;
+    let event = seed_event(&client, &user, &task, 2).await;
+
+    let offline_task = bson::oid::ObjectId::new();
+    let stolen_tag_task = bson::oid::ObjectId::new();
+    let unnamed_task = bson::oid::ObjectId::new();
+    let foreign_event = bson::oid::ObjectId::new();
+    let push = json!({
+        "tasks": [
+            {
+                "_id": id_json(offline_task),
+                "operation": "upsert",
+                "document": { "_id": id_json(offline_task), "name": "Made offline", "frequency": 3, "attachments": [id_json(bson::oid::ObjectId::new())] },
+            },
+            {
+                "_id": id_json(other_users_task._id),
+                "operation": "delete",
+            },
+            {
+                "_id": id_json(stolen_tag_task),
+                "operation": "upsert",
+                "document": { "_id": id_json(stolen_tag_task), "name": "Tagged", "frequency": 3, "tags": [id_json(other_users_tag._id)] },
+            },
+            {
+                "_id": id_json(unnamed_task),
+                "operation": "upsert",
+                "document": { "_id": id_json(unnamed_task), "name": " ", "frequency": 3 },
+            },
+            {
+                "_id": id_json(task._id),
+                "operation": "delete",
+                "base_updated_at": task.updated_at,
+            },
+        ],
+        "events": [
+            {
+                "_id": id_json(foreign_event),
+                "operation": "upsert",
+                "document": { "_id": id_json(foreign_event), "task": id_json(other_users_task._id), "date": { "$date": { "$numberLong": "0" } } },
+            },
+        ],
+    });
+    let response = post(&client, &user, "/api/sync", push).await;
+    assert_eq!(response.status(), Status::Ok);
+    let body = json_body(response).await;
+    assert_eq!(body[0]["status"], "applied");
+    assert_eq!(body[1]["status"], "forbidden");
+    assert_eq!(body[2]["status"], "invalid");
+    assert_eq!(error_fields(&body[2]["errors"]), vec!["tags"]);
+    assert_eq!(body[3]["status"], "invalid");
+    assert_eq!(error_fields(&body[3]["errors"]), vec!["name"]);
+    assert_eq!(body[4]["status"], "applied");
+    assert_eq!(body[5]["status"], "invalid");
+    assert_eq!(error_fields(&body[5]["errors"]), vec!["task"]);
+
+    // Attachments only come from /api/attachments, and deleting a task takes its events along
+    let store = client.rocket().state::<Store>().unwrap();
+    let offline_task_document = store.backend.find_task(offline_task).await.unwrap().unwrap();
+    assert_eq!(offline_task_document.attachments, None);
+    let event_document = store.backend.find_event(event._id).await.unwrap().unwrap();
+    assert!(event_document.deleted_at.is_some());
+
+    let response = get(&client, &user, String::from("/api/sync")).await;
+    let body = json_body(response).await;
+    assert_eq!(body["reset"], false);
+    assert_eq!(ids(&body["tasks"]["created"]), vec![offline_task.to_hex()]);
+
+    // Pulls overlap, so a write that was still committing during the last pull isn't missed
+    let response = get(&client, &user, format!("/api/sync?since={}", body["token"].as_str().unwrap())).await;
+    let body = json_body(response).await;
+    assert_eq!(ids(&body["tasks"]["created"]), vec![offline_task.to_hex()]);
+
+    let response = get(&client, &user, String::from("/api/sync?since=not-a-token")).await;
+    assert_eq!(response.status(), Status::BadRequest);
+}
+
+#[rocket::async_test]
+async fn calendar_feeds_are_served_by_secret() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+    let task = seed_task(&client, &user, "Water plants", None).await;
+    seed_event(&client, &user, &task, 10).await;
+
+    let response = post(&client, &user, "/api/calendar/feed-url", json!({})).await;
+    assert_eq!(response.status(), Status::Ok);
+    let body = json_body(response).await;
+    let url = body["url"].as_str().unwrap().to_string();
+    assert!(url.starts_with("http://localhost:8000/api/calendar/"));
+    assert_eq!(body["webcal_url"].as_str().unwrap(), url.replacen("http://", "webcal://", 1));
+    let path = &url[url.find("/api/calendar/").unwrap()..];
+
+    let response = client.get(path.to_string()).dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::Calendar));
+    assert!(response.into_string().await.unwrap().contains("Water plants"));
+
+    let response = client.get("/api/calendar/not-a-secret/feed.ics").dispatch().await;
+    assert_eq!(response.status(), Status::NotFound);
+}
+
+#[rocket::async_test]
+async fn webhooks_can_be_managed() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+    let other_user = seed_user(&client).await;
+
+    let response = post(&client, &user, "/api/webhooks", json!({ "url": "ftp://example.com", "events": ["task.created"] })).await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    let response = post(&client, &user, "/api/webhooks", json!({ "url": "http://example.com/hook", "events": ["task.created"] })).await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+    let response = post(&client, &user, "/api/webhooks", json!({ "url": "https://169.254.169.254/latest", "events": ["task.created"] })).await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+
+    let response = post(&client, &user, "/api/webhooks", json!({ "url": "https://example.com/hook", "events": ["task.created"] })).await;
+    assert_eq!(response.status(), Status::Ok);
+    let webhook = oid(&json_body(response).await["_id"]);
+
+    let response = get(&client, &user, String::from("/api/webhooks")).await;
+    assert_eq!(ids(&json_body(response).await), vec![webhook.clone()]);
+    let response = get(&client, &other_user, String::from("/api/webhooks")).await;
+    assert_eq!(json_body(response).await, json!([]));
+
+    let response = get(&client, &user, format!("/api/webhooks/deliveries?webhook={}&limit=10", webhook)).await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let response = delete(&client, &other_user, "/api/webhooks", json!([{ "$oid": webhook }])).await;
+    assert_eq!(response.status(), Status::NotFound);
+    let response = delete(&client, &user, "/api/webhooks", json!([{ "$oid": webhook }])).await;
+    assert_eq!(json_body(response).await["deletedCount"], 1);
+}
+
+#[rocket::async_test]
+async fn attachments_can_be_uploaded_and_removed() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+    let other_user = seed_user(&client).await;
+    let task = seed_task(&client, &user, "Water plants", None).await;
+
+    let boundary = "mossy-test-boundary";
+    let upload = format!(
+        "--{b}\r\nContent-Disposition: form-data; name=\"task\"\r\n\r\n{task}\r\n--{b}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"notes.txt\"\r\nContent-Type: text/plain\r\n\r\nfeed on Sundays\r\n--{b}--\r\n",
+        b = boundary,
+        task = task._id.to_hex(),
+    );
+    let response = client.post("/api/attachments")
+        .header(auth(&user))
+        .header(ContentType::new("multipart", "form-data").with_params(("boundary", boundary)))
+        .body(upload.clone())
+        .dispatch().await;
+    assert_eq!(response.status(), Status::Ok);
+    let attachment = oid(&json_body(response).await["_id"]);
+
+    // Uploading to someone else's task
+    let response = client.post("/api/attachments")
+        .header(auth(&other_user))
+        .header(ContentType::new("multipart", "form-data").with_params(("boundary", boundary)))
+        .body(upload)
+        .dispatch().await;
+    assert_eq!(response.status(), Status::UnprocessableEntity);
+
+    let response = get(&client, &user, format!("/api/attachments?task={}", task._id.to_hex())).await;
+    assert_eq!(ids(&json_body(response).await), vec![attachment.clone()]);
+
+    let response = get(&client, &user, String::from("/api/attachments/usage")).await;
+    assert_eq!(json_body(response).await["used"], 15);
+
+    let response = get(&client, &user, format!("/api/attachments/{}", attachment)).await;
+    assert_eq!(response.headers().get_one("Content-Disposition"), Some("attachment"));
+    assert_eq!(response.headers().get_one("X-Content-Type-Options"), Some("nosniff"));
+    assert_eq!(response.into_string().await.unwrap(), "feed on Sundays");
+    let response = get(&client, &other_user, format!("/api/attachments/{}", attachment)).await;
+    assert_eq!(response.status(), Status::NotFound);
+    let response = get(&client, &user, format!("/api/attachments/{}/thumbnail", attachment)).await;
+    assert_eq!(response.status(), Status::NotFound);
+
+    let response = delete(&client, &user, "/api/attachments", json!([{ "$oid": attachment }])).await;
+    assert_eq!(json_body(response).await["deletedCount"], 1);
+}
+
+#[rocket::async_test]
+async fn undo_reverts_the_latest_operation() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+    let task = seed_task(&client, &user, "Water plants", None).await;
+
+    let response = delete(&client, &user, "/api/tasks", json!([id_json(task._id)])).await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let response = post(&client, &user, "/api/undo", json!({})).await;
+    assert_eq!(response.status(), Status::Ok);
+    let body = json_body(response).await;
+    assert_eq!(body[0]["action"], "delete_tasks");
+    assert_eq!(body[0]["status"], "undone");
+
+    let store = client.rocket().state::<Store>().unwrap();
+    let saved_task = store.backend.find_task(task._id).await.unwrap().unwrap();
+    assert!(saved_task.deleted_at.is_none());
+
+    let response = patch(&client, &user, "/api/tasks", json!({ "_id": id_json(task._id), "name": "Water ferns" })).await;
+    assert_eq!(response.status(), Status::Ok);
+    let response = post(&client, &user, "/api/undo", json!({})).await;
+    assert_eq!(json_body(response).await[0]["status"], "undone");
+    assert_eq!(store.backend.find_task(task._id).await.unwrap().unwrap().name, "Water plants");
+
+    // Nothing left to undo
+    let response = post(&client, &user, "/api/undo", json!({})).await;
+    assert_eq!(json_body(response).await, json!([]));
+}
+
+#[rocket::async_test]
+async fn audit_log_is_limited_to_the_owner_unless_admin() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+    let other_user = seed_user(&client).await;
+    let admin = seed_admin(&client).await;
+    let task = seed_task(&client, &user, "Water plants", None).await;
+
+    let response = delete(&client, &user, "/api/tasks", json!([id_json(task._id)])).await;
+    assert_eq!(response.status(), Status::Ok);
+
+    let response = get(&client, &user, String::from("/api/audit?action=delete_tasks")).await;
+    let body = json_body(response).await;
+    assert_eq!(body.as_array().unwrap().len(), 1);
+    assert_eq!(body[0]["targets"][0], id_json(task._id));
+
+    let response = get(&client, &user, String::from("/api/audit?limit=1&offset=1")).await;
+    assert_eq!(json_body(response).await, json!([]));
+
+    let response = get(&client, &other_user, format!("/api/audit?user={}", user._id.to_hex())).await;
+    assert_eq!(response.status(), Status::Forbidden);
+
+    let response = get(&client, &admin, format!("/api/audit?user={}", user._id.to_hex())).await;
+    assert_eq!(json_body(response).await.as_array().unwrap().len(), 1);
+}
+
+#[rocket::async_test]
+async fn change_stream_opens_for_a_signed_in_user() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+
+    let response = get(&client, &user, String::from("/api/changes")).await;
+    assert_eq!(response.status(), Status::Ok);
+    assert_eq!(response.content_type(), Some(ContentType::EventStream));
+}
+
+#[rocket::async_test]
+async fn debug_routes_are_for_admins_only() {
+    let client = client().await;
+    let user = seed_user(&client).await;
+    let admin = seed_admin(&client).await;
+
+    for (method, uri, body) in [
+        (rocket::http::Method::Post, "/api/debug/tasks", json!({ "quantity": 3 })),
+        (rocket::http::Method::Post, "/api/debug/tags", json!({ "quantity": 3 })),
+        (rocket::http::Method::Post, "/api/debug/events", json!({})),
+        (rocket::http::Method::Delete, "/api/debug/events", json!({})),
+        (rocket::http::Method::Delete, "/api/debug/tags", json!({})),
+        (rocket::http::Method::Delete, "/api/debug/tasks", json!({})),
+    ] {
+        let response = send(&client, &user, method, uri, body.clone()).await;
+        assert_eq!(response.status(), Status::Forbidden, "{} {}", method, uri);
+
+        let response = send(&client, &admin, method, uri, body).await;
+        assert_eq!(response.status(), Status::Ok, "{} {}", method, uri);
+    }
+
+    let store = client.rocket().state::<Store>().unwrap();
+    assert!(store.backend.find_user_tasks(admin._id, false).await.unwrap().is_empty());
+    assert!(store.backend.find_user_tasks(admin._id, true).await.unwrap().is_empty());
+}
+
+// Migrations only ever run against MongoDB:
+// ROCKET_DATABASE_BACKEND=mongodb cargo test -- --include-ignored
+
+async fn mongo() -> Database {
+    let config = AppConfig::from_env().unwrap();
+    let mut client_options = ClientOptions::parse(&config.database_uri).await.unwrap();
+    client_options.app_name = Some("mossy".to_string());
+    let client = MongoClient::with_options(client_options).unwrap();
+    client.database(&config.database_name)
+}
+
+#[rocket::async_test]
+#[ignore = "needs MongoDB"]
+async fn migrations_only_run_once() {
+    let _client = client().await;
+    let db = mongo().await;
+
+    crate::migrations::run(&db).await.unwrap();
+    assert!(crate::migrations::pending_migrations(&db).await.unwrap().is_empty());
+    assert!(crate::migrations::run(&db).await.unwrap().is_empty());
+}
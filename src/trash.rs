@@ -0,0 +1,371 @@
+use futures::stream::TryStreamExt;
+use mongodb::{Client, Collection, options::ClientOptions, options::FindOptions};
+use mongodb::bson;
+use mongodb::bson::Document;
+use mongodb::error::Error;
+use rocket::State;
+use rocket::http::Status;
+use rocket::serde::{Serialize, Deserialize, json::Json};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use tracing::{error, warn};
+
+use crate::{Event, Tag, Task, Token, User};
+use crate::attachments::{self, Storage};
+use crate::config::AppConfig;
+use crate::metrics;
+use crate::audit::RequestMeta;
+use crate::realtime::ChangeBus;
+use crate::repository::{DocumentWrite, MongoRepository, Repository, Store};
+use crate::undo::OperationLog;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct TrashData {
+    // Anything deleted longer ago than this is purged for good
+    retention_days: u32,
+    tasks: Vec<Task>,
+    events: Vec<Event>,
+    tags: Vec<Tag>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct RestoreSummary {
+    restored_tasks: Vec<bson::oid::ObjectId>,
+    restored_events: Vec<bson::oid::ObjectId>,
+    restored_tags: Vec<bson::oid::ObjectId>,
+    // Tasks that got a restored tag linked again
+    updated_tasks: Vec<bson::oid::ObjectId>,
+}
+
+// What a restore brings back, which decides what it cascades to
+#[derive(Debug, Clone, Copy)]
+enum TrashKind {
+    Task,
+    Event,
+    Tag,
+}
+
+impl TrashKind {
+    fn restore_action(self) -> &'static str {
+        match self {
+            TrashKind::Task => "restore_tasks",
+            TrashKind::Event => "restore_events",
+            TrashKind::Tag => "restore_tags",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RestoreError {
+    // The event's task is still in the trash, so it has to be restored first
+    DeletedTaskError,
+    // Something else wrote to one of the documents between reading and restoring them
+    ConcurrentChange,
+    DatabaseError(Error),
+}
+
+impl From<Error> for RestoreError {
+    fn from(error: Error) -> Self {
+        RestoreError::DatabaseError(error)
+    }
+}
+
+pub fn purge_horizon(config: &AppConfig) -> bson::DateTime {
+    let retention = chrono::Duration::days(config.trash_retention_days.into());
+    bson::DateTime::from_chrono(chrono::Utc::now() - retention)
+}
+
+async fn read_trash_action(token: Token, store: &Store, config: &AppConfig) -> Result<TrashData, Error> {
+    let repository = store.backend.as_ref();
+
+    let user = token.0;
+
+    let mut tasks = repository.find_user_tasks(user._id, true).await?;
+    let mut events = repository.find_user_events(user._id, true).await?;
+    let mut tags = repository.find_user_tags(user._id, true).await?;
+    tasks.sort_by_key(|task| Reverse(task.deleted_at));
+    events.sort_by_key(|event| Reverse(event.deleted_at));
+    tags.sort_by_key(|tag| Reverse(tag.deleted_at));
+
+    Ok(TrashData {
+        retention_days: config.trash_retention_days,
+        tasks,
+        events,
+        tags,
+    })
+}
+
+fn restored_changes(now: bson::DateTime) -> Document {
+    bson::doc! {
+        "deleted_at": null,
+        "updated_at": now,
+    }
+}
+
+async fn plan_task_restore(repository: &dyn Repository, user: &User, tasks_data: Vec<bson::oid::ObjectId>, now: bson::DateTime) -> Result<(RestoreSummary, Vec<DocumentWrite>), RestoreError> {
+    let tasks_to_restore: Vec<Task> = repository.find_tasks_by_ids(&tasks_data).await?.into_iter()
+        .filter(|task| task.user == Some(user._id) && task.deleted_at.is_some())
+        .collect();
+    let task_ids: Vec<bson::oid::ObjectId> = tasks_to_restore.iter().map(|task| task._id).collect();
+
+    let tag_ids: HashSet<bson::oid::ObjectId> = repository.find_user_tags(user._id, false).await?.into_iter().map(|tag| tag._id).collect();
+    let task_events = repository.find_task_events(&task_ids).await?;
+    let mut summary = RestoreSummary::default();
+    let mut writes = Vec::new();
+
+    for task in tasks_to_restore {
+        // Events deleted along with the task share its deleted_at; ones deleted on their own stay in the trash
+        for event in task_events.iter().filter(|event| event.task == task._id && event.deleted_at == task.deleted_at) {
+            writes.push(DocumentWrite::new("events", event._id, event.updated_at, restored_changes(now)));
+            summary.restored_events.push(event._id);
+        }
+
+        // Links to tags that were deleted in the meantime are dropped instead of left dangling
+        let task_tags: Vec<bson::oid::ObjectId> = task.tags.unwrap_or_default().into_iter().filter(|tag_id| tag_ids.contains(tag_id)).collect();
+        let mut restored_task = restored_changes(now);
+        restored_task.insert("tags", task_tags);
+        writes.push(DocumentWrite::new("tasks", task._id, task.updated_at, restored_task));
+        summary.restored_tasks.push(task._id);
+    }
+
+    Ok((summary, writes))
+}
+
+async fn plan_event_restore(repository: &dyn Repository, user: &User, events_data: Vec<bson::oid::ObjectId>, now: bson::DateTime) -> Result<(RestoreSummary, Vec<DocumentWrite>), RestoreError> {
+    let events_to_restore: Vec<Event> = repository.find_events_by_ids(&events_data).await?.into_iter()
+        .filter(|event| event.user == Some(user._id) && event.deleted_at.is_some())
+        .collect();
+
+    let task_ids: HashSet<bson::oid::ObjectId> = events_to_restore.iter().map(|event| event.task).collect();
+    let live_tasks = repository.find_tasks_by_ids(&task_ids.iter().cloned().collect::<Vec<bson::oid::ObjectId>>()).await?.into_iter()
+        .filter(|task| task.deleted_at.is_none())
+        .count();
+    if live_tasks != task_ids.len() {
+        return Err(RestoreError::DeletedTaskError)
+    }
+
+    let mut summary = RestoreSummary::default();
+    let mut writes = Vec::new();
+    for event in events_to_restore {
+        writes.push(DocumentWrite::new("events", event._id, event.updated_at, restored_changes(now)));
+        summary.restored_events.push(event._id);
+    }
+
+    Ok((summary, writes))
+}
+
+async fn plan_tag_restore(repository: &dyn Repository, user: &User, tags_data: Vec<bson::oid::ObjectId>, now: bson::DateTime) -> Result<(RestoreSummary, Vec<DocumentWrite>), RestoreError> {
+    let deleted_tags_by_id: HashMap<bson::oid::ObjectId, Tag> = repository.find_user_tags(user._id, true).await?.into_iter().map(|tag| (tag._id, tag)).collect();
+
+    // Descendants deleted in the same cascade come back with the tag
+    let mut restored_tags: HashSet<bson::oid::ObjectId> = tags_data.into_iter().filter(|tag_id| deleted_tags_by_id.contains_key(tag_id)).collect();
+    let mut pending: Vec<bson::oid::ObjectId> = restored_tags.iter().cloned().collect();
+    while let Some(tag_id) = pending.pop() {
+        let deleted_at = deleted_tags_by_id[&tag_id].deleted_at;
+        for child in deleted_tags_by_id.values() {
+            if child.parent_tag == Some(tag_id) && child.deleted_at == deleted_at && restored_tags.insert(child._id) {
+                pending.push(child._id);
+            }
+        }
+    }
+
+    let tag_ids: HashSet<bson::oid::ObjectId> = repository.find_user_tags(user._id, false).await?.into_iter().map(|tag| tag._id).collect();
+    let unlinked_task_ids: Vec<bson::oid::ObjectId> = restored_tags.iter().flat_map(|tag_id| deleted_tags_by_id[tag_id].unlinked_tasks.clone().unwrap_or_default()).collect();
+    // unlinked_tasks only ever came from this user's tasks, but don't rely on that when writing to them
+    let mut linked_tasks: HashMap<bson::oid::ObjectId, Task> = repository.find_tasks_by_ids(&unlinked_task_ids).await?.into_iter()
+        .filter(|task| task.user == Some(user._id) && task.deleted_at.is_none())
+        .map(|task| (task._id, task))
+        .collect();
+    let mut summary = RestoreSummary::default();
+    let mut writes = Vec::new();
+
+    for tag_id in restored_tags.iter() {
+        let tag = &deleted_tags_by_id[tag_id];
+
+        // A parent that is still deleted can't hold the tag, so it goes to the top level
+        let parent_tag = tag.parent_tag.filter(|parent_id| tag_ids.contains(parent_id) || restored_tags.contains(parent_id));
+        let mut restored_tag = restored_changes(now);
+        restored_tag.insert("parent_tag", parent_tag);
+        restored_tag.insert("unlinked_tasks", bson::Bson::Null);
+        writes.push(DocumentWrite::new("tags", tag._id, tag.updated_at, restored_tag));
+        summary.restored_tags.push(*tag_id);
+
+        for task_id in tag.unlinked_tasks.clone().unwrap_or_default() {
+            if let Some(task) = linked_tasks.get_mut(&task_id) {
+                let task_tags = task.tags.get_or_insert_with(Vec::new);
+                if !task_tags.contains(tag_id) {
+                    task_tags.push(*tag_id);
+                }
+            }
+        }
+    }
+
+    // A task unlinked from several of the restored tags gets them all back in one write
+    for task in linked_tasks.into_values() {
+        let linked_task = bson::doc! {
+            "tags": task.tags.unwrap_or_default(),
+            "updated_at": now,
+        };
+        writes.push(DocumentWrite::new("tasks", task._id, task.updated_at, linked_task));
+        summary.updated_tasks.push(task._id);
+    }
+
+    Ok((summary, writes))
+}
+
+async fn restore_action(token: Token, kind: TrashKind, ids: Vec<bson::oid::ObjectId>, store: &Store, changes: &ChangeBus, meta: &RequestMeta) -> Result<RestoreSummary, RestoreError> {
+    let repository = store.backend.as_ref();
+
+    let user = token.0;
+
+    // Restoring a tag re-links tasks, and restoring a task brings back its events
+    let now = bson::DateTime::now();
+    let (summary, writes) = match kind {
+        TrashKind::Task => plan_task_restore(repository, &user, ids, now).await?,
+        TrashKind::Event => plan_event_restore(repository, &user, ids, now).await?,
+        TrashKind::Tag => plan_tag_restore(repository, &user, ids, now).await?,
+    };
+    let mut operation = OperationLog::new();
+    if !operation.apply_writes(repository, writes).await? {
+        return Err(RestoreError::ConcurrentChange)
+    }
+    operation.record(repository, user._id, kind.restore_action(), meta).await;
+
+    // Clients dropped these when they were deleted, so they come back as new
+    changes.publish(user._id, "task", "created", summary.restored_tasks.clone());
+    changes.publish(user._id, "event", "created", summary.restored_events.clone());
+    changes.publish(user._id, "tag", "created", summary.restored_tags.clone());
+    changes.publish(user._id, "task", "updated", summary.updated_tasks.clone());
+
+    Ok(summary)
+}
+
+async fn expired_ids(collection: &Collection<Document>, horizon: bson::DateTime) -> Result<Vec<bson::oid::ObjectId>, Error> {
+    let filter = bson::doc! {
+        "deleted_at": {
+            "$lt": horizon,
+        },
+    };
+    let find_options = FindOptions::builder().projection(bson::doc! { "_id": 1 }).build();
+    let mut cursor = collection.find(filter, find_options).await?;
+
+    let mut ids = Vec::new();
+    while let Some(document) = cursor.try_next().await? {
+        if let Ok(id) = document.get_object_id("_id") {
+            ids.push(id);
+        }
+    }
+
+    Ok(ids)
+}
+
+async fn purge_trash(repository: &MongoRepository, storage: &Storage, config: &AppConfig) -> Result<(), Error> {
+    let db = repository.database();
+    let horizon = purge_horizon(config);
+    let filter = bson::doc! {
+        "deleted_at": {
+            "$lt": horizon,
+        },
+    };
+
+    // Only the ids looked up here are deleted, so anything crossing the horizon meanwhile waits for the next run with its attachments
+    let task_ids = expired_ids(&db.collection::<Document>("tasks"), horizon).await?;
+    let event_ids = expired_ids(&db.collection::<Document>("events"), horizon).await?;
+    attachments::purge_attachments(repository, storage, &task_ids, &event_ids).await?;
+
+    let tasks_filter = bson::doc! {
+        "_id": {
+            "$in": task_ids,
+        },
+        "deleted_at": {
+            "$lt": horizon,
+        },
+    };
+    let events_filter = bson::doc! {
+        "_id": {
+            "$in": event_ids,
+        },
+        "deleted_at": {
+            "$lt": horizon,
+        },
+    };
+    db.collection::<Task>("tasks").delete_many(tasks_filter, None).await?;
+    db.collection::<Event>("events").delete_many(events_filter, None).await?;
+    db.collection::<Tag>("tags").delete_many(filter, None).await?;
+
+    Ok(())
+}
+
+pub async fn run_purger(config: AppConfig, storage: Storage) {
+    let mut client_options = match ClientOptions::parse(&config.database_uri).await {
+        Ok(_client_options) => _client_options,
+        Err(error) => {
+            warn!(?error, "Trash purging disabled");
+            return
+        }
+    };
+    client_options.app_name = Some("mossy".to_string());
+    client_options.command_event_handler = Some(metrics::command_timer());
+    let client = match Client::with_options(client_options) {
+        Ok(_client) => _client,
+        Err(error) => {
+            warn!(?error, "Trash purging disabled");
+            return
+        }
+    };
+    let repository = MongoRepository::new(&client, &config.database_name);
+
+    let mut interval = rocket::tokio::time::interval(Duration::from_secs(60 * 60));
+    loop {
+        interval.tick().await;
+        if let Err(error) = purge_trash(&repository, &storage, &config).await {
+            error!(?error, "Couldn't purge trash");
+        }
+    }
+}
+
+fn restore_response(summary: Result<RestoreSummary, RestoreError>) -> Result<Json<RestoreSummary>, Status> {
+    match summary {
+        Ok(summary_result) => Ok(Json(summary_result)),
+        Err(RestoreError::DeletedTaskError) => Err(Status::UnprocessableEntity),
+        Err(RestoreError::ConcurrentChange) => Err(Status::Conflict),
+        Err(RestoreError::DatabaseError(error)) => {
+            error!(?error, "Couldn't restore");
+            Err(Status::InternalServerError)
+        },
+    }
+}
+
+#[get("/api/trash", format="json")]
+pub async fn read_trash(token: Token, store: &State<Store>, config: &State<AppConfig>) -> Result<Json<TrashData>, Status> {
+    let trash = read_trash_action(token, store, config).await;
+
+    match trash {
+        Ok(trash_result) => Ok(Json(trash_result)),
+        Err(error) => {
+            error!(?error, "Couldn't read trash");
+            Err(Status::InternalServerError)
+        },
+    }
+}
+
+#[post("/api/trash/tasks/restore", format="json", data="<tasks>")]
+pub async fn restore_tasks(token: Token, tasks: Json<Vec<bson::oid::ObjectId>>, store: &State<Store>, changes: &State<ChangeBus>, meta: RequestMeta) -> Result<Json<RestoreSummary>, Status> {
+    let deserialized_tasks_list = tasks.into_inner();
+    restore_response(restore_action(token, TrashKind::Task, deserialized_tasks_list, store, changes, &meta).await)
+}
+
+#[post("/api/trash/events/restore", format="json", data="<events>")]
+pub async fn restore_events(token: Token, events: Json<Vec<bson::oid::ObjectId>>, store: &State<Store>, changes: &State<ChangeBus>, meta: RequestMeta) -> Result<Json<RestoreSummary>, Status> {
+    let deserialized_events_list = events.into_inner();
+    restore_response(restore_action(token, TrashKind::Event, deserialized_events_list, store, changes, &meta).await)
+}
+
+#[post("/api/trash/tags/restore", format="json", data="<tags>")]
+pub async fn restore_tags(token: Token, tags: Json<Vec<bson::oid::ObjectId>>, store: &State<Store>, changes: &State<ChangeBus>, meta: RequestMeta) -> Result<Json<RestoreSummary>, Status> {
+    let deserialized_tags_list = tags.into_inner();
+    restore_response(restore_action(token, TrashKind::Tag, deserialized_tags_list, store, changes, &meta).await)
+}
@@ -0,0 +1,323 @@
+use std::collections::HashMap;
+
+use mongodb::bson;
+use mongodb::bson::Document;
+use mongodb::error::Error;
+use rocket::State;
+use rocket::http::Status;
+use rocket::serde::{Serialize, Deserialize, json::Json};
+use tracing::error;
+
+use crate::Token;
+use crate::config::AppConfig;
+use crate::audit::{self, AuditChange, RequestMeta};
+use crate::realtime::ChangeBus;
+use crate::repository::{DocumentRevert, DocumentWrite, Repository, Store};
+use crate::validation::{Valid, Validate, ValidationErrors};
+
+const MAX_UNDO_COUNT: u32 = 50;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct OperationChange {
+    // "tasks", "events" or "tags"
+    collection: String,
+    document_id: bson::oid::ObjectId,
+    // None when the operation created the document
+    before: Option<Document>,
+    // Anything else writing to the document after the operation makes undoing it unsafe
+    after_updated_at: Option<bson::DateTime>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Operation {
+    _id: bson::oid::ObjectId,
+    user: bson::oid::ObjectId,
+    // The action that made the changes, e.g. "update_task"
+    action: String,
+    changes: Vec<OperationChange>,
+    created_at: bson::DateTime,
+    undone_at: Option<bson::DateTime>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct UndoData {
+    // How many of the most recent operations to revert, newest first
+    count: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct UndoResult {
+    operation: bson::oid::ObjectId,
+    action: String,
+    // "undone", or "conflict" when something changed the same documents since; nothing older is undone after a conflict
+    status: String,
+}
+
+impl Validate for UndoData {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        errors.check("count", self.count.is_none_or(|count| count <= MAX_UNDO_COUNT), "is more than can be undone at once");
+    }
+}
+
+// A document an action read before writing, and what the write left it as
+struct PendingChange {
+    change: OperationChange,
+    written: bool,
+    // None when the write removed the document
+    after: Option<Document>,
+}
+
+// Collects what a mutating action touches so it can be audited and put back later.
+// Snapshot documents before writing, then report each write that went through; unwritten snapshots are left out.
+pub struct OperationLog {
+    changes: Vec<PendingChange>,
+    undoable: bool,
+}
+
+impl OperationLog {
+    pub fn new() -> OperationLog {
+        OperationLog { changes: Vec::new(), undoable: true }
+    }
+
+    // For changes that only go to the audit log, like account settings
+    pub fn audit_only() -> OperationLog {
+        OperationLog { changes: Vec::new(), undoable: false }
+    }
+
+    fn pending(&mut self, collection: &str, document_id: bson::oid::ObjectId) -> Option<&mut PendingChange> {
+        self.changes.iter_mut().find(|pending| pending.change.collection == collection && pending.change.document_id == document_id)
+    }
+
+    // Returns the snapshot. A document that's already tracked keeps its first snapshot, so it's put back the way the action found it.
+    pub async fn snapshot_document(&mut self, repository: &dyn Repository, collection: &str, document_id: bson::oid::ObjectId) -> Result<Option<Document>, Error> {
+        let document = repository.find_document(collection, document_id).await?;
+        if let Some(_document) = &document {
+            self.track(collection, document_id, Some(_document.clone()));
+        }
+        Ok(document)
+    }
+
+    fn track(&mut self, collection: &str, document_id: bson::oid::ObjectId, before: Option<Document>) {
+        if self.pending(collection, document_id).is_some() {
+            return
+        }
+        self.changes.push(PendingChange {
+            change: OperationChange {
+                collection: collection.to_string(),
+                document_id,
+                before,
+                after_updated_at: None,
+            },
+            written: false,
+            after: None,
+        });
+    }
+
+    pub fn created<T: Serialize>(&mut self, collection: &str, document_id: bson::oid::ObjectId, document: &T) -> Result<(), Error> {
+        self.track(collection, document_id, None);
+        self.wrote(collection, document_id, Some(bson::to_document(document)?));
+        Ok(())
+    }
+
+    // The write $set these fields on a snapshotted document
+    pub fn updated(&mut self, collection: &str, document_id: bson::oid::ObjectId, changes: &Document) {
+        let Some(pending) = self.pending(collection, document_id) else {
+            return
+        };
+        let base = if pending.written { &pending.after } else { &pending.change.before };
+        let mut after = base.clone().unwrap_or_default();
+        for (key, value) in changes {
+            after.insert(key, value.clone());
+        }
+        pending.written = true;
+        pending.after = Some(after);
+    }
+
+    // What a snapshotted document looks like after the write, or None when the write removed it
+    pub fn wrote(&mut self, collection: &str, document_id: bson::oid::ObjectId, after: Option<Document>) {
+        if let Some(pending) = self.pending(collection, document_id) {
+            pending.written = true;
+            pending.after = after;
+        }
+    }
+
+    // Snapshots everything the writes touch, then applies them; false when one of the documents changed since it was read
+    pub async fn apply_writes(&mut self, repository: &dyn Repository, writes: Vec<DocumentWrite>) -> Result<bool, Error> {
+        let mut ids_by_collection: HashMap<&str, Vec<bson::oid::ObjectId>> = HashMap::new();
+        for write in writes.iter().filter(|write| !write.create) {
+            ids_by_collection.entry(write.collection).or_default().push(write.document_id);
+        }
+        for (collection, ids) in ids_by_collection {
+            for document in repository.find_documents(collection, &ids).await? {
+                if let Ok(document_id) = document.get_object_id("_id") {
+                    self.track(collection, document_id, Some(document));
+                }
+            }
+        }
+
+        let written: Vec<(&str, bson::oid::ObjectId, Document, bool)> = writes.iter().map(|write| (write.collection, write.document_id, write.changes.clone(), write.create)).collect();
+        if !repository.apply_writes(writes).await? {
+            return Ok(false)
+        }
+        for (collection, document_id, changes, create) in written {
+            if create {
+                let mut document = changes;
+                document.insert("_id", document_id);
+                self.track(collection, document_id, None);
+                self.wrote(collection, document_id, Some(document));
+            } else {
+                self.updated(collection, document_id, &changes);
+            }
+        }
+
+        Ok(true)
+    }
+
+    // Call after writing. The write already went through, so a failure here is logged rather than failing the request.
+    pub async fn record(self, repository: &dyn Repository, user_id: bson::oid::ObjectId, action: &str, meta: &RequestMeta) {
+        if let Err(error) = self.write_records(repository, user_id, action, meta).await {
+            error!(?error, action, "Couldn't record operation");
+        }
+    }
+
+    async fn write_records(self, repository: &dyn Repository, user_id: bson::oid::ObjectId, action: &str, meta: &RequestMeta) -> Result<(), Error> {
+        let mut changes = Vec::new();
+        let mut audit_changes = Vec::new();
+        for pending in self.changes {
+            let mut change = pending.change;
+            if !pending.written || change.before == pending.after {
+                continue
+            }
+            change.after_updated_at = pending.after.as_ref().and_then(|document| document.get_datetime("updated_at").ok().cloned());
+            audit_changes.push(AuditChange::new(&change.collection, change.document_id, change.before.as_ref(), pending.after.as_ref()));
+            changes.push(change);
+        }
+        if changes.is_empty() {
+            return Ok(())
+        }
+
+        let targets = changes.iter().map(|change| change.document_id).collect();
+        audit::record(repository, user_id, action, targets, audit_changes, meta).await?;
+        if !self.undoable {
+            return Ok(())
+        }
+
+        let operation = Operation {
+            _id: bson::oid::ObjectId::new(),
+            user: user_id,
+            action: action.to_string(),
+            changes,
+            created_at: bson::DateTime::now(),
+            undone_at: None,
+        };
+        repository.insert_document("operations", bson::to_document(&operation)?).await?;
+
+        Ok(())
+    }
+}
+
+fn change_kind(collection: &str) -> &str {
+    match collection {
+        "tasks" => "task",
+        "events" => "event",
+        _ => "tag",
+    }
+}
+
+async fn undo_action(token: Token, undo_data: UndoData, changes: &ChangeBus, meta: &RequestMeta, store: &Store, config: &AppConfig) -> Result<Vec<UndoResult>, Error> {
+    let repository = store.backend.as_ref();
+
+    let user = token.0;
+
+    let window_start = bson::DateTime::from_chrono(chrono::Utc::now() - chrono::Duration::seconds(config.undo_window_seconds.into()));
+    let count = undo_data.count.unwrap_or(1).max(1);
+    let operations_to_undo = repository.find_undoable_operations(user._id, window_start, count).await?;
+
+    let mut results = Vec::new();
+    for operation in operations_to_undo {
+        let now = bson::DateTime::now();
+        let mut undo_operation = OperationLog::audit_only();
+        let mut reverts = Vec::new();
+        // The (kind, action, id) notifications for what gets put back
+        let mut notifications = Vec::new();
+
+        for change in operation.changes.iter().rev() {
+            let current = undo_operation.snapshot_document(repository, &change.collection, change.document_id).await?;
+            // Anything else writing to the document since makes undoing the operation unsafe
+            let Some(current_document) = current.filter(|document| document.get_datetime("updated_at").ok().cloned() == change.after_updated_at) else {
+                reverts.clear();
+                break
+            };
+            let kind = change_kind(&change.collection).to_string();
+
+            let (document, action) = match &change.before {
+                // Created documents are tombstoned rather than removed so offline clients hear about it
+                None => {
+                    let mut deleted_document = current_document;
+                    deleted_document.insert("deleted_at", now);
+                    deleted_document.insert("updated_at", now);
+                    (deleted_document, "deleted")
+                },
+                Some(before) => {
+                    let was_deleted = current_document.get_datetime("deleted_at").is_ok();
+                    let is_deleted = before.get_datetime("deleted_at").is_ok();
+
+                    let mut restored_document = before.clone();
+                    restored_document.insert("updated_at", now);
+                    let action = match (was_deleted, is_deleted) {
+                        (true, false) => "created",
+                        (false, true) => "deleted",
+                        _ => "updated",
+                    };
+                    (restored_document, action)
+                },
+            };
+            notifications.push((kind, action.to_string(), change.document_id));
+            reverts.push(DocumentRevert::new(&change.collection, change.document_id, change.after_updated_at, document));
+        }
+
+        let reverted = !reverts.is_empty() && repository.revert_operation(operation._id, reverts.clone(), now).await?;
+        if !reverted {
+            results.push(UndoResult {
+                operation: operation._id,
+                action: operation.action,
+                status: String::from("conflict"),
+            });
+            break
+        }
+
+        for revert in reverts {
+            undo_operation.wrote(&revert.collection, revert.document_id, Some(revert.document));
+        }
+        undo_operation.record(repository, user._id, "undo", meta).await;
+
+        for (kind, action, document_id) in notifications {
+            changes.publish(user._id, &kind, &action, vec![document_id]);
+        }
+        results.push(UndoResult {
+            operation: operation._id,
+            action: operation.action,
+            status: String::from("undone"),
+        });
+    }
+
+    Ok(results)
+}
+
+#[post("/api/undo", format="json", data="<undo_data>")]
+pub async fn undo(token: Token, undo_data: Valid<UndoData>, changes: &State<ChangeBus>, meta: RequestMeta, store: &State<Store>, config: &State<AppConfig>) -> Result<Json<Vec<UndoResult>>, Status> {
+    let deserialized_undo_data = undo_data.into_inner();
+    let undo_result = undo_action(token, deserialized_undo_data, changes, &meta, store, config).await;
+
+    match undo_result {
+        Ok(_undo_result) => Ok(Json(_undo_result)),
+        Err(error) => {
+            error!(?error, "Couldn't undo");
+            Err(Status::InternalServerError)
+        },
+    }
+}
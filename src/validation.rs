@@ -0,0 +1,193 @@
+use mongodb::bson;
+use mongodb::error::Error;
+use rocket::data::{Data, FromData, Limits, Outcome};
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Responder};
+use rocket::serde::{Serialize, Deserialize, DeserializeOwned, json::Json};
+use rocket::serde::json::serde_json::error::Category;
+
+use crate::Repository;
+
+const MAX_NAME_LENGTH: usize = 200;
+const MAX_DESCRIPTION_LENGTH: usize = 10000;
+// Leaves room for clients a timezone or two ahead of us
+const MAX_FUTURE_HOURS: i64 = 36;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct FieldError {
+    field: String,
+    message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(crate = "rocket::serde")]
+pub struct ValidationErrors {
+    errors: Vec<FieldError>,
+}
+
+impl ValidationErrors {
+    pub fn new() -> ValidationErrors {
+        ValidationErrors { errors: Vec::new() }
+    }
+
+    pub fn field(field: &str, message: &str) -> ValidationErrors {
+        let mut errors = ValidationErrors::new();
+        errors.add(field, message);
+        errors
+    }
+
+    pub fn add(&mut self, field: &str, message: &str) {
+        self.errors.push(FieldError {
+            field: field.to_string(),
+            message: message.to_string(),
+        });
+    }
+
+    pub fn check(&mut self, field: &str, valid: bool, message: &str) {
+        if !valid {
+            self.add(field, message);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    pub fn check_name(&mut self, field: &str, name: &str) {
+        self.check(field, !name.trim().is_empty(), "can't be empty");
+        self.check(field, name.chars().count() <= MAX_NAME_LENGTH, "is too long");
+    }
+
+    pub fn check_text(&mut self, field: &str, text: &Option<String>) {
+        let valid = text.as_ref().is_none_or(|_text| _text.chars().count() <= MAX_DESCRIPTION_LENGTH);
+        self.check(field, valid, "is too long");
+    }
+
+    // Things get logged after they happen, so anything much later than now is a mistake
+    pub fn check_date(&mut self, field: &str, date: &str) {
+        match bson::DateTime::parse_rfc3339_str(date) {
+            Ok(_date) => {
+                let latest = chrono::Utc::now() + chrono::Duration::hours(MAX_FUTURE_HOURS);
+                self.check(field, _date.to_chrono() <= latest, "can't be in the future");
+            },
+            Err(_) => self.add(field, "must be an RFC 3339 date"),
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ValidationErrors {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Json(self).respond_to(request)?;
+        response.set_status(Status::UnprocessableEntity);
+        Ok(response)
+    }
+}
+
+// Field-level checks that need nothing but the payload itself
+pub trait Validate {
+    fn validate(&self, errors: &mut ValidationErrors);
+}
+
+// Like Json<T>, but also runs T's validation and rejects the request with 422 and the field errors
+#[derive(Debug)]
+pub struct Valid<T>(T);
+
+impl<T> Valid<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r, T> FromData<'r> for Valid<T>
+where
+    T: DeserializeOwned + Validate,
+{
+    type Error = ValidationErrors;
+
+    async fn from_data(request: &'r Request<'_>, data: Data<'r>) -> Outcome<'r, Self> {
+        let limit = request.limits().get("json").unwrap_or(Limits::JSON);
+        let body = match data.open(limit).into_string().await {
+            Ok(_body) if _body.is_complete() => _body.into_inner(),
+            Ok(_) => return Outcome::Failure((Status::PayloadTooLarge, ValidationErrors::field("body", "is too large"))),
+            Err(_) => return Outcome::Failure((Status::BadRequest, ValidationErrors::field("body", "couldn't be read"))),
+        };
+
+        let value = match rocket::serde::json::from_str::<T>(&body) {
+            Ok(_value) => _value,
+            // Valid JSON of the wrong shape, e.g. a missing field or a string where a number goes
+            Err(error) if error.classify() == Category::Data => {
+                let errors = ValidationErrors::field("body", &error.to_string());
+                request.local_cache(|| errors.clone());
+                return Outcome::Failure((Status::UnprocessableEntity, errors))
+            },
+            Err(error) => return Outcome::Failure((Status::BadRequest, ValidationErrors::field("body", &error.to_string()))),
+        };
+
+        let mut errors = ValidationErrors::new();
+        value.validate(&mut errors);
+        if !errors.is_empty() {
+            // Catchers can't see guard errors, so leave them where unprocessable_entity can find them
+            request.local_cache(|| errors.clone());
+            return Outcome::Failure((Status::UnprocessableEntity, errors))
+        }
+
+        Outcome::Success(Valid(value))
+    }
+}
+
+#[catch(422)]
+pub fn unprocessable_entity(request: &Request) -> Json<ValidationErrors> {
+    Json(request.local_cache(ValidationErrors::new).clone())
+}
+
+// For actions that can fail validation only once they've looked things up, e.g. whether a referenced task is the user's
+#[derive(Debug)]
+pub enum ActionError {
+    Invalid(ValidationErrors),
+    DatabaseError(Error),
+}
+
+impl From<Error> for ActionError {
+    fn from(error: Error) -> Self {
+        ActionError::DatabaseError(error)
+    }
+}
+
+impl From<bson::ser::Error> for ActionError {
+    fn from(error: bson::ser::Error) -> Self {
+        ActionError::DatabaseError(error.into())
+    }
+}
+
+impl From<ValidationErrors> for ActionError {
+    fn from(errors: ValidationErrors) -> Self {
+        ActionError::Invalid(errors)
+    }
+}
+
+#[derive(Responder)]
+pub enum Rejection {
+    Invalid(ValidationErrors),
+    Failed(Status),
+}
+
+pub async fn validate_task_owner(repository: &dyn Repository, user_id: bson::oid::ObjectId, field: &str, task_id: bson::oid::ObjectId) -> Result<(), ActionError> {
+    match repository.find_task(task_id).await? {
+        Some(task) if task.user == Some(user_id) && task.deleted_at.is_none() => Ok(()),
+        _ => Err(ValidationErrors::field(field, "must be one of your tasks").into()),
+    }
+}
+
+pub async fn validate_tag_owner(repository: &dyn Repository, user_id: bson::oid::ObjectId, field: &str, tag_ids: &Option<Vec<bson::oid::ObjectId>>) -> Result<(), ActionError> {
+    for tag_id in tag_ids.iter().flatten() {
+        match repository.find_tag(*tag_id).await? {
+            Some(tag) if tag.user == Some(user_id) && tag.deleted_at.is_none() => (),
+            _ => return Err(ValidationErrors::field(field, "must all be your tags").into()),
+        }
+    }
+    Ok(())
+}
+
@@ -0,0 +1,110 @@
+use mongodb::bson;
+use mongodb::bson::Document;
+use mongodb::error::Error;
+use rocket::http::{Header, Status};
+use rocket::request::{Request, Outcome, FromRequest};
+use rocket::serde::json::Json;
+use std::fmt;
+
+use crate::UpdatedData;
+use crate::validation::{ActionError, ValidationErrors};
+
+// A document's version is its updated_at, which every write already sets.
+// The ETag is that time in milliseconds, the same number clients get in updated_at.$date.$numberLong.
+pub fn etag(updated_at: Option<bson::DateTime>) -> String {
+    format!("\"{}\"", updated_at.map(|_updated_at| _updated_at.timestamp_millis()).unwrap_or(0))
+}
+
+// Two writes in the same millisecond would otherwise share a version
+pub fn next_updated_at(previous: Option<bson::DateTime>) -> bson::DateTime {
+    let now = bson::DateTime::now();
+    match previous {
+        Some(_previous) if _previous >= now => bson::DateTime::from_millis(_previous.timestamp_millis() + 1),
+        _ => now,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct IfMatch(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfMatch {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(IfMatch(request.headers().get_one("If-Match").map(String::from)))
+    }
+}
+
+impl IfMatch {
+    // Clients that don't send If-Match keep the old last-write-wins behaviour
+    pub fn matches(&self, updated_at: Option<bson::DateTime>) -> bool {
+        let Some(if_match) = &self.0 else {
+            return true
+        };
+        let current_etag = etag(updated_at);
+        if_match.split(",").map(|tag| tag.trim()).any(|tag| tag == "*" || tag.trim_start_matches("W/") == current_etag)
+    }
+}
+
+#[derive(Debug)]
+pub enum UpdateError {
+    // The client's copy is stale; holds the current server copy
+    VersionConflict(Document),
+    // The document doesn't exist, belongs to someone else or is in the trash
+    NotFound,
+    Invalid(ValidationErrors),
+    DatabaseError(Error),
+}
+
+impl From<ActionError> for UpdateError {
+    fn from(error: ActionError) -> Self {
+        match error {
+            ActionError::Invalid(errors) => UpdateError::Invalid(errors),
+            ActionError::DatabaseError(error) => UpdateError::DatabaseError(error),
+        }
+    }
+}
+
+impl From<Error> for UpdateError {
+    fn from(error: Error) -> Self {
+        UpdateError::DatabaseError(error)
+    }
+}
+
+impl fmt::Display for UpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UpdateError::VersionConflict(_) => write!(f, "version conflict"),
+            UpdateError::NotFound => write!(f, "not found"),
+            UpdateError::Invalid(_) => write!(f, "invalid fields"),
+            UpdateError::DatabaseError(error) => write!(f, "database error: {}", error),
+        }
+    }
+}
+
+impl From<bson::ser::Error> for UpdateError {
+    fn from(error: bson::ser::Error) -> Self {
+        UpdateError::DatabaseError(error.into())
+    }
+}
+
+#[derive(Responder)]
+pub enum UpdateResponse {
+    Updated(Json<UpdatedData>, Header<'static>),
+    #[response(status = 409)]
+    Conflict(Json<Document>, Header<'static>),
+    Invalid(ValidationErrors),
+    Failed(Status),
+}
+
+impl UpdateResponse {
+    pub fn updated(updated_data: UpdatedData, updated_at: bson::DateTime) -> UpdateResponse {
+        UpdateResponse::Updated(Json(updated_data), Header::new("ETag", etag(Some(updated_at))))
+    }
+
+    pub fn conflict(current: Document) -> UpdateResponse {
+        let updated_at = current.get_datetime("updated_at").ok().copied();
+        UpdateResponse::Conflict(Json(current), Header::new("ETag", etag(updated_at)))
+    }
+}
@@ -0,0 +1,528 @@
+use futures::stream::TryStreamExt;
+use hmac::{Hmac, Mac};
+use mongodb::{Client, Database, options::ClientOptions, options::FindOneAndUpdateOptions};
+use mongodb::bson;
+use mongodb::bson::Document;
+use mongodb::error::Error;
+use reqwest::redirect::Policy;
+use rocket::State;
+use rocket::http::Status;
+use rocket::serde::{Serialize, Deserialize, json::Json};
+use rocket::tokio::net::lookup_host;
+use sha2::Sha256;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tracing::{error, warn};
+
+use crate::{ReadParams, Token};
+use crate::config::AppConfig;
+use crate::metrics;
+use crate::audit::RequestMeta;
+use crate::repository::{Repository, Store};
+use crate::undo::OperationLog;
+use crate::validation::{Valid, Validate, ValidationErrors};
+
+pub const TASK_CREATED: &str = "task.created";
+pub const TASK_UPDATED: &str = "task.updated";
+pub const TASK_DELETED: &str = "task.deleted";
+pub const EVENT_CREATED: &str = "event.created";
+
+const WEBHOOK_EVENTS: [&str; 4] = [TASK_CREATED, TASK_UPDATED, TASK_DELETED, EVENT_CREATED];
+
+const MAX_DELIVERY_ATTEMPTS: u32 = 8;
+pub const DELETED_WEBHOOK_ERROR: &str = "Webhook was deleted or deactivated";
+const BASE_RETRY_DELAY_SECONDS: i64 = 30;
+const MAX_RETRY_DELAY_SECONDS: i64 = 60 * 60;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug)]
+pub enum WebhookError {
+    // A webhook that doesn't exist or belongs to someone else
+    NotFound,
+    Database(Error),
+}
+
+impl From<Error> for WebhookError {
+    fn from(error: Error) -> Self {
+        WebhookError::Database(error)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct Webhook {
+    _id: bson::oid::ObjectId,
+    user: bson::oid::ObjectId,
+    url: String,
+    secret: String,
+    events: Vec<String>,
+    active: bool,
+    created_at: bson::DateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct NewWebhookData {
+    url: String,
+    events: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct WebhookDelivery {
+    _id: bson::oid::ObjectId,
+    webhook: bson::oid::ObjectId,
+    user: bson::oid::ObjectId,
+    event: String,
+    payload: String,
+    // pending -> sending -> succeeded, or back to pending until we give up and mark it failed
+    status: String,
+    attempts: u32,
+    next_attempt_at: bson::DateTime,
+    last_status_code: Option<u16>,
+    last_error: Option<String>,
+    created_at: bson::DateTime,
+    delivered_at: Option<bson::DateTime>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct DeletedWebhooks {
+    #[serde(rename = "deletedCount")]
+    deleted_count: u64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "rocket::serde")]
+struct WebhookPayload<'a> {
+    id: bson::oid::ObjectId,
+    event: &'a str,
+    created_at: bson::DateTime,
+    data: &'a Document,
+}
+
+impl Validate for NewWebhookData {
+    fn validate(&self, errors: &mut ValidationErrors) {
+        let url = reqwest::Url::parse(&self.url).ok();
+        errors.check("url", url.as_ref().is_some_and(|url| url.scheme() == "https"), "must be an https URL");
+        errors.check("url", url.as_ref().is_none_or(is_public_url), "must point at a public host");
+        errors.check("events", !self.events.is_empty(), "can't be empty");
+        errors.check("events", self.events.iter().all(|event| WEBHOOK_EVENTS.contains(&event.as_str())), "must only contain known events");
+    }
+}
+
+// For actions to call once their change is saved. Losing one delivery is better than failing a request
+// whose write already went through, so a failure is only logged.
+pub async fn notify<T: Serialize>(repository: &dyn Repository, user_id: bson::oid::ObjectId, event: &str, document: &T) {
+    let data = match bson::to_document(document) {
+        Ok(_data) => _data,
+        Err(error) => {
+            error!(?error, event, "Couldn't serialize webhook payload");
+            return
+        },
+    };
+    if let Err(error) = repository.enqueue_webhook(user_id, event, data).await {
+        error!(?error, event, "Couldn't queue webhook deliveries");
+    }
+}
+
+// Queues a delivery for every active subscription of the user that listens for this event.
+// The actual HTTP requests happen in the background worker so mutations never wait on them.
+pub async fn enqueue(db: &Database, user_id: bson::oid::ObjectId, event: &str, data: Document) -> Result<(), Error> {
+    let webhooks = db.collection::<Webhook>("webhooks");
+    let deliveries = db.collection::<WebhookDelivery>("webhook_deliveries");
+
+    let webhooks_filter = bson::doc! {
+        "user": user_id,
+        "active": true,
+        "events": event,
+    };
+    let subscribed_webhooks: Vec<Webhook> = webhooks.find(webhooks_filter, None).await?.try_collect().await?;
+
+    let new_deliveries = new_deliveries(subscribed_webhooks, user_id, event, &data);
+    if !new_deliveries.is_empty() {
+        deliveries.insert_many(new_deliveries, None).await?;
+    }
+
+    Ok(())
+}
+
+// One pending delivery for each of the webhooks, due right away
+pub fn new_deliveries(webhooks: Vec<Webhook>, user_id: bson::oid::ObjectId, event: &str, data: &Document) -> Vec<WebhookDelivery> {
+    let mut new_deliveries = Vec::new();
+    for webhook in webhooks {
+        let delivery_id = bson::oid::ObjectId::new();
+        let now = bson::DateTime::now();
+        let payload = WebhookPayload {
+            id: delivery_id,
+            event,
+            created_at: now,
+            data,
+        };
+        let Ok(serialized_payload) = rocket::serde::json::to_string(&payload) else {
+            continue
+        };
+        new_deliveries.push(WebhookDelivery {
+            _id: delivery_id,
+            webhook: webhook._id,
+            user: user_id,
+            event: event.to_string(),
+            payload: serialized_payload,
+            status: String::from("pending"),
+            attempts: 0,
+            next_attempt_at: now,
+            last_status_code: None,
+            last_error: None,
+            created_at: now,
+            delivered_at: None,
+        });
+    }
+    new_deliveries
+}
+
+// Loopback, private, link-local and the like can't be reached from outside, so a webhook pointing there
+// would only be probing our own network
+fn is_public_address(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(v4) => {
+            let [first, second, ..] = v4.octets();
+            // 100.64.0.0/10 is carrier-grade NAT
+            let shared = first == 100 && (64..128).contains(&second);
+            !(v4.is_loopback() || v4.is_private() || v4.is_link_local() || v4.is_unspecified()
+                || v4.is_broadcast() || v4.is_documentation() || v4.is_multicast() || shared)
+        },
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return is_public_address(IpAddr::V4(v4))
+            }
+            let first_segment = v6.segments()[0];
+            // fc00::/7 is unique local, fe80::/10 link-local
+            let unique_local = first_segment & 0xfe00 == 0xfc00;
+            let link_local = first_segment & 0xffc0 == 0xfe80;
+            !(v6.is_loopback() || v6.is_unspecified() || v6.is_multicast() || unique_local || link_local)
+        },
+    }
+}
+
+// Only catches what the URL gives away; hostnames are checked again when they're resolved for a delivery
+fn is_public_url(url: &reqwest::Url) -> bool {
+    let Some(host) = url.host_str() else {
+        return false
+    };
+    // IPv6 hosts keep their brackets in URLs
+    match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(address) => is_public_address(address),
+        Err(_) => {
+            let host = host.to_ascii_lowercase();
+            host != "localhost" && !host.ends_with(".localhost")
+        },
+    }
+}
+
+// Resolves the host once and pins the client to those addresses, so a host that's public when checked
+// can't be switched to an internal address before the request connects
+async fn delivery_client(url: &reqwest::Url) -> Result<reqwest::Client, String> {
+    if url.scheme() != "https" || !is_public_url(url) {
+        return Err(String::from("Webhook URL must be https and point at a public host"))
+    }
+    let (Some(host), Some(port)) = (url.host_str(), url.port_or_known_default()) else {
+        return Err(String::from("Webhook URL has no host"))
+    };
+    let addresses: Vec<SocketAddr> = match lookup_host((host.trim_start_matches('[').trim_end_matches(']'), port)).await {
+        Ok(_addresses) => _addresses.collect(),
+        Err(error) => return Err(error.to_string()),
+    };
+    if addresses.is_empty() || addresses.iter().any(|address| !is_public_address(address.ip())) {
+        return Err(String::from("Webhook host doesn't resolve to a public address"))
+    }
+
+    // Redirects would skip these checks, so a 3xx counts as a failed delivery
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .redirect(Policy::none())
+        .resolve_to_addrs(host, &addresses)
+        .build()
+        .map_err(|error| error.to_string())
+}
+
+fn sign(secret: &str, timestamp: i64, payload: &str) -> Option<String> {
+    let Ok(mut mac) = HmacSha256::new_from_slice(secret.as_bytes()) else {
+        return None
+    };
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(payload.as_bytes());
+    Some(hex::encode(mac.finalize().into_bytes()))
+}
+
+fn retry_delay(attempts: u32) -> i64 {
+    let exponent = attempts.saturating_sub(1).min(16);
+    (BASE_RETRY_DELAY_SECONDS * 2_i64.pow(exponent)).min(MAX_RETRY_DELAY_SECONDS)
+}
+
+async fn attempt_delivery(webhook: &Webhook, delivery: &WebhookDelivery) -> Result<u16, (Option<u16>, String)> {
+    // Webhooks created before these checks existed may still point anywhere
+    let Ok(url) = reqwest::Url::parse(&webhook.url) else {
+        return Err((None, String::from("Webhook URL is invalid")))
+    };
+    let http_client = match delivery_client(&url).await {
+        Ok(_http_client) => _http_client,
+        Err(error) => return Err((None, error)),
+    };
+
+    let timestamp = chrono::Utc::now().timestamp();
+    let Some(signature) = sign(&webhook.secret, timestamp, &delivery.payload) else {
+        return Err((None, String::from("Could not sign payload")))
+    };
+
+    let response = http_client.post(url)
+        .header("Content-Type", "application/json")
+        .header("X-Mossy-Event", &delivery.event)
+        .header("X-Mossy-Delivery", delivery._id.to_hex())
+        .header("X-Mossy-Timestamp", timestamp.to_string())
+        .header("X-Mossy-Signature", format!("sha256={}", signature))
+        .body(delivery.payload.clone())
+        .send()
+        .await;
+
+    match response {
+        Ok(_response) if _response.status().is_success() => Ok(_response.status().as_u16()),
+        Ok(_response) => Err((Some(_response.status().as_u16()), format!("Unexpected status {}", _response.status()))),
+        Err(error) => Err((None, error.to_string())),
+    }
+}
+
+async fn deliver_pending(db: &Database) -> Result<(), Error> {
+    let webhooks = db.collection::<Webhook>("webhooks");
+    let deliveries = db.collection::<WebhookDelivery>("webhook_deliveries");
+
+    loop {
+        // Claim one due delivery at a time so several workers could share the queue
+        let claim_filter = bson::doc! {
+            "status": "pending",
+            "next_attempt_at": {
+                "$lte": bson::DateTime::now(),
+            },
+        };
+        let claim_update = bson::doc! {
+            "$set": {
+                "status": "sending",
+            }
+        };
+        let claim_options = FindOneAndUpdateOptions::builder().sort(bson::doc! { "next_attempt_at": 1 }).build();
+        let Some(delivery) = deliveries.find_one_and_update(claim_filter, claim_update, claim_options).await? else {
+            return Ok(())
+        };
+
+        let webhook_filter = bson::doc! {
+            "_id": delivery.webhook,
+            "active": true,
+        };
+        let result = match webhooks.find_one(webhook_filter, None).await? {
+            Some(webhook) => attempt_delivery(&webhook, &delivery).await,
+            None => Err((None, String::from(DELETED_WEBHOOK_ERROR))),
+        };
+
+        let attempts = delivery.attempts + 1;
+        let now = bson::DateTime::now();
+        let updated_delivery = match result {
+            Ok(status_code) => bson::doc! {
+                "$set": {
+                    "status": "succeeded",
+                    "attempts": attempts,
+                    "last_status_code": u32::from(status_code),
+                    "last_error": bson::Bson::Null,
+                    "delivered_at": now,
+                }
+            },
+            Err((status_code, error)) => {
+                let status = if attempts >= MAX_DELIVERY_ATTEMPTS { "failed" } else { "pending" };
+                let next_attempt_at = bson::DateTime::from_millis(now.timestamp_millis() + retry_delay(attempts) * 1000);
+                bson::doc! {
+                    "$set": {
+                        "status": status,
+                        "attempts": attempts,
+                        "next_attempt_at": next_attempt_at,
+                        "last_status_code": status_code.map(u32::from),
+                        "last_error": error,
+                    }
+                }
+            },
+        };
+        deliveries.update_one(bson::doc! { "_id": delivery._id }, updated_delivery, None).await?;
+    }
+}
+
+pub async fn run_worker(config: AppConfig) {
+    let mut client_options = match ClientOptions::parse(&config.database_uri).await {
+        Ok(_client_options) => _client_options,
+        Err(error) => {
+            warn!(?error, "Webhook deliveries disabled");
+            return
+        }
+    };
+    client_options.app_name = Some("mossy".to_string());
+    client_options.command_event_handler = Some(metrics::command_timer());
+    let client = match Client::with_options(client_options) {
+        Ok(_client) => _client,
+        Err(error) => {
+            warn!(?error, "Webhook deliveries disabled");
+            return
+        }
+    };
+    let db = client.database(&config.database_name);
+    let deliveries = db.collection::<WebhookDelivery>("webhook_deliveries");
+
+    // Anything still marked as sending was interrupted by a restart, so try it again
+    let interrupted_filter = bson::doc! { "status": "sending" };
+    let interrupted_update = bson::doc! { "$set": { "status": "pending" } };
+    if let Err(error) = deliveries.update_many(interrupted_filter, interrupted_update, None).await {
+        error!(?error, "Couldn't requeue interrupted deliveries");
+    }
+
+    let mut interval = rocket::tokio::time::interval(Duration::from_secs(5));
+    loop {
+        interval.tick().await;
+        if let Err(error) = deliver_pending(&db).await {
+            error!(?error, "Couldn't deliver pending webhooks");
+        }
+    }
+}
+
+async fn read_webhooks_action(token: Token, store: &Store) -> Result<Vec<Webhook>, WebhookError> {
+    let repository = store.backend.as_ref();
+
+    let user = token.0;
+
+    let webhooks_list = repository.find_user_webhooks(user._id).await?;
+
+    Ok(webhooks_list)
+}
+
+async fn create_webhook_action(token: Token, webhook_data: NewWebhookData, store: &Store, meta: &RequestMeta) -> Result<Webhook, WebhookError> {
+    let repository = store.backend.as_ref();
+
+    let user = token.0;
+
+    let new_webhook = Webhook {
+        _id: bson::oid::ObjectId::new(),
+        user: user._id,
+        url: webhook_data.url,
+        secret: format!("whsec_{}", bson::uuid::Uuid::new().to_string().replace("-", "")),
+        events: webhook_data.events,
+        active: true,
+        created_at: bson::DateTime::now(),
+    };
+
+    repository.insert_webhook(&new_webhook).await?;
+
+    let mut operation = OperationLog::audit_only();
+    operation.created("webhooks", new_webhook._id, &new_webhook)?;
+    operation.record(repository, user._id, "create_webhook", meta).await;
+
+    Ok(new_webhook)
+}
+
+async fn delete_webhooks_action(token: Token, webhooks_data: Vec<bson::oid::ObjectId>, store: &Store, meta: &RequestMeta) -> Result<DeletedWebhooks, WebhookError> {
+    let repository = store.backend.as_ref();
+
+    let user = token.0;
+
+    // Make sure the webhook to delete belongs to the user
+    for webhook in repository.find_webhooks_by_ids(&webhooks_data).await? {
+        if webhook.user != user._id {
+            return Err(WebhookError::NotFound)
+        };
+    }
+
+    let mut operation = OperationLog::audit_only();
+    for webhook_id in webhooks_data.iter() {
+        operation.snapshot_document(repository, "webhooks", *webhook_id).await?;
+    }
+
+    let deleted_count = repository.delete_webhooks(&webhooks_data).await?;
+    for webhook_id in webhooks_data.iter() {
+        operation.wrote("webhooks", *webhook_id, None);
+    }
+
+    operation.record(repository, user._id, "delete_webhooks", meta).await;
+
+    Ok(DeletedWebhooks { deleted_count })
+}
+
+async fn read_webhook_deliveries_action(token: Token, webhook: Option<bson::oid::ObjectId>, params: ReadParams, store: &Store) -> Result<Vec<WebhookDelivery>, WebhookError> {
+    let limit = params.limit.unwrap_or(0);
+    let offset = params.offset.unwrap_or(0);
+
+    let repository = store.backend.as_ref();
+
+    let user = token.0;
+
+    let deliveries_list = repository.find_webhook_deliveries(user._id, webhook, offset, limit).await?;
+
+    Ok(deliveries_list)
+}
+
+fn webhook_error_status(error: WebhookError) -> Status {
+    match error {
+        WebhookError::NotFound => Status::NotFound,
+        WebhookError::Database(error) => {
+            error!(?error, "Webhook request failed");
+            Status::InternalServerError
+        },
+    }
+}
+
+#[get("/api/webhooks", format="json")]
+pub async fn read_webhooks(token: Token, store: &State<Store>) -> Result<Json<Vec<Webhook>>, Status> {
+    let webhooks = read_webhooks_action(token, store).await;
+
+    match webhooks {
+        Ok(webhooks_result) => Ok(Json(webhooks_result)),
+        Err(error) => Err(webhook_error_status(error)),
+    }
+}
+
+#[post("/api/webhooks", format="json", data="<webhook>")]
+pub async fn create_webhook(token: Token, webhook: Valid<NewWebhookData>, store: &State<Store>, meta: RequestMeta) -> Result<Json<Webhook>, Status> {
+    let deserialized_webhook = webhook.into_inner();
+    let webhook = create_webhook_action(token, deserialized_webhook, store, &meta).await;
+
+    match webhook {
+        Ok(webhook_result) => Ok(Json(webhook_result)),
+        Err(error) => Err(webhook_error_status(error)),
+    }
+}
+
+#[delete("/api/webhooks", format="json", data="<webhooks>")]
+pub async fn delete_webhooks(token: Token, webhooks: Json<Vec<bson::oid::ObjectId>>, store: &State<Store>, meta: RequestMeta) -> Result<Json<DeletedWebhooks>, Status> {
+    let deserialized_webhooks_list = webhooks.into_inner();
+    let webhooks = delete_webhooks_action(token, deserialized_webhooks_list, store, &meta).await;
+
+    match webhooks {
+        Ok(webhooks_result) => Ok(Json(webhooks_result)),
+        Err(error) => Err(webhook_error_status(error)),
+    }
+}
+
+#[get("/api/webhooks/deliveries?<webhook>&<limit>&<offset>", format="json")]
+pub async fn read_webhook_deliveries(token: Token, webhook: Option<&str>, limit: Option<u32>, offset: Option<u32>, store: &State<Store>) -> Result<Json<Vec<WebhookDelivery>>, Status> {
+    let webhook_id = match webhook {
+        Some(_webhook) => match bson::oid::ObjectId::parse_str(_webhook) {
+            Ok(_webhook_id) => Some(_webhook_id),
+            Err(_) => return Err(Status::BadRequest),
+        },
+        None => None,
+    };
+    let params = ReadParams {
+        limit,
+        offset,
+    };
+    let deliveries = read_webhook_deliveries_action(token, webhook_id, params, store).await;
+
+    match deliveries {
+        Ok(deliveries_result) => Ok(Json(deliveries_result)),
+        Err(error) => Err(webhook_error_status(error)),
+    }
+}